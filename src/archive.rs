@@ -0,0 +1,281 @@
+use std::path::Path;
+
+use crate::{Result, Error};
+use crate::disk::{Disk, Filesystem as _};
+
+const BLOCK: usize = 512;
+
+// What an archive has at a single path
+#[derive(Debug, PartialEq)]
+enum Stored {
+    Dir { mode: u32 },
+    Symlink(String),
+    File { content: Vec<u8>, mode: u32 },
+}
+
+// Packs everything below `dir` into a plain ustar archive, so it can
+// still be looked at with `tar` outside of dotgirl. Paths inside are
+// relative to `dir` and always separated by `/`.
+pub fn pack(dir: &Path) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+
+    for it in Disk::walk(dir)? {
+        let relative = it
+            .strip_prefix(dir)
+            .expect("Invalid: walk returned a path outside of the directory")
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join("/");
+
+        if Disk::is_symlink(&it) {
+            let target = format!("{}", Disk::readlink(&it)?.display());
+            out.extend(header(&relative, b'2', 0o777, 0, &target)?);
+        } else if Disk::is_dir(&it) {
+            out.extend(header(&format!("{}/", relative), b'5', Disk::mode(&it)?, 0, "")?);
+        } else {
+            let content = Disk::get_bytes(&it)?;
+            out.extend(header(&relative, b'0', Disk::mode(&it)?, content.len(), "")?);
+            out.extend(content);
+            pad(&mut out);
+        }
+    }
+
+    // the end is marked by two empty blocks
+    out.extend(vec![0; BLOCK * 2]);
+
+    Ok(out)
+}
+
+// Extracts an archive made by `pack` into `dest`, which is created
+pub fn unpack(archive: &[u8], dest: &Path) -> Result<()> {
+    Disk::mkdir_all(dest)?;
+
+    for (relative, stored) in read(archive)? {
+        let path = dest.join(relative);
+        match stored {
+            Stored::Dir { mode } => {
+                Disk::mkdir_all(&path)?;
+                Disk::set_mode(&path, mode)?;
+            },
+            Stored::Symlink(target) => Disk::symlink(&target, &path)?,
+            Stored::File { content, mode } => {
+                Disk::put_bytes(&path, &content)?;
+                Disk::set_mode(&path, mode)?;
+            },
+        };
+    }
+
+    Ok(())
+}
+
+// Whether `dest` still has exactly what `unpack` put there, with
+// nothing changed, added or removed since
+pub fn is_unpacked(archive: &[u8], dest: &Path) -> Result<bool> {
+    let stored = read(archive)?;
+
+    let paths = Disk::walk(dest)?
+        .into_iter()
+        .filter(|it| it != dest)
+        .count();
+
+    if paths != stored.len() {
+        return Ok(false);
+    }
+
+    for (relative, stored) in stored {
+        let path = dest.join(relative);
+        let current = if Disk::is_symlink(&path) {
+            Stored::Symlink(format!("{}", Disk::readlink(&path)?.display()))
+        } else if Disk::is_dir(&path) {
+            Stored::Dir { mode: Disk::mode(&path)? }
+        } else if Disk::is_file(&path) {
+            Stored::File { content: Disk::get_bytes(&path)?, mode: Disk::mode(&path)? }
+        } else {
+            return Ok(false);
+        };
+
+        if current != stored {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+// Every path in `bytes` with what's stored there, in order
+fn read(bytes: &[u8]) -> Result<Vec<(String, Stored)>> {
+    let mut entries: Vec<(String, Stored)> = Vec::new();
+    let mut offset = 0;
+    while offset + BLOCK <= bytes.len() {
+        let header = &bytes[offset..offset + BLOCK];
+        offset += BLOCK;
+
+        // we're at the end
+        if header.iter().all(|it| *it == 0) {
+            break;
+        }
+
+        let name = field(&header[0..100])?;
+        let mode = u32::from_str_radix(field(&header[100..108])?.trim(), 8)
+            .map_err(|_| Error::Simple("invalid mode in archive"))?;
+        let size = usize::from_str_radix(field(&header[124..136])?.trim(), 8)
+            .map_err(|_| Error::Simple("invalid size in archive"))?;
+        let link = field(&header[157..257])?;
+
+        // make sure nothing ends up outside of `dest`
+        let relative = name.trim_end_matches('/');
+        if relative.is_empty() || relative.starts_with('/') || relative.split('/').any(|it| it == "..") {
+            return Err(Error::Simple("invalid path in archive"));
+        }
+
+        // a link from earlier in the archive could lead anywhere, so
+        // nothing may be written through it or replace it
+        let through_link = entries.iter().any(|(path, stored)| match stored {
+            Stored::Symlink(_) => relative == path || relative.starts_with(&format!("{}/", path)),
+            _ => false,
+        });
+
+        if through_link {
+            return Err(Error::Simple("archive writes through a link inside of it"));
+        }
+
+        let stored = match header[156] {
+            b'5' => Stored::Dir { mode },
+            b'2' => Stored::Symlink(String::from(link)),
+            b'0' | 0 => {
+                let end = offset + size;
+                if end > bytes.len() {
+                    return Err(Error::Simple("archive ended early"));
+                }
+
+                let content = bytes[offset..end].to_vec();
+                offset += size.div_ceil(BLOCK) * BLOCK;
+                Stored::File { content, mode }
+            },
+            _ => return Err(Error::Simple("unsupported entry in archive")),
+        };
+
+        entries.push((String::from(relative), stored));
+    }
+
+    Ok(entries)
+}
+
+fn header(name: &str, kind: u8, mode: u32, size: usize, link: &str) -> Result<Vec<u8>> {
+    if name.len() > 100 || link.len() > 100 {
+        return Err(Error::Simple("path is too long to archive"));
+    }
+
+    let mut header = vec![0; BLOCK];
+    put(&mut header, 0, name);
+    put(&mut header, 100, &format!("{:07o}", mode));
+    put(&mut header, 108, "0000000");
+    put(&mut header, 116, "0000000");
+    put(&mut header, 124, &format!("{:011o}", size));
+    put(&mut header, 136, &format!("{:011o}", 0));
+    header[156] = kind;
+    put(&mut header, 157, link);
+    put(&mut header, 257, "ustar");
+    put(&mut header, 263, "00");
+
+    // the checksum is calculated as if its own field was all spaces
+    put(&mut header, 148, "        ");
+    let checksum = header.iter().map(|it| u32::from(*it)).sum::<u32>();
+    put(&mut header, 148, &format!("{:06o}\0 ", checksum));
+
+    Ok(header)
+}
+
+fn put(header: &mut [u8], at: usize, value: &str) {
+    header[at..at + value.len()].copy_from_slice(value.as_bytes());
+}
+
+fn pad(out: &mut Vec<u8>) {
+    let rest = out.len() % BLOCK;
+    if rest != 0 {
+        out.extend(vec![0; BLOCK - rest]);
+    }
+}
+
+// fields are padded with nul bytes
+fn field(raw: &[u8]) -> Result<&str> {
+    let end = raw.iter().position(|it| *it == 0).unwrap_or(raw.len());
+    std::str::from_utf8(&raw[..end]).map_err(|_| Error::Simple("invalid header in archive"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_should_round_trip() {
+        Disk::mkdir_all("/src/sub/empty").unwrap();
+        Disk::put("/src/config", "hello config").unwrap();
+        Disk::put("/src/sub/config", &"long ".repeat(200)).unwrap();
+        Disk::put_bytes("/src/sub/font.ttf", &[0, 159, 146, 150, 255]).unwrap();
+        Disk::put("/src/run.sh", "#!/bin/sh").unwrap();
+        Disk::set_mode("/src/run.sh", 0o755).unwrap();
+        Disk::symlink("../config", "/src/sub/link").unwrap();
+
+        let archive = pack(Path::new("/src")).unwrap();
+        assert_eq!(archive.len() % BLOCK, 0);
+
+        unpack(&archive, Path::new("/dest")).unwrap();
+        assert_eq!(Disk::get("/dest/config").unwrap(), "hello config");
+        assert_eq!(Disk::get("/dest/sub/config").unwrap(), "long ".repeat(200));
+        assert_eq!(Disk::get_bytes("/dest/sub/font.ttf").unwrap(), vec![0, 159, 146, 150, 255]);
+        assert_eq!(Disk::mode("/dest/run.sh").unwrap(), 0o755);
+        assert_eq!(Disk::mode("/dest/config").unwrap(), 0o644);
+        assert!(Disk::is_dir("/dest/sub/empty"));
+        assert_eq!(Disk::readlink("/dest/sub/link").unwrap(), Path::new("../config"));
+
+        Disk::clear();
+    }
+
+    #[test]
+    fn is_unpacked_should_notice_changes() {
+        Disk::mkdir_all("/src/sub").unwrap();
+        Disk::put("/src/sub/config", "hello config").unwrap();
+
+        let archive = pack(Path::new("/src")).unwrap();
+        unpack(&archive, Path::new("/dest")).unwrap();
+        assert!(is_unpacked(&archive, Path::new("/dest")).unwrap());
+
+        Disk::put("/dest/sub/config", "edited").unwrap();
+        assert!(!is_unpacked(&archive, Path::new("/dest")).unwrap());
+
+        Disk::put("/dest/sub/config", "hello config").unwrap();
+        Disk::put("/dest/sub/new", "added").unwrap();
+        assert!(!is_unpacked(&archive, Path::new("/dest")).unwrap());
+
+        Disk::clear();
+    }
+
+    #[test]
+    fn unpack_should_refuse_writing_through_links() {
+        let mut archive = header("escape", b'2', 0o777, 0, "/elsewhere").unwrap();
+        archive.extend(header("escape/config", b'0', 0o644, 0, "").unwrap());
+        archive.extend(vec![0; BLOCK * 2]);
+
+        Disk::mkdir_all("/elsewhere").unwrap();
+        let result = unpack(&archive, Path::new("/dest"));
+        assert!(result.is_err());
+        assert!(!Disk::symlink_exists("/elsewhere/config"));
+        assert!(!Disk::symlink_exists("/dest/escape"));
+
+        Disk::clear();
+    }
+
+    #[test]
+    fn unpack_should_refuse_paths_outside_dest() {
+        let mut archive = header("../escape", b'0', 0o644, 0, "").unwrap();
+        archive.extend(vec![0; BLOCK * 2]);
+
+        let result = unpack(&archive, Path::new("/dest"));
+        assert!(result.is_err());
+        assert!(!Disk::symlink_exists("/escape"));
+
+        Disk::clear();
+    }
+}
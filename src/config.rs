@@ -0,0 +1,209 @@
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+use crate::Result;
+use crate::disk::{Disk, Filesystem as _};
+
+const CONFIG_FILE: &str = "config.toml";
+
+// What to do when something already exists where we want to link
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    Prompt,
+    Skip,
+    Overwrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+// User configuration, read from `config.toml` inside the storage dir.
+// Every field is optional in the file and falls back to its default.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub conflict: ConflictPolicy,
+    // move files out of the way into storage before overwriting them
+    pub backup: bool,
+    pub color: ColorChoice,
+    pub editor: Option<String>,
+    // trim leading dots from names in storage, turning this off
+    // keeps `.config` and `config` from ending up as the same name
+    pub trim_dots: bool,
+    // show what `add` is about to do and ask before doing it
+    pub confirm_add: bool,
+    // refuse stored names that only differ by case, since storage on
+    // macOS and windows can't tell `Config` and `config` apart
+    pub ignore_case: bool,
+    // the dir inside storage that bundles are kept in
+    pub bundle_dir: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            conflict: ConflictPolicy::Prompt,
+            backup: false,
+            color: ColorChoice::Auto,
+            editor: None,
+            trim_dots: true,
+            confirm_add: false,
+            ignore_case: cfg!(any(target_os = "macos", windows)),
+            bundle_dir: String::from("bundle"),
+        }
+    }
+}
+
+// Values given on the command line, these always win over the config file
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    pub conflict: Option<ConflictPolicy>,
+    pub backup: Option<bool>,
+    pub color: Option<ColorChoice>,
+    pub editor: Option<String>,
+}
+
+impl Config {
+    pub fn load(storage: &Path) -> Result<Self> {
+        let path = storage.join(CONFIG_FILE);
+        if !Disk::is_file(&path) {
+            return Ok(Default::default());
+        }
+
+        let raw = Disk::get(&path)?;
+        let parsed = toml::from_str::<Config>(&raw)?;
+        crate::util::validate_name(&parsed.bundle_dir)?;
+
+        // the bundle dir sits next to everything else we keep in storage
+        let reserved = [crate::IMPORT_DIR, crate::BACKUP_DIR, crate::LOCK_FILE, CONFIG_FILE];
+        if reserved.contains(&parsed.bundle_dir.as_str()) {
+            return Err(crate::Error::InvalidName(parsed.bundle_dir));
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn merge(self, overrides: &ConfigOverrides) -> Self {
+        Config {
+            conflict: overrides.conflict.unwrap_or(self.conflict),
+            backup: overrides.backup.unwrap_or(self.backup),
+            color: overrides.color.unwrap_or(self.color),
+            editor: overrides.editor.clone().or(self.editor),
+            trim_dots: self.trim_dots,
+            confirm_add: self.confirm_add,
+            ignore_case: self.ignore_case,
+            bundle_dir: self.bundle_dir,
+        }
+    }
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = crate::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw {
+            "prompt" => Ok(ConflictPolicy::Prompt),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            _ => Err(crate::Error::Simple("invalid conflict policy")),
+        }
+    }
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = crate::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(crate::Error::Simple("invalid color choice")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn load_should_fill_in_defaults() {
+        let storage = PathBuf::from("/dotgirl");
+        Disk::mkdir_all(&storage).unwrap();
+
+        // no config file at all
+        let config = Config::load(&storage).unwrap();
+        assert_eq!(config, Config::default());
+
+        // a partial config only overrides what's in it
+        Disk::put(storage.join(CONFIG_FILE), "conflict = \"skip\"\neditor = \"vim\"\n").unwrap();
+        let config = Config::load(&storage).unwrap();
+
+        assert_eq!(config.conflict, ConflictPolicy::Skip);
+        assert_eq!(config.editor, Some(String::from("vim")));
+        assert!(!config.backup);
+        assert_eq!(config.color, ColorChoice::Auto);
+
+        Disk::clear();
+    }
+
+    #[test]
+    fn load_should_refuse_reserved_bundle_dirs() {
+        let storage = PathBuf::from("/dotgirl");
+        Disk::mkdir_all(&storage).unwrap();
+
+        for name in &["import", "backup", "lock.toml", "config.toml"] {
+            Disk::put(storage.join(CONFIG_FILE), &format!("bundle_dir = \"{}\"\n", name)).unwrap();
+            assert!(Config::load(&storage).is_err());
+        }
+
+        Disk::clear();
+    }
+
+    #[test]
+    fn merge_should_prefer_overrides() {
+        let config = Config {
+            conflict: ConflictPolicy::Skip,
+            backup: true,
+            color: ColorChoice::Never,
+            editor: Some(String::from("vim")),
+            trim_dots: false,
+            confirm_add: false,
+            ignore_case: true,
+            bundle_dir: String::from("dotfiles"),
+        };
+
+        let overrides = ConfigOverrides {
+            conflict: Some(ConflictPolicy::Overwrite),
+            backup: Some(false),
+            ..Default::default()
+        };
+
+        let merged = config.merge(&overrides);
+
+        assert_eq!(merged.conflict, ConflictPolicy::Overwrite);
+        assert!(!merged.backup);
+        assert_eq!(merged.color, ColorChoice::Never);
+        assert_eq!(merged.editor, Some(String::from("vim")));
+        assert!(!merged.trim_dots);
+        assert!(merged.ignore_case);
+        assert_eq!(merged.bundle_dir, "dotfiles");
+    }
+
+    #[test]
+    fn merge_should_prefer_the_editor_flag() {
+        let config = Config { editor: Some(String::from("vim")), ..Default::default() };
+        let overrides = ConfigOverrides { editor: Some(String::from("nano")), ..Default::default() };
+
+        assert_eq!(config.merge(&overrides).editor, Some(String::from("nano")));
+    }
+}
@@ -1,82 +1,474 @@
-use crate::Result;
-use std::{path::{Path, PathBuf}, fs::File};
+use crate::{Result, Error};
+use std::{collections::BTreeMap, path::{Path, PathBuf}, fs::File, time::SystemTime};
 
-#[cfg(not(test))]
-pub type Disk = os::OsFilesystem;
+#[cfg(all(not(test), not(feature = "mem-fs")))]
+pub type Disk = TracingFilesystem<DryRunFilesystem<os::OsFilesystem>>;
+
+// everything runs in memory, for tools embedding dotgirl
+#[cfg(all(not(test), feature = "mem-fs"))]
+pub type Disk = TracingFilesystem<DryRunFilesystem<memory::SharedMemoryFilesystem>>;
+
+#[cfg(test)]
+pub type Disk = TracingFilesystem<DryRunFilesystem<memory::MemoryFilesystem>>;
+
+#[cfg(feature = "mem-fs")]
+pub use memory::SharedMemoryFilesystem;
+
+pub use trace::{TracingFilesystem, set_tracing};
+pub use dry_run::{DryRunFilesystem, set_dry_run};
+pub(crate) use dry_run::skipped as dry_run_skipped;
+
+#[cfg(test)]
+pub use trace::record;
 
+// What another thread has to run first to work on the same filesystem
+// as the one calling this. only tests keep one for every thread.
 #[cfg(test)]
-pub type Disk = memory::MemoryFilesystem;
+pub fn share_with_threads() -> impl Fn() + Send + Sync + 'static {
+    let share = memory::ThreadLocal::share();
+    let dry_run = dry_run::enabled();
+
+    move || {
+        share();
+        set_dry_run(dry_run);
+    }
+}
+
+#[cfg(not(test))]
+pub fn share_with_threads() -> impl Fn() + Send + Sync + 'static {
+    || ()
+}
+
+// What's at a path, without following symlinks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    // sockets, fifos, device nodes and whatever else the os has
+    Other,
+}
 
 pub trait Filesystem {
     fn get<P: AsRef<Path>>(path: P) -> Result<String>;
+    // the same as `get`, for files that aren't text
+    fn get_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>>;
+    // writes `content` to the file at `path`, creating it and the
+    // dirs around it if they don't exist yet
     fn put<P: AsRef<Path>>(path: P, content: &str) -> Result<()>;
+    fn put_bytes<P: AsRef<Path>>(path: P, content: &[u8]) -> Result<()>;
+    // adds `content` to the end of the file at `path`, which is
+    // created the same as with `put` if it doesn't exist yet
+    #[allow(dead_code)]
+    fn append<P: AsRef<Path>>(path: P, content: &str) -> Result<()>;
 
     fn mkdir_all<P: AsRef<Path>>(path: P) -> Result<()>;
     fn remove<P: AsRef<Path>>(path: P) -> Result<()>;
-    fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()>;
+    // copies `from` to `to`. if `to` already exists, it is replaced when
+    // `overwrite` is set, otherwise this fails without touching it.
+    fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U, overwrite: bool) -> Result<()>;
+    // moves `from` to `to`, which must not exist yet. across filesystems
+    // this copies and removes `from` afterwards, which isn't atomic.
+    fn rename<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()>;
     fn symlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()>;
+    // fails with `CrossDevice` if `from` and `to` are on different
+    // filesystems, hardlinks can't cross those
+    fn hardlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()>;
+    // copies the file `from` to `to`, which must not exist yet, with
+    // both sharing their data until one of them changes. this fails
+    // if the filesystem can't do that, leaving nothing at `to`.
+    fn reflink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()>;
+    fn junction<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()>;
+    fn readlink<P: AsRef<Path>>(path: P) -> Result<PathBuf>;
+    fn read_dir<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>>;
+    fn walk<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>>;
+    fn file_size<P: AsRef<Path>>(path: P) -> Result<u64>;
+    fn file_type<P: AsRef<Path>>(path: P) -> Result<FileType>;
+    // extended attributes of `path` itself, by name. where they
+    // aren't supported there are none, and setting them does nothing
+    fn get_xattrs<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Vec<u8>>>;
+    fn set_xattrs<P: AsRef<Path>>(path: P, attrs: &BTreeMap<String, Vec<u8>>) -> Result<()>;
+    // when the file at `path` was last changed. the memory
+    // backends don't keep times, there it's always the epoch
+    fn modified<P: AsRef<Path>>(path: P) -> Result<SystemTime>;
+    fn set_modified<P: AsRef<Path>>(path: P, time: SystemTime) -> Result<()>;
+    // the permission bits of `path` itself, e.g. 0o755 for scripts.
+    // windows only knows read only files, and the memory backends
+    // only keep modes for files, dirs are always 0o755 there
+    fn mode<P: AsRef<Path>>(path: P) -> Result<u32>;
+    fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()>;
 
     fn is_dir<P: AsRef<Path>>(path: P) -> bool;
     fn is_file<P: AsRef<Path>>(path: P) -> bool;
     fn is_symlink<P: AsRef<Path>>(path: P) -> bool;
+    // whether `path` leads anywhere, following symlinks. a
+    // dangling symlink doesn't exist by this definition
+    fn exists<P: AsRef<Path>>(path: P) -> bool;
+    // whether anything is at `path` itself, including dangling symlinks
+    fn symlink_exists<P: AsRef<Path>>(path: P) -> bool;
+    // whether we're allowed to create and remove things in the dir `path`
+    fn is_writable<P: AsRef<Path>>(path: P) -> bool;
+    // whether both paths lead to the same file, e.g. through a hardlink
+    fn is_same_file<T: AsRef<Path>, U: AsRef<Path>>(a: T, b: U) -> bool;
 }
 
 mod os {
     use super::*;
     use std::{fs, io::prelude::*};
 
+    // EXDEV, and ERROR_NOT_SAME_DEVICE on windows
+    const CROSS_DEVICE: i32 = if cfg!(windows) { 17 } else { 18 };
+
+    // Copies the contents of `from` into the new dir `to`. links inside
+    // are copied as links, e.g. a `current` pointing at `release-3`
+    // should keep doing that instead of turning into a copy of it.
+    fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+        fs::create_dir_all(to).map_err(Error::io("create dir", &to))?;
+
+        for it in fs::read_dir(from).map_err(Error::io("read dir", &from))? {
+            let it = it.map_err(Error::io("read dir", &from))?;
+            let target = to.join(it.file_name());
+            let file_type = it.file_type().map_err(Error::io("read", it.path()))?;
+
+            if file_type.is_symlink() {
+                OsFilesystem::symlink(OsFilesystem::readlink(it.path())?, &target)?;
+            } else if file_type.is_dir() {
+                copy_dir(&it.path(), &target)?;
+            } else {
+                fs::copy(it.path(), &target).map_err(Error::io("copy", it.path()))?;
+            }
+        }
+
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub struct OsFilesystem;
     impl Filesystem for OsFilesystem {
         fn get<P: AsRef<Path>>(path: P) -> Result<String> {
-            let contents = fs::read_to_string(&path)?;
+            let contents = fs::read_to_string(&path).map_err(Error::io("read", &path))?;
+            Ok(contents)
+        }
+
+        fn get_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+            let contents = fs::read(&path).map_err(Error::io("read", &path))?;
             Ok(contents)
         }
 
         fn put<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
-            let mut out = File::create(&path)?;
-            out.write_all(content.as_bytes())?;
+            Self::put_bytes(path, content.as_bytes())
+        }
+
+        fn put_bytes<P: AsRef<Path>>(path: P, content: &[u8]) -> Result<()> {
+            if let Some(parent) = path.as_ref().parent().filter(|it| !it.as_os_str().is_empty()) {
+                Self::mkdir_all(parent)?;
+            }
+
+            let mut out = File::create(&path).map_err(Error::io("create", &path))?;
+            out.write_all(content).map_err(Error::io("write", &path))?;
+            Ok(())
+        }
+
+        fn append<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+            if let Some(parent) = path.as_ref().parent().filter(|it| !it.as_os_str().is_empty()) {
+                Self::mkdir_all(parent)?;
+            }
+
+            let mut out = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .map_err(Error::io("create", &path))?;
+
+            out.write_all(content.as_bytes()).map_err(Error::io("write", &path))?;
             Ok(())
         }
 
         fn mkdir_all<P: AsRef<Path>>(path: P) -> Result<()> {
-            fs::create_dir_all(&path)?;
+            fs::create_dir_all(&path).map_err(Error::io("create dir", &path))?;
             Ok(())
         }
 
         fn remove<P: AsRef<Path>>(path: P) -> Result<()> {
-            let buf = PathBuf::from(path.as_ref());
-            if buf.is_dir() {
-                fs::remove_dir_all(&path)?;
+            // never follow links here, or removing a link into storage
+            // would take everything inside storage with it
+            let file_type = fs::symlink_metadata(&path)
+                .map_err(Error::io("remove", &path))?
+                .file_type();
+
+            if file_type.is_symlink() {
+                // links to directories are directories themselves on
+                // windows, and have to be removed as such
+                if fs::remove_file(&path).is_err() {
+                    fs::remove_dir(&path).map_err(Error::io("remove", &path))?;
+                }
+            } else if file_type.is_dir() {
+                fs::remove_dir_all(&path).map_err(Error::io("remove", &path))?;
             } else {
-                fs::remove_file(&path)?;
+                fs::remove_file(&path).map_err(Error::io("remove", &path))?;
             }
 
             Ok(())
         }
 
-        fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+        fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U, overwrite: bool) -> Result<()> {
             let buf = PathBuf::from(from.as_ref());
 
-            if buf.is_dir() {
-                let mut options = fs_extra::dir::CopyOptions::new();
-                options.copy_inside = true;
-                options.overwrite = true;
+            if fs::symlink_metadata(&to).is_ok() {
+                if !overwrite {
+                    return Err(crate::Error::Simple("copy dst already exists"));
+                }
 
-                fs_extra::dir::copy(&from, &to, &options)?;
+                // an existing directory would be merged with, so we
+                // have to get rid of it first
+                Self::remove(&to)?;
+            }
+
+            if buf.is_dir() {
+                copy_dir(from.as_ref(), to.as_ref())?;
             } else {
-                fs::copy(&from, &to)?;
+                fs::copy(&from, &to).map_err(Error::io("copy", &from))?;
             }
 
             Ok(())
         }
 
+        fn rename<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            // renaming would replace files and empty dirs at `to`
+            if fs::symlink_metadata(&to).is_ok() {
+                return Err(crate::Error::Simple("rename dst already exists"));
+            }
+
+            match fs::rename(&from, &to) {
+                Ok(()) => Ok(()),
+                Err(error) if error.raw_os_error() == Some(CROSS_DEVICE) => {
+                    Self::copy(&from, &to, false)?;
+                    Self::remove(&from)
+                },
+                Err(error) => Err(Error::io("rename", &from)(error)),
+            }
+        }
+
+        #[cfg(unix)]
         fn symlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
             use std::os::unix::fs::symlink;
-            symlink(&from, &to)?;
+            symlink(&from, &to).map_err(Error::io("symlink", &to))?;
+            Ok(())
+        }
+
+        // windows needs to know what kind of thing we're linking to, and
+        // will fail with a permission error without developer mode
+        #[cfg(windows)]
+        fn symlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            use std::os::windows::fs::{symlink_dir, symlink_file};
+
+            // relative targets are relative to the link, not to us
+            let resolved = match to.as_ref().parent() {
+                Some(parent) => parent.join(&from),
+                None => from.as_ref().to_path_buf(),
+            };
+
+            if resolved.is_dir() {
+                symlink_dir(&from, &to).map_err(Error::io("symlink", &to))?;
+            } else {
+                symlink_file(&from, &to).map_err(Error::io("symlink", &to))?;
+            }
+
+            Ok(())
+        }
+
+        fn hardlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            fs::hard_link(&from, &to).map_err(|error| match error.raw_os_error() {
+                Some(CROSS_DEVICE) => Error::CrossDevice(from.as_ref().to_path_buf(), to.as_ref().to_path_buf()),
+                _ => Error::io("hardlink", &to)(error),
+            })
+        }
+
+        #[cfg(target_os = "linux")]
+        fn reflink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            use std::os::unix::io::AsRawFd;
+
+            let src = fs::File::open(&from).map_err(Error::io("read", &from))?;
+            let dst = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&to)
+                .map_err(Error::io("create", &to))?;
+
+            let result = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+            if result != 0 {
+                let error = std::io::Error::last_os_error();
+                drop(dst);
+                fs::remove_file(&to).map_err(Error::io("remove", &to))?;
+                return Err(Error::io("reflink", &to)(error));
+            }
+
+            let permissions = src.metadata().map_err(Error::io("read", &from))?.permissions();
+            fs::set_permissions(&to, permissions).map_err(Error::io("write", &to))?;
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        fn reflink<T: AsRef<Path>, U: AsRef<Path>>(_from: T, _to: U) -> Result<()> {
+            Err(crate::Error::Simple("reflinks are not supported on this platform"))
+        }
+
+        #[cfg(windows)]
+        fn junction<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            use std::process::Command;
+
+            // there's no api for junctions in std, but they don't need
+            // any privileges so mklink will do
+            let status = Command::new("cmd")
+                .arg("/C")
+                .arg("mklink")
+                .arg("/J")
+                .arg(to.as_ref())
+                .arg(from.as_ref())
+                .status()?;
+
+            if !status.success() {
+                return Err(crate::Error::Simple("mklink failed to create junction"));
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(windows))]
+        fn junction<T: AsRef<Path>, U: AsRef<Path>>(_: T, _: U) -> Result<()> {
+            Err(crate::Error::Simple("junctions are only supported on windows"))
+        }
+
+        fn readlink<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+            let target = fs::read_link(&path).map_err(Error::io("read link", &path))?;
+            Ok(target)
+        }
+
+        fn read_dir<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            let mut result = fs::read_dir(&path)
+                .map_err(Error::io("read dir", &path))?
+                .map(|it| it.map(|entry| entry.path()))
+                .collect::<std::result::Result<Vec<PathBuf>, _>>()
+                .map_err(Error::io("read dir", &path))?;
+
+            result.sort();
+            Ok(result)
+        }
+
+        fn walk<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            let mut result = Vec::new();
+
+            for it in Self::read_dir(&path)? {
+                // don't descend into symlinked dirs, we only want
+                // to see what's actually stored below `path`
+                let is_dir = fs::symlink_metadata(&it).map_err(Error::io("read", &it))?.is_dir();
+                result.push(it.clone());
+
+                if is_dir {
+                    result.extend(Self::walk(&it)?);
+                }
+            }
+
+            Ok(result)
+        }
+
+        fn file_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+            let metadata = fs::symlink_metadata(&path).map_err(Error::io("read", &path))?;
+            Ok(metadata.len())
+        }
+
+        fn file_type<P: AsRef<Path>>(path: P) -> Result<FileType> {
+            let it = fs::symlink_metadata(&path).map_err(Error::io("read", &path))?.file_type();
+
+            Ok(if it.is_symlink() {
+                FileType::Symlink
+            } else if it.is_dir() {
+                FileType::Dir
+            } else if it.is_file() {
+                FileType::File
+            } else {
+                FileType::Other
+            })
+        }
+
+        #[cfg(target_os = "linux")]
+        fn get_xattrs<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Vec<u8>>> {
+            let mut result = BTreeMap::new();
+            for name in xattr::list(&path).map_err(Error::io("read attributes", &path))? {
+                // it might have been removed since it was listed
+                if let Some(value) = xattr::get(&path, &name).map_err(Error::io("read attributes", &path))? {
+                    result.insert(name.to_string_lossy().into_owned(), value);
+                }
+            }
+
+            Ok(result)
+        }
+
+        #[cfg(target_os = "linux")]
+        fn set_xattrs<P: AsRef<Path>>(path: P, attrs: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+            for (name, value) in attrs {
+                xattr::set(&path, name, value).map_err(Error::io("write attributes", &path))?;
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        fn get_xattrs<P: AsRef<Path>>(_: P) -> Result<BTreeMap<String, Vec<u8>>> {
+            Ok(BTreeMap::new())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        fn set_xattrs<P: AsRef<Path>>(_: P, _: &BTreeMap<String, Vec<u8>>) -> Result<()> {
             Ok(())
         }
 
+        fn modified<P: AsRef<Path>>(path: P) -> Result<SystemTime> {
+            let metadata = fs::symlink_metadata(&path).map_err(Error::io("read", &path))?;
+            metadata.modified().map_err(Error::io("read", &path))
+        }
+
+        fn set_modified<P: AsRef<Path>>(path: P, time: SystemTime) -> Result<()> {
+            fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .and_then(|it| it.set_modified(time))
+                .map_err(Error::io("write", &path))
+        }
+
+        #[cfg(unix)]
+        fn mode<P: AsRef<Path>>(path: P) -> Result<u32> {
+            use std::os::unix::fs::PermissionsExt;
+
+            let metadata = fs::symlink_metadata(&path).map_err(Error::io("read", &path))?;
+            Ok(metadata.permissions().mode() & 0o7777)
+        }
+
+        #[cfg(not(unix))]
+        fn mode<P: AsRef<Path>>(path: P) -> Result<u32> {
+            let metadata = fs::symlink_metadata(&path).map_err(Error::io("read", &path))?;
+
+            Ok(match (metadata.is_dir(), metadata.permissions().readonly()) {
+                (true, _) => 0o755,
+                (false, true) => 0o444,
+                (false, false) => 0o644,
+            })
+        }
+
+        #[cfg(unix)]
+        fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).map_err(Error::io("write", &path))
+        }
+
+        #[cfg(not(unix))]
+        fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+            let mut permissions = fs::metadata(&path).map_err(Error::io("read", &path))?.permissions();
+            permissions.set_readonly(mode & 0o200 == 0);
+            fs::set_permissions(&path, permissions).map_err(Error::io("write", &path))
+        }
+
         fn is_dir<P: AsRef<Path>>(path: P) -> bool {
             let buf = PathBuf::from(path.as_ref());
             buf.is_dir()
@@ -93,38 +485,142 @@ mod os {
                 .map(|it| it.file_type().is_symlink())
                 .unwrap_or(false)
         }
+
+        fn exists<P: AsRef<Path>>(path: P) -> bool {
+            fs::metadata(path).is_ok()
+        }
+
+        fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
+            fs::symlink_metadata(path).is_ok()
+        }
+
+        // the mode alone doesn't say whether it's ours to write
+        #[cfg(unix)]
+        fn is_writable<P: AsRef<Path>>(path: P) -> bool {
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = match std::ffi::CString::new(path.as_ref().as_os_str().as_bytes()) {
+                Ok(path) => path,
+                Err(_) => return false,
+            };
+
+            unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+        }
+
+        #[cfg(not(unix))]
+        fn is_writable<P: AsRef<Path>>(path: P) -> bool {
+            fs::metadata(path)
+                .map(|it| !it.permissions().readonly())
+                .unwrap_or(false)
+        }
+
+        #[cfg(unix)]
+        fn is_same_file<T: AsRef<Path>, U: AsRef<Path>>(a: T, b: U) -> bool {
+            use std::os::unix::fs::MetadataExt;
+
+            match (fs::metadata(a), fs::metadata(b)) {
+                (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+                _ => false,
+            }
+        }
+
+        // there's no stable way to tell, so files are trusted to be
+        // the ones we linked
+        #[cfg(not(unix))]
+        fn is_same_file<T: AsRef<Path>, U: AsRef<Path>>(a: T, b: U) -> bool {
+            Self::is_file(a) && Self::is_file(b)
+        }
     }
 }
 
-#[cfg(test)]
+#[cfg(any(test, feature = "mem-fs"))]
 mod memory {
     use super::*;
     use std::{
         cell::RefCell,
         collections::HashMap,
+        io,
+        marker::PhantomData,
+        sync::{Arc, Mutex},
     };
 
+    // what the os reports for directories and links where a file is
+    // expected, so errors look the same on both backends
+    fn not_a_file<P: AsRef<Path>>(op: &'static str, path: P) -> Error {
+        Error::io(op, path)(io::Error::other("not a file"))
+    }
+
+    // whether `key` is `path` itself or something inside of it,
+    // without matching siblings that only share a prefix
+    fn is_within(key: &str, path: &str) -> bool {
+        key == path || key.starts_with(&format!("{}/", path.trim_end_matches('/')))
+    }
+
     #[derive(Clone, Debug)]
-    enum Entry {
-        File(Option<String>),
-        Dir,
-        Symlink,
+    pub enum Entry {
+        File { content: Vec<u8>, mode: u32 },
+        // read only dirs are only there to be looked at by tests,
+        // nothing else about them is enforced
+        Dir { readonly: bool },
+        Symlink(PathBuf),
+    }
+
+    // Where the entries of a memory filesystem are kept
+    pub trait Store {
+        fn with<R, F: FnOnce(&mut HashMap<String, Entry>) -> R>(f: F) -> R;
     }
 
     // each thread needs its own in-memory filesystem, since tests will run in parallel
     // and conflict if we don't separate their filesystems.
     thread_local! {
-        static DISK: RefCell<HashMap<String, Entry>> = RefCell::new(HashMap::new());
+        static DISK: RefCell<Arc<Mutex<HashMap<String, Entry>>>> = RefCell::new(Default::default());
+    }
+
+    pub struct ThreadLocal;
+    impl Store for ThreadLocal {
+        fn with<R, F: FnOnce(&mut HashMap<String, Entry>) -> R>(f: F) -> R {
+            let disk = DISK.with(|it| it.borrow().clone());
+            let mut disk = disk.lock().unwrap_or_else(|it| it.into_inner());
+            f(&mut disk)
+        }
     }
 
+    impl ThreadLocal {
+        // Lets whichever thread calls the result use the filesystem
+        // of the one calling this, instead of its own
+        #[allow(dead_code)]
+        pub fn share() -> impl Fn() + Send + Sync + 'static {
+            let disk = DISK.with(|it| it.borrow().clone());
+            move || DISK.with(|it| *it.borrow_mut() = disk.clone())
+        }
+    }
+
+    // one filesystem for the whole process, for embedding
+    // dotgirl somewhere that uses more than one thread
+    static SHARED: Mutex<Option<HashMap<String, Entry>>> = Mutex::new(None);
+
+    pub struct Shared;
+    impl Store for Shared {
+        fn with<R, F: FnOnce(&mut HashMap<String, Entry>) -> R>(f: F) -> R {
+            // a panic while holding the lock doesn't leave
+            // the map itself in a broken state
+            let mut disk = SHARED.lock().unwrap_or_else(|it| it.into_inner());
+            f(disk.get_or_insert_with(HashMap::new))
+        }
+    }
+
+    #[allow(dead_code)]
+    pub struct Memory<S: Store>(PhantomData<S>);
+
     #[allow(dead_code)]
-    pub struct MemoryFilesystem;
+    pub type MemoryFilesystem = Memory<ThreadLocal>;
+    #[allow(dead_code)]
+    pub type SharedMemoryFilesystem = Memory<Shared>;
 
-    impl MemoryFilesystem {
+    impl<S: Store> Memory<S> {
         pub fn print() {
-            DISK.with(|disk| {
-                let disk = disk.borrow();
-                let mut keys = disk.keys().map(|it| it.clone()).collect::<Vec<String>>();
+            S::with(|disk| {
+                let mut keys = disk.keys().cloned().collect::<Vec<String>>();
                 keys.sort_unstable();
 
                 for k in keys.iter() {
@@ -134,30 +630,46 @@ mod memory {
         }
 
         pub fn clear() {
-            DISK.with(|disk| {
-                disk.borrow_mut().clear();
+            S::with(|disk| {
+                disk.clear();
+            });
+        }
+
+        pub fn set_readonly<P: AsRef<Path>>(path: P, readonly: bool) {
+            S::with(|disk| {
+                let key = format!("{}", path.as_ref().display());
+                if let Some(Entry::Dir { readonly: current }) = disk.get_mut(&key) {
+                    *current = readonly;
+                }
             });
         }
     }
 
-    impl Filesystem for MemoryFilesystem {
+    impl<S: Store> Filesystem for Memory<S> {
         fn get<P: AsRef<Path>>(path: P) -> Result<String> {
-            let mut result = Ok(String::from(""));
+            // the same error the os gives for files that aren't text
+            String::from_utf8(Self::get_bytes(&path)?).map_err(|_| {
+                let source = io::Error::new(io::ErrorKind::InvalidData, "stream did not contain valid UTF-8");
+                Error::io("read", &path)(source)
+            })
+        }
 
-            DISK.with(|disk| {
-                let disk = disk.borrow();
+        fn get_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+            let mut result = Ok(Vec::new());
 
+            S::with(|disk| {
                 let key = format!("{}", path.as_ref().display());
                 let entry = disk.get(&key).cloned();
 
                 if let Some(entry) = entry {
-                    if let Entry::File(Some(content)) = entry {
+                    if let Entry::File { content, .. } = entry {
                         result = Ok(content.clone())
                     } else {
-                        result = Err(crate::Error::Simple("file was not readable"))
+                        result = Err(not_a_file("read", &path))
                     }
                 } else {
-                    result = Err(crate::Error::Simple("file not found"))
+                    let source = io::Error::from(io::ErrorKind::NotFound);
+                    result = Err(Error::io("read", &path)(source))
                 }
             });
 
@@ -165,39 +677,77 @@ mod memory {
         }
 
         fn put<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+            Self::put_bytes(path, content.as_bytes())
+        }
+
+        fn put_bytes<P: AsRef<Path>>(path: P, content: &[u8]) -> Result<()> {
+            if let Some(parent) = path.as_ref().parent().filter(|it| !Self::symlink_exists(it)) {
+                Self::mkdir_all(parent)?;
+            }
+
+            let mut result = Ok(());
             let key = format!("{}", path.as_ref().display());
-            let content = String::from(content);
-            DISK.with(|disk| {
-                disk.borrow_mut().insert(key, Entry::File(Some(content)));
+            let content = content.to_vec();
+
+            S::with(|disk| {
+                match disk.get_mut(&key) {
+                    Some(Entry::Dir { .. }) => result = Err(not_a_file("create", &path)),
+                    // like the os, replacing the content keeps the mode
+                    Some(Entry::File { content: existing, .. }) => *existing = content,
+                    _ => {
+                        disk.insert(key, Entry::File { content, mode: 0o644 });
+                    },
+                };
             });
 
-            Ok(())
+            result
         }
 
-        fn mkdir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+        fn append<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+            if let Some(parent) = path.as_ref().parent().filter(|it| !Self::symlink_exists(it)) {
+                Self::mkdir_all(parent)?;
+            }
+
             let mut result = Ok(());
+            let key = format!("{}", path.as_ref().display());
+
+            S::with(|disk| {
+                match disk.get_mut(&key) {
+                    Some(Entry::Dir { .. }) => result = Err(not_a_file("write", &path)),
+                    Some(Entry::File { content: existing, .. }) => existing.extend(content.as_bytes()),
+                    _ => {
+                        disk.insert(key, Entry::File { content: content.as_bytes().to_vec(), mode: 0o644 });
+                    },
+                };
+            });
+
+            result
+        }
 
-            DISK.with(|disk| {
-                let mut disk = disk.borrow_mut();
+        fn mkdir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+            let mut result = Ok(());
 
+            S::with(|disk| {
                 let parts = PathBuf::from(path.as_ref());
                 let mut buf = PathBuf::from("");
 
                 for part in parts.components() {
-                    buf.push(&part);
+                    buf.push(part);
                     let key = format!("{}", buf.display());
 
                     match disk.get(&key) {
-                        Some(Entry::File(_)) => {
+                        Some(Entry::File { .. }) => {
                             result = Err(crate::Error::Simple("file existed"));
                         },
-                        Some(Entry::Symlink) => {
+                        Some(Entry::Symlink(_)) => {
                             result = Err(crate::Error::Simple("symlink existed"));
                         },
-                        _ => {},
+                        // existing dirs keep being read only
+                        Some(Entry::Dir { .. }) => continue,
+                        None => {},
                     };
 
-                    disk.insert(key, Entry::Dir);
+                    disk.insert(key, Entry::Dir { readonly: false });
                 }
             });
 
@@ -205,13 +755,11 @@ mod memory {
         }
 
         fn remove<P: AsRef<Path>>(path: P) -> Result<()> {
-            DISK.with(|disk| {
-                let mut disk = disk.borrow_mut();
-
+            S::with(|disk| {
                 let key = format!("{}", path.as_ref().display());
                 let to_delete = disk
                     .iter()
-                    .filter(|(k, _)| k.starts_with(&key))
+                    .filter(|(k, _)| is_within(k, &key))
                     .map(|(k, _)| k.clone())
                     .collect::<Vec<String>>();
 
@@ -223,12 +771,19 @@ mod memory {
             Ok(())
         }
 
-        fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
-            let mut result = Ok(());
+        fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U, overwrite: bool) -> Result<()> {
+            let exists = Self::is_file(&to) || Self::is_dir(&to) || Self::is_symlink(&to);
+            if exists {
+                if !overwrite {
+                    return Err(crate::Error::Simple("copy dst already exists"));
+                }
+
+                Self::remove(&to)?;
+            }
 
-            DISK.with(|disk| {
-                let mut disk = disk.borrow_mut();
+            let mut result = Ok(());
 
+            S::with(|disk| {
                 let from_key = format!("{}", from.as_ref().display());
                 let from_entry = disk
                     .get(&from_key)
@@ -244,14 +799,17 @@ mod memory {
 
                 let key = format!("{}", to.as_ref().display());
 
-                if let Entry::Dir = from_entry {
+                // children keep everything after the source dir, which
+                // has to be cut off exactly once and at a separator.
+                // links among them stay links, the same as on disk.
+                if let Entry::Dir { .. } = from_entry {
+                    let from_prefix = from_key.trim_end_matches('/');
+                    let to_prefix = key.trim_end_matches('/');
+
                     let to_save = disk
                         .keys()
-                        .filter(|it| it.starts_with(&from_key))
-                        .map(|it| {
-                            let suffix = it.trim_start_matches(&from_key);
-                            (it.clone(), format!("{}{}", key, suffix))
-                        })
+                        .filter(|it| **it != from_key && is_within(it, &from_key))
+                        .map(|it| (it.clone(), format!("{}{}", to_prefix, &it[from_prefix.len()..])))
                         .collect::<Vec<(String, String)>>();
 
                     to_save.into_iter().for_each(|(from, to)| {
@@ -260,62 +818,1228 @@ mod memory {
                     });
                 }
 
+                // the destination itself is always there, even
+                // if the directory was empty
                 disk.insert(key, from_entry);
             });
+
+            result
+        }
+
+        // there's only one filesystem, so this never has to copy
+        fn rename<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            if Self::symlink_exists(&to) {
+                return Err(crate::Error::Simple("rename dst already exists"));
+            }
+
+            if !Self::symlink_exists(&from) {
+                let source = io::Error::from(io::ErrorKind::NotFound);
+                return Err(Error::io("rename", &from)(source));
+            }
+
+            S::with(|disk| {
+                let from_key = format!("{}", from.as_ref().display());
+                let to_key = format!("{}", to.as_ref().display());
+                let from_prefix = from_key.trim_end_matches('/');
+
+                let moved = disk
+                    .keys()
+                    .filter(|it| is_within(it, &from_key))
+                    .cloned()
+                    .collect::<Vec<String>>();
+
+                for it in moved {
+                    let entry = disk.remove(&it).expect("Invalid: key was just listed");
+                    disk.insert(format!("{}{}", to_key.trim_end_matches('/'), &it[from_prefix.len()..]), entry);
+                }
+            });
+
             Ok(())
         }
 
-        fn symlink<T: AsRef<Path>, U: AsRef<Path>>(_: T, to: U) -> Result<()> {
-            DISK.with(|disk| {
-                let mut disk = disk.borrow_mut();
+        fn symlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            S::with(|disk| {
                 let key = format!("{}", to.as_ref().display());
-                disk.insert(key, Entry::Symlink);
+                disk.insert(key, Entry::Symlink(from.as_ref().to_path_buf()));
             });
 
             Ok(())
         }
 
-        fn is_dir<P: AsRef<Path>>(path: P) -> bool {
-            let mut result = false;
+        fn hardlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            let mut result = Ok(());
+
+            // we can't share contents between entries, so a hardlink
+            // is just a copy of the file as far as we're concerned
+            S::with(|disk| {
+                let from_key = format!("{}", from.as_ref().display());
+                let key = format!("{}", to.as_ref().display());
+
+                match disk.get(&from_key).cloned() {
+                    Some(entry @ Entry::File { .. }) => {
+                        disk.insert(key, entry);
+                    },
+                    _ => result = Err(crate::Error::Simple("hardlink src is not a file")),
+                };
+            });
+
+            result
+        }
+
+        // there's no data to share, so this is just a copy
+        fn reflink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            if !Self::is_file(&from) {
+                return Err(crate::Error::Simple("reflink src is not a file"));
+            }
+
+            Self::copy(from, to, false)
+        }
+
+        fn junction<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            Self::symlink(from, to)
+        }
+
+        fn readlink<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+            let mut result = Err(crate::Error::Simple("symlink not found"));
 
-            DISK.with(|disk| {
-                let disk = disk.borrow();
+            S::with(|disk| {
                 let key = format!("{}", path.as_ref().display());
-                if let Some(Entry::Dir) = disk.get(&key) {
-                    result = true;
+                if let Some(Entry::Symlink(target)) = disk.get(&key) {
+                    result = Ok(target.clone());
                 }
             });
 
             result
         }
 
-        fn is_file<P: AsRef<Path>>(path: P) -> bool {
-            let mut result = false;
+        fn read_dir<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            let mut result = Ok(vec![]);
 
-            DISK.with(|disk| {
-                let disk = disk.borrow();
+            S::with(|disk| {
                 let key = format!("{}", path.as_ref().display());
-                if let Some(Entry::File(_)) = disk.get(&key) {
-                    result = true;
+
+                if let Some(Entry::Dir { .. }) = disk.get(&key) {
+                    // only direct children, so nothing after the prefix may
+                    // contain another separator
+                    let prefix = format!("{}/", key.trim_end_matches('/'));
+                    let mut children = disk
+                        .keys()
+                        .filter(|it| it.starts_with(&prefix) && it.len() > prefix.len())
+                        .filter(|it| !it[prefix.len()..].contains('/'))
+                        .map(PathBuf::from)
+                        .collect::<Vec<PathBuf>>();
+
+                    children.sort();
+                    result = Ok(children);
+                } else {
+                    result = Err(crate::Error::Simple("dir not found"));
                 }
             });
 
             result
         }
 
-        fn is_symlink<P: AsRef<Path>>(path: P) -> bool {
-            let mut result = false;
+        fn walk<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            let mut result = Ok(vec![]);
 
-            DISK.with(|disk| {
-                let disk = disk.borrow();
+            S::with(|disk| {
                 let key = format!("{}", path.as_ref().display());
-                if let Some(Entry::Symlink) = disk.get(&key) {
-                    result = true;
+
+                if let Some(Entry::Dir { .. }) = disk.get(&key) {
+                    let prefix = format!("{}/", key.trim_end_matches('/'));
+                    let mut children = disk
+                        .keys()
+                        .filter(|it| it.starts_with(&prefix) && it.len() > prefix.len())
+                        .map(PathBuf::from)
+                        .collect::<Vec<PathBuf>>();
+
+                    children.sort();
+                    result = Ok(children);
+                } else {
+                    result = Err(crate::Error::Simple("dir not found"));
                 }
             });
 
             result
         }
-    }
-}
 
+        fn file_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+            let mut result = Ok(0);
+
+            S::with(|disk| {
+                let key = format!("{}", path.as_ref().display());
+
+                result = match disk.get(&key) {
+                    Some(Entry::File { content, .. }) => Ok(content.len() as u64),
+                    Some(_) => Ok(0),
+                    None => Err(crate::Error::Simple("file not found")),
+                };
+            });
+
+            result
+        }
+
+        fn file_type<P: AsRef<Path>>(path: P) -> Result<FileType> {
+            let mut result = Err(crate::Error::Simple("file not found"));
+
+            S::with(|disk| {
+                let key = format!("{}", path.as_ref().display());
+
+                result = match disk.get(&key) {
+                    Some(Entry::File { .. }) => Ok(FileType::File),
+                    Some(Entry::Dir { .. }) => Ok(FileType::Dir),
+                    Some(Entry::Symlink(_)) => Ok(FileType::Symlink),
+                    None => Err(crate::Error::Simple("file not found")),
+                };
+            });
+
+            result
+        }
+
+        // entries don't have attributes here
+        fn get_xattrs<P: AsRef<Path>>(_: P) -> Result<BTreeMap<String, Vec<u8>>> {
+            Ok(BTreeMap::new())
+        }
+
+        fn set_xattrs<P: AsRef<Path>>(_: P, _: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+            Ok(())
+        }
+
+        // or times
+        fn modified<P: AsRef<Path>>(_: P) -> Result<SystemTime> {
+            Ok(SystemTime::UNIX_EPOCH)
+        }
+
+        fn set_modified<P: AsRef<Path>>(_: P, _: SystemTime) -> Result<()> {
+            Ok(())
+        }
+
+        fn mode<P: AsRef<Path>>(path: P) -> Result<u32> {
+            let key = format!("{}", path.as_ref().display());
+
+            S::with(|disk| match disk.get(&key) {
+                Some(Entry::File { mode, .. }) => Ok(*mode),
+                Some(Entry::Dir { .. }) => Ok(0o755),
+                Some(Entry::Symlink(_)) => Ok(0o777),
+                None => Err(Error::io("read", &path)(io::Error::from(io::ErrorKind::NotFound))),
+            })
+        }
+
+        fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+            let key = format!("{}", path.as_ref().display());
+
+            S::with(|disk| match disk.get_mut(&key) {
+                Some(Entry::File { mode: current, .. }) => {
+                    *current = mode;
+                    Ok(())
+                },
+                Some(_) => Ok(()),
+                None => Err(Error::io("write", &path)(io::Error::from(io::ErrorKind::NotFound))),
+            })
+        }
+
+        fn is_dir<P: AsRef<Path>>(path: P) -> bool {
+            let mut result = false;
+
+            S::with(|disk| {
+                let key = format!("{}", path.as_ref().display());
+                if let Some(Entry::Dir { .. }) = disk.get(&key) {
+                    result = true;
+                }
+            });
+
+            result
+        }
+
+        fn is_file<P: AsRef<Path>>(path: P) -> bool {
+            let mut result = false;
+
+            S::with(|disk| {
+                let key = format!("{}", path.as_ref().display());
+                if let Some(Entry::File { .. }) = disk.get(&key) {
+                    result = true;
+                }
+            });
+
+            result
+        }
+
+        fn is_symlink<P: AsRef<Path>>(path: P) -> bool {
+            let mut result = false;
+
+            S::with(|disk| {
+                let key = format!("{}", path.as_ref().display());
+                if let Some(Entry::Symlink(_)) = disk.get(&key) {
+                    result = true;
+                }
+            });
+
+            result
+        }
+
+        fn exists<P: AsRef<Path>>(path: P) -> bool {
+            // same as the os, a chain this long is treated as a loop
+            const MAX_HOPS: usize = 40;
+
+            let mut current = path.as_ref().to_path_buf();
+            for _ in 0..MAX_HOPS {
+                let target = match Self::readlink(&current) {
+                    Ok(target) => target,
+                    Err(_) => return Self::is_file(&current) || Self::is_dir(&current),
+                };
+
+                // relative targets are relative to the link's dir
+                current = crate::util::normalize(&current
+                    .parent()
+                    .map(|parent| parent.join(&target))
+                    .unwrap_or(target));
+            }
+
+            false
+        }
+
+        fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
+            Self::is_file(&path) || Self::is_dir(&path) || Self::is_symlink(&path)
+        }
+
+        fn is_writable<P: AsRef<Path>>(path: P) -> bool {
+            let mut result = false;
+
+            S::with(|disk| {
+                let key = format!("{}", path.as_ref().display());
+                if let Some(Entry::Dir { readonly: false }) = disk.get(&key) {
+                    result = true;
+                }
+            });
+
+            result
+        }
+
+        // hardlinks are copies here, so as long as neither was
+        // changed they're the same file
+        fn is_same_file<T: AsRef<Path>, U: AsRef<Path>>(a: T, b: U) -> bool {
+            Self::is_file(&a) && Self::is_file(&b) && Self::get(&a).ok() == Self::get(&b).ok()
+        }
+    }
+}
+
+// Logs every call that touches the filesystem before passing it on to
+// `F`, so it's possible to see where things actually ended up
+mod trace {
+    use super::*;
+    use std::{
+        cell::RefCell,
+        marker::PhantomData,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    // set once from the command line
+    static TRACING: AtomicBool = AtomicBool::new(false);
+
+    thread_local! {
+        // calls made on this thread while recording, for tests
+        static RECORDED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+    }
+
+    pub fn set_tracing(enabled: bool) {
+        TRACING.store(enabled, Ordering::Relaxed);
+    }
+
+    // Runs `f` and returns the calls it made on this thread
+    #[cfg(test)]
+    pub fn record<T, F: FnOnce() -> T>(f: F) -> (T, Vec<String>) {
+        RECORDED.with(|it| *it.borrow_mut() = Some(Vec::new()));
+        let result = f();
+        let calls = RECORDED.with(|it| it.borrow_mut().take().unwrap_or_default());
+
+        (result, calls)
+    }
+
+    fn trace(call: String) {
+        if TRACING.load(Ordering::Relaxed) {
+            eprintln!("trace: {}", call);
+        }
+
+        RECORDED.with(|it| {
+            if let Some(calls) = it.borrow_mut().as_mut() {
+                calls.push(call);
+            }
+        });
+    }
+
+    #[allow(dead_code)]
+    pub struct TracingFilesystem<F: Filesystem>(PhantomData<F>);
+
+    impl<F: Filesystem> Filesystem for TracingFilesystem<F> {
+        fn get<P: AsRef<Path>>(path: P) -> Result<String> {
+            trace(format!("get {}", path.as_ref().display()));
+            F::get(path)
+        }
+
+        fn get_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+            trace(format!("get {}", path.as_ref().display()));
+            F::get_bytes(path)
+        }
+
+        fn put<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+            trace(format!("put {} ({} bytes)", path.as_ref().display(), content.len()));
+            F::put(path, content)
+        }
+
+        fn put_bytes<P: AsRef<Path>>(path: P, content: &[u8]) -> Result<()> {
+            trace(format!("put {} ({} bytes)", path.as_ref().display(), content.len()));
+            F::put_bytes(path, content)
+        }
+
+        fn append<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+            trace(format!("append {} ({} bytes)", path.as_ref().display(), content.len()));
+            F::append(path, content)
+        }
+
+        fn mkdir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+            trace(format!("mkdir_all {}", path.as_ref().display()));
+            F::mkdir_all(path)
+        }
+
+        fn remove<P: AsRef<Path>>(path: P) -> Result<()> {
+            trace(format!("remove {}", path.as_ref().display()));
+            F::remove(path)
+        }
+
+        fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U, overwrite: bool) -> Result<()> {
+            trace(format!(
+                "copy {} -> {}{}",
+                from.as_ref().display(),
+                to.as_ref().display(),
+                if overwrite { " (overwrite)" } else { "" },
+            ));
+
+            F::copy(from, to, overwrite)
+        }
+
+        fn symlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            trace(format!("symlink {} -> {}", to.as_ref().display(), from.as_ref().display()));
+            F::symlink(from, to)
+        }
+
+        fn rename<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            trace(format!("rename {} -> {}", from.as_ref().display(), to.as_ref().display()));
+            F::rename(from, to)
+        }
+
+        fn hardlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            trace(format!("hardlink {} -> {}", to.as_ref().display(), from.as_ref().display()));
+            F::hardlink(from, to)
+        }
+
+        fn reflink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            trace(format!("reflink {} -> {}", to.as_ref().display(), from.as_ref().display()));
+            F::reflink(from, to)
+        }
+
+        fn junction<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            trace(format!("junction {} -> {}", to.as_ref().display(), from.as_ref().display()));
+            F::junction(from, to)
+        }
+
+        // looking around isn't traced, there's too much of it to be useful
+        fn readlink<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+            F::readlink(path)
+        }
+
+        fn read_dir<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            F::read_dir(path)
+        }
+
+        fn walk<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            F::walk(path)
+        }
+
+        fn file_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+            F::file_size(path)
+        }
+
+        fn file_type<P: AsRef<Path>>(path: P) -> Result<FileType> {
+            F::file_type(path)
+        }
+
+        fn get_xattrs<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Vec<u8>>> {
+            F::get_xattrs(path)
+        }
+
+        fn set_xattrs<P: AsRef<Path>>(path: P, attrs: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+            trace(format!("set_xattrs {} ({} attributes)", path.as_ref().display(), attrs.len()));
+            F::set_xattrs(path, attrs)
+        }
+
+        fn modified<P: AsRef<Path>>(path: P) -> Result<SystemTime> {
+            F::modified(path)
+        }
+
+        fn set_modified<P: AsRef<Path>>(path: P, time: SystemTime) -> Result<()> {
+            trace(format!("set_modified {}", path.as_ref().display()));
+            F::set_modified(path, time)
+        }
+
+        fn mode<P: AsRef<Path>>(path: P) -> Result<u32> {
+            F::mode(path)
+        }
+
+        fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+            trace(format!("set_mode {} {:o}", path.as_ref().display(), mode));
+            F::set_mode(path, mode)
+        }
+
+        fn is_dir<P: AsRef<Path>>(path: P) -> bool {
+            F::is_dir(path)
+        }
+
+        fn is_file<P: AsRef<Path>>(path: P) -> bool {
+            F::is_file(path)
+        }
+
+        fn is_symlink<P: AsRef<Path>>(path: P) -> bool {
+            F::is_symlink(path)
+        }
+
+        fn exists<P: AsRef<Path>>(path: P) -> bool {
+            F::exists(path)
+        }
+
+        fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
+            F::symlink_exists(path)
+        }
+
+        fn is_writable<P: AsRef<Path>>(path: P) -> bool {
+            F::is_writable(path)
+        }
+
+        fn is_same_file<T: AsRef<Path>, U: AsRef<Path>>(a: T, b: U) -> bool {
+            F::is_same_file(a, b)
+        }
+    }
+
+    // so tests can still clear the memory filesystem through `Disk`
+    #[cfg(any(test, feature = "mem-fs"))]
+    impl<S: memory::Store> TracingFilesystem<DryRunFilesystem<memory::Memory<S>>> {
+        #[allow(dead_code)]
+        pub fn print() {
+            memory::Memory::<S>::print()
+        }
+
+        #[allow(dead_code)]
+        pub fn clear() {
+            memory::Memory::<S>::clear()
+        }
+
+        #[allow(dead_code)]
+        pub fn set_readonly<P: AsRef<Path>>(path: P, readonly: bool) {
+            memory::Memory::<S>::set_readonly(path, readonly)
+        }
+    }
+}
+
+// Passes looking around on to `F`, but only reports everything that
+// would change something while dry running
+mod dry_run {
+    use super::*;
+    use std::marker::PhantomData;
+
+    // set once from the command line. tests each have their own
+    // memory filesystem per thread, so they get their own switch too
+    #[cfg(not(test))]
+    static DRY_RUN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+    #[cfg(test)]
+    thread_local! {
+        static DRY_RUN: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+
+    #[cfg(not(test))]
+    pub fn set_dry_run(enabled: bool) {
+        DRY_RUN.store(enabled, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn set_dry_run(enabled: bool) {
+        DRY_RUN.with(|it| it.set(enabled));
+    }
+
+    #[cfg(not(test))]
+    fn enabled() -> bool {
+        DRY_RUN.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn enabled() -> bool {
+        DRY_RUN.with(|it| it.get())
+    }
+
+    // reports `call` and whether it should be left out
+    pub fn skipped(call: String) -> bool {
+        if !enabled() {
+            return false;
+        }
+
+        eprintln!("dry run: {}", call);
+        true
+    }
+
+    #[allow(dead_code)]
+    pub struct DryRunFilesystem<F: Filesystem>(PhantomData<F>);
+
+    impl<F: Filesystem> Filesystem for DryRunFilesystem<F> {
+        fn get<P: AsRef<Path>>(path: P) -> Result<String> {
+            F::get(path)
+        }
+
+        fn get_bytes<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+            F::get_bytes(path)
+        }
+
+        fn put<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+            if skipped(format!("put {}", path.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::put(path, content)
+        }
+
+        fn put_bytes<P: AsRef<Path>>(path: P, content: &[u8]) -> Result<()> {
+            if skipped(format!("put {}", path.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::put_bytes(path, content)
+        }
+
+        fn append<P: AsRef<Path>>(path: P, content: &str) -> Result<()> {
+            if skipped(format!("append {}", path.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::append(path, content)
+        }
+
+        fn mkdir_all<P: AsRef<Path>>(path: P) -> Result<()> {
+            if skipped(format!("mkdir_all {}", path.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::mkdir_all(path)
+        }
+
+        fn remove<P: AsRef<Path>>(path: P) -> Result<()> {
+            if skipped(format!("remove {}", path.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::remove(path)
+        }
+
+        fn copy<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U, overwrite: bool) -> Result<()> {
+            if skipped(format!("copy {} -> {}", from.as_ref().display(), to.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::copy(from, to, overwrite)
+        }
+
+        fn symlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            if skipped(format!("symlink {} -> {}", to.as_ref().display(), from.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::symlink(from, to)
+        }
+
+        fn rename<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            if skipped(format!("rename {} -> {}", from.as_ref().display(), to.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::rename(from, to)
+        }
+
+        fn hardlink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            if skipped(format!("hardlink {} -> {}", to.as_ref().display(), from.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::hardlink(from, to)
+        }
+
+        fn reflink<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            if skipped(format!("reflink {} -> {}", to.as_ref().display(), from.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::reflink(from, to)
+        }
+
+        fn junction<T: AsRef<Path>, U: AsRef<Path>>(from: T, to: U) -> Result<()> {
+            if skipped(format!("junction {} -> {}", to.as_ref().display(), from.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::junction(from, to)
+        }
+
+        fn readlink<P: AsRef<Path>>(path: P) -> Result<PathBuf> {
+            F::readlink(path)
+        }
+
+        fn read_dir<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            F::read_dir(path)
+        }
+
+        fn walk<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+            F::walk(path)
+        }
+
+        fn file_size<P: AsRef<Path>>(path: P) -> Result<u64> {
+            F::file_size(path)
+        }
+
+        fn file_type<P: AsRef<Path>>(path: P) -> Result<FileType> {
+            F::file_type(path)
+        }
+
+        fn get_xattrs<P: AsRef<Path>>(path: P) -> Result<BTreeMap<String, Vec<u8>>> {
+            F::get_xattrs(path)
+        }
+
+        fn set_xattrs<P: AsRef<Path>>(path: P, attrs: &BTreeMap<String, Vec<u8>>) -> Result<()> {
+            if skipped(format!("set_xattrs {}", path.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::set_xattrs(path, attrs)
+        }
+
+        fn modified<P: AsRef<Path>>(path: P) -> Result<SystemTime> {
+            F::modified(path)
+        }
+
+        fn set_modified<P: AsRef<Path>>(path: P, time: SystemTime) -> Result<()> {
+            if skipped(format!("set_modified {}", path.as_ref().display())) {
+                return Ok(());
+            }
+
+            F::set_modified(path, time)
+        }
+
+        fn mode<P: AsRef<Path>>(path: P) -> Result<u32> {
+            F::mode(path)
+        }
+
+        fn set_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<()> {
+            if skipped(format!("set_mode {} {:o}", path.as_ref().display(), mode)) {
+                return Ok(());
+            }
+
+            F::set_mode(path, mode)
+        }
+
+        fn is_dir<P: AsRef<Path>>(path: P) -> bool {
+            F::is_dir(path)
+        }
+
+        fn is_file<P: AsRef<Path>>(path: P) -> bool {
+            F::is_file(path)
+        }
+
+        fn is_symlink<P: AsRef<Path>>(path: P) -> bool {
+            F::is_symlink(path)
+        }
+
+        fn exists<P: AsRef<Path>>(path: P) -> bool {
+            F::exists(path)
+        }
+
+        fn symlink_exists<P: AsRef<Path>>(path: P) -> bool {
+            F::symlink_exists(path)
+        }
+
+        fn is_writable<P: AsRef<Path>>(path: P) -> bool {
+            F::is_writable(path)
+        }
+
+        fn is_same_file<T: AsRef<Path>, U: AsRef<Path>>(a: T, b: U) -> bool {
+            F::is_same_file(a, b)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the same copies and removals inside `root` and returns
+    // everything that ends up there, so both backends can be compared
+    fn copy_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        let path = |it: &str| root.join(it);
+
+        F::mkdir_all(path("src/conf/sub")).unwrap();
+        F::mkdir_all(path("dst")).unwrap();
+        F::put(path("src/conf/a"), "a").unwrap();
+        F::put(path("src/conf/sub/b"), "b").unwrap();
+        // shares a prefix with `conf`, but isn't inside it
+        F::put(path("src/config"), "config").unwrap();
+        F::symlink("config", path("src/link")).unwrap();
+        // links inside of a copied dir stay links
+        F::symlink("a", path("src/conf/current")).unwrap();
+
+        let mut observed = vec![];
+
+        F::copy(path("src/conf"), path("dst/conf"), false).unwrap();
+        F::copy(path("src/config"), path("dst/config"), false).unwrap();
+
+        let again = F::copy(path("src/config"), path("dst/config"), false);
+        observed.push(format!("copy without overwrite failed: {}", again.is_err()));
+
+        let missing = F::copy(path("src/nothing"), path("dst/nothing"), false);
+        observed.push(format!("copy of missing src failed: {}", missing.is_err()));
+
+        F::put(path("src/conf/a"), "changed").unwrap();
+        F::remove(path("src/conf/sub")).unwrap();
+        F::copy(path("src/conf"), path("dst/conf"), true).unwrap();
+
+        F::remove(path("src/conf")).unwrap();
+
+        for it in F::walk(root).unwrap() {
+            let relative = it.strip_prefix(root).unwrap().display();
+
+            let kind = if F::is_symlink(&it) {
+                format!("link to {}", F::readlink(&it).unwrap().display())
+            } else if F::is_dir(&it) {
+                String::from("dir")
+            } else if F::is_file(&it) {
+                format!("file with {}", F::get(&it).unwrap())
+            } else {
+                String::from("nothing")
+            };
+
+            observed.push(format!("{}: {}", relative, kind));
+        }
+
+        // walk order is up to the backend
+        observed.sort();
+        observed
+    }
+
+    #[test]
+    fn copy_should_behave_the_same_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = copy_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = copy_sequence::<memory::MemoryFilesystem>(Path::new("/fidelity"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert!(on_os.contains(&String::from("dst/conf/a: file with changed")));
+        assert!(on_os.contains(&String::from("dst/conf/current: link to a")));
+        assert!(!on_os.iter().any(|it| it.starts_with("dst/conf/sub")));
+        assert!(on_os.contains(&String::from("src/config: file with config")));
+    }
+
+    // copies an empty directory, and one with a nested file inside a
+    // directory with the same name as it
+    fn dir_copy_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        F::mkdir_all(root.join("src/empty")).unwrap();
+        F::mkdir_all(root.join("src/a/a")).unwrap();
+        F::put(root.join("src/a/a/file"), "file").unwrap();
+
+        F::copy(root.join("src/empty"), root.join("empty"), false).unwrap();
+        F::copy(root.join("src/a"), root.join("copy"), false).unwrap();
+
+        ["empty", "copy", "copy/a", "copy/a/file", "copy/file"]
+            .iter()
+            .map(|it| format!("{}: dir {}, file {}", it, F::is_dir(root.join(it)), F::is_file(root.join(it))))
+            .collect()
+    }
+
+    #[test]
+    fn dir_copy_should_behave_the_same_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = dir_copy_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = dir_copy_sequence::<memory::MemoryFilesystem>(Path::new("/dircopy"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert_eq!(on_os, vec![
+            "empty: dir true, file false",
+            "copy: dir true, file false",
+            "copy/a: dir true, file false",
+            "copy/a/file: dir false, file true",
+            "copy/file: dir false, file false",
+        ]);
+    }
+
+    fn nested_put_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        F::mkdir_all(root).unwrap();
+        F::put(root.join("a/b/config"), "config").unwrap();
+
+        ["a", "a/b", "a/b/config"]
+            .iter()
+            .map(|it| format!("{}: dir {}, file {}", it, F::is_dir(root.join(it)), F::is_file(root.join(it))))
+            .collect()
+    }
+
+    #[test]
+    fn put_should_create_missing_dirs_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = nested_put_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = nested_put_sequence::<memory::MemoryFilesystem>(Path::new("/nested"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert_eq!(on_os, vec![
+            "a: dir true, file false",
+            "a/b: dir true, file false",
+            "a/b/config: dir false, file true",
+        ]);
+    }
+
+    fn append_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        F::mkdir_all(root).unwrap();
+        F::append(root.join("log/history"), "first\n").unwrap();
+        F::append(root.join("log/history"), "second\n").unwrap();
+
+        let into_dir = F::append(root.join("log"), "third\n");
+        vec![F::get(root.join("log/history")).unwrap(), format!("append to dir failed: {}", into_dir.is_err())]
+    }
+
+    #[test]
+    fn append_should_add_to_the_end_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = append_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = append_sequence::<memory::MemoryFilesystem>(Path::new("/append"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert_eq!(on_os, vec!["first\nsecond\n", "append to dir failed: true"]);
+    }
+
+    fn bytes_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        F::mkdir_all(root).unwrap();
+        F::put_bytes(root.join("font.ttf"), &[0, 159, 146, 150, 255]).unwrap();
+        F::put_bytes(root.join("text"), b"hello").unwrap();
+
+        vec![
+            format!("{:?}", F::get_bytes(root.join("font.ttf")).unwrap()),
+            format!("read as text failed: {}", F::get(root.join("font.ttf")).is_err()),
+            F::get(root.join("text")).unwrap(),
+            format!("size: {}", F::file_size(root.join("font.ttf")).unwrap()),
+        ]
+    }
+
+    #[test]
+    fn bytes_should_round_trip_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = bytes_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = bytes_sequence::<memory::MemoryFilesystem>(Path::new("/bytes"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert_eq!(on_os[0], "[0, 159, 146, 150, 255]");
+    }
+
+    fn mode_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        F::mkdir_all(root).unwrap();
+        F::put(root.join("run.sh"), "#!/bin/sh").unwrap();
+        F::set_mode(root.join("run.sh"), 0o755).unwrap();
+        let set = F::mode(root.join("run.sh")).unwrap();
+
+        // writing it again keeps the mode
+        F::put(root.join("run.sh"), "#!/bin/sh\nexit 0").unwrap();
+        let kept = F::mode(root.join("run.sh")).unwrap();
+
+        vec![format!("{:o}", set), format!("{:o}", kept), format!("missing failed: {}", F::mode(root.join("nothing")).is_err())]
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn mode_should_behave_the_same_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = mode_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = mode_sequence::<memory::MemoryFilesystem>(Path::new("/mode"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert_eq!(on_os, vec!["755", "755", "missing failed: true"]);
+    }
+
+    fn rename_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        F::mkdir_all(root.join("src/conf")).unwrap();
+        F::mkdir_all(root.join("dst")).unwrap();
+        F::put(root.join("src/config"), "config").unwrap();
+        F::put(root.join("src/conf/a"), "a").unwrap();
+        F::symlink("a", root.join("src/conf/current")).unwrap();
+
+        F::rename(root.join("src/config"), root.join("dst/config")).unwrap();
+        F::rename(root.join("src/conf"), root.join("dst/conf")).unwrap();
+
+        F::put(root.join("src/config"), "again").unwrap();
+        let onto_existing = F::rename(root.join("src/config"), root.join("dst/config"));
+        let missing = F::rename(root.join("src/nothing"), root.join("dst/nothing"));
+
+        let mut observed = F::walk(root)
+            .unwrap()
+            .iter()
+            .filter(|it| *it != root)
+            .map(|it| it.strip_prefix(root).unwrap().display().to_string())
+            .collect::<Vec<String>>();
+
+        observed.sort();
+        observed.push(format!("current links to {}", F::readlink(root.join("dst/conf/current")).unwrap().display()));
+        observed.push(format!("config has {}", F::get(root.join("dst/config")).unwrap()));
+        observed.push(format!("rename onto existing failed: {}", onto_existing.is_err()));
+        observed.push(format!("rename of missing src failed: {}", missing.is_err()));
+        observed
+    }
+
+    #[test]
+    fn rename_should_behave_the_same_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = rename_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = rename_sequence::<memory::MemoryFilesystem>(Path::new("/rename"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert_eq!(on_os, vec![
+            "dst", "dst/conf", "dst/conf/a", "dst/conf/current", "dst/config", "src", "src/config",
+            "current links to a",
+            "config has config",
+            "rename onto existing failed: true",
+            "rename of missing src failed: true",
+        ]);
+    }
+
+    // only runs where there's a tmpfs on another device than the
+    // temp dir, which is the case on most linux systems
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn rename_should_copy_across_filesystems() {
+        use std::os::unix::fs::MetadataExt;
+
+        let root = tempfile::tempdir().unwrap();
+        let other = match tempfile::tempdir_in("/dev/shm") {
+            Ok(other) => other,
+            Err(_) => return,
+        };
+
+        let device = |it: &Path| std::fs::metadata(it).unwrap().dev();
+        if device(root.path()) == device(other.path()) {
+            return;
+        }
+
+        os::OsFilesystem::put(root.path().join("conf/a"), "a").unwrap();
+        os::OsFilesystem::symlink("a", root.path().join("conf/current")).unwrap();
+        os::OsFilesystem::rename(root.path().join("conf"), other.path().join("conf")).unwrap();
+
+        assert!(!os::OsFilesystem::symlink_exists(root.path().join("conf")));
+        assert_eq!(os::OsFilesystem::get(other.path().join("conf/a")).unwrap(), "a");
+        assert!(os::OsFilesystem::is_symlink(other.path().join("conf/current")));
+    }
+
+    fn remove_link_sequence<F: Filesystem>(root: &Path) -> Vec<String> {
+        F::mkdir_all(root.join("storage/sub")).unwrap();
+        F::put(root.join("storage/config"), "config").unwrap();
+        F::put(root.join("storage/sub/config"), "config").unwrap();
+        F::symlink(root.join("storage"), root.join("link")).unwrap();
+
+        F::remove(root.join("link")).unwrap();
+
+        let mut observed = F::walk(root)
+            .unwrap()
+            .iter()
+            .map(|it| format!("{}", it.strip_prefix(root).unwrap().display()))
+            .collect::<Vec<String>>();
+
+        observed.sort();
+        observed
+    }
+
+    #[test]
+    fn remove_should_not_follow_links_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = remove_link_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = remove_link_sequence::<memory::MemoryFilesystem>(Path::new("/removal"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert!(!on_os.contains(&String::from("link")));
+        assert!(on_os.contains(&String::from("storage/config")));
+        assert!(on_os.contains(&String::from("storage/sub/config")));
+    }
+
+    // the path and operation of a failed read of something missing
+    // and a failed write over a directory
+    fn io_error_sequence<F: Filesystem>(root: &Path) -> Vec<(PathBuf, &'static str)> {
+        F::mkdir_all(root.join("dir")).unwrap();
+
+        let failures = vec![
+            F::get(root.join("missing")).map(|_| ()),
+            F::put(root.join("dir"), "content"),
+        ];
+
+        failures
+            .into_iter()
+            .map(|it| match it {
+                Err(Error::Io { path, op, .. }) => (path.strip_prefix(root).unwrap().to_path_buf(), op),
+                other => panic!("Expected an io error, got {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn io_errors_should_carry_the_path_on_both_backends() {
+        let root = tempfile::tempdir().unwrap();
+        let on_os = io_error_sequence::<os::OsFilesystem>(root.path());
+
+        let on_memory = io_error_sequence::<memory::MemoryFilesystem>(Path::new("/errors"));
+        memory::MemoryFilesystem::clear();
+
+        assert_eq!(on_memory, on_os);
+        assert_eq!(on_os, vec![(PathBuf::from("missing"), "read"), (PathBuf::from("dir"), "create")]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reflink_should_clone_or_leave_nothing() {
+        type Os = os::OsFilesystem;
+
+        let root = tempfile::tempdir().unwrap();
+        let from = root.path().join("from");
+        let to = root.path().join("to");
+        Os::put(&from, "shared content").unwrap();
+
+        // most filesystems (e.g. ext4 or tmpfs) can't share data
+        match Os::reflink(&from, &to) {
+            Ok(()) => assert_eq!(Os::get(&to).unwrap(), "shared content"),
+            Err(_) => assert!(!Os::symlink_exists(&to)),
+        };
+
+        // never clobbers what's already there
+        Os::put(&to, "existing").unwrap();
+        assert!(Os::reflink(&from, &to).is_err());
+        assert_eq!(Os::get(&to).unwrap(), "existing");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn xattrs_should_round_trip_on_os() {
+        type Os = os::OsFilesystem;
+
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("config");
+        Os::put(&path, "config").unwrap();
+
+        let mut attrs = BTreeMap::new();
+        attrs.insert(String::from("user.dotgirl.test"), b"tagged\x00value".to_vec());
+
+        // not every filesystem takes user attributes (e.g. older tmpfs)
+        match Os::set_xattrs(&path, &attrs) {
+            Err(Error::Io { ref source, .. }) if source.raw_os_error() == Some(95) => return,
+            other => other.unwrap(),
+        };
+
+        let read = Os::get_xattrs(&path).unwrap();
+        assert_eq!(read.get("user.dotgirl.test"), attrs.get("user.dotgirl.test"));
+
+        // a plain copy doesn't keep them, which is why we store them
+        let copy = root.path().join("copy");
+        Os::copy(&path, &copy, false).unwrap();
+        assert!(!Os::get_xattrs(&copy).unwrap().contains_key("user.dotgirl.test"));
+
+        Os::set_xattrs(&copy, &read).unwrap();
+        assert_eq!(Os::get_xattrs(&copy).unwrap().get("user.dotgirl.test"), attrs.get("user.dotgirl.test"));
+    }
+
+    #[test]
+    fn memory_exists_should_not_follow_dangling_links() {
+        type Memory = memory::MemoryFilesystem;
+
+        Memory::mkdir_all("/dir").unwrap();
+        Memory::put("/dir/file", "content").unwrap();
+        Memory::symlink("/dir/file", "/dir/link").unwrap();
+        Memory::symlink("file", "/dir/relative").unwrap();
+        Memory::symlink("/dir/gone", "/dir/dangling").unwrap();
+
+        assert!(Memory::exists("/dir/link"));
+        assert!(Memory::symlink_exists("/dir/link"));
+        assert!(Memory::exists("/dir/relative"));
+
+        assert!(!Memory::exists("/dir/dangling"));
+        assert!(Memory::symlink_exists("/dir/dangling"));
+
+        assert!(!Memory::exists("/dir/nothing"));
+        assert!(!Memory::symlink_exists("/dir/nothing"));
+
+        Memory::clear();
+    }
+
+    #[test]
+    fn shared_memory_should_be_shared_between_threads() {
+        type Shared = memory::SharedMemoryFilesystem;
+
+        Shared::mkdir_all("/shared").unwrap();
+
+        let handles = (0..8)
+            .map(|it| std::thread::spawn(move || {
+                Shared::put(format!("/shared/{}", it), &it.to_string()).unwrap();
+                Shared::symlink(format!("/shared/{}", it), format!("/shared/link-{}", it)).unwrap();
+            }))
+            .collect::<Vec<_>>();
+
+        for it in handles {
+            it.join().unwrap();
+        }
+
+        assert_eq!(Shared::read_dir("/shared").unwrap().len(), 16);
+        assert_eq!(Shared::get("/shared/3").unwrap(), "3");
+        assert!(Shared::exists("/shared/link-7"));
+
+        // every thread sees the same thing
+        let seen = std::thread::spawn(|| Shared::walk("/shared").unwrap()).join().unwrap();
+        assert_eq!(seen, Shared::walk("/shared").unwrap());
+
+        // the one the tests use is still separate
+        assert!(!memory::MemoryFilesystem::is_dir("/shared"));
+
+        Shared::clear();
+        assert!(!Shared::is_dir("/shared"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn os_exists_should_not_follow_dangling_links() {
+        type Os = os::OsFilesystem;
+
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path();
+
+        Os::put(dir.join("file"), "content").unwrap();
+        Os::symlink(dir.join("file"), dir.join("link")).unwrap();
+        Os::symlink("file", dir.join("relative")).unwrap();
+        Os::symlink(dir.join("gone"), dir.join("dangling")).unwrap();
+
+        assert!(Os::exists(dir.join("link")));
+        assert!(Os::symlink_exists(dir.join("link")));
+        assert!(Os::exists(dir.join("relative")));
+
+        assert!(!Os::exists(dir.join("dangling")));
+        assert!(Os::symlink_exists(dir.join("dangling")));
+
+        assert!(!Os::exists(dir.join("nothing")));
+        assert!(!Os::symlink_exists(dir.join("nothing")));
+    }
+}
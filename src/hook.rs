@@ -0,0 +1,198 @@
+use crate::Result;
+use std::ffi::OsStr;
+use std::path::Path;
+
+#[cfg(not(test))]
+pub type Shell = dry_run::DryRunRunner<os::OsRunner>;
+
+#[cfg(test)]
+pub type Shell = dry_run::DryRunRunner<memory::MemoryRunner>;
+
+pub trait Runner {
+    // runs `command` through the shell inside `cwd`,
+    // returning its exit code
+    fn run<P: AsRef<Path>>(command: &str, cwd: P) -> Result<i32>;
+    // same as `run`, but keeps what the command printed
+    fn output<P: AsRef<Path>>(command: &str, cwd: P) -> Result<(i32, String)>;
+    // runs `args` as root through sudo, without a shell in between
+    fn sudo<S: AsRef<OsStr>>(args: &[S]) -> Result<i32>;
+}
+
+mod os {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    #[allow(dead_code)]
+    fn shell(command: &str) -> Command {
+        #[cfg(windows)]
+        let mut shell = {
+            let mut it = Command::new("cmd");
+            it.arg("/C");
+            it
+        };
+
+        #[cfg(not(windows))]
+        let mut shell = {
+            let mut it = Command::new("sh");
+            it.arg("-c");
+            it
+        };
+
+        shell.arg(command);
+        shell
+    }
+
+    #[allow(dead_code)]
+    pub struct OsRunner;
+    impl Runner for OsRunner {
+        fn run<P: AsRef<Path>>(command: &str, cwd: P) -> Result<i32> {
+            let status = shell(command)
+                .current_dir(cwd.as_ref())
+                .status()?;
+
+            // killed by a signal, there is no exit code
+            Ok(status.code().unwrap_or(-1))
+        }
+
+        fn output<P: AsRef<Path>>(command: &str, cwd: P) -> Result<(i32, String)> {
+            let output = shell(command)
+                .current_dir(cwd.as_ref())
+                .stderr(Stdio::inherit())
+                .output()?;
+
+            let printed = String::from_utf8_lossy(&output.stdout).into_owned();
+            Ok((output.status.code().unwrap_or(-1), printed))
+        }
+
+        fn sudo<S: AsRef<OsStr>>(args: &[S]) -> Result<i32> {
+            let status = Command::new("sudo")
+                .args(args)
+                .status()?;
+
+            Ok(status.code().unwrap_or(-1))
+        }
+    }
+}
+
+// Only reports commands while dry running, the same way the filesystem
+// does. what's only read through `output` still runs.
+mod dry_run {
+    use super::*;
+    use crate::disk::dry_run_skipped;
+    use std::marker::PhantomData;
+
+    #[allow(dead_code)]
+    pub struct DryRunRunner<R: Runner>(PhantomData<R>);
+
+    impl<R: Runner> Runner for DryRunRunner<R> {
+        fn run<P: AsRef<Path>>(command: &str, cwd: P) -> Result<i32> {
+            if dry_run_skipped(format!("run `{}` in {}", command, cwd.as_ref().display())) {
+                return Ok(0);
+            }
+
+            R::run(command, cwd)
+        }
+
+        fn output<P: AsRef<Path>>(command: &str, cwd: P) -> Result<(i32, String)> {
+            R::output(command, cwd)
+        }
+
+        fn sudo<S: AsRef<OsStr>>(args: &[S]) -> Result<i32> {
+            let command = args
+                .iter()
+                .map(|it| it.as_ref().to_string_lossy().into_owned())
+                .collect::<Vec<String>>()
+                .join(" ");
+
+            if dry_run_skipped(format!("run `sudo {}`", command)) {
+                return Ok(0);
+            }
+
+            R::sudo(args)
+        }
+    }
+
+    // so tests can still look at the commands through `Shell`
+    #[cfg(test)]
+    impl DryRunRunner<memory::MemoryRunner> {
+        pub fn calls() -> Vec<(String, std::path::PathBuf)> {
+            memory::MemoryRunner::calls()
+        }
+
+        pub fn set_exit_code(code: i32) {
+            memory::MemoryRunner::set_exit_code(code)
+        }
+
+        pub fn set_output(output: &str) {
+            memory::MemoryRunner::set_output(output)
+        }
+
+        pub fn clear() {
+            memory::MemoryRunner::clear()
+        }
+    }
+}
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::{cell::RefCell, path::PathBuf};
+
+    // same as the memory filesystem, every test thread
+    // gets its own record of commands
+    thread_local! {
+        static CALLS: RefCell<Vec<(String, PathBuf)>> = const { RefCell::new(Vec::new()) };
+        static EXIT_CODE: RefCell<i32> = const { RefCell::new(0) };
+        static OUTPUT: RefCell<String> = const { RefCell::new(String::new()) };
+    }
+
+    #[allow(dead_code)]
+    pub struct MemoryRunner;
+
+    impl MemoryRunner {
+        pub fn calls() -> Vec<(String, PathBuf)> {
+            CALLS.with(|calls| calls.borrow().clone())
+        }
+
+        pub fn set_exit_code(code: i32) {
+            EXIT_CODE.with(|it| *it.borrow_mut() = code);
+        }
+
+        // what every command prints from now on
+        pub fn set_output(output: &str) {
+            OUTPUT.with(|it| *it.borrow_mut() = String::from(output));
+        }
+
+        pub fn clear() {
+            CALLS.with(|calls| calls.borrow_mut().clear());
+            EXIT_CODE.with(|it| *it.borrow_mut() = 0);
+            OUTPUT.with(|it| it.borrow_mut().clear());
+        }
+    }
+
+    impl Runner for MemoryRunner {
+        fn run<P: AsRef<Path>>(command: &str, cwd: P) -> Result<i32> {
+            CALLS.with(|calls| {
+                calls.borrow_mut().push((String::from(command), cwd.as_ref().to_path_buf()));
+            });
+
+            Ok(EXIT_CODE.with(|it| *it.borrow()))
+        }
+
+        fn output<P: AsRef<Path>>(command: &str, cwd: P) -> Result<(i32, String)> {
+            let code = Self::run(command, cwd)?;
+            Ok((code, OUTPUT.with(|it| it.borrow().clone())))
+        }
+
+        // recorded like a shell command, without a working dir
+        fn sudo<S: AsRef<OsStr>>(args: &[S]) -> Result<i32> {
+            let command = args
+                .iter()
+                .map(|it| it.as_ref().to_string_lossy().into_owned())
+                .collect::<Vec<String>>()
+                .join(" ");
+
+            Self::run(&format!("sudo {}", command), PathBuf::new())
+        }
+    }
+}
@@ -0,0 +1,8241 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use serde::{Serialize, Deserialize};
+use schemars::JsonSchema;
+
+mod util;
+mod disk;
+mod config;
+mod hook;
+mod manifest;
+mod archive;
+mod resolve;
+
+use disk::{Disk, FileType, Filesystem as _};
+use hook::{Shell, Runner as _};
+use manifest::Manifest;
+
+pub use config::{Config, ConfigOverrides, ConflictPolicy, ColorChoice};
+pub use util::parse_duration;
+pub use disk::{set_tracing, set_dry_run};
+pub use resolve::{ConflictResolver, DialoguerResolver, Resolution};
+
+// for tools driving dotgirl in memory, to set up and look at
+// what the commands work with
+#[cfg(feature = "mem-fs")]
+pub mod embed {
+    pub use crate::disk::{Filesystem, FileType, SharedMemoryFilesystem};
+}
+
+const STORAGE_DIR: &str = "dotgirl";
+
+pub(crate) const BACKUP_DIR: &str = "backup";
+// where imported bundles are unpacked before we know their name
+pub(crate) const IMPORT_DIR: &str = "import";
+pub(crate) const LOCK_FILE: &str = "lock.toml";
+// where links are placed instead of the home of whoever runs us
+const TARGET_HOME_VAR: &str = "DOTGIRL_TARGET_HOME";
+// where `~user` is looked up
+const PASSWD_FILE: &str = "/etc/passwd";
+const BUNDLE_FILE: &str = "bundle.toml";
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(std::io::Error),
+    // doing `op` with `path` failed
+    Io {
+        path: PathBuf,
+        op: &'static str,
+        source: std::io::Error,
+    },
+    HomedirNotFound,
+    ParseError(toml::de::Error),
+    SerializeError(toml::ser::Error),
+    JsonError(serde_json::Error),
+    PatternError(glob::PatternError),
+    LastComponentInvalid(String),
+    BundleNotFound,
+    BundleMissingMeta,
+    BundlesFailed(Vec<String>),
+    InvalidName(String),
+    StorageEntryExists(String),
+    SymlinkLoop(String),
+    PermissionDenied(PathBuf),
+    PathNotFound(PathBuf),
+    // an input that isn't inside the base it should be relative to
+    OutsideBase(PathBuf),
+    UnknownEntry(String),
+    // a remote another linked bundle already manages, and that bundle
+    RemoteClaimed(String, String),
+    // an input inside a directory entry, and the bundle it's in
+    AlreadyManaged(PathBuf, String),
+    // bundles that depend on each other, starting and ending with
+    // the same one
+    DependencyCycle(Vec<String>),
+    UnsetEnvVar(String),
+    NoGlobMatches(String),
+    LocalOutsideStorage(String),
+    Unhealthy(usize),
+    HookFailed(i32),
+    ManifestMismatch(Vec<String>),
+    // an input that's neither a file nor a directory, e.g. a socket
+    UnsupportedFileType(PathBuf),
+    // a bundle whose storage doesn't match the checksum in the lock
+    BundleChecksumMismatch(String),
+    // a git command that failed while syncing, and what to do about it
+    GitError(String),
+    // a command that would change storage while it's read only
+    StorageReadOnly,
+    // a hardlink from storage to a remote on another filesystem
+    CrossDevice(PathBuf, PathBuf),
+    // an entry that would be linked onto itself or into storage
+    SelfReferentialEntry(String),
+    // two stored names that only differ by case
+    EntryCollision(PathBuf, PathBuf),
+    // a `{name}` in a template that there's nothing for
+    UnknownPlaceholder(String),
+    Simple(&'static str),
+}
+
+impl Error {
+    // Attaches the path and what we tried to do with it to an io
+    // error, e.g. `fs::read(&path).map_err(Error::io("read", &path))`
+    pub(crate) fn io<P: AsRef<Path>>(op: &'static str, path: P) -> impl FnOnce(std::io::Error) -> Error {
+        let path = path.as_ref().to_path_buf();
+        move |source| Error::Io { path, op, source }
+    }
+
+    fn is_permission_denied(&self) -> bool {
+        match self {
+            Error::IoError(error) | Error::Io { source: error, .. } => {
+                error.kind() == std::io::ErrorKind::PermissionDenied
+            },
+            _ => false,
+        }
+    }
+}
+
+impl std::convert::From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::IoError(error)
+    }
+}
+
+impl std::convert::From<toml::de::Error> for Error {
+    fn from(error: toml::de::Error) -> Self {
+        Error::ParseError(error)
+    }
+}
+
+impl std::convert::From<toml::ser::Error> for Error {
+    fn from(error: toml::ser::Error) -> Self {
+        Error::SerializeError(error)
+    }
+}
+
+impl std::convert::From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::JsonError(error)
+    }
+}
+
+impl std::convert::From<glob::PatternError> for Error {
+    fn from(error: glob::PatternError) -> Self {
+        Error::PatternError(error)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Bundle {
+    pub id: String,
+    // unix timestamp of when the bundle was first added, this is
+    // missing for bundles that were created before we tracked it
+    #[serde(default)]
+    pub created: Option<u64>,
+    // disabled bundles are left out when linking everything
+    #[serde(default = "enabled_by_default", skip_serializing_if = "is_enabled")]
+    pub enabled: bool,
+    // the only hosts the bundle is linked on, every host if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    // what relative remotes are relative to, e.g. `$HOME/.config`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    // bundles that are linked before this one
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+    pub entries: Vec<Entry>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+fn is_enabled(enabled: &bool) -> bool {
+    *enabled
+}
+
+// Commands that run inside the bundle dir at certain points
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Hooks {
+    // runs before anything is added to an existing bundle,
+    // a non-zero exit code aborts the add
+    pub pre_add: Option<String>,
+    pub after_link: Option<String>,
+}
+
+impl Bundle {
+    fn allows_host(&self, host: &str) -> bool {
+        self.hosts.is_empty() || self.hosts.iter().any(|it| it == host)
+    }
+
+    // The bundle as it should be linked on `host`
+    fn view(&self, host: &str) -> Bundle {
+        let entries = self.entries
+            .iter()
+            .filter(|it| it.host.as_ref().is_none_or(|h| h == host))
+            .cloned()
+            .collect();
+
+        Bundle { entries, ..self.clone() }
+    }
+
+    // Picks host specific variants. An entry stored as `<local>.<suffix>`
+    // next to one stored as `<local>` is linked to the generic entry's
+    // remote instead of it, and not to its own. Variants without a
+    // generic entry and those for other suffixes are linked as usual.
+    fn variants(&self, suffix: &str) -> Bundle {
+        if suffix.is_empty() {
+            return self.clone();
+        }
+
+        let variant_of = |it: &Entry| format!("{}.{}", it.local, suffix);
+
+        let entries = self.entries
+            .iter()
+            .filter(|it| !self.entries.iter().any(|generic| variant_of(generic) == it.local))
+            .map(|it| match self.entries.iter().find(|v| v.local == variant_of(it)) {
+                Some(variant) => Entry { remote: it.remote.clone(), ..variant.clone() },
+                None => it.clone(),
+            })
+            .collect();
+
+        Bundle { entries, ..self.clone() }
+    }
+
+    // Only the entries with the given remotes, or all of them
+    // if there are none
+    fn subset(&self, remotes: &[String]) -> Bundle {
+        if remotes.is_empty() {
+            return self.clone();
+        }
+
+        let entries = self.entries
+            .iter()
+            .filter(|it| remotes.contains(&it.remote))
+            .cloned()
+            .collect();
+
+        Bundle { entries, ..self.clone() }
+    }
+
+    // The bundle with variables in remotes expanded and relative ones
+    // joined to the base, which is where its entries are actually
+    // linked. Remotes in `bundle.toml` stay as they were written, so
+    // they can differ between machines.
+    fn expanded_base(&self, env: &Env) -> Result<Option<PathBuf>> {
+        // a home of `/` would leave us with `//` otherwise
+        Ok(match &self.base {
+            Some(base) => Some(Path::new(&util::expand_vars(base, |name| lookup_var(env, name))?)
+                .components()
+                .collect::<PathBuf>()),
+            None => None,
+        })
+    }
+
+    fn expanded(&self, env: &Env) -> Result<Bundle> {
+        let base = self.expanded_base(env)?;
+        let mut expanded = self.clone();
+        for it in expanded.entries.iter_mut() {
+            let remote = util::expand_vars(&it.remote, |name| lookup_var(env, name))?;
+            it.remote = match &base {
+                Some(base) if Path::new(&remote).is_relative() => format!("{}", base.join(&remote).display()),
+                _ => remote,
+            };
+        }
+
+        Ok(expanded)
+    }
+
+    // The entries that were linked for `linked`
+    fn linked_view(&self, linked: &Linked, env: &Env) -> Result<Bundle> {
+        let view = match &linked.host {
+            Some(host) => self.view(host).variants(linked.suffix.as_ref().unwrap_or(host)),
+            None => self.clone(),
+        };
+
+        Ok(view.expanded(env)?.subset(&linked.only))
+    }
+}
+
+impl Hooks {
+    fn is_empty(&self) -> bool {
+        self.pre_add.is_none() && self.after_link.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Entry {
+    pub local: String,
+    pub remote: String,
+    // how the entry was last placed at `remote`
+    #[serde(default, skip_serializing_if = "LinkType::is_symlink")]
+    pub link_type: LinkType,
+    // only link this entry on the given host, entries
+    // without one are linked everywhere
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // overrides the configured conflict policy for this entry,
+    // e.g. defaults that should never replace the user's own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on_conflict: Option<ConflictPolicy>,
+    // where this was added from, only kept so the user can look it up
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    // why this was added, also only for the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    // directories are linked as real directories with each file
+    // linked on its own, so new files can be created next to them
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub per_file: bool,
+    // extended attributes of the file when it was added, hex encoded
+    // by name, so copies can get them back
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+fn is_false(it: &bool) -> bool {
+    !*it
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LinkType {
+    #[default]
+    Symlink,
+    Junction,
+    Hardlink,
+    Copy,
+    // the entry is stored as a tar archive that's extracted at `remote`
+    Archive,
+    // only the directories inside the entry are stored, and created
+    // at `remote` without any files
+    Skeleton,
+}
+
+impl LinkType {
+    fn is_symlink(&self) -> bool {
+        *self == LinkType::Symlink
+    }
+}
+
+impl std::str::FromStr for LinkType {
+    type Err = Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw {
+            "symlink" => Ok(LinkType::Symlink),
+            "junction" => Ok(LinkType::Junction),
+            "hardlink" => Ok(LinkType::Hardlink),
+            "copy" => Ok(LinkType::Copy),
+            "archive" => Ok(LinkType::Archive),
+            "skeleton" => Ok(LinkType::Skeleton),
+            _ => Err(Error::Simple("invalid link type")),
+        }
+    }
+}
+
+impl std::fmt::Display for LinkType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            LinkType::Symlink => "symlink",
+            LinkType::Junction => "junction",
+            LinkType::Hardlink => "hardlink",
+            LinkType::Copy => "copy",
+            LinkType::Archive => "archive",
+            LinkType::Skeleton => "skeleton",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// Whether `cmd_add` shares data with what it copies into storage
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Reflink {
+    // share data if the filesystem can, copy otherwise
+    #[default]
+    Auto,
+    // fail if the data can't be shared
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for Reflink {
+    type Err = Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw {
+            "auto" => Ok(Reflink::Auto),
+            "always" => Ok(Reflink::Always),
+            "never" => Ok(Reflink::Never),
+            _ => Err(Error::Simple("invalid reflink mode")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Linked {
+    pub id: String,
+    // which host's entries were linked, missing for bundles
+    // that were linked before we had hosts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    // remotes of the entries that were picked when only some
+    // were linked, empty if all of them were
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only: Vec<String>,
+    // the suffix host specific variants were picked by, if it
+    // wasn't the host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suffix: Option<String>,
+    // hash over everything in the bundle's storage when it was last
+    // linked, missing in locks from before we had checksums
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    // the bundle as it was when it was last linked, so it can be
+    // recovered if its `bundle.toml` goes missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+    #[serde(default, skip_serializing_if = "Hooks::is_empty")]
+    pub hooks: Hooks,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entries: Vec<Entry>,
+    // leaves that were linked into an existing directory instead of
+    // replacing it, so we know what to remove when unlinking
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub merged: Vec<Entry>,
+}
+
+impl Linked {
+    // The bundle as it was when it was last linked
+    fn bundle(&self) -> Bundle {
+        Bundle {
+            id: self.id.clone(),
+            created: self.created,
+            enabled: true,
+            base: self.base.clone(),
+            hosts: self.hosts.clone(),
+            dependencies: self.dependencies.clone(),
+            hooks: self.hooks.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+}
+
+// The result of linking a bundle. `linked` has every bundle entry that
+// was linked (including merged ones), and `merged` the individual leaves
+// that were linked into existing directories.
+#[derive(Debug, Default)]
+struct LinkResult {
+    linked: Vec<Entry>,
+    merged: Vec<Entry>,
+    skipped: Vec<(Entry, SkipReason)>,
+    // what happened to every entry, in the order they were linked
+    actions: Vec<(Entry, LinkAction)>,
+}
+
+impl LinkResult {
+    fn link(&mut self, entry: Entry, action: LinkAction) {
+        self.actions.push((entry.clone(), action));
+        self.linked.push(entry);
+    }
+
+    fn skip(&mut self, entry: &Entry, reason: SkipReason) {
+        self.actions.push((entry.clone(), LinkAction::Skipped(reason)));
+        self.skipped.push((entry.clone(), reason));
+    }
+}
+
+// What `link` did with a single entry
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkAction {
+    Linked,
+    // something was in the way and was removed
+    Overwritten,
+    // same as overwritten, but what was there is in the backup dir
+    BackedUp,
+    // linked into a directory that was already there
+    Merged,
+    Skipped(SkipReason),
+}
+
+impl std::fmt::Display for LinkAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LinkAction::Linked => write!(f, "linked"),
+            LinkAction::Overwritten => write!(f, "linked, replacing what was there"),
+            LinkAction::BackedUp => write!(f, "linked, backing up what was there"),
+            LinkAction::Merged => write!(f, "merged into the existing directory"),
+            LinkAction::Skipped(reason) => write!(f, "skipped, {}", reason),
+        }
+    }
+}
+
+// Why `link` left an entry alone
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    // it was already linked to the right place
+    AlreadyLinked,
+    // the user chose to skip it when asked
+    UserSkip,
+    // something was in the way and the conflict policy said to skip
+    Conflict,
+    // there's nothing in storage to link
+    MissingSource,
+}
+
+impl std::fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let text = match self {
+            SkipReason::AlreadyLinked => "already linked",
+            SkipReason::UserSkip => "skipped when asked",
+            SkipReason::Conflict => "something else is in the way",
+            SkipReason::MissingSource => "missing from storage",
+        };
+
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Skipped {
+    pub bundle: String,
+    pub path: String,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntryAction {
+    pub bundle: String,
+    pub path: String,
+    pub action: LinkAction,
+}
+
+// What `cmd_link` did with each entry, by remote path
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkSummary {
+    pub linked: Vec<String>,
+    pub skipped: Vec<Skipped>,
+    #[serde(default)]
+    pub actions: Vec<EntryAction>,
+}
+
+impl LinkSummary {
+    fn add(&mut self, bundle_id: &str, result: &LinkResult) {
+        self.linked.extend(result.linked.iter().map(|it| it.remote.clone()));
+        self.skipped.extend(result.skipped.iter().map(|(it, reason)| Skipped {
+            bundle: String::from(bundle_id),
+            path: it.remote.clone(),
+            reason: *reason,
+        }));
+        self.actions.extend(result.actions.iter().map(|(it, action)| EntryAction {
+            bundle: String::from(bundle_id),
+            path: it.remote.clone(),
+            action: *action,
+        }));
+    }
+
+    // `verbose` lists every entry instead of only the skipped ones
+    fn render(&self, format: OutputFormat, quiet_skip: bool, verbose: bool) -> Result<String> {
+        if format == OutputFormat::Json {
+            return Ok(serde_json::to_string_pretty(&self)?);
+        }
+
+        let mut lines = vec![format!("linked {} entries", self.linked.len())];
+        if verbose {
+            let shown = self.actions.iter().filter(|it| match it.action {
+                LinkAction::Skipped(_) => !quiet_skip,
+                _ => true,
+            });
+
+            for it in shown {
+                lines.push(format!("{}: {}", it.path, it.action));
+            }
+        } else if !quiet_skip {
+            for it in &self.skipped {
+                lines.push(format!("skipped {}: {}", it.path, it.reason));
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+}
+
+// How many entries a command changed and how, the same for every
+// command that changes things
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OperationReport {
+    pub added: usize,
+    pub linked: usize,
+    pub unlinked: usize,
+    pub skipped: usize,
+    pub backed_up: usize,
+    // bundles that failed, for commands that go through all of them
+    pub errors: usize,
+}
+
+impl OperationReport {
+    fn render(&self, format: OutputFormat) -> Result<String> {
+        if format == OutputFormat::Json {
+            return Ok(serde_json::to_string(&self)?);
+        }
+
+        let counts = [
+            (self.added, "added"),
+            (self.linked, "linked"),
+            (self.unlinked, "unlinked"),
+            (self.skipped, "skipped"),
+            (self.backed_up, "backed up"),
+            (self.errors, "failed"),
+        ];
+
+        let counts = counts
+            .iter()
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, what)| format!("{} {}", count, what))
+            .collect::<Vec<String>>();
+
+        match counts.is_empty() {
+            true => Ok(String::from("summary: nothing to do")),
+            false => Ok(format!("summary: {}", counts.join(", "))),
+        }
+    }
+}
+
+impl From<&LinkSummary> for OperationReport {
+    fn from(summary: &LinkSummary) -> Self {
+        OperationReport {
+            linked: summary.linked.len(),
+            skipped: summary.skipped.len(),
+            backed_up: summary.actions.iter().filter(|it| it.action == LinkAction::BackedUp).count(),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<&AddSummary> for OperationReport {
+    fn from(summary: &AddSummary) -> Self {
+        OperationReport {
+            added: summary.added.len() + summary.updated.len(),
+            skipped: summary.unchanged.len(),
+            ..Default::default()
+        }
+    }
+}
+
+// The report goes to stderr, so it never gets mixed into results
+// that are meant to be read by other programs
+fn print_report(env: &Env, report: &OperationReport) -> Result<()> {
+    if env.output.summary {
+        eprintln!("{}", report.render(env.output.format)?);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct Lock {
+    pub linked: Vec<Linked>,
+}
+
+// The files there's a json schema for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchemaKind {
+    Lock,
+    Bundle,
+}
+
+impl std::str::FromStr for SchemaKind {
+    type Err = Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw {
+            "lock" => Ok(SchemaKind::Lock),
+            "bundle" => Ok(SchemaKind::Bundle),
+            _ => Err(Error::Simple("invalid schema kind")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Name,
+    Size,
+    Created,
+}
+
+impl std::str::FromStr for SortKey {
+    type Err = Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw {
+            "name" => Ok(SortKey::Name),
+            "size" => Ok(SortKey::Size),
+            "created" => Ok(SortKey::Created),
+            _ => Err(Error::Simple("invalid sort key")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::Simple("invalid output format")),
+        }
+    }
+}
+
+// Where and how commands report their results
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub format: OutputFormat,
+    // write to this file instead of stdout
+    pub path: Option<PathBuf>,
+    // end commands that change things with a count of what they did
+    pub summary: bool,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output { format: OutputFormat::Text, path: None, summary: false }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BundleSummary {
+    pub id: String,
+    pub entries: usize,
+    pub size: u64,
+    pub created: Option<u64>,
+    pub linked: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    // nothing exists where the entry should be linked
+    MissingLink,
+    // a symlink exists, but it points somewhere else
+    WrongTarget,
+    // something we didn't put there is in the way
+    Foreign,
+    // a symlink to somewhere outside of storage is in the way, most
+    // likely left by another dotfile manager
+    ForeignLink,
+    // the entry is gone from storage
+    MissingSource,
+    // the lockfile references a bundle that doesn't exist
+    MissingBundle,
+    // the bundle dir exists, but has no bundle.toml
+    MissingMeta,
+    // the lockfile has the same bundle more than once
+    DuplicateBundle,
+    // more than one linked bundle has an entry at the same remote
+    DuplicateRemote,
+    // an entry in the lockfile is missing its local or remote path
+    EmptyPath,
+    // an entry in the lockfile is linked onto storage itself
+    SelfReferential,
+}
+
+impl ProblemKind {
+    fn describe(self) -> &'static str {
+        match self {
+            ProblemKind::MissingLink => "is not linked",
+            ProblemKind::WrongTarget => "links somewhere else",
+            ProblemKind::Foreign => "was replaced by something else",
+            ProblemKind::ForeignLink => "is linked outside of storage by something else",
+            ProblemKind::MissingSource => "is missing from storage",
+            ProblemKind::MissingBundle => "does not exist",
+            ProblemKind::MissingMeta => "has no bundle.toml",
+            ProblemKind::DuplicateBundle => "is in the lock more than once",
+            ProblemKind::DuplicateRemote => "is linked by more than one bundle",
+            ProblemKind::EmptyPath => "has an entry without a path in the lock",
+            ProblemKind::SelfReferential => "would be linked onto storage itself",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    pub bundle: String,
+    pub path: String,
+    pub kind: ProblemKind,
+    // where the entry was originally added from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+    pub ok: bool,
+    pub problems: Vec<Problem>,
+}
+
+impl Health {
+    fn new(problems: Vec<Problem>) -> Self {
+        Health { ok: problems.is_empty(), problems }
+    }
+
+    fn into_result(self) -> Result<()> {
+        if self.ok {
+            return Ok(());
+        }
+
+        Err(Error::Unhealthy(self.problems.len()))
+    }
+}
+
+// Options for `cmd_add` that can be set from the command line
+#[derive(Debug, Clone, Default)]
+pub struct AddOptions {
+    // stored name for the input, overriding the derived one
+    pub name: Option<String>,
+    // replace things that already exist in storage
+    pub force: bool,
+    // how to link the added inputs, defaults to symlinks
+    pub link_type: Option<LinkType>,
+    // add the targets of symlinked inputs instead of skipping them
+    pub follow_symlinks: bool,
+    // only link the added inputs on this host
+    pub host: Option<String>,
+    // keep leading dots in stored names, regardless of the config
+    pub keep_dots: bool,
+    // only move the inputs into storage, a later `link` puts them back
+    pub no_link: bool,
+    // store directories as a single archive instead of their contents
+    pub archive: bool,
+    // create the entry with this content instead of moving an input
+    // into storage, the single path is only where it's linked to
+    pub content: Option<String>,
+    // how many inputs to move into storage at once, 0 is the same as 1
+    pub max_parallel: usize,
+    pub reflink: Reflink,
+    // a directory laid out like home, each of its children is copied
+    // into storage and linked to the same place inside of home
+    pub from_dir: Option<PathBuf>,
+    // record remotes relative to this instead of as absolute paths
+    pub relative_to: Option<PathBuf>,
+    // only show what would happen
+    pub dry_run: bool,
+    // keep the extended attributes of added files
+    pub xattrs: bool,
+    // ask once before changing anything, the same as `confirm_add`
+    // in the config
+    pub confirm: bool,
+    // only store the directories inside of the inputs, the inputs
+    // themselves stay where they are
+    pub parents_only: bool,
+    // link the single input here instead of where it came from, the
+    // input is copied into storage and left alone
+    pub remote: Option<PathBuf>,
+    // a git repo laid out like home, every file it tracks is copied
+    // into storage and linked to the same place inside of home
+    pub paths_from_git: Option<PathBuf>,
+    // a note for the single input
+    pub note: Option<String>,
+    // notes for some of the inputs, by remote
+    pub notes: Vec<(PathBuf, String)>,
+    // leave out inputs that are already linked into the bundle, so
+    // the same inputs can be added again without changing anything
+    pub only_new: bool,
+}
+
+impl AddOptions {
+    // whether inputs are copied into storage instead of moved, with
+    // whatever is at their remote left to the conflict policy
+    fn copies_inputs(&self) -> bool {
+        self.from_dir.is_some() || self.remote.is_some() || self.paths_from_git.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AddStatus {
+    Added,
+    Updated,
+    Unchanged,
+    // the input already links to its place in storage, so
+    // there's nothing to move and the link has to stay
+    Linked,
+}
+
+// What `cmd_add` did with each input, by remote path
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AddSummary {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub unchanged: Vec<String>,
+}
+
+impl AddSummary {
+    fn render(&self, format: OutputFormat) -> Result<String> {
+        if format == OutputFormat::Json {
+            return Ok(serde_json::to_string_pretty(&self)?);
+        }
+
+        if self.added.is_empty() && self.updated.is_empty() {
+            return Ok(String::from("nothing changed"));
+        }
+
+        Ok(format!(
+            "{} added, {} updated, {} unchanged",
+            self.added.len(), self.updated.len(), self.unchanged.len(),
+        ))
+    }
+}
+
+// Options for `cmd_gc` that can be set from the command line. Backups
+// are removed if they're older than `older_than` seconds, but the
+// newest `keep` are always left alone.
+#[derive(Debug, Clone, Default)]
+pub struct GcOptions {
+    pub older_than: Option<u64>,
+    pub keep: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GcSummary {
+    pub removed: Vec<String>,
+    // bytes of files that were removed
+    pub reclaimed: u64,
+}
+
+impl GcSummary {
+    fn render(&self, format: OutputFormat) -> Result<String> {
+        if format == OutputFormat::Json {
+            return Ok(serde_json::to_string_pretty(&self)?);
+        }
+
+        if self.removed.is_empty() {
+            return Ok(String::from("nothing to remove"));
+        }
+
+        Ok(format!("removed {} backups, reclaimed {} bytes", self.removed.len(), self.reclaimed))
+    }
+}
+
+// Options for `cmd_unlink` that can be set from the command line
+#[derive(Debug, Clone, Default)]
+pub struct UnlinkOptions {
+    // put copies of the stored entries back where the links were
+    pub restore: bool,
+    // remove the bundle from storage afterwards, only makes
+    // sense together with `restore`
+    pub purge: bool,
+    // also remove links that point somewhere other than storage
+    pub force: bool,
+}
+
+// Options for `cmd_link` that can be set from the command line
+#[derive(Debug, Clone, Default)]
+pub struct LinkOptions {
+    // overrides the link type recorded for each entry
+    pub link_type: Option<LinkType>,
+    // skip running the bundle's hooks
+    pub no_hooks: bool,
+    // link the entries of another host instead of the current one
+    pub from: Option<String>,
+    // retry with sudo when we're not allowed to write somewhere,
+    // without asking first
+    pub sudo: bool,
+    // don't print skipped entries
+    pub quiet_skip: bool,
+    // only show what would happen
+    pub dry_run: bool,
+    // point symlinks at storage relative to where they are
+    pub relative: bool,
+    // only link the entries with these remotes
+    pub entries: Vec<String>,
+    // link remotes even if another bundle manages them already
+    pub ignore_cross_bundle: bool,
+    // pick variants stored with this suffix instead of the host's
+    pub hostname_suffix: Option<String>,
+    // don't link the bundle's dependencies first
+    pub no_deps: bool,
+    // link the files inside of directories one by one
+    pub per_file: bool,
+    // give copies the modification time of what's in storage
+    pub preserve_mtime: bool,
+    // print what happened to every entry
+    pub verbose: bool,
+    // link bundles even on hosts they aren't meant for
+    pub force_host: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Env {
+    pub home: PathBuf,
+    pub storage: PathBuf,
+    pub config: Config,
+    // the host we're running on
+    pub host: String,
+    // whether we can ask the user things
+    pub interactive: bool,
+    pub output: Output,
+    // whether changes are recorded in the lock, turning this off
+    // lets what's linked drift from what the lock says
+    pub update_lock: bool,
+    pub clock: Clock,
+    // check bundles against the checksum in the lock and their
+    // manifest before using them
+    pub verify: bool,
+    // only allow linking, nothing in storage is changed, including the lock
+    pub storage_readonly: bool,
+    // answers for conflicts when the policy is to ask
+    pub resolver: Rc<dyn ConflictResolver>,
+}
+
+// Where timestamps come from, so they can be fixed in tests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    System,
+    // always returns the same unix timestamp
+    Fixed(u64),
+}
+
+impl Clock {
+    pub fn now(&self) -> u64 {
+        match self {
+            Clock::System => util::now(),
+            Clock::Fixed(it) => *it,
+        }
+    }
+}
+
+impl Env {
+    pub fn new(home: PathBuf) -> Self {
+        let storage = home.join(STORAGE_DIR);
+        // e.g. a shared image that's mounted read only
+        let storage_readonly = Disk::is_dir(&storage) && !Disk::is_writable(&storage);
+
+        Env {
+            home,
+            storage,
+            config: Default::default(),
+            host: util::hostname(),
+            interactive: atty::is(atty::Stream::Stdin),
+            output: Default::default(),
+            update_lock: true,
+            clock: Clock::System,
+            verify: false,
+            storage_readonly,
+            resolver: Rc::new(DialoguerResolver::default()),
+        }
+    }
+
+    // Where bundles are kept inside of storage
+    fn bundle_dir(&self) -> PathBuf {
+        self.storage.join(&self.config.bundle_dir)
+    }
+}
+
+fn get_storage_dir(env: &Env) -> Result<PathBuf> {
+    let path = env.storage.clone();
+    Disk::mkdir_all(&env.storage)?;
+
+    Ok(path)
+}
+
+pub fn get_lockfile(env: &Env) -> Result<Lock> {
+    let path = env.storage.join(LOCK_FILE);
+
+    if !Disk::is_file(&path) {
+        return Ok(Default::default());
+    }
+
+    let contents = Disk::get(path)?;
+    let parsed = toml::from_str::<Lock>(&contents)?;
+
+    Ok(parsed)
+}
+
+// Fails for commands that only make sense if they can change storage,
+// before they've done anything
+fn check_storage_writable(env: &Env) -> Result<()> {
+    match env.storage_readonly {
+        true => Err(Error::StorageReadOnly),
+        false => Ok(()),
+    }
+}
+
+fn write_lockfile(env: &Env, lockfile: &Lock) -> Result<()> {
+    if !env.update_lock || env.storage_readonly {
+        return Ok(());
+    }
+
+    let mut lock_path = get_storage_dir(env)?;
+    lock_path.push(LOCK_FILE);
+
+    let ser = serialize_lock(lockfile)?;
+    Disk::put(&lock_path, &ser)?;
+
+    Ok(())
+}
+
+fn serialize_lock(lockfile: &Lock) -> Result<String> {
+    // keep the file stable no matter what order things were linked in
+    let mut lockfile = lockfile.clone();
+    lockfile.linked.sort_by(|a, b| a.id.cmp(&b.id));
+    for it in lockfile.linked.iter_mut() {
+        sort_entries(&mut it.entries);
+        sort_entries(&mut it.merged);
+    }
+
+    Ok(toml::to_string(&lockfile)?)
+}
+
+fn get_bundle_names(env: &Env) -> Result<Vec<String>> {
+    let dir = env.bundle_dir();
+    if !Disk::is_dir(&dir) {
+        return Ok(vec![]);
+    }
+
+    let names = Disk::read_dir(&dir)?
+        .into_iter()
+        .filter(|it| Disk::is_dir(it))
+        .filter_map(|it| {
+            it.file_name()
+                .and_then(|name| name.to_str())
+                .map(String::from)
+        })
+        .collect::<Vec<String>>();
+
+    Ok(names)
+}
+
+fn read_bundle(env: &Env, bundle_name: &str) -> Result<Bundle> {
+    let dir = env.bundle_dir().join(bundle_name);
+    if !Disk::is_dir(&dir) {
+        return Err(Error::BundleNotFound);
+    }
+
+    let dot_meta_path = dir.join(BUNDLE_FILE);
+    if !Disk::is_file(&dot_meta_path) {
+        return Err(Error::BundleMissingMeta);
+    }
+
+    if env.verify {
+        verify_checksum(env, bundle_name)?;
+    }
+
+    let raw = Disk::get(&dot_meta_path)?;
+    let bundle = toml::from_str::<Bundle>(&raw)?;
+
+    Ok(bundle)
+}
+
+fn write_bundle(env: &Env, bundle: &Bundle) -> Result<()> {
+    let path = env.bundle_dir()
+        .join(&bundle.id)
+        .join(BUNDLE_FILE);
+
+    let mut bundle = bundle.clone();
+    sort_entries(&mut bundle.entries);
+
+    let ser = toml::to_string(&bundle)?;
+    Disk::put(&path, &ser)?;
+
+    Ok(())
+}
+
+// Entries are written sorted, so storage diffs don't depend on the
+// order things were added in
+fn sort_entries(entries: &mut [Entry]) {
+    entries.sort_by(|a, b| a.remote.cmp(&b.remote).then_with(|| a.local.cmp(&b.local)));
+}
+
+// Reads the bundle, rebuilding its `bundle.toml` from the lock if
+// it went missing while the bundle was linked
+fn recover_bundle(env: &Env, lockfile: &Lock, bundle_name: &str) -> Result<Bundle> {
+    match read_bundle(env, bundle_name) {
+        Err(Error::BundleMissingMeta) => {},
+        other => return other,
+    };
+
+    let linked = lockfile.linked
+        .iter()
+        .find(|it| it.id == bundle_name && !it.entries.is_empty())
+        .ok_or(Error::BundleMissingMeta)?;
+
+    eprintln!("warning: `{}` is missing its {}, recovering it from the lock", bundle_name, BUNDLE_FILE);
+    check_storage_writable(env)?;
+
+    let bundle = linked.bundle();
+    write_bundle(env, &bundle)?;
+    Ok(bundle)
+}
+
+// Remembers which link type was actually used for each linked entry,
+// since it might differ from the requested one
+fn record_link_types(env: &Env, bundle: &mut Bundle, linked: &[Entry]) -> Result<()> {
+    let mut changed = false;
+
+    for it in bundle.entries.iter_mut() {
+        let used = linked.iter().find(|l| l.local == it.local);
+        if let Some(used) = used {
+            if used.link_type != it.link_type || used.per_file != it.per_file {
+                it.link_type = used.link_type;
+                it.per_file = used.per_file;
+                changed = true;
+            }
+        }
+    }
+
+    // they're only remembered, linking the same way again is fine
+    if changed && !env.storage_readonly {
+        write_bundle(env, bundle)?;
+    }
+
+    Ok(())
+}
+
+// Records `id` as linked. `only` has the remotes of the entries
+// that were picked, linking all of them if it's empty.
+fn mark_linked(
+    lockfile: &mut Lock,
+    bundle: &Bundle,
+    host: &str,
+    suffix: &str,
+    only: &[String],
+    merged: Vec<Entry>,
+) {
+    let suffix = if suffix == host { None } else { Some(String::from(suffix)) };
+
+    let linked = match lockfile.linked.iter().position(|it| it.id == bundle.id) {
+        Some(index) => &mut lockfile.linked[index],
+        None => {
+            lockfile.linked.push(Linked {
+                id: bundle.id.clone(),
+                only: only.to_vec(),
+                ..Default::default()
+            });
+            lockfile.linked.last_mut().expect("Invalid: it was just pushed")
+        },
+    };
+
+    linked.host = Some(String::from(host));
+    linked.suffix = suffix;
+    linked.entries = bundle.entries.clone();
+    linked.created = bundle.created;
+    linked.base = bundle.base.clone();
+    linked.hosts = bundle.hosts.clone();
+    linked.dependencies = bundle.dependencies.clone();
+    linked.hooks = bundle.hooks.clone();
+
+    // picking more entries adds to the ones that were linked
+    // before, and once everything is linked it stays that way
+    if only.is_empty() || linked.only.is_empty() {
+        linked.only.clear();
+    } else {
+        for it in only {
+            if !linked.only.contains(it) {
+                linked.only.push(it.clone());
+            }
+        }
+    }
+
+    for it in merged {
+        if !linked.merged.iter().any(|m| m.remote == it.remote) {
+            linked.merged.push(it);
+        }
+    }
+}
+
+// Remembers what the bundle's storage looks like right now, so
+// changes made to it behind our back can be noticed later. only call
+// this if the checksum matched before we changed anything, one that
+// doesn't match has to stay that way.
+fn record_checksum(env: &Env, lockfile: &mut Lock, id: &str) -> Result<()> {
+    if let Some(linked) = lockfile.linked.iter_mut().find(|it| it.id == id) {
+        linked.checksum = Some(manifest::checksum(&env.bundle_dir().join(id))?);
+    }
+
+    Ok(())
+}
+
+// Bundles without a checksum can't be verified, so they're trusted
+fn checksum_matches(env: &Env, lockfile: &Lock, id: &str) -> Result<bool> {
+    let expected = lockfile.linked
+        .iter()
+        .find(|it| it.id == id)
+        .and_then(|it| it.checksum.as_ref());
+
+    match expected {
+        Some(expected) => Ok(manifest::checksum(&env.bundle_dir().join(id))? == *expected),
+        None => Ok(true),
+    }
+}
+
+fn verify_checksum(env: &Env, bundle_name: &str) -> Result<()> {
+    if !checksum_matches(env, &get_lockfile(env)?, bundle_name)? {
+        return Err(Error::BundleChecksumMismatch(String::from(bundle_name)));
+    }
+
+    Ok(())
+}
+
+// Collects the outcome of an operation over several bundles, so that
+// we can print a single report at the end instead of bailing out on
+// the first bundle that fails
+#[derive(Debug, Default)]
+struct BulkReport {
+    done: Vec<String>,
+    skipped: Vec<String>,
+    failed: Vec<(String, Error)>,
+}
+
+impl BulkReport {
+    fn print(&self, verb: &str) {
+        println!("{} {} bundle(s)", verb, self.done.len());
+
+        if !self.skipped.is_empty() {
+            println!("skipped {} entries:", self.skipped.len());
+            for it in &self.skipped {
+                println!("  {}", it);
+            }
+        }
+
+        if !self.failed.is_empty() {
+            println!("failed {} bundle(s):", self.failed.len());
+            for (id, error) in &self.failed {
+                println!("  {}: {:?}", id, error);
+            }
+        }
+    }
+
+    fn into_result(self) -> Result<()> {
+        if self.failed.is_empty() {
+            return Ok(());
+        }
+
+        let failed = self.failed
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect::<Vec<String>>();
+
+        Err(Error::BundlesFailed(failed))
+    }
+}
+
+// Variables that can be used in remotes. `HOME` is always the home
+// we're linking into, everything else comes from the environment.
+fn lookup_var(env: &Env, name: &str) -> Option<String> {
+    match name {
+        "HOME" => Some(format!("{}", env.home.display())),
+        "USER" => std::env::var("USER").or_else(|_| std::env::var("USERNAME")).ok(),
+        _ => std::env::var(name).ok(),
+    }
+}
+
+// The home links are placed in, if it's not our own. storage stays
+// where it is, and the command line wins over the environment.
+pub fn target_home(flag: Option<&str>) -> Option<PathBuf> {
+    flag.map(PathBuf::from)
+        .or_else(|| std::env::var_os(TARGET_HOME_VAR).map(PathBuf::from))
+        .filter(|it| !it.as_os_str().is_empty())
+}
+
+// The home dir of `user` from the passwd file
+fn home_of(user: &str) -> Option<PathBuf> {
+    Disk::get(PASSWD_FILE)
+        .ok()?
+        .lines()
+        .map(|it| it.split(':').collect::<Vec<&str>>())
+        .find(|it| it.len() >= 6 && it[0] == user)
+        .map(|it| PathBuf::from(it[5]))
+}
+
+// Expands a leading `~` or `~user` and glob patterns in an input, since
+// they might have been quoted or not come from a shell at all. `~` is
+// always our home, which might not be the one we're running as.
+pub fn expand_input(env: &Env, input: &str) -> Result<Vec<PathBuf>> {
+    let input = match input {
+        "~" => format!("{}", env.home.display()),
+        it if it.starts_with("~/") => format!("{}", env.home.join(&it[2..]).display()),
+        // unknown users are left alone, the same as in a shell
+        it if it.starts_with('~') => {
+            let (user, rest) = match it[1..].find('/') {
+                Some(end) => (&it[1..=end], &it[end + 2..]),
+                None => (&it[1..], ""),
+            };
+
+            match home_of(user) {
+                Some(home) if rest.is_empty() => format!("{}", home.display()),
+                Some(home) => format!("{}", home.join(rest).display()),
+                None => String::from(it),
+            }
+        },
+        it => String::from(it),
+    };
+
+    if !input.contains(['*', '?', '[']) {
+        return Ok(vec![PathBuf::from(input)]);
+    }
+
+    // entries we can't read are skipped, same as the shell does
+    let mut paths = glob::glob(&input)?
+        .filter_map(std::result::Result::ok)
+        .collect::<Vec<PathBuf>>();
+
+    if paths.is_empty() {
+        return Err(Error::NoGlobMatches(input));
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+pub fn cmd_add(
+    env: &Env,
+    bundle_name: &str,
+    paths: &Vec<PathBuf>,
+    options: &AddOptions,
+) -> Result<AddSummary> {
+    check_storage_writable(env)?;
+    let mut lockfile = get_lockfile(env)?;
+
+    if let Some(name) = &options.name {
+        if paths.len() != 1 {
+            return Err(Error::Simple("--name can only be used with a single input"));
+        }
+
+        util::validate_name(name)?;
+    }
+
+    if options.content.is_some() && (paths.len() != 1 || options.archive) {
+        return Err(Error::Simple("content can only be added for a single remote, without --archive"));
+    }
+
+    if let Some(remote) = &options.remote {
+        if paths.len() != 1 || options.content.is_some() || options.from_dir.is_some() || options.archive {
+            return Err(Error::Simple("--remote can only be used with a single input, without --archive"));
+        }
+
+        if !remote.is_absolute() {
+            return Err(Error::Simple("--remote has to be an absolute path or start with ~/"));
+        }
+    }
+
+    if options.note.is_some() && paths.len() != 1 {
+        return Err(Error::Simple("--note can only be used with a single input, use --note-for otherwise"));
+    }
+
+    if options.parents_only && (options.archive || options.content.is_some() || options.copies_inputs()) {
+        return Err(Error::Simple("--parents-only can't be combined with --archive, content or a mirrored directory"));
+    }
+
+    // TODO(happens): More validation
+    //   - Check that directories don't contain each other
+    //   - Check for duplicates
+    //   - Exclude storage directory
+    let bundle_path = env.bundle_dir().join(bundle_name);
+
+    let keep_dots = options.keep_dots || !env.config.trim_dots;
+
+    // anything inside a linked directory already lives in storage,
+    // adding it again would move it into itself
+    for it in paths.iter().chain(options.remote.iter()) {
+        if let Some(owner) = find_covering_bundle(env, it)? {
+            return Err(Error::AlreadyManaged(it.clone(), owner));
+        }
+    }
+
+    // check everything up front, so a typo in one input doesn't
+    // leave the others already moved
+    let missing = paths
+        .iter()
+        .find(|it| options.content.is_none() && !Disk::symlink_exists(it));
+
+    if let Some(missing) = missing {
+        return Err(Error::PathNotFound(missing.clone()));
+    }
+
+    // inputs are where they're linked to, together with where they
+    // are copied from if that's somewhere else
+    let inputs = match (&options.from_dir, &options.paths_from_git) {
+        (Some(_), Some(_)) => {
+            return Err(Error::Simple("a mirrored directory and paths from git can't be added together"));
+        },
+        (None, Some(repo)) => {
+            if !paths.is_empty() || options.content.is_some() || options.archive || options.remote.is_some() {
+                return Err(Error::Simple("paths from git can only be added on their own, without --archive"));
+            }
+
+            tracked_files(repo)?
+                .into_iter()
+                .map(|it| (env.home.join(&it), Some(repo.join(&it))))
+                .collect::<Vec<(PathBuf, Option<PathBuf>)>>()
+        },
+        (Some(dir), None) => {
+            if !paths.is_empty() || options.content.is_some() || options.archive {
+                return Err(Error::Simple("a mirrored directory can only be added on its own, without --archive"));
+            }
+
+            if !Disk::is_dir(dir) {
+                return Err(Error::PathNotFound(dir.clone()));
+            }
+
+            Disk::read_dir(dir)?
+                .into_iter()
+                .map(|it| {
+                    let name = it.file_name().expect("Invalid: read_dir returned a path without a name");
+                    (env.home.join(name), Some(it.clone()))
+                })
+                .collect::<Vec<(PathBuf, Option<PathBuf>)>>()
+        },
+        (None, None) => match &options.remote {
+            Some(remote) => vec![(remote.clone(), Some(paths[0].clone()))],
+            None => paths.iter().map(|it| (it.clone(), None)).collect(),
+        },
+    };
+
+    let unknown = options.notes
+        .iter()
+        .find(|(remote, _)| !inputs.iter().any(|(it, _)| it == remote));
+
+    if let Some((remote, _)) = unknown {
+        return Err(Error::UnknownEntry(format!("{}", remote.display())));
+    }
+
+    if let Some(base) = &options.relative_to {
+        let outside = inputs.iter().find(|(remote, _)| !remote.starts_with(base));
+        if let Some((remote, _)) = outside {
+            return Err(Error::OutsideBase(remote.clone()));
+        }
+    }
+
+    // links into the bundle are from an earlier add of the same inputs
+    let inputs = match options.only_new {
+        true => inputs
+            .into_iter()
+            .filter(|(remote, _)| !Disk::is_symlink(remote) || links_outside(remote, &bundle_path))
+            .collect(),
+        false => inputs,
+    };
+
+    if options.only_new && inputs.is_empty() {
+        return Ok(AddSummary::default());
+    }
+
+    let mut planned = Vec::new();
+    let mut unsupported = Vec::new();
+    for (remote, mirrored) in &inputs {
+        let remote_name = match &options.name {
+            Some(name) => name.clone(),
+            // files from git keep their place inside the repo, so
+            // ones with the same name don't end up in the same spot
+            None if options.paths_from_git.is_some() => remote
+                .strip_prefix(&env.home)
+                .expect("Invalid: files from git are inside of home")
+                .components()
+                .map(|it| util::get_name(&PathBuf::from(it.as_os_str()), keep_dots))
+                .collect::<Result<Vec<String>>>()?
+                .join("/"),
+            None => util::get_name(remote, keep_dots)?,
+        };
+
+        let local = if options.archive {
+            bundle_path.join(format!("{}.tar", remote_name))
+        } else {
+            bundle_path.join(remote_name)
+        };
+
+        // copied inputs are left alone, and `remote` is left to the
+        // conflict policy when linking
+        if let Some(source) = mirrored {
+            let status = if !Disk::symlink_exists(&local) {
+                AddStatus::Added
+            } else if dir_contents_equal(source, &local)? {
+                AddStatus::Unchanged
+            } else {
+                AddStatus::Updated
+            };
+
+            planned.push((remote.clone(), source.clone(), local, status));
+            continue;
+        }
+
+        // there's nothing at `remote` to look at, only the content
+        // we were given and what's already in storage
+        if let Some(content) = &options.content {
+            let status = if !Disk::symlink_exists(&local) {
+                AddStatus::Added
+            } else if Disk::is_file(&local) && Disk::get_bytes(&local)? == content.as_bytes() {
+                AddStatus::Unchanged
+            } else {
+                AddStatus::Updated
+            };
+
+            planned.push((remote.clone(), remote.clone(), local, status));
+            continue;
+        }
+
+        // this is already linked to its place in storage
+        if links_to(remote, &local) {
+            planned.push((remote.clone(), local.clone(), local, AddStatus::Linked));
+            continue;
+        }
+
+        // Filter out symlinks, unless we're adopting their targets
+        if Disk::is_symlink(remote) && !options.follow_symlinks {
+            continue;
+        }
+
+        // the link itself is replaced, but what it points to is
+        // what actually ends up in storage
+        let source = if Disk::is_symlink(remote) {
+            resolve_symlink(remote)?
+        } else {
+            remote.clone()
+        };
+
+        // there's no sensible way to store these
+        if Disk::file_type(&source)? == FileType::Other {
+            eprintln!("warning: skipping {}, it's not a file or directory", remote.display());
+            unsupported.push(remote.clone());
+            continue;
+        }
+
+        if options.archive && !Disk::is_dir(&source) {
+            return Err(Error::Simple("--archive only works with directories"));
+        }
+
+        if options.parents_only && !Disk::is_dir(&source) {
+            return Err(Error::Simple("--parents-only only works with directories"));
+        }
+
+        let unchanged = if !Disk::symlink_exists(&local) {
+            false
+        } else if options.parents_only {
+            skeleton_of(&source)? == skeleton_of(&local)?
+        } else if options.archive {
+            archive::pack(&source)? == Disk::get_bytes(&local)?
+        } else {
+            dir_contents_equal(&source, &local)?
+        };
+
+        let status = if !Disk::symlink_exists(&local) {
+            AddStatus::Added
+        } else if unchanged {
+            AddStatus::Unchanged
+        } else {
+            AddStatus::Updated
+        };
+
+        planned.push((remote.clone(), source, local, status));
+    }
+
+    if !unsupported.is_empty() && unsupported.len() == inputs.len() {
+        return Err(Error::UnsupportedFileType(unsupported.remove(0)));
+    }
+
+    // forcing wouldn't help here, one would silently replace the other
+    if env.config.ignore_case {
+        let existing = match Disk::is_file(bundle_path.join(BUNDLE_FILE)) {
+            true => read_bundle(env, bundle_name)?.entries,
+            false => vec![],
+        };
+
+        let existing = existing.into_iter().map(|it| PathBuf::from(it.local)).collect::<Vec<PathBuf>>();
+        let added = planned.iter().map(|(_, _, local, _)| local.clone()).collect::<Vec<PathBuf>>();
+
+        if let Some((a, b)) = find_case_collision(&added, &existing) {
+            return Err(Error::EntryCollision(a, b));
+        }
+    }
+
+    // Make sure we don't clobber anything that's already in storage
+    // before we start moving things around
+    if !options.force {
+        let existing = planned
+            .iter()
+            .find(|(_, _, _, status)| *status == AddStatus::Updated);
+
+        if let Some((_, _, local, _)) = existing {
+            return Err(Error::StorageEntryExists(format!("{}", local.display())));
+        }
+    }
+
+    // once something is moved into storage, not being able to link
+    // it back would leave nothing where it was
+    let unwritable = planned
+        .iter()
+        .filter(|(remote, _, local, _)| !links_to(remote, local))
+        .find_map(|(remote, _, _, _)| unwritable_parent(remote));
+
+    if let Some(parent) = unwritable {
+        return Err(Error::PermissionDenied(parent));
+    }
+
+    if options.dry_run {
+        write_output(env, &preview_add(&planned, options))?;
+        return Ok(AddSummary::default());
+    }
+
+    if options.confirm || env.config.confirm_add {
+        if !env.interactive {
+            return Err(Error::Simple("can't confirm adding without a terminal"));
+        }
+
+        if !env.resolver.confirm_plan(&plan_add(&planned, options)) {
+            println!("nothing was added");
+            return Ok(AddSummary::default());
+        }
+    }
+
+    let dot_meta_path = bundle_path.join(BUNDLE_FILE);
+
+    let mut bundle = if Disk::is_file(&dot_meta_path) {
+        println!("adding to existing bundle `{}`", bundle_name);
+        read_bundle(env, bundle_name)?
+    } else {
+        println!("creating bundle `{}`", bundle_name);
+        Disk::mkdir_all(&bundle_path)?;
+
+        Bundle {
+            id: String::from(bundle_name),
+            created: Some(env.clock.now()),
+            enabled: true,
+            base: None,
+            hosts: vec![],
+            dependencies: vec![],
+            hooks: Default::default(),
+            entries: vec![],
+        }
+    };
+
+    // the base is written so it still fits on other machines
+    if let Some(base) = &options.relative_to {
+        let base = match base.strip_prefix(&env.home) {
+            Ok(rest) if rest.as_os_str().is_empty() => String::from("$HOME"),
+            Ok(rest) => format!("$HOME/{}", rest.display()),
+            Err(_) => format!("{}", base.display()),
+        };
+
+        match &bundle.base {
+            Some(existing) if *existing != base => {
+                return Err(Error::Simple("the bundle already has a different base"));
+            },
+            _ => bundle.base = Some(base),
+        };
+    }
+
+    if let Some(command) = &bundle.hooks.pre_add {
+        println!("running pre_add hook for `{}`: {}", bundle.id, command);
+
+        let code = Shell::run(command, &bundle_path)?;
+        if code != 0 {
+            return Err(Error::HookFailed(code));
+        }
+    }
+
+    // copies into storage don't keep them, so they're read first
+    let xattrs = planned
+        .iter()
+        .map(|(_, source, _, _)| match options.xattrs && options.content.is_none() && Disk::is_file(source) {
+            true => Disk::get_xattrs(source),
+            false => Ok(BTreeMap::new()),
+        })
+        .collect::<Result<Vec<BTreeMap<String, Vec<u8>>>>>()?;
+
+    // the same goes for the hashes, so a file we can't read stops us
+    // before anything is moved instead of leaving it half added
+    let key_of = |local: &Path| {
+        local
+            .strip_prefix(&bundle_path)
+            .expect("Invalid: locals are inside of the bundle")
+            .components()
+            .map(|it| it.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join("/")
+    };
+
+    let mut manifest = Manifest::generate(&bundle_path)?;
+    for (_, source, local, _) in &planned {
+        let key = key_of(local);
+
+        // skeletons don't have any files
+        match (&options.content, options.archive) {
+            (Some(content), _) => manifest.replace_content(&key, content.as_bytes()),
+            (None, true) => manifest.replace_content(&key, &archive::pack(source)?),
+            (None, false) if options.parents_only => manifest.forget(&key),
+            (None, false) => manifest.replace(&key, source)?,
+        };
+    }
+
+    // whether the checksum in the lock can follow what we're changing
+    let trusted = checksum_matches(env, &lockfile, bundle_name)?;
+    let moves = {
+        let options = options.clone();
+        let share = disk::share_with_threads();
+        util::run_parallel(planned.clone(), options.max_parallel.max(1), move |(remote, source, local, status)| {
+            share();
+            move_into_storage(&remote, &source, &local, status, &options)
+        })
+    };
+
+    // new entries are placed like the ones already in the bundle,
+    // unless they were told otherwise
+    let link_type = options.link_type.or_else(|| {
+        shared_by_entries(&bundle.entries, |it| it.link_type)
+            .filter(|it| *it != LinkType::Archive && *it != LinkType::Skeleton)
+    });
+    let host = options.host.clone().or_else(|| shared_by_entries(&bundle.entries, |it| it.host.clone())?);
+
+    let mut summary = AddSummary::default();
+    let mut entries = Vec::new();
+    for (((remote, source, local, status), moved), xattrs) in planned.into_iter().zip(moves).zip(xattrs) {
+        // TODO(happens): Report on skipped
+        if moved.is_err() {
+            // whatever is left in storage is what the manifest has to match
+            match Disk::symlink_exists(&local) {
+                true => manifest.replace(&key_of(&local), &local)?,
+                false => manifest.forget(&key_of(&local)),
+            };
+
+            continue;
+        }
+
+        if !xattrs.is_empty() {
+            Disk::set_xattrs(&local, &xattrs)?;
+        }
+
+        let xattrs = xattrs
+            .iter()
+            .map(|(name, value)| (name.clone(), util::to_hex(value)))
+            .collect();
+
+        let local = format!("{}", local.display());
+        let stored = match &options.relative_to {
+            Some(base) => remote.strip_prefix(base).expect("Invalid: inputs were checked to be inside the base"),
+            None => remote.as_path(),
+        };
+        let stored = format!("{}", stored.display());
+        let remote = format!("{}", remote.display());
+        let origin = match options.content {
+            Some(_) => None,
+            None => Some(format!("{}", source.display())),
+        };
+
+        match status {
+            AddStatus::Added => summary.added.push(remote.clone()),
+            AddStatus::Updated => summary.updated.push(remote.clone()),
+            AddStatus::Unchanged | AddStatus::Linked => summary.unchanged.push(remote.clone()),
+        };
+
+        // keep what was set by hand for an entry we're replacing
+        let replaced = bundle.entries.iter().find(|it| it.local == local);
+        let on_conflict = replaced.and_then(|it| it.on_conflict);
+        let per_file = replaced.is_some_and(|it| it.per_file);
+        let note = options.notes
+            .iter()
+            .find(|(it, _)| format!("{}", it.display()) == remote)
+            .map(|(_, note)| note.clone())
+            .or_else(|| options.note.clone())
+            .or_else(|| replaced.and_then(|it| it.note.clone()));
+
+        entries.push(Entry {
+            local,
+            remote: stored,
+            link_type: match (options.archive, options.parents_only) {
+                (true, _) => LinkType::Archive,
+                (_, true) => LinkType::Skeleton,
+                _ => link_type.unwrap_or_default(),
+            },
+            host: host.clone(),
+            on_conflict,
+            origin,
+            note,
+            per_file,
+            xattrs,
+        });
+    }
+
+    // entries that were overwritten are replaced by the new ones
+    let added = entries.iter().map(|it| it.local.clone()).collect::<Vec<String>>();
+    bundle.entries.retain(|it| !added.contains(&it.local));
+    bundle.entries.extend(entries);
+
+    // Save the dotfile for the bundle itself, this has all the paths
+    write_bundle(env, &bundle)?;
+    manifest.write(&bundle_path)?;
+
+    if options.no_link {
+        write_output(env, &summary.render(env.output.format)?)?;
+        print_report(env, &OperationReport::from(&summary))?;
+        return Ok(summary);
+    }
+
+    // only the new entries are linked, the ones that were already in
+    // the bundle stay however they were. anything in the way of them
+    // is left to the conflict policy, like when linking.
+    let host = host.unwrap_or_else(|| env.host.clone());
+    let link_options = LinkOptions {
+        link_type: options.link_type,
+        ..Default::default()
+    };
+    let mut view = bundle.view(&host).variants(&host).expanded(env)?;
+    view.entries.retain(|it| added.contains(&it.local));
+
+    let result = link(env, &view, &[], false, &link_options)?;
+    record_link_types(env, &mut bundle, &result.linked)?;
+
+    // a bundle that had entries before might only be partly linked
+    let only = match bundle.entries.len() == added.len() {
+        true => vec![],
+        false => view.entries.iter().map(|it| it.remote.clone()).collect(),
+    };
+    mark_linked(&mut lockfile, &bundle, &host, &host, &only, result.merged);
+    if trusted {
+        record_checksum(env, &mut lockfile, &bundle.id)?;
+    }
+    write_lockfile(env, &lockfile)?;
+
+    write_output(env, &summary.render(env.output.format)?)?;
+    print_report(env, &OperationReport { linked: result.linked.len(), ..OperationReport::from(&summary) })?;
+    Ok(summary)
+}
+
+// The value every entry has for `field`, if there are any entries
+// and they all agree on it
+fn shared_by_entries<T, F>(entries: &[Entry], field: F) -> Option<T>
+where
+    T: PartialEq,
+    F: Fn(&Entry) -> T,
+{
+    let mut values = entries.iter().map(field);
+    let first = values.next()?;
+
+    match values.all(|it| it == first) {
+        true => Some(first),
+        false => None,
+    }
+}
+
+// Describes what `cmd_add` would do with each input, and whether
+// linking it would run into something that's already there
+fn preview_add(planned: &[(PathBuf, PathBuf, PathBuf, AddStatus)], options: &AddOptions) -> String {
+    // only these link somewhere other than where they came from
+    let replacing = options.content.is_some() || options.copies_inputs();
+
+    let lines = planned
+        .iter()
+        .map(|(remote, _, local, status)| {
+            let action = match status {
+                AddStatus::Added => "would add",
+                AddStatus::Updated => "would update",
+                AddStatus::Unchanged | AddStatus::Linked => "unchanged",
+            };
+
+            let existing = if Disk::is_symlink(remote) {
+                Disk::readlink(remote)
+                    .map(|it| format!("symlink to {}", it.display()))
+                    .unwrap_or_else(|_| String::from("symlink"))
+            } else if Disk::is_dir(remote) {
+                String::from("directory")
+            } else {
+                String::from("file")
+            };
+
+            let note = if !Disk::symlink_exists(remote) {
+                String::from("nothing there yet")
+            } else if links_to(remote, local) {
+                String::from("already linked")
+            } else if options.parents_only {
+                String::from("stores its directories, the files stay")
+            } else if replacing {
+                format!("conflicts with existing {}", existing)
+            } else {
+                format!("moves {} into storage", existing)
+            };
+
+            format!("{} {}: {}", action, remote.display(), note)
+        })
+        .collect::<Vec<String>>();
+
+    lines.join("\n")
+}
+
+// A short summary of what `add` is about to do, followed by the details
+fn plan_add(planned: &[(PathBuf, PathBuf, PathBuf, AddStatus)], options: &AddOptions) -> String {
+    let replacing = options.content.is_some() || options.copies_inputs();
+    let pending = planned
+        .iter()
+        .filter(|(remote, _, local, _)| !links_to(remote, local))
+        .collect::<Vec<_>>();
+
+    let moves = if replacing || options.parents_only { 0 } else { pending.len() };
+    let links = if options.no_link || options.parents_only { 0 } else { pending.len() };
+    let conflicts = if replacing {
+        pending.iter().filter(|(remote, _, _, _)| Disk::symlink_exists(remote)).count()
+    } else {
+        0
+    };
+
+    format!(
+        "{} files to move, {} symlinks to create, {} conflicts\n{}",
+        moves, links, conflicts, preview_add(planned, options),
+    )
+}
+
+// The bundle with a directory entry that `path` is inside of
+fn find_covering_bundle(env: &Env, path: &Path) -> Result<Option<String>> {
+    for bundle_name in get_bundle_names(env)? {
+        // broken bundles can't cover anything
+        let bundle = match read_bundle(env, &bundle_name).and_then(|it| it.expanded(env)) {
+            Ok(bundle) => bundle,
+            Err(_) => continue,
+        };
+
+        let covering = bundle.entries.iter().any(|it| {
+            // skeletons only have directories, the files are the user's
+            let is_dir = it.link_type == LinkType::Archive
+                || it.link_type != LinkType::Skeleton && Disk::is_dir(&it.local);
+            is_dir && path != Path::new(&it.remote) && path.starts_with(&it.remote)
+        });
+
+        if covering {
+            return Ok(Some(bundle.id));
+        }
+    }
+
+    Ok(None)
+}
+
+// Finds the bundle with an entry at `path`, or a directory entry it's
+// inside of. Given a bundle, no other bundle is looked at.
+pub fn cmd_which(env: &Env, path: &Path, only: Option<&str>) -> Result<Option<String>> {
+    let names = match only {
+        Some(name) => vec![String::from(name)],
+        None => get_bundle_names(env)?,
+    };
+
+    for bundle_name in names {
+        let bundle = match read_bundle(env, &bundle_name).and_then(|it| it.expanded(env)) {
+            Ok(bundle) => bundle,
+            // a bundle that was asked for has to be readable
+            Err(error) if only.is_some() => return Err(error),
+            Err(_) => continue,
+        };
+
+        let entry = match bundle.entries.iter().find(|it| path.starts_with(&it.remote)) {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        let local = match path.strip_prefix(&entry.remote) {
+            Ok(rest) if !rest.as_os_str().is_empty() => Path::new(&entry.local).join(rest),
+            _ => PathBuf::from(&entry.local),
+        };
+
+        println!("{} is in `{}` at {}", path.display(), bundle.id, local.display());
+        return Ok(Some(bundle.id));
+    }
+
+    match only {
+        Some(name) => println!("{} is not in bundle `{}`", path.display(), name),
+        None => println!("{} is not in any bundle", path.display()),
+    };
+
+    Ok(None)
+}
+
+// Puts a single input for `cmd_add` into storage. If this fails,
+// `remote` is left where it was.
+fn move_into_storage(
+    remote: &Path,
+    source: &Path,
+    local: &Path,
+    status: AddStatus,
+    options: &AddOptions,
+) -> Result<()> {
+    match status {
+        AddStatus::Linked => Ok(()),
+        // whatever is at `remote` is left to the conflict policy
+        _ if options.content.is_some() => match status {
+            AddStatus::Unchanged => Ok(()),
+            _ => Disk::put(local, options.content.as_ref().unwrap()),
+        },
+        _ if options.copies_inputs() => match status {
+            AddStatus::Unchanged => Ok(()),
+            // files from git might be nested
+            _ => local.parent()
+                .map_or(Ok(()), Disk::mkdir_all)
+                .and_then(|_| copy_into_storage(source, local, options.force, options.reflink)),
+        },
+        // the input keeps its files, storage only gets its directories
+        _ if options.parents_only => match status {
+            AddStatus::Unchanged | AddStatus::Linked => Ok(()),
+            AddStatus::Updated => Disk::remove(local).and_then(|_| create_skeleton(source, local)),
+            AddStatus::Added => create_skeleton(source, local),
+        },
+        // storage already has the same thing, no need to copy it again
+        AddStatus::Unchanged => Disk::remove(remote),
+        _ if options.archive => archive::pack(source)
+            .and_then(|it| Disk::put_bytes(local, &it))
+            .and_then(|_| Disk::remove(remote)),
+        // moving is atomic where it doesn't have to copy. reflinks
+        // that were asked for need a copy of their own though.
+        _ if source == remote && options.reflink != Reflink::Always => match status {
+            AddStatus::Updated => replace_in_storage(source, local),
+            _ => Disk::rename(source, local),
+        },
+        // adopted targets stay where they are, other things
+        // might still be pointing at them
+        _ => copy_into_storage(source, local, options.force, options.reflink)
+            .and_then(|_| Disk::remove(remote)),
+    }
+}
+
+// Moves `from` over what's stored at `local`. the stored copy is only
+// removed once `from` is in storage next to it, and if anything fails
+// after that `from` is put back.
+fn replace_in_storage(from: &Path, local: &Path) -> Result<()> {
+    let name = local.file_name().expect("Invalid: stored paths always have a name");
+    let staged = local.with_file_name(format!("{}.dotgirl-new", name.to_string_lossy()));
+
+    Disk::rename(from, &staged)?;
+    Disk::remove(local)
+        .and_then(|_| Disk::rename(&staged, local))
+        .inspect_err(|_error| {
+            let _ = Disk::rename(&staged, from);
+        })
+}
+
+fn copy_into_storage(from: &Path, to: &Path, overwrite: bool, mode: Reflink) -> Result<()> {
+    let reflink = || {
+        if Disk::symlink_exists(to) {
+            if !overwrite {
+                return Err(Error::Simple("copy dst already exists"));
+            }
+
+            Disk::remove(to)?;
+        }
+
+        // don't leave half of a directory behind for the copy
+        reflink_tree(from, to).inspect_err(|_error| {
+            let _ = Disk::remove(to);
+        })
+    };
+
+    reflink_or_copy(mode, reflink, || Disk::copy(from, to, overwrite))
+}
+
+// Picks between sharing data and copying it for `mode`
+fn reflink_or_copy<R, C>(mode: Reflink, reflink: R, copy: C) -> Result<()>
+where
+    R: FnOnce() -> Result<()>,
+    C: FnOnce() -> Result<()>,
+{
+    match mode {
+        Reflink::Auto => reflink().or_else(|_| copy()),
+        Reflink::Always => reflink(),
+        Reflink::Never => copy(),
+    }
+}
+
+// The directories below `dir`, relative to it and sorted
+fn skeleton_of(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Disk::walk(dir)?
+        .into_iter()
+        .filter(|it| Disk::is_dir(it) && !Disk::is_symlink(it))
+        .filter_map(|it| it.strip_prefix(dir).ok().map(Path::to_path_buf))
+        .collect::<Vec<PathBuf>>();
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+// Creates `to` and every directory below `from` inside of it,
+// leaving out all files
+fn create_skeleton(from: &Path, to: &Path) -> Result<()> {
+    Disk::mkdir_all(to)?;
+
+    for it in skeleton_of(from)? {
+        Disk::mkdir_all(to.join(it))?;
+    }
+
+    Ok(())
+}
+
+// Reflinks every file below `from` to the same place below `to`
+fn reflink_tree(from: &Path, to: &Path) -> Result<()> {
+    if !Disk::is_dir(from) {
+        return Disk::reflink(from, to);
+    }
+
+    Disk::mkdir_all(to)?;
+    for it in Disk::walk(from)? {
+        let target = to.join(it
+            .strip_prefix(from)
+            .expect("Invalid: walk returned a path outside of the directory"));
+
+        if Disk::is_symlink(&it) {
+            Disk::symlink(Disk::readlink(&it)?, &target)?;
+        } else if Disk::is_dir(&it) {
+            Disk::mkdir_all(&target)?;
+        } else {
+            Disk::reflink(&it, &target)?;
+        }
+    }
+
+    Ok(())
+}
+
+// The files git tracks in `repo`, relative to it. only tracked files
+// that were deleted from the worktree since are left out.
+fn tracked_files(repo: &Path) -> Result<Vec<PathBuf>> {
+    if !Disk::is_dir(repo) {
+        return Err(Error::PathNotFound(repo.to_path_buf()));
+    }
+
+    let (code, listed) = Shell::output("git ls-files -z", repo)?;
+    if code != 0 {
+        return Err(Error::GitError(format!("can't list the files tracked in {}", repo.display())));
+    }
+
+    let mut files = Vec::new();
+    for it in listed.split('\0').filter(|it| !it.is_empty()) {
+        let relative = PathBuf::from(it);
+
+        // git only lists paths inside the repo, but anything else
+        // would be linked somewhere outside of home
+        let inside = relative.components().all(|it| matches!(it, Component::Normal(_)));
+
+        if !inside {
+            eprintln!("warning: skipping {}, it's not inside of the repo", it);
+            continue;
+        }
+
+        if !Disk::symlink_exists(repo.join(&relative)) {
+            continue;
+        }
+
+        files.push(relative);
+    }
+
+    Ok(files)
+}
+
+// An added path and another added or existing one that only differ
+// by case, and would end up as the same file on a case-insensitive
+// filesystem
+fn find_case_collision(added: &[PathBuf], existing: &[PathBuf]) -> Option<(PathBuf, PathBuf)> {
+    let folded = |it: &Path| it.to_string_lossy().to_lowercase();
+
+    added.iter().enumerate().find_map(|(i, it)| {
+        added[..i]
+            .iter()
+            .chain(existing.iter())
+            .find(|other| *other != it && folded(other) == folded(it))
+            .map(|other| (other.clone(), it.clone()))
+    })
+}
+
+// The closest dir around `remote` that exists, if we can't write to
+// it. that's where a link to `remote` would have to be created.
+fn unwritable_parent(remote: &Path) -> Option<PathBuf> {
+    remote
+        .ancestors()
+        .skip(1)
+        .find(|it| Disk::is_dir(it))
+        .filter(|it| !Disk::is_writable(it))
+        .map(|it| it.to_path_buf())
+}
+
+// Follows a chain of symlinks to the first thing that isn't one
+fn resolve_symlink(path: &Path) -> Result<PathBuf> {
+    // same limit linux uses before giving up with ELOOP
+    const MAX_HOPS: usize = 40;
+
+    let mut current = path.to_path_buf();
+    let mut seen = Vec::new();
+
+    while Disk::is_symlink(&current) {
+        if seen.contains(&current) || seen.len() >= MAX_HOPS {
+            return Err(Error::SymlinkLoop(format!("{}", path.display())));
+        }
+
+        let target = Disk::readlink(&current)?;
+        let next = match current.parent() {
+            // relative targets are relative to the link's dir
+            Some(parent) => parent.join(target),
+            None => target,
+        };
+
+        seen.push(current);
+        current = next
+            .components()
+            .filter(|it| *it != Component::CurDir)
+            .collect();
+    }
+
+    if !Disk::exists(&current) {
+        return Err(Error::Simple("symlink target doesn't exist"));
+    }
+
+    Ok(current)
+}
+
+pub fn cmd_link(env: &Env, bundle_name: &str, options: &LinkOptions) -> Result<LinkSummary> {
+    if !options.no_deps {
+        // the order already covers dependencies of dependencies
+        let dependency_options = LinkOptions {
+            entries: vec![],
+            no_deps: true,
+            ..options.clone()
+        };
+
+        // ones that are linked already are left alone, their hooks
+        // shouldn't run again every time something depending on them is linked
+        let lockfile = get_lockfile(env)?;
+        let unlinked = dependency_order(env, bundle_name)?
+            .into_iter()
+            .filter(|it| it != bundle_name && !lockfile.linked.iter().any(|linked| linked.id == *it))
+            .collect::<Vec<String>>();
+
+        for it in unlinked {
+            cmd_link(env, &it, &dependency_options)?;
+        }
+    }
+
+    let mut lockfile = get_lockfile(env)?;
+
+    // TODO(happens): Confirm if already linked
+
+    let mut bundle = recover_bundle(env, &lockfile, bundle_name)?;
+    if !options.force_host && !bundle.allows_host(&env.host) {
+        println!("skipping `{}`, it's only linked on {}", bundle.id, bundle.hosts.join(", "));
+        print_report(env, &OperationReport { skipped: bundle.entries.len(), ..Default::default() })?;
+        return Ok(LinkSummary::default());
+    }
+
+    verify_manifest(env, &bundle.id)?;
+    let trusted = checksum_matches(env, &lockfile, &bundle.id)?;
+
+    let host = options.from.as_ref().unwrap_or(&env.host);
+    let suffix = options.hostname_suffix.as_ref().unwrap_or(host);
+    let view = bundle.view(host).variants(suffix).expanded(env)?;
+
+    let unknown = options.entries
+        .iter()
+        .find(|remote| !view.entries.iter().any(|it| it.remote == **remote));
+
+    if let Some(remote) = unknown {
+        return Err(Error::UnknownEntry(remote.clone()));
+    }
+
+    let view = view.subset(&options.entries);
+    if !options.ignore_cross_bundle {
+        check_claimed(&lockfile, &view)?;
+    }
+
+    if options.dry_run {
+        write_output(env, &preview_link(env, &view, options)?)?;
+        return Ok(LinkSummary::default());
+    }
+
+    let result = link(env, &view, &[], false, options)?;
+    let mut summary = LinkSummary::default();
+    summary.add(&bundle.id, &result);
+
+    record_link_types(env, &mut bundle, &result.linked)?;
+    mark_linked(&mut lockfile, &bundle, host, suffix, &options.entries, result.merged);
+    if trusted {
+        record_checksum(env, &mut lockfile, &bundle.id)?;
+    }
+    write_lockfile(env, &lockfile)?;
+
+    run_after_link(env, &bundle, options)?;
+
+    write_output(env, &summary.render(env.output.format, options.quiet_skip, options.verbose)?)?;
+    print_report(env, &OperationReport::from(&summary))?;
+    Ok(summary)
+}
+
+// The bundles `bundle_name` depends on in the order they have to
+// be linked, ending with the bundle itself
+fn dependency_order(env: &Env, bundle_name: &str) -> Result<Vec<String>> {
+    let lockfile = get_lockfile(env)?;
+    let mut order = Vec::new();
+    visit_dependencies(env, &lockfile, bundle_name, &mut vec![], &mut order)?;
+
+    Ok(order)
+}
+
+// `path` has the bundles we're currently inside of, running into
+// one of them again means they depend on each other
+fn visit_dependencies(
+    env: &Env,
+    lockfile: &Lock,
+    bundle_name: &str,
+    path: &mut Vec<String>,
+    order: &mut Vec<String>,
+) -> Result<()> {
+    if order.iter().any(|it| it == bundle_name) {
+        return Ok(());
+    }
+
+    if let Some(start) = path.iter().position(|it| it == bundle_name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(String::from(bundle_name));
+        return Err(Error::DependencyCycle(cycle));
+    }
+
+    let bundle = recover_bundle(env, lockfile, bundle_name)?;
+
+    path.push(String::from(bundle_name));
+    for it in &bundle.dependencies {
+        visit_dependencies(env, lockfile, it, path, order)?;
+    }
+    path.pop();
+
+    order.push(String::from(bundle_name));
+    Ok(())
+}
+
+pub fn cmd_link_all(env: &Env, options: &LinkOptions) -> Result<()> {
+    let mut lockfile = get_lockfile(env)?;
+    let mut report = BulkReport::default();
+    let mut summary = LinkSummary::default();
+    let host = options.from.as_ref().unwrap_or(&env.host);
+    let suffix = options.hostname_suffix.as_ref().unwrap_or(host);
+
+    if options.dry_run {
+        let mut previews = Vec::new();
+        for bundle_name in get_bundle_names(env)? {
+            let preview = read_bundle(env, &bundle_name).and_then(|bundle| {
+                if !bundle.enabled || (!options.force_host && !bundle.allows_host(&env.host)) {
+                    return Ok(None);
+                }
+
+                preview_link(env, &bundle.view(host).variants(suffix).expanded(env)?, options).map(Some)
+            });
+
+            match preview {
+                Ok(Some(preview)) => {
+                    previews.push(preview);
+                    report.done.push(bundle_name);
+                },
+                Ok(None) => {},
+                Err(error) => report.failed.push((bundle_name, error)),
+            };
+        }
+
+        write_output(env, &previews.join("\n"))?;
+        if !report.failed.is_empty() {
+            report.print("previewed");
+        }
+
+        return report.into_result();
+    }
+
+    for bundle_name in get_bundle_names(env)? {
+        let bundle = match recover_bundle(env, &lockfile, &bundle_name) {
+            Ok(bundle) if !bundle.enabled => {
+                if !options.quiet_skip {
+                    report.skipped.push(format!("{} (disabled)", bundle_name));
+                }
+
+                continue;
+            },
+            Ok(bundle) if !options.force_host && !bundle.allows_host(&env.host) => {
+                if !options.quiet_skip {
+                    report.skipped.push(format!("{} (only for {})", bundle_name, bundle.hosts.join(", ")));
+                }
+
+                continue;
+            },
+            other => other,
+        };
+
+        let result = bundle
+            .and_then(|mut bundle| {
+                verify_manifest(env, &bundle.id)?;
+                let trusted = checksum_matches(env, &lockfile, &bundle.id)?;
+                let view = bundle.view(host).variants(suffix).expanded(env)?;
+                if !options.ignore_cross_bundle {
+                    check_claimed(&lockfile, &view)?;
+                }
+
+                let result = link(env, &view, &[], false, options)?;
+                record_link_types(env, &mut bundle, &result.linked)?;
+                Ok((bundle, result, trusted))
+            });
+
+        match result {
+            Ok((bundle, result, trusted)) => {
+                summary.add(&bundle.id, &result);
+                if !options.quiet_skip {
+                    result.skipped
+                        .iter()
+                        .for_each(|(it, reason)| report.skipped.push(format!("{} ({})", it.remote, reason)));
+                }
+
+                mark_linked(&mut lockfile, &bundle, host, suffix, &[], result.merged);
+                if trusted {
+                    if let Err(error) = record_checksum(env, &mut lockfile, &bundle.id) {
+                        report.failed.push((bundle_name, error));
+                        continue;
+                    }
+                }
+
+                match run_after_link(env, &bundle, options) {
+                    Ok(()) => report.done.push(bundle_name),
+                    Err(error) => report.failed.push((bundle_name, error)),
+                };
+            },
+            Err(error) => report.failed.push((bundle_name, error)),
+        };
+    }
+
+    write_lockfile(env, &lockfile)?;
+    if env.output.format == OutputFormat::Json {
+        write_output(env, &summary.render(env.output.format, options.quiet_skip, options.verbose)?)?;
+    } else {
+        report.print("linked");
+    }
+
+    print_report(env, &OperationReport { errors: report.failed.len(), ..OperationReport::from(&summary) })?;
+    report.into_result()
+}
+
+// Fails if another linked bundle already manages one of the remotes
+// in `bundle`, since linking it would silently take the remote away
+fn check_claimed(lockfile: &Lock, bundle: &Bundle) -> Result<()> {
+    for other in lockfile.linked.iter().filter(|it| it.id != bundle.id) {
+        let claimed = other.entries
+            .iter()
+            .filter(|it| other.host.as_ref().is_none_or(|host| it.host.as_ref().is_none_or(|h| h == host)))
+            .filter(|it| other.only.is_empty() || other.only.contains(&it.remote))
+            .find(|it| bundle.entries.iter().any(|entry| entry.remote == it.remote));
+
+        if let Some(entry) = claimed {
+            return Err(Error::RemoteClaimed(entry.remote.clone(), other.id.clone()));
+        }
+    }
+
+    Ok(())
+}
+
+// Pulls changes into storage, links every linked bundle they touched
+// again and pushes. Storage has to be a git work tree for this.
+pub fn cmd_sync(env: &Env) -> Result<()> {
+    check_storage_writable(env)?;
+
+    if !Disk::symlink_exists(env.storage.join(".git")) {
+        return Err(Error::Simple("storage is not a git repository"));
+    }
+
+    // what we pull is asked for, so checksums that matched before
+    // can follow it. the others have to keep telling on their bundle.
+    let lockfile = get_lockfile(env)?;
+    let trusted = lockfile.linked
+        .iter()
+        .filter(|it| checksum_matches(env, &lockfile, &it.id).unwrap_or(false))
+        .map(|it| it.id.clone())
+        .collect::<Vec<String>>();
+
+    println!("pulling changes into {}", env.storage.display());
+    if Shell::run("git pull --rebase", &env.storage)? != 0 {
+        return Err(Error::GitError(format!(
+            "pulling failed, resolve the conflicts in {} and sync again",
+            env.storage.display(),
+        )));
+    }
+
+    let lockfile = get_lockfile(env)?;
+    for linked in &lockfile.linked {
+        // bundles without a checksum might have changed as well
+        let unchanged = linked.checksum.is_some()
+            && checksum_matches(env, &lockfile, &linked.id).unwrap_or(false);
+
+        if unchanged {
+            continue;
+        }
+
+        println!("relinking `{}`", linked.id);
+        let options = LinkOptions {
+            entries: linked.only.clone(),
+            from: linked.host.clone(),
+            hostname_suffix: linked.suffix.clone(),
+            no_deps: true,
+            ..Default::default()
+        };
+
+        cmd_link(env, &linked.id, &options)?;
+
+        if trusted.contains(&linked.id) {
+            let mut relinked = get_lockfile(env)?;
+            record_checksum(env, &mut relinked, &linked.id)?;
+            write_lockfile(env, &relinked)?;
+        }
+    }
+
+    println!("pushing changes from {}", env.storage.display());
+    if Shell::run("git push", &env.storage)? != 0 {
+        return Err(Error::GitError(String::from("pushing failed, everything else is synced")));
+    }
+
+    Ok(())
+}
+
+// Makes sure nothing in storage changed since the manifest was
+// written, bundles without one are trusted as they are. files are
+// edited through their links all the time and nothing writes the
+// manifest again after that, so this only happens when verifying.
+fn verify_manifest(env: &Env, bundle_name: &str) -> Result<()> {
+    if !env.verify {
+        return Ok(());
+    }
+
+    let dir = env.bundle_dir().join(bundle_name);
+
+    match Manifest::load(&dir)? {
+        Some(manifest) => manifest.verify(&dir),
+        None => Ok(()),
+    }
+}
+
+pub fn cmd_verify_manifest(env: &Env, bundle_name: &str) -> Result<()> {
+    let bundle = read_bundle(env, bundle_name)?;
+    let dir = env.bundle_dir().join(&bundle.id);
+
+    let manifest = Manifest::load(&dir)?
+        .ok_or(Error::Simple("bundle has no manifest"))?;
+
+    manifest.verify(&dir)?;
+    println!("all files in `{}` match the manifest", bundle.id);
+
+    Ok(())
+}
+
+// Runs the bundle's `after_link` hook, if it has one. A failing
+// hook is reported, but doesn't undo the link
+fn run_after_link(env: &Env, bundle: &Bundle, options: &LinkOptions) -> Result<()> {
+    let command = match &bundle.hooks.after_link {
+        Some(command) if !options.no_hooks => command,
+        _ => return Ok(()),
+    };
+
+    let dir = env.bundle_dir().join(&bundle.id);
+    println!("running after_link hook for `{}`: {}", bundle.id, command);
+
+    let code = Shell::run(command, &dir)?;
+    if code != 0 {
+        println!("after_link hook for `{}` exited with status {}", bundle.id, code);
+    }
+
+    Ok(())
+}
+
+// Whether `a` and `b` have the same contents. Directories are
+// equal if they contain the same paths with equal files.
+fn dir_contents_equal(a: &Path, b: &Path) -> Result<bool> {
+    if Disk::is_file(a) || Disk::is_file(b) {
+        let equal = Disk::is_file(a)
+            && Disk::is_file(b)
+            && Disk::get_bytes(a)? == Disk::get_bytes(b)?;
+
+        return Ok(equal);
+    }
+
+    if !Disk::is_dir(a) || !Disk::is_dir(b) {
+        return Ok(false);
+    }
+
+    let relative = |root: &Path| -> Result<Vec<PathBuf>> {
+        let paths = Disk::walk(root)?
+            .into_iter()
+            .filter_map(|it| it.strip_prefix(root).ok().map(Path::to_path_buf))
+            .collect();
+
+        Ok(paths)
+    };
+
+    let paths = relative(a)?;
+    if paths != relative(b)? {
+        return Ok(false);
+    }
+
+    for it in paths {
+        let (left, right) = (a.join(&it), b.join(&it));
+        if Disk::is_dir(&left) != Disk::is_dir(&right) {
+            return Ok(false);
+        }
+
+        if !Disk::is_dir(&left) && !dir_contents_equal(&left, &right)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn get_bundle_size(env: &Env, bundle_name: &str) -> Result<u64> {
+    dir_size(&env.bundle_dir().join(bundle_name))
+}
+
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for it in Disk::walk(dir)? {
+        if Disk::is_file(&it) {
+            size += Disk::file_size(&it)?;
+        }
+    }
+
+    Ok(size)
+}
+
+pub fn list_bundles(env: &Env, sort: SortKey, reverse: bool) -> Result<Vec<BundleSummary>> {
+    let lockfile = get_lockfile(env)?;
+
+    let mut result = Vec::new();
+    for bundle_name in get_bundle_names(env)? {
+        let bundle = read_bundle(env, &bundle_name)?;
+        let size = if sort == SortKey::Size {
+            get_bundle_size(env, &bundle_name)?
+        } else {
+            0
+        };
+
+        result.push(BundleSummary {
+            linked: lockfile.linked.iter().any(|it| it.id == bundle.id),
+            entries: bundle.entries.len(),
+            created: bundle.created,
+            id: bundle.id,
+            size,
+        });
+    }
+
+    result.sort_by(|a, b| {
+        use std::cmp::Ordering;
+
+        let by_key = match sort {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Created => match (a.created, b.created) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                // bundles without a timestamp always go last,
+                // regardless of the direction we're sorting in
+                (Some(_), None) => return Ordering::Less,
+                (None, Some(_)) => return Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+        };
+
+        let ordering = by_key.then_with(|| a.id.cmp(&b.id));
+        if reverse { ordering.reverse() } else { ordering }
+    });
+
+    Ok(result)
+}
+
+pub fn cmd_list(env: &Env, sort: SortKey, reverse: bool, long: bool, template: Option<&str>) -> Result<()> {
+    let lockfile = get_lockfile(env)?;
+
+    if let Some(template) = template {
+        // a typo should show up even without any bundles
+        render_template(template, |name| list_field(&Default::default(), name))?;
+
+        let lines = list_bundles(env, sort, reverse)?
+            .iter()
+            .map(|it| render_template(template, |name| list_field(it, name)))
+            .collect::<Result<Vec<String>>>()?;
+
+        if !lines.is_empty() {
+            write_output(env, &lines.join("\n"))?;
+        }
+
+        return Ok(());
+    }
+
+    for it in list_bundles(env, sort, reverse)? {
+        let linked = if it.linked { "linked" } else { "" };
+        println!("{}\t{} entries\t{}", it.id, it.entries, linked);
+
+        let bundle = read_bundle(env, &it.id)?;
+        if long && !bundle.entries.is_empty() {
+            println!("{}", render_entries(env, &lockfile, &bundle)?);
+        }
+    }
+
+    Ok(())
+}
+
+// What `{name}` stands for in a `list` template
+fn list_field(summary: &BundleSummary, name: &str) -> Option<String> {
+    match name {
+        "id" => Some(summary.id.clone()),
+        "count" => Some(summary.entries.to_string()),
+        "created" => Some(summary.created.map(|it| it.to_string()).unwrap_or_default()),
+        "linked" => Some(summary.linked.to_string()),
+        _ => None,
+    }
+}
+
+// Replaces every `{name}` in `template` with what `field` gives for
+// it, `{{` is a literal brace. `\t` and `\n` are turned into what
+// they stand for, since they're hard to type in a shell.
+fn render_template<F>(template: &str, field: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut rendered = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find(['{', '\\']) {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+
+        let (replacement, consumed) = if tail.starts_with("{{") {
+            (String::from("{"), 2)
+        } else if tail.starts_with("\\t") {
+            (String::from("\t"), 2)
+        } else if tail.starts_with("\\n") {
+            (String::from("\n"), 2)
+        } else if tail.starts_with('\\') {
+            (String::from("\\"), 1)
+        } else {
+            let end = tail.find('}').ok_or_else(|| Error::UnknownPlaceholder(String::from(tail)))?;
+            let name = &tail[1..end];
+            let value = field(name).ok_or_else(|| Error::UnknownPlaceholder(String::from(name)))?;
+            (value, end + 1)
+        };
+
+        rendered.push_str(&replacement);
+        rest = &tail[consumed..];
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+// The entries of `bundle` as they were linked, nothing if it isn't
+fn linked_entries(env: &Env, lockfile: &Lock, bundle: &Bundle) -> Result<Vec<Entry>> {
+    match lockfile.linked.iter().find(|it| it.id == bundle.id) {
+        Some(linked) => Ok(bundle.linked_view(linked, env)?.entries),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Whether `entry` is still linked the way it was, by looking it up
+// in what was linked. entries for other hosts or that weren't picked
+// aren't in there at all.
+fn entry_state(env: &Env, linked: &[Entry], entry: &Entry) -> &'static str {
+    match linked.iter().find(|it| it.local == entry.local) {
+        None => "not linked",
+        Some(it) => match check_entry(it, &env.storage) {
+            None => "linked",
+            Some(ProblemKind::MissingLink) => "missing link",
+            Some(ProblemKind::WrongTarget) => "wrong target",
+            Some(ProblemKind::Foreign) => "foreign",
+            Some(ProblemKind::ForeignLink) => "foreign-link",
+            Some(_) => "missing source",
+        },
+    }
+}
+
+// A line for each entry of `bundle` with how it's linked and whether
+// that's still the case, in aligned columns
+fn render_entries(env: &Env, lockfile: &Lock, bundle: &Bundle) -> Result<String> {
+    let linked = linked_entries(env, lockfile, bundle)?;
+
+    let rows = bundle.entries
+        .iter()
+        .map(|entry| {
+            let state = entry_state(env, &linked, entry);
+
+            let mut origin = match &entry.origin {
+                Some(origin) if *origin != entry.remote => format!("  (added from {})", origin),
+                _ => String::new(),
+            };
+
+            if let Some(note) = &entry.note {
+                origin.push_str(&format!("  # {}", note));
+            }
+
+            (entry.remote.clone(), entry.link_type.to_string(), state, origin)
+        })
+        .collect::<Vec<(String, String, &str, String)>>();
+
+    let remote_width = rows.iter().map(|it| it.0.len()).max().unwrap_or(0);
+    let mode_width = rows.iter().map(|it| it.1.len()).max().unwrap_or(0);
+
+    let lines = rows
+        .iter()
+        .map(|(remote, mode, state, origin)| format!(
+            "\t{:remote_width$}  {:mode_width$}  {}{}",
+            remote, mode, state, origin,
+            remote_width = remote_width,
+            mode_width = mode_width,
+        ))
+        .collect::<Vec<String>>();
+
+    Ok(lines.join("\n"))
+}
+
+// Everything there is to know about a single bundle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BundleInfo {
+    pub id: String,
+    pub created: Option<u64>,
+    pub enabled: bool,
+    pub hosts: Vec<String>,
+    pub base: Option<String>,
+    pub dependencies: Vec<String>,
+    pub entries: usize,
+    // bytes the bundle takes up in storage
+    pub size: u64,
+    pub linked: bool,
+    // how many entries are in each state, e.g. `linked` or `foreign`
+    pub states: BTreeMap<String, usize>,
+}
+
+impl BundleInfo {
+    fn render(&self, json: bool) -> Result<String> {
+        if json {
+            return Ok(serde_json::to_string_pretty(&self)?);
+        }
+
+        let list = |it: &[String], empty: &str| match it.is_empty() {
+            true => String::from(empty),
+            false => it.join(", "),
+        };
+
+        let states = self.states
+            .iter()
+            .map(|(state, count)| format!("{} {}", count, state))
+            .collect::<Vec<String>>();
+
+        let lines = vec![
+            format!("id:           {}", self.id),
+            format!("created:      {}", self.created.map_or(String::from("unknown"), |it| it.to_string())),
+            format!("enabled:      {}", self.enabled),
+            format!("hosts:        {}", list(&self.hosts, "all")),
+            format!("base:         {}", self.base.as_ref().map_or("none", |it| it.as_str())),
+            format!("dependencies: {}", list(&self.dependencies, "none")),
+            format!("entries:      {}", self.entries),
+            format!("size:         {} bytes", self.size),
+            format!("linked:       {}", self.linked),
+            format!("states:       {}", list(&states, "none")),
+        ];
+
+        Ok(lines.join("\n"))
+    }
+}
+
+pub fn cmd_info(env: &Env, bundle_name: &str, json: bool) -> Result<BundleInfo> {
+    let lockfile = get_lockfile(env)?;
+    let bundle = read_bundle(env, bundle_name)?;
+    let linked = linked_entries(env, &lockfile, &bundle)?;
+
+    let mut states = BTreeMap::new();
+    for it in &bundle.entries {
+        *states.entry(String::from(entry_state(env, &linked, it))).or_insert(0) += 1;
+    }
+
+    let info = BundleInfo {
+        id: bundle.id.clone(),
+        created: bundle.created,
+        enabled: bundle.enabled,
+        hosts: bundle.hosts.clone(),
+        base: bundle.base.clone(),
+        dependencies: bundle.dependencies.clone(),
+        entries: bundle.entries.len(),
+        size: get_bundle_size(env, &bundle.id)?,
+        linked: lockfile.linked.iter().any(|it| it.id == bundle.id),
+        states,
+    };
+
+    write_output(env, &info.render(json || env.output.format == OutputFormat::Json)?)?;
+    Ok(info)
+}
+
+pub fn cmd_enable(env: &Env, bundle_name: &str) -> Result<()> {
+    check_storage_writable(env)?;
+    let mut bundle = read_bundle(env, bundle_name)?;
+    bundle.enabled = true;
+    write_bundle(env, &bundle)?;
+
+    cmd_link(env, bundle_name, &Default::default())?;
+    Ok(())
+}
+
+pub fn cmd_disable(env: &Env, bundle_name: &str) -> Result<()> {
+    check_storage_writable(env)?;
+    let mut bundle = read_bundle(env, bundle_name)?;
+    bundle.enabled = false;
+    write_bundle(env, &bundle)?;
+
+    cmd_unlink(env, bundle_name, &Default::default())
+}
+
+pub fn cmd_gc(env: &Env, options: &GcOptions) -> Result<GcSummary> {
+    if options.older_than.is_none() && options.keep.is_none() {
+        return Err(Error::Simple("gc needs --older-than or --keep"));
+    }
+
+    check_storage_writable(env)?;
+
+    let mut summary = GcSummary::default();
+    let dir = env.storage.join(BACKUP_DIR);
+
+    if Disk::is_dir(&dir) {
+        // backups are named after when they were made, anything
+        // else in there isn't ours to remove
+        let mut backups = Disk::read_dir(&dir)?
+            .into_iter()
+            .filter_map(|it| {
+                let created = it.file_name()?.to_str()?.parse::<u64>().ok()?;
+                Some((created, it))
+            })
+            .collect::<Vec<(u64, PathBuf)>>();
+
+        // newest first, so the ones we keep come first
+        backups.sort_by_key(|it| std::cmp::Reverse(it.0));
+
+        let now = env.clock.now();
+        for (index, (created, path)) in backups.iter().enumerate() {
+            let kept = options.keep.map(|keep| index < keep).unwrap_or(false);
+            let expired = options.older_than
+                .map(|age| now.saturating_sub(*created) > age)
+                .unwrap_or(true);
+
+            if kept || !expired {
+                continue;
+            }
+
+            summary.reclaimed += dir_size(path)?;
+            Disk::remove(path)?;
+            summary.removed.push(format!("{}", path.display()));
+        }
+    }
+
+    write_output(env, &summary.render(env.output.format)?)?;
+    Ok(summary)
+}
+
+pub fn cmd_unlink(env: &Env, bundle_name: &str, options: &UnlinkOptions) -> Result<()> {
+    if options.purge {
+        check_storage_writable(env)?;
+    }
+
+    let mut lockfile = get_lockfile(env)?;
+
+    let linked = match lockfile.linked.iter().find(|it| it.id == bundle_name) {
+        Some(linked) => linked.clone(),
+        None => {
+            println!("bundle `{}` is not linked", bundle_name);
+            return Ok(());
+        },
+    };
+
+    let bundle = read_bundle(env, bundle_name)?;
+
+    let view = bundle.linked_view(&linked, env)?;
+    let unlinked = unlink(&view, &linked.merged, options.force)?;
+    if options.restore {
+        restore(&unlinked, &linked.merged)?;
+    }
+
+    lockfile.linked.retain(|it| it.id != bundle.id);
+    write_lockfile(env, &lockfile)?;
+
+    if options.purge {
+        purge(env, &bundle.id)?;
+    }
+
+    print_report(env, &OperationReport {
+        unlinked: unlinked.len(),
+        skipped: view.entries.len() - unlinked.len(),
+        ..Default::default()
+    })
+}
+
+pub fn cmd_unlink_all(env: &Env, options: &UnlinkOptions) -> Result<()> {
+    if options.purge {
+        check_storage_writable(env)?;
+    }
+
+    let mut lockfile = get_lockfile(env)?;
+    let mut report = BulkReport::default();
+    let mut unlinked_entries = 0;
+
+    for linked in lockfile.linked.clone() {
+        let bundle_name = linked.id.clone();
+        let result = read_bundle(env, &bundle_name)
+            .and_then(|bundle| {
+                let bundle = bundle.linked_view(&linked, env)?;
+                let unlinked = unlink(&bundle, &linked.merged, options.force)?;
+                if options.restore {
+                    restore(&unlinked, &linked.merged)?;
+                }
+
+                Ok((bundle, unlinked))
+            });
+
+        match result {
+            Ok((bundle, unlinked)) => {
+                unlinked_entries += unlinked.len();
+                bundle.entries
+                    .iter()
+                    .filter(|it| !unlinked.iter().any(|u| u.remote == it.remote))
+                    .for_each(|it| report.skipped.push(it.remote.clone()));
+
+                lockfile.linked.retain(|it| it.id != bundle.id);
+
+                let purged = if options.purge { purge(env, &bundle.id) } else { Ok(()) };
+                match purged {
+                    Ok(()) => report.done.push(bundle_name),
+                    Err(error) => report.failed.push((bundle_name, error)),
+                };
+            },
+            Err(error) => report.failed.push((bundle_name, error)),
+        };
+    }
+
+    write_lockfile(env, &lockfile)?;
+    report.print("unlinked");
+
+    print_report(env, &OperationReport {
+        unlinked: unlinked_entries,
+        skipped: report.skipped.len(),
+        errors: report.failed.len(),
+        ..Default::default()
+    })?;
+
+    report.into_result()
+}
+
+// Moves where an entry is linked to, without adding it again. If
+// the bundle is linked, the old link is removed and the new one
+// created right away.
+pub fn cmd_rename_entry(env: &Env, bundle_name: &str, old_remote: &Path, new_remote: &Path) -> Result<()> {
+    check_storage_writable(env)?;
+    let mut lockfile = get_lockfile(env)?;
+    let mut bundle = read_bundle(env, bundle_name)?;
+    let trusted = checksum_matches(env, &lockfile, &bundle.id)?;
+    let old = format!("{}", old_remote.display());
+    let new = format!("{}", new_remote.display());
+
+    let expanded = bundle.expanded(env)?;
+    let index = expanded.entries
+        .iter()
+        .position(|it| it.remote == old)
+        .ok_or_else(|| Error::UnknownEntry(old.clone()))?;
+
+    if expanded.entries.iter().any(|it| it.remote == new) {
+        return Err(Error::RemoteClaimed(new, bundle.id.clone()));
+    }
+
+    let renamed = Bundle {
+        entries: vec![Entry { remote: new.clone(), ..expanded.entries[index].clone() }],
+        ..bundle.clone()
+    };
+    check_claimed(&lockfile, &renamed)?;
+
+    // what's linked at the old remote right now, which is nothing
+    // if only other entries of the bundle are linked
+    let current = match lockfile.linked.iter().find(|it| it.id == bundle.id) {
+        Some(linked) => bundle.linked_view(linked, env)?.subset(std::slice::from_ref(&old)),
+        None => Bundle { entries: vec![], ..bundle.clone() },
+    };
+
+    // stay relative to the base if the entry was and still fits
+    let stored = match bundle.expanded_base(env)? {
+        Some(base) if Path::new(&bundle.entries[index].remote).is_relative() => new_remote
+            .strip_prefix(&base)
+            .map(|it| format!("{}", it.display()))
+            .unwrap_or_else(|_| new.clone()),
+        _ => new.clone(),
+    };
+    bundle.entries[index].remote = stored;
+
+    if let Some(linked) = lockfile.linked.iter_mut().find(|it| it.id == bundle.id) {
+        if !current.entries.is_empty() {
+            unlink(&current, &linked.merged, false)?;
+            linked.merged.retain(|it| !Path::new(&it.remote).starts_with(&old));
+
+            let moved = Bundle {
+                entries: current.entries.iter().map(|it| Entry { remote: new.clone(), ..it.clone() }).collect(),
+                ..current
+            };
+
+            let result = link(env, &moved, &[], false, &Default::default())?;
+            linked.merged.extend(result.merged);
+        }
+
+        for it in linked.only.iter_mut().filter(|it| **it == old) {
+            *it = new.clone();
+        }
+
+        linked.entries = bundle.entries.clone();
+    }
+
+    write_bundle(env, &bundle)?;
+    if trusted {
+        record_checksum(env, &mut lockfile, &bundle.id)?;
+    }
+    write_lockfile(env, &lockfile)?;
+
+    println!("{} is now linked to {}", old, new);
+    Ok(())
+}
+
+// Whether the symlink at `remote` leads somewhere outside of `storage`
+fn links_outside(remote: &Path, storage: &Path) -> bool {
+    let target = match Disk::readlink(remote) {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+
+    let resolved = match remote.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+    };
+
+    !util::normalize(&resolved).starts_with(util::normalize(storage))
+}
+
+// An entry whose remote is its own local, or anything else inside
+// of storage. linking it would replace storage with a link to itself.
+fn is_self_referential(env: &Env, entry: &Entry) -> bool {
+    let remote = util::normalize(Path::new(&entry.remote));
+    remote == util::normalize(Path::new(&entry.local)) || remote.starts_with(util::normalize(&env.storage))
+}
+
+// Checks whether an entry is still placed the way we left it
+fn check_entry(entry: &Entry, storage: &Path) -> Option<ProblemKind> {
+    if !Disk::exists(&entry.local) {
+        return Some(ProblemKind::MissingSource);
+    }
+
+    match entry.link_type {
+        LinkType::Symlink | LinkType::Junction => {
+            if !Disk::is_symlink(&entry.remote) {
+                if Disk::symlink_exists(&entry.remote) {
+                    return Some(ProblemKind::Foreign);
+                }
+
+                return Some(ProblemKind::MissingLink);
+            }
+
+            if links_outside(Path::new(&entry.remote), storage) {
+                return Some(ProblemKind::ForeignLink);
+            }
+
+            if !links_to(Path::new(&entry.remote), Path::new(&entry.local)) {
+                return Some(ProblemKind::WrongTarget);
+            }
+        },
+        LinkType::Hardlink | LinkType::Copy | LinkType::Archive => {
+            if Disk::is_symlink(&entry.remote) && links_outside(Path::new(&entry.remote), storage) {
+                return Some(ProblemKind::ForeignLink);
+            }
+
+            if Disk::is_symlink(&entry.remote) {
+                return Some(ProblemKind::Foreign);
+            }
+
+            if !Disk::symlink_exists(&entry.remote) {
+                return Some(ProblemKind::MissingLink);
+            }
+
+            // e.g. an editor that saves by replacing the file
+            if entry.link_type == LinkType::Hardlink && !is_our_hardlink(entry) {
+                return Some(ProblemKind::Foreign);
+            }
+        },
+        LinkType::Skeleton => {
+            if Disk::is_symlink(&entry.remote) || Disk::is_file(&entry.remote) {
+                return Some(ProblemKind::Foreign);
+            }
+
+            if !Disk::is_dir(&entry.remote) {
+                return Some(ProblemKind::MissingLink);
+            }
+        },
+    };
+
+    None
+}
+
+fn bundle_problem(bundle_name: &str, error: Error) -> Result<Problem> {
+    let kind = match error {
+        Error::BundleNotFound => ProblemKind::MissingBundle,
+        Error::BundleMissingMeta => ProblemKind::MissingMeta,
+        other => return Err(other),
+    };
+
+    Ok(Problem {
+        bundle: bundle_name.to_owned(),
+        path: String::new(),
+        kind,
+        origin: None,
+        note: None,
+    })
+}
+
+pub fn check_status(env: &Env) -> Result<Health> {
+    let lockfile = get_lockfile(env)?;
+
+    let mut problems = Vec::new();
+    for linked in &lockfile.linked {
+        let bundle = match read_bundle(env, &linked.id).and_then(|it| it.linked_view(linked, env)) {
+            Ok(bundle) => bundle,
+            Err(error) => {
+                problems.push(bundle_problem(&linked.id, error)?);
+                continue;
+            },
+        };
+
+        for it in &bundle.entries {
+            // merged dirs are real dirs, their leaves are checked instead
+            if it.per_file || linked.merged.iter().any(|m| Path::new(&m.remote).starts_with(&it.remote)) {
+                continue;
+            }
+
+            if let Some(kind) = check_entry(it, &env.storage) {
+                problems.push(Problem {
+                    bundle: bundle.id.clone(),
+                    path: it.remote.clone(),
+                    kind,
+                    origin: it.origin.clone(),
+                    note: it.note.clone(),
+                });
+            }
+        }
+
+        for it in &linked.merged {
+            if let Some(kind) = check_entry(it, &env.storage) {
+                problems.push(Problem {
+                    bundle: bundle.id.clone(),
+                    path: it.remote.clone(),
+                    kind,
+                    origin: it.origin.clone(),
+                    note: it.note.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(Health::new(problems))
+}
+
+// Same as `check_status`, but also looks at bundles that
+// aren't linked right now
+pub fn check_doctor(env: &Env) -> Result<Health> {
+    let lockfile = get_lockfile(env)?;
+    let mut problems = check_status(env)?.problems;
+
+    for bundle_name in get_bundle_names(env)? {
+        if lockfile.linked.iter().any(|it| it.id == bundle_name) {
+            continue;
+        }
+
+        let bundle = match read_bundle(env, &bundle_name) {
+            Ok(bundle) => bundle,
+            Err(error) => {
+                problems.push(bundle_problem(&bundle_name, error)?);
+                continue;
+            },
+        };
+
+        for it in bundle.entries.iter().filter(|it| !Disk::exists(&it.local)) {
+            problems.push(Problem {
+                bundle: bundle.id.clone(),
+                path: it.remote.clone(),
+                kind: ProblemKind::MissingSource,
+                origin: it.origin.clone(),
+                note: it.note.clone(),
+            });
+        }
+    }
+
+    Ok(Health::new(problems))
+}
+
+// Checks that the lockfile makes sense on its own, without looking
+// at what's actually linked
+pub fn check_lock(env: &Env) -> Result<Health> {
+    let lockfile = get_lockfile(env)?;
+    let problem = |bundle: &str, path: &str, kind| Problem {
+        bundle: String::from(bundle),
+        path: String::from(path),
+        kind,
+        origin: None,
+        note: None,
+    };
+
+    let mut problems = Vec::new();
+    for (i, linked) in lockfile.linked.iter().enumerate() {
+        let earlier = &lockfile.linked[..i];
+        if earlier.iter().any(|it| it.id == linked.id) {
+            problems.push(problem(&linked.id, "", ProblemKind::DuplicateBundle));
+            continue;
+        }
+
+        if !Disk::is_dir(env.bundle_dir().join(&linked.id)) {
+            problems.push(problem(&linked.id, "", ProblemKind::MissingBundle));
+        }
+
+        let entries = linked.entries.iter().chain(linked.merged.iter());
+        if entries.clone().any(|it| it.local.is_empty() || it.remote.is_empty()) {
+            problems.push(problem(&linked.id, "", ProblemKind::EmptyPath));
+        }
+
+        for it in entries.clone().filter(|it| !it.remote.is_empty() && is_self_referential(env, it)) {
+            problems.push(problem(&linked.id, &it.remote, ProblemKind::SelfReferential));
+        }
+
+        // only reported once, for the bundle that comes later
+        let claimed = entries
+            .filter(|it| !it.remote.is_empty())
+            .filter(|it| earlier.iter().any(|other| {
+                other.id != linked.id && other.entries.iter().any(|e| e.remote == it.remote)
+            }));
+
+        for it in claimed {
+            problems.push(problem(&linked.id, &it.remote, ProblemKind::DuplicateRemote));
+        }
+    }
+
+    Ok(Health::new(problems))
+}
+
+fn render_health(health: &Health, json: bool) -> Result<String> {
+    if json {
+        return Ok(serde_json::to_string_pretty(&health)?);
+    }
+
+    if health.ok {
+        return Ok(String::from("everything looks fine"));
+    }
+
+    let lines = health.problems
+        .iter()
+        .map(|it| {
+            let line = match (it.path.as_ref(), &it.origin) {
+                ("", _) => format!("bundle `{}` {}", it.bundle, it.kind.describe()),
+                (path, Some(origin)) if origin != path => format!(
+                    "{}: {} {} (added from {})",
+                    it.bundle, path, it.kind.describe(), origin,
+                ),
+                (path, _) => format!("{}: {} {}", it.bundle, path, it.kind.describe()),
+            };
+
+            match &it.note {
+                Some(note) => format!("{}  # {}", line, note),
+                None => line,
+            }
+        })
+        .collect::<Vec<String>>();
+
+    Ok(lines.join("\n"))
+}
+
+// Options for `cmd_status` that can be set from the command line
+#[derive(Debug, Clone, Default)]
+pub struct StatusOptions {
+    pub json: bool,
+    // repair links that are missing or point somewhere else
+    pub fix: bool,
+    // also replace things at `remote` that aren't ours when fixing
+    pub force: bool,
+}
+
+pub fn cmd_status(env: &Env, options: &StatusOptions) -> Result<()> {
+    let mut health = check_status(env)?;
+    let json = options.json || env.output.format == OutputFormat::Json;
+
+    if options.fix {
+        for it in fix_problems(env, &health.problems, options.force)? {
+            if !json {
+                println!("fixed {}", it);
+            }
+        }
+
+        // whatever is left couldn't be fixed
+        health = check_status(env)?;
+    }
+
+    write_output(env, &render_health(&health, json)?)?;
+    health.into_result()
+}
+
+// Links entries again where that's safe, returning the remotes
+// that were fixed. Things at `remote` that aren't ours are only
+// replaced with `force`.
+fn fix_problems(env: &Env, problems: &[Problem], force: bool) -> Result<Vec<String>> {
+    let lockfile = get_lockfile(env)?;
+    let mut fixed = Vec::new();
+
+    for problem in problems {
+        let fixable = match problem.kind {
+            // a symlink is cheap to put back, whoever changed it
+            ProblemKind::MissingLink | ProblemKind::WrongTarget | ProblemKind::ForeignLink => true,
+            ProblemKind::Foreign => force,
+            _ => false,
+        };
+
+        if !fixable {
+            continue;
+        }
+
+        let linked = match lockfile.linked.iter().find(|it| it.id == problem.bundle) {
+            Some(linked) => linked,
+            None => continue,
+        };
+
+        let bundle = read_bundle(env, &problem.bundle)?.linked_view(linked, env)?;
+        let entry = bundle.entries
+            .iter()
+            .chain(linked.merged.iter())
+            .find(|it| it.remote == problem.path);
+
+        let entry = match entry {
+            Some(entry) => entry,
+            None => continue,
+        };
+
+        let remote = PathBuf::from(&entry.remote);
+        if Disk::symlink_exists(&remote) {
+            replace(env, &remote, &Default::default())?;
+        }
+
+        if let Some(parent) = remote.parent() {
+            if !Disk::is_dir(parent) {
+                Disk::mkdir_all(parent)?;
+            }
+        }
+
+        place_link(env, Path::new(&entry.local), &remote, entry.link_type, &Default::default())?;
+        fixed.push(entry.remote.clone());
+    }
+
+    Ok(fixed)
+}
+
+// Options for `cmd_doctor` that can be set from the command line
+#[derive(Debug, Clone, Default)]
+pub struct DoctorOptions {
+    pub json: bool,
+    // print nothing, the result only shows in whether this fails
+    pub check: bool,
+}
+
+pub fn cmd_doctor(env: &Env, options: &DoctorOptions) -> Result<()> {
+    let health = check_doctor(env)?;
+    let json = options.json || env.output.format == OutputFormat::Json;
+
+    if !options.check {
+        write_output(env, &render_health(&health, json)?)?;
+    }
+
+    health.into_result()
+}
+
+// Prints what's wrong with the lockfile, without changing anything
+pub fn cmd_validate(env: &Env) -> Result<Vec<Problem>> {
+    let health = check_lock(env)?;
+    write_output(env, &render_health(&health, env.output.format == OutputFormat::Json)?)?;
+
+    Ok(health.problems)
+}
+
+// Checks that every symlink in the lock still points into storage,
+// by only reading the links. Nothing is printed unless one doesn't,
+// so it's cheap enough to run often, e.g. from cron
+pub fn cmd_verify_links(env: &Env) -> Result<()> {
+    let lockfile = get_lockfile(env)?;
+
+    let mut broken = 0;
+    for linked in &lockfile.linked {
+        // locks written before the base was kept in them still
+        // need it from the bundle
+        let mut bundle = linked.bundle();
+        if bundle.base.is_none() {
+            bundle.base = read_bundle(env, &linked.id).ok().and_then(|it| it.base);
+        }
+
+        // per file directories are real, their files are in `merged`
+        let entries = bundle.linked_view(linked, env)?.entries
+            .into_iter()
+            .chain(linked.merged.iter().cloned())
+            .filter(|it| !it.per_file)
+            .filter(|it| it.link_type == LinkType::Symlink || it.link_type == LinkType::Junction);
+
+        for it in entries {
+            let remote = Path::new(&it.remote);
+            if links_to(remote, Path::new(&it.local)) {
+                continue;
+            }
+
+            broken += 1;
+            match Disk::readlink(remote) {
+                Ok(target) => println!("{}: {} points to {}", linked.id, it.remote, target.display()),
+                Err(_) => println!("{}: {} is not a link", linked.id, it.remote),
+            };
+        }
+    }
+
+    match broken {
+        0 => Ok(()),
+        _ => Err(Error::Unhealthy(broken)),
+    }
+}
+
+// How `cmd_print_lock` shows the lockfile
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockDump {
+    // what we read it as
+    Parsed,
+    // what we'd write it as
+    Canonical,
+    // the file itself
+    Raw,
+}
+
+// Prints the lockfile for debugging, so it's visible how what's
+// in the file is understood
+pub fn cmd_print_lock(env: &Env, dump: LockDump) -> Result<()> {
+    let rendered = match dump {
+        LockDump::Parsed => format!("{:#?}", get_lockfile(env)?),
+        LockDump::Canonical => serialize_lock(&get_lockfile(env)?)?,
+        LockDump::Raw => {
+            let path = env.storage.join(LOCK_FILE);
+            if Disk::is_file(&path) { Disk::get(&path)? } else { String::new() }
+        },
+    };
+
+    write_output(env, &rendered)
+}
+
+// Throws away the lock and writes a new one from what's in storage,
+// counting every bundle as linked whose entries are in place. what
+// was merged into existing directories can't be told apart from
+// what was there before, so that's lost.
+pub fn cmd_reindex(env: &Env) -> Result<()> {
+    check_storage_writable(env)?;
+
+    // checksums are carried over, a bundle that changed behind our
+    // back shouldn't look untouched just because we reindexed
+    let previous = get_lockfile(env).unwrap_or_default();
+
+    let lock_path = env.storage.join(LOCK_FILE);
+    if Disk::is_file(&lock_path) {
+        let saved = backup(env, &lock_path)?;
+        println!("backed up the old lock to {}", saved.display());
+    }
+
+    let mut lockfile = Lock::default();
+    let mut names = get_bundle_names(env)?;
+    names.sort();
+
+    for bundle_name in names.iter() {
+        let bundle = match read_bundle(env, bundle_name) {
+            Ok(bundle) => bundle,
+            Err(error) => {
+                eprintln!("warning: skipping `{}`, it can't be read: {:?}", bundle_name, error);
+                continue;
+            },
+        };
+
+        let view = bundle.view(&env.host).variants(&env.host).expanded(env)?;
+        let linked = view.entries
+            .iter()
+            .filter(|it| check_entry(it, &env.storage).is_none())
+            .map(|it| it.remote.clone())
+            .collect::<Vec<String>>();
+
+        if linked.is_empty() {
+            continue;
+        }
+
+        let only = if linked.len() == view.entries.len() { vec![] } else { linked };
+        mark_linked(&mut lockfile, &bundle, &env.host, &env.host, &only, vec![]);
+
+        if let Some(linked) = lockfile.linked.iter_mut().find(|it| it.id == bundle.id) {
+            linked.checksum = previous.linked
+                .iter()
+                .find(|it| it.id == bundle.id)
+                .and_then(|it| it.checksum.clone());
+        }
+
+        if checksum_matches(env, &lockfile, &bundle.id)? {
+            record_checksum(env, &mut lockfile, &bundle.id)?;
+        }
+    }
+
+    write_lockfile(env, &lockfile)?;
+    println!("found {} linked of {} bundles", lockfile.linked.len(), names.len());
+
+    Ok(())
+}
+
+// Writes everything stored for a bundle to `out` as a tar archive,
+// which `cmd_import` can put into storage somewhere else
+pub fn cmd_export<W: std::io::Write>(env: &Env, bundle_name: &str, out: &mut W) -> Result<()> {
+    let bundle = read_bundle(env, bundle_name)?;
+    let dir = env.bundle_dir().join(&bundle.id);
+
+    // files were probably edited since the manifest was written,
+    // and the importer checks what it gets against it
+    let manifest = Manifest::generate(&dir)?;
+    if Manifest::load(&dir)?.as_ref() != Some(&manifest) {
+        check_storage_writable(env)?;
+        manifest.write(&dir)?;
+    }
+
+    let archive = archive::pack(&dir)?;
+
+    out.write_all(&archive)?;
+    out.flush()?;
+
+    Ok(())
+}
+
+// Puts a bundle exported by `cmd_export` into storage without linking
+// it, returning its name. Existing bundles are never replaced.
+pub fn cmd_import<R: std::io::Read>(env: &Env, input: &mut R) -> Result<String> {
+    check_storage_writable(env)?;
+
+    let mut archive = Vec::new();
+    input.read_to_end(&mut archive)?;
+
+    // the name is only known once the bundle file is unpacked
+    let staging = env.storage.join(IMPORT_DIR);
+    if Disk::symlink_exists(&staging) {
+        Disk::remove(&staging)?;
+    }
+
+    let imported = archive::unpack(&archive, &staging).and_then(|_| {
+        let raw = Disk::get(staging.join(BUNDLE_FILE))?;
+        let bundle = toml::from_str::<Bundle>(&raw)?;
+        util::validate_name(&bundle.id)?;
+
+        let dest = env.bundle_dir().join(&bundle.id);
+        if Disk::symlink_exists(&dest) {
+            return Err(Error::StorageEntryExists(format!("{}", dest.display())));
+        }
+
+        // archives from before manifests are taken as they are
+        if let Some(manifest) = Manifest::load(&staging)? {
+            manifest.verify(&staging)?;
+        }
+
+        // locals still point into the storage of wherever it came from
+        let entries = bundle.entries
+            .iter()
+            .map(|it| {
+                let rest = imported_local(&it.local, &bundle.id, &staging)?;
+                Ok(Entry { local: format!("{}", dest.join(rest).display()), ..it.clone() })
+            })
+            .collect::<Result<Vec<Entry>>>()?;
+
+        Disk::mkdir_all(env.bundle_dir())?;
+        Disk::copy(&staging, &dest, false)?;
+
+        let bundle = Bundle { entries, ..bundle };
+        write_bundle(env, &bundle)?;
+        Ok(bundle.id)
+    });
+
+    if Disk::symlink_exists(&staging) {
+        Disk::remove(&staging)?;
+    }
+
+    let id = imported?;
+
+    println!("imported bundle `{}`", id);
+    Ok(id)
+}
+
+// Where `local` from another machine's storage is inside of the bundle
+// `id` unpacked at `dir`, by what comes after the bundle's own dir
+fn imported_local(local: &str, id: &str, dir: &Path) -> Result<PathBuf> {
+    let parts = Path::new(local).components().collect::<Vec<Component>>();
+
+    parts
+        .iter()
+        .enumerate()
+        .filter(|(_, it)| it.as_os_str() == id)
+        .map(|(i, _)| parts[i + 1..].iter().collect::<PathBuf>())
+        .find(|rest| !rest.as_os_str().is_empty() && Disk::symlink_exists(dir.join(rest)))
+        .ok_or_else(|| Error::LocalOutsideStorage(String::from(local)))
+}
+
+// Prints a json schema for lock or bundle files, which editors
+// can use to check them while they're edited by hand
+pub fn cmd_schema(env: &Env, kind: SchemaKind) -> Result<()> {
+    let schema = serde_json::to_string_pretty(&schema(kind))?;
+    write_output(env, &schema)
+}
+
+fn schema(kind: SchemaKind) -> schemars::schema::RootSchema {
+    match kind {
+        SchemaKind::Lock => schemars::schema_for!(Lock),
+        SchemaKind::Bundle => schemars::schema_for!(Bundle),
+    }
+}
+
+// Prints the result of a command, or writes it to the output
+// file if there is one
+fn write_output(env: &Env, rendered: &str) -> Result<()> {
+    let path = match &env.output.path {
+        Some(path) => path,
+        None => {
+            println!("{}", rendered);
+            return Ok(());
+        },
+    };
+
+    if let Some(parent) = path.parent() {
+        Disk::mkdir_all(parent)?;
+    }
+
+    Disk::put(path, rendered)?;
+    Ok(())
+}
+
+// Copies the stored contents of unlinked entries back into place. Dirs
+// we merged into are still there, so only their leaves are copied back.
+fn restore(unlinked: &[Entry], merged: &[Entry]) -> Result<()> {
+    for it in unlinked {
+        if !Disk::symlink_exists(&it.remote) {
+            match it.link_type {
+                LinkType::Archive => archive::unpack(&Disk::get_bytes(&it.local)?, Path::new(&it.remote))?,
+                LinkType::Skeleton => create_skeleton(Path::new(&it.local), Path::new(&it.remote))?,
+                _ => {
+                    Disk::copy(&it.local, &it.remote, false)?;
+                    apply_xattrs(it, Path::new(&it.remote))?;
+                },
+            };
+
+            continue;
+        }
+
+        let leaves = merged
+            .iter()
+            .filter(|m| Path::new(&m.remote).starts_with(&it.remote))
+            .filter(|m| !Disk::symlink_exists(&m.remote));
+
+        for leaf in leaves {
+            Disk::copy(&leaf.local, &leaf.remote, false)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Puts the extended attributes that were kept for `entry` on a copy
+fn apply_xattrs(entry: &Entry, path: &Path) -> Result<()> {
+    if entry.xattrs.is_empty() || !Disk::is_file(path) {
+        return Ok(());
+    }
+
+    let attrs = entry.xattrs
+        .iter()
+        .map(|(name, value)| Ok((name.clone(), util::from_hex(value)?)))
+        .collect::<Result<BTreeMap<String, Vec<u8>>>>()?;
+
+    Disk::set_xattrs(path, &attrs)
+}
+
+// Sets the modification time of every file copied to `remote` to
+// the one of the file in storage it was copied from
+fn copy_mtimes(local: &Path, remote: &Path) -> Result<()> {
+    if Disk::is_file(local) {
+        return Disk::set_modified(remote, Disk::modified(local)?);
+    }
+
+    for it in Disk::walk(local)? {
+        if !Disk::is_file(&it) || Disk::is_symlink(&it) {
+            continue;
+        }
+
+        let relative = it.strip_prefix(local).expect("Invalid: walk returned a path outside of the dir");
+        Disk::set_modified(remote.join(relative), Disk::modified(&it)?)?;
+    }
+
+    Ok(())
+}
+
+fn purge(env: &Env, bundle_name: &str) -> Result<()> {
+    let dir = env.bundle_dir().join(bundle_name);
+    Disk::remove(&dir)?;
+
+    println!("removed bundle `{}` from storage", bundle_name);
+    Ok(())
+}
+
+// What linking `bundle` would do with each entry, labeled so the
+// conflicts stand out: `create` where nothing is in the way, `replace`
+// where something of the user's is, `relink` for our links that point
+// somewhere else and `ok` for entries that are already in place.
+// Files that would be copied over get a diff to review.
+fn preview_link(env: &Env, bundle: &Bundle, options: &LinkOptions) -> Result<String> {
+    let mut lines = Vec::new();
+    let label = |label: &str, remote: &str| format!("{:8} {}", label, remote);
+
+    for it in &bundle.entries {
+        let remote_path: PathBuf = it.remote.clone().into();
+        let local_path: PathBuf = it.local.clone().into();
+        let link_type = effective_link_type(it, options);
+
+        if dangles(link_type, &local_path) {
+            lines.push(format!("{}: {}", label("skip", &it.remote), SkipReason::MissingSource));
+            continue;
+        }
+
+        if (it.per_file || options.per_file) && Disk::is_dir(&local_path) {
+            lines.push(format!("{} (each file on its own)", label("per-file", &it.remote)));
+            continue;
+        }
+
+        if !Disk::symlink_exists(&remote_path) {
+            lines.push(label("create", &it.remote));
+            continue;
+        }
+
+        let in_place = match link_type {
+            LinkType::Symlink | LinkType::Junction => links_to(&remote_path, &local_path),
+            LinkType::Hardlink => Disk::is_file(&remote_path) && Disk::is_same_file(&local_path, &remote_path),
+            _ => false,
+        };
+
+        if in_place {
+            lines.push(label("ok", &it.remote));
+            continue;
+        }
+
+        let is_plain_file = Disk::is_file(&remote_path) && !Disk::is_symlink(&remote_path);
+        if link_type == LinkType::Copy && Disk::is_file(&local_path) && is_plain_file {
+            let current = Disk::get_bytes(&remote_path)?;
+            let deployed = Disk::get_bytes(&local_path)?;
+
+            if current == deployed {
+                lines.push(label("ok", &it.remote));
+                continue;
+            }
+
+            lines.push(label("replace", &it.remote));
+
+            // there's nothing sensible to show for files that aren't text
+            if let (Ok(current), Ok(deployed)) = (std::str::from_utf8(&current), std::str::from_utf8(&deployed)) {
+                lines.push(util::unified_diff(current, deployed, &it.remote, &it.local));
+            }
+
+            continue;
+        }
+
+        // a link of ours, only pointing somewhere else in storage
+        let ours = Disk::is_symlink(&remote_path) && !links_outside(&remote_path, &env.storage);
+        if ours && matches!(link_type, LinkType::Symlink | LinkType::Junction) {
+            let target = Disk::readlink(&remote_path)?;
+            lines.push(format!("{} (points to {})", label("relink", &it.remote), target.display()));
+            continue;
+        }
+
+        lines.push(label("replace", &it.remote));
+    }
+
+    Ok(lines.join("\n"))
+}
+
+fn link(
+    env: &Env,
+    bundle: &Bundle,
+    overwrite: &[&str],
+    overwrite_all: bool,
+    options: &LinkOptions,
+) -> Result<LinkResult> {
+    // TODO(happens): Check if linked bundles conflict with this one
+
+    // a hand-edited bundle could point anywhere, and we're about
+    // to replace whatever is at `remote` with it. nothing is
+    // touched unless every entry is fine.
+    for it in &bundle.entries {
+        if !inside_storage(env, Path::new(&it.local)) {
+            return Err(Error::LocalOutsideStorage(it.local.clone()));
+        }
+
+        if is_self_referential(env, it) {
+            return Err(Error::SelfReferentialEntry(it.remote.clone()));
+        }
+    }
+
+    let mut result = LinkResult::default();
+    let mut overwrite_all = overwrite_all;
+    for it in &bundle.entries {
+        // first, make sure that all dirs leading up to the file
+        // or dir exist (if there is no parent that means we
+        // are placing the file at '/', which is fine, i guess?)
+        let remote_path: PathBuf = it.remote.clone().into();
+        let local_path: PathBuf = it.local.clone().into();
+        let link_type = effective_link_type(it, options);
+
+        if dangles(link_type, &local_path) {
+            result.skip(it, SkipReason::MissingSource);
+            continue;
+        }
+
+        if let Some(parent) = remote_path.parent() {
+            // this is weird, but can happen
+            if Disk::is_file(parent) && env.resolver.resolve_parent_is_file(&it.remote, parent) {
+                Disk::remove(parent)?;
+            }
+
+            if !Disk::is_dir(parent) {
+                Disk::mkdir_all(parent)?;
+            }
+        }
+
+        // skeletons only add directories, so an existing directory
+        // is never in the way, and neither is anything inside of it
+        if link_type == LinkType::Skeleton && (Disk::is_dir(&remote_path) || !Disk::symlink_exists(&remote_path)) {
+            create_skeleton(&local_path, &remote_path)?;
+            result.link(it.clone(), LinkAction::Linked);
+            continue;
+        }
+
+        // a policy set on the entry itself also wins over
+        // choosing "overwrite all" earlier
+        let overwriting = overwrite_all && it.on_conflict.is_none();
+        let policy = if overwriting || overwrite.contains(&it.remote.as_ref()) {
+            ConflictPolicy::Overwrite
+        } else {
+            it.on_conflict.unwrap_or(env.config.conflict)
+        };
+
+        let per_file = (it.per_file || options.per_file) && Disk::is_dir(&local_path);
+
+        // a link to the whole directory is ours to replace
+        if per_file && links_to(&remote_path, &local_path) {
+            Disk::remove(&remote_path)?;
+        }
+
+        // the directory is ours to fill if it's there already, what's
+        // inside of it is asked about one by one
+        if per_file && (!Disk::symlink_exists(&remote_path) || Disk::is_dir(&remote_path) && !Disk::is_symlink(&remote_path)) {
+            Disk::mkdir_all(&remote_path)?;
+            result.merged.extend(merge_dir(env, &local_path, &remote_path, policy, options)?);
+            result.link(Entry { per_file: true, ..it.clone() }, LinkAction::Merged);
+            continue;
+        }
+
+        // nothing to do if this was already linked before
+        if link_type == LinkType::Symlink && links_to(&remote_path, &local_path) {
+            result.skip(it, SkipReason::AlreadyLinked);
+            continue;
+        }
+
+        let mut action = LinkAction::Linked;
+        if Disk::symlink_exists(&remote_path) {
+            match policy {
+                ConflictPolicy::Skip => {
+                    result.skip(it, SkipReason::Conflict);
+                    continue;
+                },
+                ConflictPolicy::Overwrite => {},
+                ConflictPolicy::Prompt => {
+                    // merging only makes sense if we're linking a directory
+                    // into a real directory the user already has
+                    let can_merge = Disk::is_dir(&local_path)
+                        && Disk::is_dir(&remote_path)
+                        && !Disk::is_symlink(&remote_path);
+
+                    match env.resolver.resolve_existing(&it.remote, can_merge) {
+                        Resolution::Skip => {
+                            result.skip(it, SkipReason::UserSkip);
+                            continue;
+                        },
+                        Resolution::OverwriteAll => overwrite_all = true,
+                        Resolution::Merge if can_merge => {
+                            let merged = merge_dir(env, &local_path, &remote_path, policy, options)?;
+                            result.merged.extend(merged);
+                            result.link(it.clone(), LinkAction::Merged);
+                            continue;
+                        },
+                        Resolution::Merge | Resolution::Overwrite => {},
+                    };
+                },
+            };
+
+            // if we drop through to here, we're supposed to nuke it and
+            // replace it
+            replace(env, &remote_path, options)?;
+            action = if env.config.backup { LinkAction::BackedUp } else { LinkAction::Overwritten };
+        }
+
+        let used = place_link(env, &local_path, &remote_path, link_type, options)?;
+        if used == LinkType::Copy {
+            apply_xattrs(it, &remote_path)?;
+
+            if options.preserve_mtime {
+                copy_mtimes(&local_path, &remote_path)?;
+            }
+        }
+
+        result.link(Entry { link_type: used, ..it.clone() }, action);
+    }
+
+    Ok(result)
+}
+
+// Archives can only ever be extracted, every other entry can be
+// linked however the user wants
+fn effective_link_type(entry: &Entry, options: &LinkOptions) -> LinkType {
+    match entry.link_type {
+        LinkType::Archive => LinkType::Archive,
+        LinkType::Skeleton => LinkType::Skeleton,
+        other => options.link_type.unwrap_or(other),
+    }
+}
+
+// Whether linking `local` would only leave a dangling link behind.
+// every other link type fails on its own when there's nothing to link
+fn dangles(link_type: LinkType, local: &Path) -> bool {
+    (link_type == LinkType::Symlink || link_type == LinkType::Junction) && !Disk::exists(local)
+}
+
+// What a symlink at `remote` should contain to lead to `local`
+fn symlink_target(local: &Path, remote: &Path, options: &LinkOptions) -> PathBuf {
+    if !options.relative {
+        return local.to_path_buf();
+    }
+
+    match remote.parent().and_then(|parent| util::relative_path(parent, local)) {
+        Some(relative) => relative,
+        None => {
+            eprintln!(
+                "warning: can't link {} relative to {}, linking it absolute",
+                remote.display(), local.display(),
+            );
+
+            local.to_path_buf()
+        },
+    }
+}
+
+// Whether `remote` is a symlink leading to `local`, no matter if
+// it was linked absolute or relative
+fn links_to(remote: &Path, local: &Path) -> bool {
+    let target = match Disk::readlink(remote) {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+
+    let resolved = match remote.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+    };
+
+    // either side might be reached through other links, e.g. a
+    // home that's a link itself
+    util::normalize(&resolved) == util::normalize(local) || real_path(&resolved) == real_path(local)
+}
+
+// Whether `local` is in storage once the links on the way to it are
+// followed, a dir in storage could be a link leading out of it. the
+// stored file itself can be a link, so only its parent is resolved.
+fn inside_storage(env: &Env, local: &Path) -> bool {
+    let resolved = match (local.parent(), local.file_name()) {
+        (Some(parent), Some(name)) => real_path(parent).join(name),
+        _ => real_path(local),
+    };
+
+    resolved.starts_with(real_path(&env.storage))
+}
+
+// `path` with every symlink along the way followed, like `realpath`.
+// whatever doesn't exist is taken as it is.
+fn real_path(path: &Path) -> PathBuf {
+    // same limit as `resolve_symlink`, past it links are taken as they are
+    const MAX_HOPS: usize = 40;
+
+    let components = |path: &Path| path
+        .components()
+        .rev()
+        .map(|it| it.as_os_str().to_os_string())
+        .collect::<Vec<std::ffi::OsString>>();
+
+    let mut rest = components(path);
+    let mut result = PathBuf::new();
+    let mut hops = 0;
+
+    while let Some(it) = rest.pop() {
+        match Path::new(&it).components().next() {
+            Some(Component::CurDir) => continue,
+            Some(Component::ParentDir) => {
+                result.pop();
+                continue;
+            },
+            _ => result.push(&it),
+        };
+
+        if hops < MAX_HOPS && Disk::is_symlink(&result) {
+            if let Ok(target) = Disk::readlink(&result) {
+                hops += 1;
+                result.pop();
+                rest.extend(components(&target));
+            }
+        }
+    }
+
+    result
+}
+
+// Places `local` at `remote` using the given link type. If we're not
+// allowed to create the link, this retries with sudo, or on windows (where
+// it's usually symlinks without developer mode) falls back to copying.
+// Returns the link type that was used.
+fn place_link(
+    env: &Env,
+    local: &Path,
+    remote: &Path,
+    link_type: LinkType,
+    options: &LinkOptions,
+) -> Result<LinkType> {
+    let result = match link_type {
+        LinkType::Symlink => Disk::symlink(symlink_target(local, remote, options), remote),
+        LinkType::Junction => Disk::junction(local, remote),
+        LinkType::Hardlink => Disk::hardlink(local, remote),
+        LinkType::Copy => Disk::copy(local, remote, true),
+        LinkType::Archive => Disk::get_bytes(local).and_then(|it| archive::unpack(&it, remote)),
+        LinkType::Skeleton => create_skeleton(local, remote),
+    };
+
+    if cfg!(windows) {
+        return copy_on_permission_error(local, remote, link_type, result);
+    }
+
+    let command: &[&str] = match link_type {
+        LinkType::Symlink => &["ln", "-s"],
+        LinkType::Hardlink => &["ln"],
+        LinkType::Copy => &["cp", "-R"],
+        // these don't exist outside of windows anyways
+        LinkType::Junction => return result.map(|_| link_type),
+        // there's nothing to retry with, extracting is all on us
+        LinkType::Archive | LinkType::Skeleton => return result.map(|_| link_type),
+    };
+
+    let source = match link_type {
+        LinkType::Symlink => symlink_target(local, remote, options),
+        _ => local.to_path_buf(),
+    };
+
+    let mut args = command.iter().map(OsStr::new).collect::<Vec<&OsStr>>();
+    args.push(source.as_os_str());
+    args.push(remote.as_os_str());
+
+    sudo_on_permission_error(env, options.sudo, remote, &args, result)?;
+    Ok(link_type)
+}
+
+// Retries something that failed because we weren't allowed to write to
+// `path` by running `args` through sudo, if that was allowed up front or
+// the user agrees to it
+fn sudo_on_permission_error(
+    env: &Env,
+    sudo: bool,
+    path: &Path,
+    args: &[&OsStr],
+    result: Result<()>,
+) -> Result<()> {
+    match result {
+        Err(ref error) if error.is_permission_denied() => {},
+        other => return other,
+    };
+
+    if cfg!(windows) {
+        return Err(Error::PermissionDenied(path.to_path_buf()));
+    }
+
+    let allowed = sudo || env.interactive && env.resolver.allow_sudo(path);
+
+    if !allowed {
+        return Err(Error::PermissionDenied(path.to_path_buf()));
+    }
+
+    match Shell::sudo(args)? {
+        0 => Ok(()),
+        _ => Err(Error::PermissionDenied(path.to_path_buf())),
+    }
+}
+
+fn copy_on_permission_error(
+    local: &Path,
+    remote: &Path,
+    link_type: LinkType,
+    result: Result<()>,
+) -> Result<LinkType> {
+    match result {
+        Ok(()) => Ok(link_type),
+        Err(ref error)
+            if error.is_permission_denied()
+                && link_type != LinkType::Copy
+                && link_type != LinkType::Archive
+                && link_type != LinkType::Skeleton =>
+        {
+            println!(
+                "warning: not allowed to create {} at {}, copying instead",
+                link_type, remote.display(),
+            );
+
+            Disk::copy(local, remote, true)?;
+            Ok(LinkType::Copy)
+        },
+        Err(error) => Err(error),
+    }
+}
+
+// Links every file inside the `local` storage directory into the
+// existing `remote` directory, creating real directories on the way.
+// Anything in the way is handled according to `policy`. Returns the
+// leaves that were linked.
+fn merge_dir(
+    env: &Env,
+    local: &Path,
+    remote: &Path,
+    policy: ConflictPolicy,
+    options: &LinkOptions,
+) -> Result<Vec<Entry>> {
+    let mut result = Vec::new();
+    let mut skipped: Vec<PathBuf> = Vec::new();
+    let mut overwrite_all = false;
+
+    for it in Disk::walk(local)? {
+        let relative = it.strip_prefix(local)
+            .expect("Invalid: walk returned a path outside of the directory");
+
+        // if the user chose to skip a directory, skip everything inside it too
+        if skipped.iter().any(|s| relative.starts_with(s)) {
+            continue;
+        }
+
+        let target = remote.join(relative);
+        let target_is_dir = Disk::is_dir(&target) && !Disk::is_symlink(&target);
+        let target_exists = Disk::is_file(&target)
+            || Disk::is_dir(&target)
+            || Disk::is_symlink(&target);
+
+        if Disk::is_dir(&it) && target_is_dir {
+            continue;
+        }
+
+        // linked before, so linking again doesn't ask about it
+        if links_to(&target, &it) {
+            result.push(leaf_entry(&it, &target));
+            continue;
+        }
+
+        // anything that's in the way gets the same treatment as a
+        // conflicting entry in a regular link
+        if target_exists {
+            let target_name = format!("{}", target.display());
+            let resolution = match policy {
+                ConflictPolicy::Prompt if !overwrite_all => env.resolver.resolve_existing(&target_name, false),
+                ConflictPolicy::Skip => Resolution::Skip,
+                ConflictPolicy::Prompt | ConflictPolicy::Overwrite => Resolution::Overwrite,
+            };
+
+            match resolution {
+                Resolution::Skip => {
+                    skipped.push(relative.to_path_buf());
+                    continue;
+                },
+                Resolution::OverwriteAll => overwrite_all = true,
+                Resolution::Overwrite | Resolution::Merge => {},
+            };
+
+            replace(env, &target, options)?;
+        }
+
+        if Disk::is_dir(&it) {
+            Disk::mkdir_all(&target)?;
+            continue;
+        }
+
+        Disk::symlink(symlink_target(&it, &target, options), &target)?;
+        result.push(leaf_entry(&it, &target));
+    }
+
+    Ok(result)
+}
+
+fn leaf_entry(local: &Path, remote: &Path) -> Entry {
+    Entry {
+        local: format!("{}", local.display()),
+        remote: format!("{}", remote.display()),
+        link_type: LinkType::Symlink,
+        host: None,
+        on_conflict: None,
+        origin: None,
+        note: None,
+        per_file: false,
+        xattrs: Default::default(),
+    }
+}
+
+// Gets `remote` out of the way so we can link something in its place,
+// backing it up first if the user wants us to
+fn replace(env: &Env, remote: &Path, options: &LinkOptions) -> Result<()> {
+    if env.config.backup {
+        let dest = backup(env, remote)?;
+        println!("backed up {} to {}", remote.display(), dest.display());
+    }
+
+    let result = Disk::remove(remote);
+    let args = [OsStr::new("rm"), OsStr::new("-rf"), remote.as_os_str()];
+    sudo_on_permission_error(env, options.sudo, remote, &args, result)
+}
+
+// Copies whatever is at `remote` into the backup dir inside storage,
+// keeping its full path so it's obvious where it came from
+fn backup(env: &Env, remote: &Path) -> Result<PathBuf> {
+    check_storage_writable(env)?;
+
+    let relative = remote
+        .components()
+        .filter(|it| matches!(it, Component::Normal(_)))
+        .collect::<PathBuf>();
+
+    let dest = env.storage
+        .join(BACKUP_DIR)
+        .join(env.clock.now().to_string())
+        .join(relative);
+
+    if let Some(parent) = dest.parent() {
+        Disk::mkdir_all(parent)?;
+    }
+
+    Disk::copy(remote, &dest, true)?;
+    Ok(dest)
+}
+
+// Whether `entry` is a link we can remove. Links the user pointed
+// somewhere else since are only removed with `force`.
+fn is_our_link(entry: &Entry, force: bool) -> bool {
+    if !Disk::is_symlink(&entry.remote) {
+        return false;
+    }
+
+    if force || links_to(Path::new(&entry.remote), Path::new(&entry.local)) {
+        return true;
+    }
+
+    let target = Disk::readlink(&entry.remote)
+        .map(|it| format!("{}", it.display()))
+        .unwrap_or_default();
+
+    eprintln!(
+        "warning: not unlinking {}, it points to {} instead of storage (use --force to remove it anyway)",
+        entry.remote, target,
+    );
+
+    false
+}
+
+// Whether the file at `remote` is still the one in storage, directories
+// are linked file by file so there's nothing to compare
+fn is_our_hardlink(entry: &Entry) -> bool {
+    !Disk::is_file(&entry.local) || Disk::is_same_file(&entry.local, &entry.remote)
+}
+
+// Whether what's at `remote` is still the same as the copy in storage.
+// if we can't tell, it's treated as changed.
+fn is_our_copy(entry: &Entry) -> bool {
+    dir_contents_equal(Path::new(&entry.local), Path::new(&entry.remote)).unwrap_or(false)
+}
+
+// Whether what's at `remote` is still exactly what was extracted from
+// the archive in storage. if we can't tell, it's treated as changed.
+fn is_our_extraction(entry: &Entry) -> bool {
+    Disk::get_bytes(&entry.local)
+        .and_then(|it| archive::is_unpacked(&it, Path::new(&entry.remote)))
+        .unwrap_or(false)
+}
+
+fn unlink(bundle: &Bundle, merged: &[Entry], force: bool) -> Result<Vec<Entry>> {
+    let mut result = Vec::new();
+    for it in &bundle.entries {
+        // e.g. the directory around it was deleted, there's nothing
+        // left to remove or to put back
+        if !Disk::symlink_exists(&it.remote) {
+            eprintln!("warning: {} is already gone, treating it as unlinked", it.remote);
+            continue;
+        }
+
+        // only remove the things we actually put there, if the user
+        // replaced the link with something else we leave it alone
+        let placed = match it.link_type {
+            LinkType::Symlink | LinkType::Junction => is_our_link(it, force),
+            LinkType::Hardlink if !force && Disk::is_file(&it.remote) && !is_our_hardlink(it) => {
+                eprintln!(
+                    "warning: not unlinking {}, it was replaced since it was linked (use --force to remove it anyway)",
+                    it.remote,
+                );
+
+                false
+            },
+            LinkType::Copy if !force && !Disk::is_symlink(&it.remote) && !is_our_copy(it) => {
+                eprintln!(
+                    "warning: not unlinking {}, it was edited since it was copied (use --force to remove it anyway)",
+                    it.remote,
+                );
+
+                false
+            },
+            LinkType::Archive if !force && Disk::is_dir(&it.remote) && !is_our_extraction(it) => {
+                eprintln!(
+                    "warning: not unlinking {}, it was changed since it was extracted (use --force to remove it anyway)",
+                    it.remote,
+                );
+
+                false
+            },
+            LinkType::Hardlink | LinkType::Copy | LinkType::Archive => {
+                Disk::symlink_exists(&it.remote) && !Disk::is_symlink(&it.remote)
+            },
+            // the directories might have files in them by now,
+            // so they're left alone
+            LinkType::Skeleton => {
+                result.push(it.clone());
+                continue;
+            },
+        };
+
+        if placed {
+            Disk::remove(&it.remote)?;
+            result.push(it.clone());
+            continue;
+        }
+
+        // directories we merged into stay, only the leaves we
+        // linked into them are removed
+        let leaves = merged
+            .iter()
+            .filter(|m| Path::new(&m.remote).starts_with(&it.remote))
+            .collect::<Vec<&Entry>>();
+
+        if leaves.is_empty() {
+            continue;
+        }
+
+        for leaf in leaves {
+            if is_our_link(leaf, force) {
+                Disk::remove(&leaf.remote)?;
+            }
+        }
+
+        result.push(it.clone());
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmd_add_should_work_for_new_bundle() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+
+        let paths = vec![
+            config_dir.join("a"),
+            config_dir.join("b"),
+        ];
+
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+
+        Disk::print();
+
+        assert!(Disk::is_symlink(config_dir.join("a")));
+        assert!(Disk::is_symlink(config_dir.join("b")));
+        assert_eq!(Disk::readlink(config_dir.join("a")).unwrap(), bundle_dir.join("a"));
+
+        assert!(Disk::is_file(bundle_dir.join("bundle.toml")));
+
+        assert!(Disk::is_dir(bundle_dir.join("a")));
+        assert!(Disk::is_dir(bundle_dir.join("a/sub")));
+        assert!(Disk::is_dir(bundle_dir.join("b")));
+
+        assert!(Disk::is_file(bundle_dir.join("a/config")));
+        assert!(Disk::is_file(bundle_dir.join("a/sub/config")));
+        assert!(Disk::is_file(bundle_dir.join("a/.hidden-config")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_work_for_existing_bundle() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+
+        // create bundle
+        let paths = vec![config_dir.join("a")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+        println!("printing disk after first add");
+        Disk::print();
+
+        assert!(Disk::is_symlink(config_dir.join("a")));
+        assert!(Disk::is_dir(bundle_dir.join("a")));
+
+        // add to bundle
+        let paths = vec![config_dir.join("b")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+        println!("printing disk after second add");
+        Disk::print();
+
+        assert!(Disk::is_symlink(config_dir.join("b")));
+        assert!(Disk::is_dir(bundle_dir.join("b")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_only_link_the_new_entries() {
+        let (env, config_dir) = setup();
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default()).unwrap();
+
+        // the user put their own file back where the first entry was
+        Disk::remove(config_dir.join("config")).unwrap();
+        Disk::put(config_dir.join("config"), "mine").unwrap();
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join(".hidden-config")], &Default::default()).unwrap();
+
+        assert!(Disk::is_symlink(config_dir.join(".hidden-config")));
+        assert!(!Disk::is_symlink(config_dir.join("config")));
+        assert_eq!(Disk::get(config_dir.join("config")).unwrap(), "mine");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_trim_dot_prefix() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join(".hidden-config")];
+
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+
+        Disk::print();
+
+        assert!(Disk::is_symlink(config_dir.join(".hidden-config")));
+        assert!(Disk::is_file(env.storage.join("bundle/test_bundle/hidden-config")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_use_name_override() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("config")];
+        let options = AddOptions {
+            name: Some(String::from("custom")),
+            ..Default::default()
+        };
+
+        cmd_add(&env, "test_bundle", &paths, &options).expect("Add should have worked");
+
+        Disk::print();
+
+        let local = env.storage.join("bundle/test_bundle/custom");
+        assert!(Disk::is_file(&local));
+        assert!(!Disk::is_file(env.storage.join("bundle/test_bundle/config")));
+        assert!(Disk::is_symlink(config_dir.join("config")));
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].local, format!("{}", local.display()));
+        assert_eq!(bundle.entries[0].remote, format!("{}", config_dir.join("config").display()));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_report_unchanged_inputs() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+
+        let summary = cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        assert_eq!(summary.added.len(), 2);
+
+        // the inputs are our own links by now
+        let summary = cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        assert!(summary.added.is_empty());
+        assert!(summary.updated.is_empty());
+        assert_eq!(summary.unchanged.len(), 2);
+        assert!(Disk::is_symlink(config_dir.join("a")));
+
+        // a real file with the same contents doesn't need --force
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        Disk::put(config_dir.join("config"), "hello config").unwrap();
+
+        let paths = vec![config_dir.join("config")];
+        let summary = cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        assert_eq!(summary.unchanged, vec![format!("{}", config_dir.join("config").display())]);
+        assert!(Disk::is_symlink(config_dir.join("config")));
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().entries.len(), 2);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_report_updated_inputs() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        Disk::mkdir_all(config_dir.join("a/sub")).unwrap();
+        Disk::put(config_dir.join("a/config"), "hello config").unwrap();
+        Disk::put(config_dir.join("a/.hidden-config"), "hello config").unwrap();
+        Disk::put(config_dir.join("a/sub/config"), "changed").unwrap();
+        Disk::put(config_dir.join("config"), "hello config").unwrap();
+
+        let result = cmd_add(&env, "test_bundle", &paths, &Default::default());
+        assert!(matches!(result, Err(Error::StorageEntryExists(_))));
+
+        let options = AddOptions { force: true, ..Default::default() };
+        let summary = cmd_add(&env, "test_bundle", &paths, &options).unwrap();
+        assert_eq!(summary.updated, vec![format!("{}", config_dir.join("a").display())]);
+        assert_eq!(summary.unchanged, vec![format!("{}", config_dir.join("config").display())]);
+        assert_eq!(Disk::get(bundle_dir.join("a/sub/config")).unwrap(), "changed");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_write_json_summary_to_file() {
+        let (mut env, config_dir) = setup();
+        let out = PathBuf::from("/logs/provision/result.json");
+        env.output = Output { format: OutputFormat::Json, path: Some(out.clone()), summary: false };
+
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+
+        let written = Disk::get(&out).expect("Summary should have been written");
+        let summary = serde_json::from_str::<AddSummary>(&written).expect("Should be valid json");
+
+        assert_eq!(summary.added, vec![
+            format!("{}", config_dir.join("a").display()),
+            format!("{}", config_dir.join("config").display()),
+        ]);
+        assert!(summary.updated.is_empty());
+        assert!(summary.unchanged.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_write_entries_in_stable_order() {
+        let serialized = |paths: &[&str]| {
+            let (env, config_dir) = setup();
+            let paths = paths.iter().map(|it| config_dir.join(it)).collect();
+            cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+            // the creation time is the only thing allowed to differ
+            let bundle = Disk::get(env.storage.join("bundle/test_bundle").join(BUNDLE_FILE)).unwrap();
+            let bundle = bundle
+                .lines()
+                .filter(|it| !it.starts_with("created"))
+                .collect::<Vec<&str>>()
+                .join("\n");
+
+            let lock = Disk::get(env.storage.join(LOCK_FILE)).unwrap();
+            clean();
+
+            (bundle, lock)
+        };
+
+        let forward = serialized(&["a", "b", "config"]);
+        let backward = serialized(&["config", "b", "a"]);
+        assert_eq!(forward, backward);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_trim_dots_by_default() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        let remote = config_dir.join(".hidden-config");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).unwrap();
+
+        assert!(Disk::is_file(bundle_dir.join("hidden-config")));
+        assert_eq!(Disk::readlink(&remote).unwrap(), bundle_dir.join("hidden-config"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_keep_dots_if_asked() {
+        let (mut env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        Disk::put(config_dir.join(".config"), "hidden").unwrap();
+
+        // these would collide if the dot was trimmed
+        let paths = vec![config_dir.join("config"), config_dir.join(".config")];
+        let options = AddOptions { keep_dots: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).unwrap();
+
+        assert_eq!(Disk::readlink(config_dir.join("config")).unwrap(), bundle_dir.join("config"));
+        assert_eq!(Disk::readlink(config_dir.join(".config")).unwrap(), bundle_dir.join(".config"));
+        assert_eq!(Disk::get(bundle_dir.join(".config")).unwrap(), "hidden");
+
+        // the config can turn trimming off for good
+        env.config.trim_dots = false;
+        let remote = config_dir.join(".hidden-config");
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        assert_eq!(Disk::readlink(&remote).unwrap(), bundle_dir.join(".hidden-config"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_adopt_symlink_targets() {
+        let (env, config_dir) = setup();
+        let target = PathBuf::from("/dotfiles/vimrc");
+        let link = config_dir.join("vimrc");
+
+        Disk::mkdir_all("/dotfiles").unwrap();
+        Disk::put(&target, "set number").unwrap();
+        Disk::symlink(&target, &link).unwrap();
+
+        // symlinks are skipped by default
+        cmd_add(&env, "vim", &vec![link.clone()], &Default::default()).expect("Add should have worked");
+        assert_eq!(Disk::readlink(&link).unwrap(), target);
+        assert!(read_bundle(&env, "vim").unwrap().entries.is_empty());
+
+        let options = AddOptions { follow_symlinks: true, ..Default::default() };
+        cmd_add(&env, "vim", &vec![link.clone()], &options).expect("Add should have worked");
+
+        let local = env.storage.join("bundle/vim/vimrc");
+        assert_eq!(Disk::get(&local).unwrap(), "set number");
+        assert_eq!(Disk::readlink(&link).unwrap(), local);
+        assert!(Disk::is_file(&target));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_follow_symlink_chains() {
+        let (env, config_dir) = setup();
+        let first = config_dir.join("first");
+        let second = config_dir.join("second");
+
+        Disk::symlink("second", &first).unwrap();
+        Disk::symlink(config_dir.join("b"), &second).unwrap();
+
+        let options = AddOptions { follow_symlinks: true, ..Default::default() };
+        cmd_add(&env, "chain", &vec![first.clone()], &options).expect("Add should have worked");
+
+        assert!(Disk::is_file(env.storage.join("bundle/chain/first/config")));
+        assert!(Disk::is_symlink(&first));
+
+        // loops can't be resolved at all
+        Disk::symlink("./loop-b", config_dir.join("loop-a")).unwrap();
+        Disk::symlink("loop-a", config_dir.join("loop-b")).unwrap();
+
+        let result = cmd_add(&env, "chain", &vec![config_dir.join("loop-a")], &options);
+        assert!(matches!(result, Err(Error::SymlinkLoop(_))));
+        assert!(Disk::is_symlink(config_dir.join("loop-a")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_reject_invalid_name_override() {
+        let (env, config_dir) = setup();
+        let options = AddOptions {
+            name: Some(String::from("nested/custom")),
+            ..Default::default()
+        };
+
+        let paths = vec![config_dir.join("config")];
+        let result = cmd_add(&env, "test_bundle", &paths, &options);
+        assert!(matches!(result, Err(Error::InvalidName(_))));
+
+        let paths = vec![config_dir.join("a"), config_dir.join("b")];
+        let options = AddOptions {
+            name: Some(String::from("custom")),
+            ..Default::default()
+        };
+
+        assert!(cmd_add(&env, "test_bundle", &paths, &options).is_err());
+        assert!(Disk::is_dir(config_dir.join("a")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_catch_names_that_only_differ_by_case() {
+        let (mut env, config_dir) = setup();
+        env.config.ignore_case = true;
+        Disk::put(config_dir.join("Config"), "upper").unwrap();
+
+        let inputs = vec![config_dir.join("Config"), config_dir.join("config")];
+        match cmd_add(&env, "test_bundle", &inputs, &Default::default()) {
+            Err(Error::EntryCollision(a, b)) => {
+                assert_eq!(a, env.storage.join("bundle/test_bundle/Config"));
+                assert_eq!(b, env.storage.join("bundle/test_bundle/config"));
+            },
+            other => panic!("Expected a collision, got {:?}", other),
+        };
+
+        assert!(!Disk::is_dir(env.storage.join("bundle/test_bundle")));
+
+        // the same goes for names that are already in the bundle
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default()).unwrap();
+        let options = AddOptions { force: true, ..Default::default() };
+        let result = cmd_add(&env, "test_bundle", &vec![config_dir.join("Config")], &options);
+        assert!(matches!(result, Err(Error::EntryCollision(_, _))));
+        assert!(Disk::is_file(config_dir.join("Config")));
+
+        // a case-sensitive filesystem can keep both
+        env.config.ignore_case = false;
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("Config")], &Default::default()).unwrap();
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().entries.len(), 2);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_keep_links_inside_of_directories() {
+        let (env, config_dir) = setup();
+        Disk::mkdir_all(config_dir.join("app/release-3")).unwrap();
+        Disk::put(config_dir.join("app/release-3/config"), "release").unwrap();
+        Disk::symlink("release-3", config_dir.join("app/current")).unwrap();
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("app")], &Default::default()).unwrap();
+
+        let stored = env.storage.join("bundle/test_bundle/app");
+        assert!(Disk::is_symlink(stored.join("current")));
+        assert_eq!(Disk::readlink(stored.join("current")).unwrap(), PathBuf::from("release-3"));
+        assert_eq!(Disk::get(stored.join("release-3/config")).unwrap(), "release");
+        assert!(links_to(&config_dir.join("app"), &stored));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_place_new_entries_like_the_rest_of_the_bundle() {
+        let (env, config_dir) = setup();
+        let options = AddOptions { link_type: Some(LinkType::Copy), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).unwrap();
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join(".hidden-config")], &Default::default()).unwrap();
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert!(bundle.entries.iter().all(|it| it.link_type == LinkType::Copy));
+        assert!(Disk::is_file(config_dir.join(".hidden-config")));
+        assert!(!Disk::is_symlink(config_dir.join(".hidden-config")));
+
+        // an explicit link type still wins
+        let options = AddOptions { link_type: Some(LinkType::Symlink), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("b/config")], &options).unwrap();
+        assert!(Disk::is_symlink(config_dir.join("b/config")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_link_new_entries_for_the_host_they_inherit() {
+        let (mut env, config_dir) = setup();
+        env.host = String::from("laptop");
+
+        let options = AddOptions { host: Some(String::from("work")), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).unwrap();
+        cmd_add(&env, "test_bundle", &vec![config_dir.join(".hidden-config")], &Default::default()).unwrap();
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert!(bundle.entries.iter().all(|it| it.host == Some(String::from("work"))));
+        assert!(Disk::is_symlink(config_dir.join(".hidden-config")));
+
+        let lockfile = get_lockfile(&env).unwrap();
+        assert_eq!(lockfile.linked[0].host, Some(String::from("work")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_only_add_new_inputs_with_only_new() {
+        let (env, config_dir) = setup();
+        let bundle_file = env.storage.join("bundle/test_bundle").join(BUNDLE_FILE);
+        let options = AddOptions { only_new: true, ..Default::default() };
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).unwrap();
+
+        let inputs = vec![config_dir.join("config"), config_dir.join("b")];
+        let summary = cmd_add(&env, "test_bundle", &inputs, &options).unwrap();
+        assert_eq!(summary.added, vec![format!("{}", config_dir.join("b").display())]);
+        assert!(summary.unchanged.is_empty());
+
+        let bundle = Disk::get(&bundle_file).unwrap();
+        let lock = Disk::get(env.storage.join(LOCK_FILE)).unwrap();
+
+        let summary = cmd_add(&env, "test_bundle", &inputs, &options).unwrap();
+        assert!(summary.added.is_empty() && summary.updated.is_empty() && summary.unchanged.is_empty());
+        assert_eq!(Disk::get(&bundle_file).unwrap(), bundle);
+        assert_eq!(Disk::get(env.storage.join(LOCK_FILE)).unwrap(), lock);
+        assert!(links_to(&config_dir.join("config"), &env.storage.join("bundle/test_bundle/config")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_refuse_to_clobber_storage() {
+        let (env, config_dir) = setup();
+        let local = env.storage.join("bundle/test_bundle/config");
+
+        Disk::put(config_dir.join("a/config"), "first").unwrap();
+        Disk::put(config_dir.join("b/config"), "second").unwrap();
+
+        let paths = vec![config_dir.join("a/config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+        // both files end up with the same stored name
+        let paths = vec![config_dir.join("b/config")];
+        let result = cmd_add(&env, "test_bundle", &paths, &Default::default());
+
+        Disk::print();
+
+        assert!(matches!(result, Err(Error::StorageEntryExists(_))));
+        assert_eq!(Disk::get(&local).unwrap(), "first");
+        assert!(Disk::is_file(config_dir.join("b/config")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_overwrite_storage_with_force() {
+        let (env, config_dir) = setup();
+        let local = env.storage.join("bundle/test_bundle/config");
+
+        Disk::put(config_dir.join("a/config"), "first").unwrap();
+        Disk::put(config_dir.join("b/config"), "second").unwrap();
+
+        let paths = vec![config_dir.join("a/config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+        let paths = vec![config_dir.join("b/config")];
+        let options = AddOptions { force: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).expect("Add should have worked");
+
+        Disk::print();
+
+        assert_eq!(Disk::get(&local).unwrap(), "second");
+        assert!(Disk::is_symlink(config_dir.join("b/config")));
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries.len(), 1);
+        assert_eq!(bundle.entries[0].remote, format!("{}", config_dir.join("b/config").display()));
+
+        clean();
+    }
+
+    #[test]
+    fn links_to_should_follow_links_on_either_side() {
+        let (_, config_dir) = setup();
+        Disk::put("/var/home/dotgirl/config", "stored").unwrap();
+        Disk::symlink("/var/home", "/home").unwrap();
+
+        Disk::symlink("/home/dotgirl/config", config_dir.join("absolute")).unwrap();
+        Disk::symlink("../home/dotgirl/config", config_dir.join("relative")).unwrap();
+        Disk::symlink("/home/dotgirl/other", config_dir.join("other")).unwrap();
+
+        let local = Path::new("/var/home/dotgirl/config");
+        assert!(links_to(&config_dir.join("absolute"), local));
+        assert!(links_to(&config_dir.join("relative"), local));
+        assert!(links_to(&config_dir.join("absolute"), Path::new("/home/dotgirl/config")));
+        assert!(!links_to(&config_dir.join("other"), local));
+
+        clean();
+    }
+
+    #[test]
+    fn place_link_should_copy_on_permission_error() {
+        let (_, config_dir) = setup();
+        let local = config_dir.join("config");
+        let remote = config_dir.join("copied");
+
+        let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        let used = copy_on_permission_error(&local, &remote, LinkType::Symlink, Err(denied.into()))
+            .expect("Fallback should have worked");
+
+        assert_eq!(used, LinkType::Copy);
+        assert!(Disk::is_file(&remote));
+        assert!(!Disk::is_symlink(&remote));
+
+        // other errors should not be swallowed
+        let other = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let result = copy_on_permission_error(&local, &remote, LinkType::Symlink, Err(other.into()));
+        assert!(result.is_err());
+
+        clean();
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn sudo_on_permission_error_should_report_path() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("etc");
+        let args = [OsStr::new("rm"), remote.as_os_str()];
+
+        let denied = || Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied).into());
+        match sudo_on_permission_error(&env, false, &remote, &args, denied()) {
+            Err(Error::PermissionDenied(path)) => assert_eq!(path, remote),
+            other => panic!("Expected a permission error, got {:?}", other),
+        };
+
+        assert!(Shell::calls().is_empty());
+
+        // with --sudo we don't ask and retry right away
+        sudo_on_permission_error(&env, true, &remote, &args, denied())
+            .expect("Retry should have worked");
+
+        let expected = format!("sudo rm {}", remote.display());
+        assert_eq!(Shell::calls(), vec![(expected, PathBuf::new())]);
+
+        clean();
+    }
+
+    #[test]
+    fn link_should_refuse_entries_linked_onto_storage() {
+        let (env, config_dir) = setup();
+        seed_bundles(&env);
+
+        let mut bundle = read_bundle(&env, "alpha").unwrap();
+        let local = bundle.entries[0].local.clone();
+        bundle.entries.push(Entry { remote: local.clone(), ..bundle.entries[0].clone() });
+        write_bundle(&env, &bundle).unwrap();
+
+        match cmd_link(&env, "alpha", &Default::default()) {
+            Err(Error::SelfReferentialEntry(remote)) => assert_eq!(remote, local),
+            other => panic!("Expected the entry to be refused, got {:?}", other),
+        };
+
+        // not even the fine entry was linked
+        assert!(!Disk::symlink_exists("/config/alpha"));
+        assert!(Disk::is_file(&local) && !Disk::is_symlink(&local));
+        assert!(Disk::is_file(config_dir.join("config")));
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_unlink_should_treat_missing_remotes_as_unlinked() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("a/sub/config"), config_dir.join("b/config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+        Disk::remove(config_dir.join("a")).unwrap();
+
+        let options = UnlinkOptions { restore: true, ..Default::default() };
+        cmd_unlink(&env, "test_bundle", &options).expect("Unlink should have worked");
+
+        assert!(!Disk::symlink_exists(config_dir.join("a/sub/config")));
+        assert!(Disk::is_file(config_dir.join("b/config")) && !Disk::is_symlink(config_dir.join("b/config")));
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_and_cmd_link_should_use_the_configured_bundle_dir() {
+        let (mut env, config_dir) = setup();
+        env.config.bundle_dir = String::from("bundles");
+        let paths = vec![config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        let local = env.storage.join("bundles/test_bundle/config");
+        assert_eq!(env.bundle_dir().join("test_bundle/config"), local);
+        assert_eq!(Disk::readlink(config_dir.join("config")).unwrap(), local);
+        assert!(!Disk::exists(env.storage.join("bundle")));
+
+        clean();
+    }
+
+    #[test]
+    fn link_should_refuse_locals_outside_storage() {
+        let (env, config_dir) = setup();
+        seed_bundles(&env);
+
+        let mut bundle = read_bundle(&env, "alpha").unwrap();
+        bundle.entries[0].local = format!("{}/bundle/alpha/../../../config/config", env.storage.display());
+        write_bundle(&env, &bundle).unwrap();
+
+        match cmd_link(&env, "alpha", &Default::default()) {
+            Err(Error::LocalOutsideStorage(local)) => assert_eq!(local, bundle.entries[0].local),
+            other => panic!("Expected the local to be refused, got {:?}", other),
+        };
+
+        assert!(!Disk::symlink_exists("/config/alpha"));
+        assert!(Disk::is_file(config_dir.join("config")));
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn link_should_refuse_locals_behind_links_out_of_storage() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        Disk::put("/outside/config", "not ours").unwrap();
+        Disk::symlink("/outside", env.bundle_dir().join("alpha/escape")).unwrap();
+
+        let mut bundle = read_bundle(&env, "alpha").unwrap();
+        bundle.entries[0].local = format!("{}", env.bundle_dir().join("alpha/escape/config").display());
+        write_bundle(&env, &bundle).unwrap();
+
+        match cmd_link(&env, "alpha", &Default::default()) {
+            Err(Error::LocalOutsideStorage(local)) => assert_eq!(local, bundle.entries[0].local),
+            other => panic!("Expected the local to be refused, got {:?}", other),
+        };
+
+        assert!(!Disk::symlink_exists("/config/alpha"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_record_link_type() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("config");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        let options = LinkOptions {
+            link_type: Some(LinkType::Hardlink),
+            ..Default::default()
+        };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+
+        assert!(Disk::is_file(&remote));
+        assert!(!Disk::is_symlink(&remote));
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].link_type, LinkType::Hardlink);
+
+        // unlinking should remove the hardlink, since we know we put it there
+        cmd_unlink(&env, "test_bundle", &Default::default()).expect("Unlink should have worked");
+        assert!(!Disk::is_file(&remote));
+
+        clean();
+    }
+
+    #[test]
+    fn check_status_should_notice_replaced_hardlinks() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("config");
+
+        let options = AddOptions { link_type: Some(LinkType::Hardlink), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).unwrap();
+        assert!(check_status(&env).unwrap().ok);
+
+        // saved by an editor that writes a new file in its place
+        Disk::remove(&remote).unwrap();
+        Disk::put(&remote, "changed config").unwrap();
+
+        let health = check_status(&env).unwrap();
+        assert_eq!(health.problems.len(), 1);
+        assert_eq!(health.problems[0].kind, ProblemKind::Foreign);
+
+        // the changes aren't thrown away
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        assert_eq!(Disk::get(&remote).unwrap(), "changed config");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_skip_existing_with_skip_policy() {
+        let (mut env, config_dir) = setup();
+        let remote = config_dir.join("config");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        Disk::put(&remote, "mine").unwrap();
+
+        env.config.conflict = ConflictPolicy::Skip;
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        assert!(!Disk::is_symlink(&remote));
+        assert_eq!(Disk::get(&remote).unwrap(), "mine");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_report_skip_reasons() {
+        let (mut env, _) = setup();
+        seed_bundles(&env);
+
+        let skip_reasons = |summary: LinkSummary| summary.skipped
+            .iter()
+            .map(|it| (it.path.clone(), it.reason))
+            .collect::<Vec<(String, SkipReason)>>();
+
+        // nothing to link for alpha
+        Disk::remove(env.storage.join("bundle/alpha/config")).unwrap();
+        let summary = cmd_link(&env, "alpha", &Default::default()).unwrap();
+        assert!(summary.linked.is_empty());
+        assert_eq!(skip_reasons(summary), vec![
+            (String::from("/config/alpha"), SkipReason::MissingSource),
+        ]);
+
+        let summary = cmd_link(&env, "beta", &Default::default()).unwrap();
+        assert_eq!(summary.linked, vec![String::from("/config/beta")]);
+        let summary = cmd_link(&env, "beta", &Default::default()).unwrap();
+        assert_eq!(skip_reasons(summary), vec![
+            (String::from("/config/beta"), SkipReason::AlreadyLinked),
+        ]);
+
+        Disk::put("/config/gamma", "mine").unwrap();
+        env.config.conflict = ConflictPolicy::Skip;
+        let summary = cmd_link(&env, "gamma", &Default::default()).unwrap();
+        assert_eq!(skip_reasons(summary), vec![
+            (String::from("/config/gamma"), SkipReason::Conflict),
+        ]);
+
+        env.resolver = Rc::new(ScriptedResolver {
+            answers: std::cell::RefCell::new(vec![Resolution::Skip]),
+            ..Default::default()
+        });
+        env.config.conflict = ConflictPolicy::Prompt;
+        let summary = cmd_link(&env, "gamma", &Default::default()).unwrap();
+        assert_eq!(skip_reasons(summary), vec![
+            (String::from("/config/gamma"), SkipReason::UserSkip),
+        ]);
+        assert_eq!(Disk::get("/config/gamma").unwrap(), "mine");
+
+        clean();
+    }
+
+    // answers conflicts in order and remembers what it was asked about
+    #[derive(Debug, Default)]
+    struct ScriptedResolver {
+        answers: std::cell::RefCell<Vec<Resolution>>,
+        asked: std::cell::RefCell<Vec<String>>,
+    }
+
+    impl ConflictResolver for ScriptedResolver {
+        fn resolve_existing(&self, remote: &str, _: bool) -> Resolution {
+            self.asked.borrow_mut().push(String::from(remote));
+            self.answers.borrow_mut().remove(0)
+        }
+
+        fn resolve_parent_is_file(&self, remote: &str, _: &Path) -> bool {
+            self.asked.borrow_mut().push(String::from(remote));
+            true
+        }
+
+        fn allow_sudo(&self, _: &Path) -> bool {
+            false
+        }
+
+        fn confirm_plan(&self, plan: &str) -> bool {
+            self.asked.borrow_mut().push(String::from(plan));
+            false
+        }
+    }
+
+    #[test]
+    fn link_should_ask_the_resolver_about_conflicts() {
+        let (mut env, _) = setup();
+        seed_bundles(&env);
+
+        let resolver = Rc::new(ScriptedResolver {
+            answers: std::cell::RefCell::new(vec![Resolution::Skip, Resolution::Overwrite]),
+            ..Default::default()
+        });
+
+        env.resolver = resolver.clone();
+        env.interactive = true;
+        env.config.conflict = ConflictPolicy::Prompt;
+
+        Disk::put("/config/alpha", "mine").unwrap();
+        Disk::put("/config/beta", "mine").unwrap();
+
+        let summary = cmd_link(&env, "alpha", &Default::default()).unwrap();
+        assert_eq!(summary.skipped[0].reason, SkipReason::UserSkip);
+        assert_eq!(Disk::get("/config/alpha").unwrap(), "mine");
+
+        cmd_link(&env, "beta", &Default::default()).unwrap();
+        assert!(Disk::is_symlink("/config/beta"));
+
+        // a file where a directory should be is replaced if it says so
+        let mut bundle = read_bundle(&env, "gamma").unwrap();
+        bundle.entries[0].remote = String::from("/config/gamma/config");
+        write_bundle(&env, &bundle).unwrap();
+        Disk::put("/config/gamma", "in the way").unwrap();
+
+        cmd_link(&env, "gamma", &Default::default()).unwrap();
+        assert!(Disk::is_symlink("/config/gamma/config"));
+
+        assert_eq!(*resolver.asked.borrow(), vec![
+            String::from("/config/alpha"),
+            String::from("/config/beta"),
+            String::from("/config/gamma/config"),
+        ]);
+
+        clean();
+    }
+
+    #[test]
+    fn link_summary_should_render_skip_reasons() {
+        let summary = LinkSummary {
+            linked: vec![String::from("/config/alpha")],
+            skipped: vec![Skipped {
+                bundle: String::from("beta"),
+                path: String::from("/config/beta"),
+                reason: SkipReason::UserSkip,
+            }],
+            actions: vec![],
+        };
+
+        let json = summary.render(OutputFormat::Json, false, false).unwrap();
+        assert!(json.contains("\"reason\": \"user_skip\""));
+
+        let text = summary.render(OutputFormat::Text, false, false).unwrap();
+        assert_eq!(text, "linked 1 entries\nskipped /config/beta: skipped when asked");
+
+        let quiet = summary.render(OutputFormat::Text, true, false).unwrap();
+        assert_eq!(quiet, "linked 1 entries");
+    }
+
+    #[test]
+    fn cmd_link_should_report_what_happened_to_each_entry() {
+        let (mut env, config_dir) = setup();
+        let remote = |name: &str| config_dir.join(name);
+        let paths = vec![remote("a"), remote("b"), remote("config")];
+
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        Disk::put(remote("config"), "in the way").unwrap();
+        Disk::mkdir_all(remote("b")).unwrap();
+        env.config.conflict = ConflictPolicy::Overwrite;
+        env.config.backup = true;
+
+        let options = LinkOptions { verbose: true, ..Default::default() };
+        let summary = cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+
+        let actions = summary.actions
+            .iter()
+            .map(|it| (it.path.clone(), it.action))
+            .collect::<Vec<(String, LinkAction)>>();
+
+        assert_eq!(actions, vec![
+            (format!("{}", remote("a").display()), LinkAction::Linked),
+            (format!("{}", remote("b").display()), LinkAction::BackedUp),
+            (format!("{}", remote("config").display()), LinkAction::BackedUp),
+        ]);
+
+        // linking again finds everything in place
+        env.config.backup = false;
+        let summary = cmd_link(&env, "test_bundle", &options).unwrap();
+        assert!(summary.actions.iter().all(|it| it.action == LinkAction::Skipped(SkipReason::AlreadyLinked)));
+
+        let text = summary.render(OutputFormat::Text, false, true).unwrap();
+        assert_eq!(text.lines().nth(1).unwrap(), format!("{}: skipped, already linked", remote("a").display()));
+
+        clean();
+    }
+
+    #[test]
+    fn preview_link_should_diff_copied_files() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let mut bundle = read_bundle(&env, "alpha").unwrap();
+        bundle.entries[0].link_type = LinkType::Copy;
+        Disk::put("/config/alpha", "a shorter config file").unwrap();
+
+        let options = LinkOptions { dry_run: true, ..Default::default() };
+        let preview = preview_link(&env, &bundle, &options).unwrap();
+        assert_eq!(preview, format!(
+            "replace  /config/alpha\n--- /config/alpha\n+++ {}\n@@ -1,1 +1,1 @@\n-a shorter config file\n+a much longer config file",
+            bundle.entries[0].local,
+        ));
+
+        // nothing was touched
+        cmd_link(&env, "alpha", &options).unwrap();
+        assert_eq!(Disk::get("/config/alpha").unwrap(), "a shorter config file");
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_all_should_preview_the_bundles_that_can_be_read() {
+        let (mut env, _) = setup();
+        let out = env.storage.join("preview.out");
+        env.output.path = Some(out.clone());
+        seed_bundles(&env);
+
+        Disk::put(env.bundle_dir().join("beta").join(BUNDLE_FILE), "not a bundle").unwrap();
+
+        let options = LinkOptions { dry_run: true, ..Default::default() };
+        match cmd_link_all(&env, &options) {
+            Err(Error::BundlesFailed(failed)) => assert_eq!(failed, vec!["beta"]),
+            other => panic!("Expected beta to fail, got {:?}", other),
+        };
+
+        assert_eq!(Disk::get(&out).unwrap(), "create   /config/alpha\ncreate   /config/gamma");
+        assert!(!Disk::symlink_exists("/config/alpha"));
+
+        clean();
+    }
+
+    #[test]
+    fn preview_link_should_describe_new_files() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let bundle = read_bundle(&env, "beta").unwrap();
+        let options = LinkOptions { dry_run: true, ..Default::default() };
+        assert_eq!(preview_link(&env, &bundle, &options).unwrap(), "create   /config/beta");
+
+        cmd_link(&env, "beta", &options).unwrap();
+        assert!(!Disk::symlink_exists("/config/beta"));
+
+        clean();
+    }
+
+    #[test]
+    fn preview_link_should_label_what_happens_to_each_entry() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let mut bundle = read_bundle(&env, "alpha").unwrap();
+        let local = |id: &str| env.bundle_dir().join(id).join("config");
+        for id in &["beta", "gamma", "delta", "epsilon"] {
+            bundle.entries.push(Entry {
+                local: format!("{}", local(if *id == "beta" || *id == "gamma" { id } else { "alpha" }).display()),
+                remote: format!("/config/{}", id),
+                ..bundle.entries[0].clone()
+            });
+        }
+
+        // alpha is already linked, beta is in the way, gamma points
+        // elsewhere in storage, delta doesn't exist yet and epsilon is
+        // a link of the user's
+        Disk::symlink(local("alpha"), "/config/alpha").unwrap();
+        Disk::put("/config/beta", "mine").unwrap();
+        Disk::symlink(local("beta"), "/config/gamma").unwrap();
+        Disk::symlink("/somewhere/else", "/config/epsilon").unwrap();
+
+        let options = LinkOptions { dry_run: true, ..Default::default() };
+        let before = Disk::walk("/config").unwrap();
+
+        let relinked = format!("relink   /config/gamma (points to {})", local("beta").display());
+        assert_eq!(preview_link(&env, &bundle, &options).unwrap().lines().collect::<Vec<&str>>(), vec![
+            "ok       /config/alpha",
+            "replace  /config/beta",
+            relinked.as_str(),
+            "create   /config/delta",
+            "replace  /config/epsilon",
+        ]);
+
+        write_bundle(&env, &bundle).unwrap();
+        cmd_link(&env, "alpha", &options).unwrap();
+        assert_eq!(Disk::walk("/config").unwrap(), before);
+        assert_eq!(Disk::get("/config/beta").unwrap(), "mine");
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_count_what_happened_for_the_summary() {
+        let (mut env, config_dir) = setup();
+        let remote = |name: &str| config_dir.join(name);
+        let paths = vec![remote("a"), remote("b"), remote("config")];
+
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        let mut bundle = read_bundle(&env, "test_bundle").unwrap();
+        let config = format!("{}", remote("config").display());
+        bundle.entries.iter_mut().filter(|it| it.remote == config).for_each(|it| it.on_conflict = Some(ConflictPolicy::Skip));
+        write_bundle(&env, &bundle).unwrap();
+
+        Disk::put(remote("config"), "in the way").unwrap();
+        Disk::mkdir_all(remote("b")).unwrap();
+        env.config.conflict = ConflictPolicy::Overwrite;
+        env.config.backup = true;
+
+        let summary = cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+        let report = OperationReport::from(&summary);
+
+        assert_eq!(report, OperationReport { linked: 2, skipped: 1, backed_up: 1, ..Default::default() });
+        assert_eq!(report.render(OutputFormat::Text).unwrap(), "summary: 2 linked, 1 skipped, 1 backed up");
+        let json = report.render(OutputFormat::Json).unwrap();
+        assert_eq!(serde_json::from_str::<OperationReport>(&json).unwrap(), report);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_use_conflict_policy_of_entry() {
+        let (env, _) = setup();
+        let dir = env.storage.join("bundle/git");
+
+        let entry = |name: &str, on_conflict: ConflictPolicy| {
+            Disk::put(dir.join(name), "default").unwrap();
+            Disk::put(format!("/config/{}", name), "mine").unwrap();
+
+            Entry {
+                local: format!("{}", dir.join(name).display()),
+                remote: format!("/config/{}", name),
+                link_type: LinkType::Symlink,
+                host: None,
+                on_conflict: Some(on_conflict),
+                origin: None,
+                note: None,
+                per_file: false,
+                xattrs: Default::default(),
+            }
+        };
+
+        Disk::mkdir_all(&dir).unwrap();
+        Disk::mkdir_all("/config").unwrap();
+        let bundle = Bundle {
+            id: String::from("git"),
+            created: None,
+            enabled: true,
+            base: None,
+            hosts: vec![],
+            dependencies: vec![],
+            hooks: Default::default(),
+            entries: vec![
+                entry("gitconfig", ConflictPolicy::Skip),
+                entry("gitignore", ConflictPolicy::Overwrite),
+            ],
+        };
+        write_bundle(&env, &bundle).unwrap();
+
+        let raw = Disk::get(dir.join(BUNDLE_FILE)).unwrap();
+        assert!(raw.contains("on_conflict = \"skip\""));
+        assert!(raw.contains("on_conflict = \"overwrite\""));
+
+        // the global policy would prompt for both
+        let summary = cmd_link(&env, "git", &Default::default()).unwrap();
+        assert_eq!(summary.linked, vec![String::from("/config/gitignore")]);
+
+        assert!(!Disk::is_symlink("/config/gitconfig"));
+        assert_eq!(Disk::get("/config/gitconfig").unwrap(), "mine");
+        assert!(Disk::is_symlink("/config/gitignore"));
+        assert_eq!(Disk::readlink("/config/gitignore").unwrap(), dir.join("gitignore"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_recover_missing_bundle_file() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        let paths = vec![config_dir.join("a"), config_dir.join("b")];
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).unwrap();
+
+        let mut before = read_bundle(&env, "test_bundle").unwrap();
+        before.base = Some(String::from("$HOME/config"));
+        before.hosts = vec![env.host.clone()];
+        before.hooks.after_link = Some(String::from("true"));
+        write_bundle(&env, &before).unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).unwrap();
+
+        Disk::remove(bundle_dir.join(BUNDLE_FILE)).unwrap();
+        for it in &paths {
+            Disk::remove(it).unwrap();
+        }
+
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have recovered");
+
+        let after = read_bundle(&env, "test_bundle").expect("Bundle file should be back");
+        assert_eq!(after.entries.len(), before.entries.len());
+        assert!(after.created.is_some());
+        assert_eq!(after.created, before.created);
+        assert_eq!(after.base, before.base);
+        assert_eq!(after.hosts, before.hosts);
+        assert_eq!(after.hooks.after_link, before.hooks.after_link);
+        for it in &paths {
+            assert!(Disk::is_symlink(it));
+        }
+
+        // without a lock entry there's nothing to recover from
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        Disk::remove(bundle_dir.join(BUNDLE_FILE)).unwrap();
+        match cmd_link(&env, "test_bundle", &Default::default()) {
+            Err(Error::BundleMissingMeta) => {},
+            other => panic!("Expected a missing bundle file, got {:?}", other),
+        };
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_not_touch_lock_without_lock_update() {
+        let (mut env, config_dir) = setup();
+        let remote = config_dir.join("config");
+        let lock_path = env.storage.join(LOCK_FILE);
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        let before = Disk::get(&lock_path).unwrap();
+
+        env.update_lock = false;
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        assert!(Disk::is_symlink(&remote));
+        assert_eq!(Disk::get(&lock_path).unwrap(), before);
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_record_origin() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("config");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].origin, Some(format!("{}", remote.display())));
+
+        let raw = Disk::get(env.storage.join("bundle/test_bundle").join(BUNDLE_FILE)).unwrap();
+        assert!(raw.contains(&format!("origin = \"{}\"", remote.display())));
+
+        // linking goes by remote, wherever the entry came from
+        let mut moved = bundle.clone();
+        moved.entries[0].origin = Some(String::from("/somewhere/else"));
+        write_bundle(&env, &moved).unwrap();
+
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        assert!(Disk::is_symlink(&remote));
+        assert!(!Disk::symlink_exists("/somewhere/else"));
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].origin, Some(String::from("/somewhere/else")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_gc_should_prune_old_backups() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link(&env, "alpha", &Default::default()).unwrap();
+
+        let day = 24 * 60 * 60;
+        let now = env.clock.now();
+        let backup_dir = env.storage.join(BACKUP_DIR);
+
+        let backup = |age: u64, content: &str| {
+            let dir = backup_dir.join((now - age).to_string());
+            Disk::mkdir_all(dir.join("config")).unwrap();
+            Disk::put(dir.join("config/file"), content).unwrap();
+            dir
+        };
+
+        let ancient = backup(10 * day, "ten days");
+        let old = backup(5 * day, "five");
+        let recent = backup(60 * 60, "an hour");
+
+        // not a backup we made
+        Disk::mkdir_all(backup_dir.join("notes")).unwrap();
+        let lock = Disk::get(env.storage.join(LOCK_FILE)).unwrap();
+
+        let options = GcOptions { older_than: Some(7 * day), keep: None };
+        let summary = cmd_gc(&env, &options).expect("Gc should have worked");
+        assert_eq!(summary.removed, vec![format!("{}", ancient.display())]);
+        assert_eq!(summary.reclaimed, 8);
+
+        assert!(!Disk::symlink_exists(&ancient));
+        assert!(Disk::is_dir(&old));
+
+        // the newest backup is kept, even though it's old enough
+        let options = GcOptions { older_than: Some(0), keep: Some(1) };
+        let summary = cmd_gc(&env, &options).expect("Gc should have worked");
+        assert_eq!(summary.removed, vec![format!("{}", old.display())]);
+        assert_eq!(summary.reclaimed, 4);
+
+        assert!(Disk::is_file(recent.join("config/file")));
+        assert!(Disk::is_dir(backup_dir.join("notes")));
+        assert!(Disk::is_file(env.storage.join("bundle/alpha/config")));
+        assert_eq!(Disk::get(env.storage.join(LOCK_FILE)).unwrap(), lock);
+
+        assert!(cmd_gc(&env, &Default::default()).is_err());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_create_relative_symlinks() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("config");
+        let local = env.storage.join("bundle/test_bundle/config");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        let options = LinkOptions { relative: true, ..Default::default() };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+
+        let target = Disk::readlink(&remote).unwrap();
+        assert!(target.is_relative());
+        assert_eq!(target, PathBuf::from("../dotgirl/bundle/test_bundle/config"));
+        assert_eq!(util::normalize(&config_dir.join(&target)), local);
+        assert!(Disk::exists(&remote));
+
+        // it's still recognized as ours
+        assert!(check_status(&env).unwrap().ok);
+        let summary = cmd_link(&env, "test_bundle", &Default::default()).unwrap();
+        assert_eq!(summary.skipped[0].reason, SkipReason::AlreadyLinked);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_not_touch_anything_if_an_input_is_missing() {
+        let (env, config_dir) = setup();
+        let missing = config_dir.join("typo");
+        let paths = vec![config_dir.join("a"), config_dir.join("b"), missing.clone()];
+
+        match cmd_add(&env, "test_bundle", &paths, &Default::default()) {
+            Err(Error::PathNotFound(path)) => assert_eq!(path, missing),
+            other => panic!("Expected a missing path, got {:?}", other),
+        };
+
+        assert!(Disk::is_dir(config_dir.join("a")));
+        assert!(!Disk::is_symlink(config_dir.join("a")));
+        assert!(Disk::is_file(config_dir.join("b/config")));
+        assert!(!Disk::symlink_exists(env.storage.join("bundle/test_bundle")));
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_dry_run_should_predict_conflicts() {
+        let (mut env, config_dir) = setup();
+        let out = env.storage.join("add.out");
+        env.output.path = Some(out.clone());
+
+        let options = AddOptions { dry_run: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("a"), config_dir.join("config")], &options)
+            .expect("Dry run should have worked");
+
+        assert_eq!(Disk::get(&out).unwrap(), ["would add /config/a: moves directory into storage",
+            "would add /config/config: moves file into storage"].join("\n"));
+
+        // content is linked over whatever is already there
+        Disk::symlink("/elsewhere", config_dir.join("link")).unwrap();
+        let options = AddOptions {
+            content: Some(String::from("generated")),
+            ..options
+        };
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).unwrap();
+        assert_eq!(Disk::get(&out).unwrap(), "would add /config/config: conflicts with existing file");
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("link")], &options).unwrap();
+        assert_eq!(Disk::get(&out).unwrap(), "would add /config/link: conflicts with existing symlink to /elsewhere");
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("new")], &options).unwrap();
+        assert_eq!(Disk::get(&out).unwrap(), "would add /config/new: nothing there yet");
+
+        // nothing was touched
+        assert!(!Disk::is_symlink(config_dir.join("a")));
+        assert_eq!(Disk::get(config_dir.join("config")).unwrap(), "hello config");
+        assert!(!Disk::symlink_exists(env.storage.join("bundle/test_bundle")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_not_change_anything_unless_confirmed() {
+        let (mut env, config_dir) = setup();
+        let resolver = Rc::new(ScriptedResolver::default());
+        env.resolver = resolver.clone();
+        env.interactive = true;
+
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+
+        // a dry run never asks
+        let options = AddOptions { confirm: true, dry_run: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).unwrap();
+        assert!(resolver.asked.borrow().is_empty());
+
+        let options = AddOptions { confirm: true, ..Default::default() };
+        let summary = cmd_add(&env, "test_bundle", &paths, &options).expect("Add should have worked");
+        assert!(summary.added.is_empty());
+
+        let asked = resolver.asked.borrow();
+        assert!(asked[0].starts_with("2 files to move, 2 symlinks to create, 0 conflicts\n"));
+
+        assert!(!Disk::is_symlink(config_dir.join("a")));
+        assert_eq!(Disk::get(config_dir.join("config")).unwrap(), "hello config");
+        assert!(!Disk::symlink_exists(env.storage.join("bundle/test_bundle")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_refuse_inputs_inside_directory_entries() {
+        let (env, config_dir) = setup();
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("a")], &Default::default()).unwrap();
+
+        // written through the linked directory, so it's already in storage
+        let stored = env.storage.join("bundle/test_bundle/a/newfile");
+        Disk::put(&stored, "hello new").unwrap();
+
+        let input = config_dir.join("a/newfile");
+        match cmd_add(&env, "other_bundle", &vec![input.clone()], &Default::default()) {
+            Err(Error::AlreadyManaged(path, bundle)) => {
+                assert_eq!(path, input);
+                assert_eq!(bundle, "test_bundle");
+            },
+            other => panic!("Expected an input that's already managed, got {:?}", other),
+        };
+
+        assert_eq!(Disk::get(&stored).unwrap(), "hello new");
+        assert!(!Disk::symlink_exists(env.storage.join("bundle/other_bundle")));
+
+        // files next to the directory are still fine
+        cmd_add(&env, "other_bundle", &vec![config_dir.join("config")], &Default::default())
+            .expect("Add should have worked");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_not_link_with_no_link() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("a"), config_dir.join("b")];
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).expect("Add should have worked");
+
+        for it in &paths {
+            assert!(!Disk::symlink_exists(it));
+        }
+
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        assert!(Disk::is_file(bundle_dir.join("a/config")));
+        assert!(Disk::is_file(bundle_dir.join("b/config")));
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().entries.len(), 2);
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+        for it in &paths {
+            assert!(Disk::is_symlink(it));
+        }
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_keep_existing_links_with_no_link() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("config");
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        let summary = cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).expect("Add should have worked");
+
+        assert_eq!(summary.unchanged, vec![format!("{}", remote.display())]);
+        assert_eq!(Disk::readlink(&remote).unwrap(), env.storage.join("bundle/test_bundle/config"));
+        assert_eq!(Disk::get(&remote).unwrap(), "hello config");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_only_link_on_allowed_hosts() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let mut bundle = read_bundle(&env, "beta").unwrap();
+        bundle.hosts = vec![String::from("desktop"), String::from("work")];
+        write_bundle(&env, &bundle).unwrap();
+
+        // the test env is on `laptop`
+        cmd_link(&env, "beta", &Default::default()).expect("Skipping should have worked");
+        cmd_link_all(&env, &Default::default()).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/alpha"));
+        assert!(!Disk::symlink_exists("/config/beta"));
+        assert!(!get_lockfile(&env).unwrap().linked.iter().any(|it| it.id == "beta"));
+
+        let options = LinkOptions { force_host: true, ..Default::default() };
+        cmd_link(&env, "beta", &options).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/beta"));
+        cmd_unlink(&env, "beta", &Default::default()).unwrap();
+
+        let env = Env { host: String::from("work"), ..env };
+        cmd_link(&env, "beta", &Default::default()).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/beta"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_all_should_skip_disabled_bundles() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link(&env, "beta", &Default::default()).unwrap();
+
+        cmd_disable(&env, "beta").expect("Disable should have worked");
+        assert!(!Disk::symlink_exists("/config/beta"));
+        assert!(!read_bundle(&env, "beta").unwrap().enabled);
+
+        let raw = Disk::get(env.storage.join("bundle/beta").join(BUNDLE_FILE)).unwrap();
+        assert!(raw.contains("enabled = false"));
+
+        cmd_link_all(&env, &Default::default()).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/alpha"));
+        assert!(Disk::is_symlink("/config/gamma"));
+        assert!(!Disk::symlink_exists("/config/beta"));
+
+        let linked = get_lockfile(&env).unwrap().linked;
+        assert!(!linked.iter().any(|it| it.id == "beta"));
+
+        cmd_enable(&env, "beta").expect("Enable should have worked");
+        assert!(Disk::is_symlink("/config/beta"));
+        assert!(read_bundle(&env, "beta").unwrap().enabled);
+
+        let linked = get_lockfile(&env).unwrap().linked;
+        assert!(linked.iter().any(|it| it.id == "beta"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_status_should_fix_broken_links() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link_all(&env, &Default::default()).unwrap();
+
+        // alpha is gone, beta points somewhere else, gamma was replaced
+        Disk::remove("/config/alpha").unwrap();
+        Disk::remove("/config/beta").unwrap();
+        Disk::symlink("/elsewhere", "/config/beta").unwrap();
+        Disk::remove("/config/gamma").unwrap();
+        Disk::put("/config/gamma", "mine").unwrap();
+
+        let options = StatusOptions { fix: true, ..Default::default() };
+        match cmd_status(&env, &options) {
+            Err(Error::Unhealthy(1)) => {},
+            other => panic!("Expected one problem to be left, got {:?}", other),
+        };
+
+        assert!(links_to(Path::new("/config/alpha"), &env.storage.join("bundle/alpha/config")));
+        assert!(links_to(Path::new("/config/beta"), &env.storage.join("bundle/beta/config")));
+        assert_eq!(Disk::get("/config/gamma").unwrap(), "mine");
+
+        let health = check_status(&env).unwrap();
+        assert_eq!(health.problems.len(), 1);
+        assert_eq!(health.problems[0].kind, ProblemKind::Foreign);
+
+        let options = StatusOptions { fix: true, force: true, ..Default::default() };
+        cmd_status(&env, &options).expect("Everything should be fixed");
+        assert!(Disk::is_symlink("/config/gamma"));
+
+        clean();
+    }
+
+    #[test]
+    fn check_status_should_tell_foreign_links_apart() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link_all(&env, &Default::default()).unwrap();
+
+        // alpha was linked by another tool, beta to the wrong place
+        // in storage and gamma was replaced by a file
+        Disk::remove("/config/alpha").unwrap();
+        Disk::symlink("/home/stow/alpha", "/config/alpha").unwrap();
+        Disk::remove("/config/beta").unwrap();
+        Disk::symlink(env.storage.join("bundle/alpha/config"), "/config/beta").unwrap();
+        Disk::remove("/config/gamma").unwrap();
+        Disk::put("/config/gamma", "mine").unwrap();
+
+        let mut kinds = check_status(&env).unwrap().problems
+            .into_iter()
+            .map(|it| (it.path, it.kind))
+            .collect::<Vec<(String, ProblemKind)>>();
+        kinds.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(kinds, vec![
+            (String::from("/config/alpha"), ProblemKind::ForeignLink),
+            (String::from("/config/beta"), ProblemKind::WrongTarget),
+            (String::from("/config/gamma"), ProblemKind::Foreign),
+        ]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_store_only_directories_for_skeletons() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("tree");
+        let local = env.storage.join("bundle/test_bundle/tree");
+
+        Disk::mkdir_all(remote.join("x/y")).unwrap();
+        Disk::mkdir_all(remote.join("z")).unwrap();
+        Disk::put(remote.join("x/file"), "mine").unwrap();
+
+        let options = AddOptions { parents_only: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).expect("Add should have worked");
+
+        assert_eq!(skeleton_of(&local).unwrap(), vec![PathBuf::from("x"), PathBuf::from("x/y"), PathBuf::from("z")]);
+        assert!(!Disk::symlink_exists(local.join("x/file")));
+
+        // the input is left as it was
+        assert!(!Disk::is_symlink(&remote));
+        assert_eq!(Disk::get(remote.join("x/file")).unwrap(), "mine");
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].link_type, LinkType::Skeleton);
+        assert!(check_status(&env).unwrap().ok);
+
+        // unlinking leaves the directories and whatever is in them
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        assert_eq!(Disk::get(remote.join("x/file")).unwrap(), "mine");
+
+        // somewhere the directories don't exist yet
+        Disk::remove(&remote).unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        assert!(Disk::is_dir(remote.join("x/y")));
+        assert!(Disk::is_dir(remote.join("z")));
+        assert!(!Disk::symlink_exists(remote.join("x/file")));
+
+        let files = vec![config_dir.join("config")];
+        assert!(cmd_add(&env, "test_bundle", &files, &options).is_err());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_store_directories_as_archives() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("a");
+        let local = env.storage.join("bundle/test_bundle/a.tar");
+
+        let options = AddOptions { archive: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).expect("Add should have worked");
+
+        assert!(Disk::is_file(&local));
+        assert!(!Disk::symlink_exists(env.storage.join("bundle/test_bundle/a")));
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].link_type, LinkType::Archive);
+
+        // it's extracted, not linked
+        assert!(Disk::is_dir(&remote));
+        assert!(!Disk::is_symlink(&remote));
+        assert_eq!(Disk::get(remote.join("sub/config")).unwrap(), "hello config");
+        assert_eq!(Disk::get(remote.join(".hidden-config")).unwrap(), "hello config");
+        assert!(check_status(&env).unwrap().ok);
+
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        assert!(!Disk::symlink_exists(&remote));
+
+        // asking for symlinks doesn't change how archives are placed
+        let link_options = LinkOptions { link_type: Some(LinkType::Symlink), ..Default::default() };
+        cmd_link(&env, "test_bundle", &link_options).unwrap();
+        assert!(!Disk::is_symlink(&remote));
+        assert_eq!(Disk::get(remote.join("config")).unwrap(), "hello config");
+
+        let restore = UnlinkOptions { restore: true, ..Default::default() };
+        cmd_unlink(&env, "test_bundle", &restore).unwrap();
+        assert_eq!(Disk::get(remote.join("sub/config")).unwrap(), "hello config");
+
+        let files = vec![config_dir.join("config")];
+        assert!(cmd_add(&env, "test_bundle", &files, &options).is_err());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_unlink_should_keep_extracted_archives_that_were_changed() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("a");
+        let options = AddOptions { archive: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).expect("Add should have worked");
+
+        Disk::put(remote.join("config"), "edited").unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        assert_eq!(Disk::get(remote.join("config")).unwrap(), "edited");
+        assert_eq!(Disk::get(remote.join("sub/config")).unwrap(), "hello config");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_unlink_should_keep_copies_that_were_edited() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("config");
+        let options = AddOptions { link_type: Some(LinkType::Copy), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).expect("Add should have worked");
+
+        Disk::put(&remote, "edited").unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        assert_eq!(Disk::get(&remote).unwrap(), "edited");
+
+        // forcing it removes them anyway
+        Disk::remove(&remote).unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).unwrap();
+        assert!(Disk::is_file(&remote) && !Disk::is_symlink(&remote));
+        Disk::put(&remote, "edited").unwrap();
+
+        let options = UnlinkOptions { force: true, ..Default::default() };
+        cmd_unlink(&env, "test_bundle", &options).unwrap();
+        assert!(!Disk::symlink_exists(&remote));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_use_clock_for_created() {
+        let (mut env, config_dir) = setup();
+        env.clock = Clock::Fixed(1234);
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("a")], &Default::default()).unwrap();
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().created, Some(1234));
+
+        // adding to it again keeps when it was created
+        env.clock = Clock::Fixed(5678);
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("b")], &Default::default()).unwrap();
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().created, Some(1234));
+
+        clean();
+    }
+
+    #[test]
+    fn reflink_or_copy_should_fall_back_for_auto() {
+        use std::cell::Cell;
+
+        let copied = Cell::new(false);
+        let copy = || {
+            copied.set(true);
+            Ok(())
+        };
+
+        let failing = || Err(Error::Simple("unsupported"));
+        let working = || Ok(());
+
+        assert!(reflink_or_copy(Reflink::Auto, failing, copy).is_ok());
+        assert!(copied.replace(false));
+
+        assert!(reflink_or_copy(Reflink::Auto, working, copy).is_ok());
+        assert!(!copied.replace(false));
+
+        assert!(reflink_or_copy(Reflink::Always, failing, copy).is_err());
+        assert!(!copied.replace(false));
+
+        assert!(reflink_or_copy(Reflink::Never, working, copy).is_ok());
+        assert!(copied.replace(false));
+    }
+
+    #[test]
+    fn cmd_add_should_reflink_moved_inputs_when_asked_to() {
+        let (env, config_dir) = setup();
+        let options = AddOptions { reflink: Reflink::Always, no_link: true, ..Default::default() };
+
+        let (result, calls) = disk::record(|| {
+            cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options)
+        });
+
+        result.expect("Add should have worked");
+        assert!(calls.iter().any(|it| it.starts_with("reflink /dotgirl/bundle/test_bundle/config")));
+        assert!(!calls.iter().any(|it| it.starts_with("rename")));
+
+        clean();
+    }
+
+    #[test]
+    fn replace_in_storage_should_keep_the_stored_copy_until_it_can_swap() {
+        let (_, config_dir) = setup();
+        let local = PathBuf::from("/dotgirl/bundle/test_bundle/config");
+        Disk::put(&local, "stored").unwrap();
+
+        assert!(replace_in_storage(&config_dir.join("missing"), &local).is_err());
+        assert_eq!(Disk::get(&local).unwrap(), "stored");
+
+        replace_in_storage(&config_dir.join("config"), &local).expect("Replacing should have worked");
+        assert!(!Disk::symlink_exists(config_dir.join("config")));
+        assert_eq!(Disk::read_dir("/dotgirl/bundle/test_bundle").unwrap(), vec![local.clone()]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_not_depend_on_parallelism() {
+        let (env, config_dir) = setup();
+        let names = ["a", "b", "config", ".hidden-config"];
+
+        let mut added = Vec::new();
+        for max_parallel in &[1, 4] {
+            let bundle_name = format!("bundle_{}", max_parallel);
+            let paths = names
+                .iter()
+                .map(|it| config_dir.join(it))
+                .collect::<Vec<PathBuf>>();
+
+            let options = AddOptions { max_parallel: *max_parallel, ..Default::default() };
+            cmd_add(&env, &bundle_name, &paths, &options).expect("Add should have worked");
+
+            // put everything back for the next round
+            cmd_unlink(&env, &bundle_name, &UnlinkOptions { restore: true, ..Default::default() }).unwrap();
+
+            let bundle = read_bundle(&env, &bundle_name).unwrap();
+            let storage = format!("{}", env.bundle_dir().join(&bundle_name).display());
+            let entries = bundle.entries
+                .iter()
+                .map(|it| (it.local.replace(&storage, ""), it.remote.clone(), it.link_type))
+                .collect::<Vec<(String, String, LinkType)>>();
+
+            added.push(entries);
+        }
+
+        assert_eq!(added[0].len(), names.len());
+        assert_eq!(added[0], added[1]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_expand_variables_in_remotes() {
+        let (env, config_dir) = setup();
+        std::env::set_var("DOTGIRL_TEST_CONFIG_DIR", &config_dir);
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).unwrap();
+
+        let mut bundle = read_bundle(&env, "test_bundle").unwrap();
+        bundle.entries[0].remote = String::from("${DOTGIRL_TEST_CONFIG_DIR}/expanded");
+        write_bundle(&env, &bundle).unwrap();
+
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+        assert!(Disk::is_symlink(config_dir.join("expanded")));
+
+        // what's stored stays the same
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].remote, "${DOTGIRL_TEST_CONFIG_DIR}/expanded");
+
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        assert!(!Disk::symlink_exists(config_dir.join("expanded")));
+
+        let mut bundle = read_bundle(&env, "test_bundle").unwrap();
+        bundle.entries[0].remote = String::from("$DOTGIRL_TEST_UNSET/expanded");
+        write_bundle(&env, &bundle).unwrap();
+
+        match cmd_link(&env, "test_bundle", &Default::default()) {
+            Err(Error::UnsetEnvVar(name)) => assert_eq!(name, "DOTGIRL_TEST_UNSET"),
+            other => panic!("Expected an unset variable, got {:?}", other),
+        };
+
+        clean();
+    }
+
+    #[test]
+    fn dry_run_should_only_report_what_add_does() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("a")], &Default::default()).unwrap();
+        let lock_before = Disk::get(env.storage.join(LOCK_FILE)).unwrap();
+
+        disk::set_dry_run(true);
+        let (result, calls) = disk::record(|| {
+            cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default())
+        });
+        disk::set_dry_run(false);
+
+        result.expect("Add should have worked");
+        assert!(calls.iter().any(|it| it == "rename /config/config -> /dotgirl/bundle/test_bundle/config"));
+        assert!(calls.iter().any(|it| it.starts_with("put /dotgirl/lock.toml")));
+
+        assert!(!Disk::is_symlink(config_dir.join("config")));
+        assert_eq!(Disk::get(config_dir.join("config")).unwrap(), "hello config");
+        assert!(!Disk::symlink_exists(bundle_dir.join("config")));
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().entries.len(), 1);
+        assert_eq!(Disk::get(env.storage.join(LOCK_FILE)).unwrap(), lock_before);
+
+        clean();
+    }
+
+    #[test]
+    fn tracing_should_record_what_add_does() {
+        let (env, config_dir) = setup();
+
+        let (result, calls) = disk::record(|| {
+            cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default())
+        });
+
+        result.expect("Add should have worked");
+
+        // sizes change whenever the files we write do
+        let calls = calls
+            .iter()
+            .map(|it| it.split(" (").next().unwrap())
+            .collect::<Vec<&str>>();
+
+        assert_eq!(calls, vec![
+            "mkdir_all /dotgirl/bundle/test_bundle",
+            "get /config/config",
+            "rename /config/config -> /dotgirl/bundle/test_bundle/config",
+            "put /dotgirl/bundle/test_bundle/bundle.toml",
+            "put /dotgirl/bundle/test_bundle/manifest.toml",
+            "symlink /config/config -> /dotgirl/bundle/test_bundle/config",
+            "get /dotgirl/bundle/test_bundle/bundle.toml",
+            "get /dotgirl/bundle/test_bundle/config",
+            "get /dotgirl/bundle/test_bundle/manifest.toml",
+            "mkdir_all /dotgirl",
+            "put /dotgirl/lock.toml",
+        ]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_mirror_a_directory_into_home() {
+        let (env, _) = setup();
+        let dotfiles = env.home.join("dotfiles");
+        Disk::mkdir_all(dotfiles.join(".config/app")).unwrap();
+        Disk::put(dotfiles.join(".gitconfig"), "hello gitconfig").unwrap();
+        Disk::put(dotfiles.join(".config/app/config"), "hello config").unwrap();
+
+        let options = AddOptions { from_dir: Some(dotfiles.clone()), ..Default::default() };
+        cmd_add(&env, "dotfiles", &vec![], &options).expect("Add should have worked");
+
+        let bundle_dir = env.storage.join("bundle/dotfiles");
+        let bundle = read_bundle(&env, "dotfiles").unwrap();
+        let mapping = bundle.entries
+            .iter()
+            .map(|it| (it.local.clone(), it.remote.clone()))
+            .collect::<Vec<(String, String)>>();
+
+        assert_eq!(mapping, vec![
+            (format!("{}", bundle_dir.join("config").display()), format!("{}", env.home.join(".config").display())),
+            (format!("{}", bundle_dir.join("gitconfig").display()), format!("{}", env.home.join(".gitconfig").display())),
+        ]);
+
+        // nesting is kept inside of each entry
+        assert_eq!(Disk::get(bundle_dir.join("config/app/config")).unwrap(), "hello config");
+        assert_eq!(Disk::readlink(env.home.join(".config")).unwrap(), bundle_dir.join("config"));
+        assert_eq!(Disk::readlink(env.home.join(".gitconfig")).unwrap(), bundle_dir.join("gitconfig"));
+
+        // and the mirrored directory is left as it was
+        assert_eq!(Disk::get(dotfiles.join(".gitconfig")).unwrap(), "hello gitconfig");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_not_replace_existing_remotes_when_mirroring() {
+        let (mut env, _) = setup();
+        let dotfiles = env.home.join("dotfiles");
+        Disk::mkdir_all(dotfiles.join(".config/app")).unwrap();
+        Disk::put(dotfiles.join(".config/app/config"), "mirrored config").unwrap();
+        Disk::mkdir_all(env.home.join(".config/other")).unwrap();
+        Disk::put(env.home.join(".config/other/config"), "mine").unwrap();
+
+        env.config.conflict = ConflictPolicy::Skip;
+        let options = AddOptions { from_dir: Some(dotfiles.clone()), ..Default::default() };
+        cmd_add(&env, "dotfiles", &vec![], &options).expect("Add should have worked");
+
+        assert!(!Disk::is_symlink(env.home.join(".config")));
+        assert_eq!(Disk::get(env.home.join(".config/other/config")).unwrap(), "mine");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_link_to_an_overridden_remote() {
+        let (env, config_dir) = setup();
+        let source = config_dir.join("built/gitconfig");
+        let remote = env.home.join(".gitconfig");
+        let local = env.storage.join("bundle/test_bundle/gitconfig");
+        Disk::mkdir_all(config_dir.join("built")).unwrap();
+        Disk::put(&source, "built gitconfig").unwrap();
+
+        let options = AddOptions { remote: Some(remote.clone()), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![source.clone()], &options).expect("Add should have worked");
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].remote, format!("{}", remote.display()));
+        assert_eq!(bundle.entries[0].local, format!("{}", local.display()));
+        assert_eq!(Disk::readlink(&remote).unwrap(), local);
+
+        // the source is copied, not moved
+        assert!(!Disk::is_symlink(&source));
+        assert_eq!(Disk::get(&source).unwrap(), "built gitconfig");
+
+        let options = AddOptions { remote: Some(PathBuf::from("relative")), ..Default::default() };
+        assert!(cmd_add(&env, "test_bundle", &vec![source.clone()], &options).is_err());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_leave_an_existing_overridden_remote_to_the_conflict_policy() {
+        let (mut env, config_dir) = setup();
+        let remote = env.home.join(".gitconfig");
+        Disk::put(&remote, "my gitconfig").unwrap();
+
+        env.config.conflict = ConflictPolicy::Skip;
+        let options = AddOptions { remote: Some(remote.clone()), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).expect("Add should have worked");
+
+        assert!(!Disk::is_symlink(&remote));
+        assert_eq!(Disk::get(&remote).unwrap(), "my gitconfig");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_which_should_only_look_at_the_given_bundle() {
+        let (env, config_dir) = setup();
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default()).unwrap();
+        cmd_add(&env, "other", &vec![config_dir.join("a")], &Default::default()).unwrap();
+
+        let inside = config_dir.join("a/sub/config");
+        assert_eq!(cmd_which(&env, &inside, None).unwrap(), Some(String::from("other")));
+        assert_eq!(cmd_which(&env, &inside, Some("test_bundle")).unwrap(), None);
+
+        let config = config_dir.join("config");
+        assert_eq!(cmd_which(&env, &config, Some("test_bundle")).unwrap(), Some(String::from("test_bundle")));
+        assert_eq!(cmd_which(&env, &config, Some("other")).unwrap(), None);
+
+        assert!(cmd_which(&env, &config, Some("nothing")).is_err());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_create_entries_from_content() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("generated");
+        let local = env.storage.join("bundle/test_bundle/generated");
+
+        let options = AddOptions {
+            content: Some(String::from("generated config")),
+            ..Default::default()
+        };
+
+        let summary = cmd_add(&env, "test_bundle", &vec![remote.clone()], &options)
+            .expect("Add should have worked");
+
+        assert_eq!(summary.added, vec![format!("{}", remote.display())]);
+        assert_eq!(Disk::get(&local).unwrap(), "generated config");
+        assert!(Disk::is_symlink(&remote));
+        assert_eq!(Disk::readlink(&remote).unwrap(), local);
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].origin, None);
+
+        // there's only one place to put the content
+        let paths = vec![remote.clone(), config_dir.join("config")];
+        assert!(cmd_add(&env, "test_bundle", &paths, &options).is_err());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_leave_existing_remotes_of_content_to_the_conflict_policy() {
+        let (mut env, config_dir) = setup();
+        let remote = config_dir.join("config");
+        let options = AddOptions {
+            content: Some(String::from("generated config")),
+            ..Default::default()
+        };
+
+        env.config.conflict = ConflictPolicy::Skip;
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).expect("Add should have worked");
+        assert!(!Disk::is_symlink(&remote));
+        assert_eq!(Disk::get(&remote).unwrap(), "hello config");
+
+        env.config.conflict = ConflictPolicy::Overwrite;
+        env.config.backup = true;
+        let options = AddOptions { force: true, ..options };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).expect("Add should have worked");
+        assert!(Disk::is_symlink(&remote));
+
+        let backups = Disk::walk(env.storage.join(BACKUP_DIR)).unwrap();
+        assert!(backups.iter().any(|it| it.ends_with("config/config") && Disk::get(it).unwrap() == "hello config"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_place_links_below_the_target_home() {
+        let (mut env, config_dir) = setup();
+        let options = AddOptions { relative_to: Some(env.home.clone()), ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        let storage = env.storage.clone();
+        env.home = target_home(Some("/target")).unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        assert_eq!(env.storage, storage);
+        assert!(links_to(Path::new("/target/config/config"), &storage.join("bundle/test_bundle/config")));
+        assert!(!Disk::symlink_exists(config_dir.join("config")));
+
+        // so is everything given relative to home
+        let expanded = expand_input(&env, "~/config/config").unwrap();
+        assert_eq!(expanded, vec![PathBuf::from("/target/config/config")]);
+
+        clean();
+    }
+
+    #[test]
+    fn expand_input_should_expand_tildes() {
+        let (mut env, _) = setup();
+        env.home = PathBuf::from("/home/me");
+        Disk::put(PASSWD_FILE, "root:x:0:0:root:/root:/bin/sh\nother:x:1000:1000::/srv/other:/bin/sh\n").unwrap();
+
+        let expand = |input: &str| expand_input(&env, input).unwrap();
+        assert_eq!(expand("~"), vec![PathBuf::from("/home/me")]);
+        assert_eq!(expand("~/foo"), vec![PathBuf::from("/home/me/foo")]);
+        assert_eq!(expand("~other"), vec![PathBuf::from("/srv/other")]);
+        assert_eq!(expand("~other/.config"), vec![PathBuf::from("/srv/other/.config")]);
+        assert_eq!(expand("~nobody/foo"), vec![PathBuf::from("~nobody/foo")]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_record_remotes_relative_to_base() {
+        let (env, config_dir) = setup();
+        let options = AddOptions { relative_to: Some(config_dir.clone()), ..Default::default() };
+
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &options).expect("Add should have worked");
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        let remotes = bundle.entries.iter().map(|it| it.remote.as_str()).collect::<Vec<&str>>();
+        assert_eq!(remotes, vec!["a", "config"]);
+        assert_eq!(bundle.base, Some(String::from("$HOME/config")));
+        assert!(Disk::is_symlink(config_dir.join("a")));
+
+        // inputs outside of the base are refused
+        let outside = vec![env.home.join("elsewhere")];
+        Disk::put(&outside[0], "hello elsewhere").unwrap();
+        match cmd_add(&env, "test_bundle", &outside, &options) {
+            Err(Error::OutsideBase(path)) => assert_eq!(path, outside[0]),
+            other => panic!("Expected input outside of base, got {:?}", other),
+        };
+
+        // another machine links them below its own home
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        let other = Env { home: PathBuf::from("/other"), ..env.clone() };
+        Disk::mkdir_all("/other/config").unwrap();
+        cmd_link(&other, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        let local = env.storage.join("bundle/test_bundle/a");
+        assert_eq!(Disk::readlink("/other/config/a").unwrap(), local);
+        assert!(!Disk::symlink_exists(config_dir.join("a")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_prefer_host_specific_variants() {
+        let (env, config_dir) = setup();
+        let env = Env { host: String::from("work"), ..env };
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        Disk::put(config_dir.join("config.work"), "hello work").unwrap();
+
+        let paths = vec![config_dir.join("config"), config_dir.join("config.work")];
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).expect("Add should have worked");
+
+        // the variant takes the generic entry's place
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+        assert_eq!(Disk::readlink(config_dir.join("config")).unwrap(), bundle_dir.join("config.work"));
+        assert!(!Disk::symlink_exists(config_dir.join("config.work")));
+        assert!(check_status(&env).unwrap().ok);
+
+        // without a matching variant, everything is linked as is
+        cmd_unlink(&env, "test_bundle", &Default::default()).expect("Unlink should have worked");
+        let options = LinkOptions { hostname_suffix: Some(String::from("home")), ..Default::default() };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+        assert_eq!(Disk::readlink(config_dir.join("config")).unwrap(), bundle_dir.join("config"));
+        assert_eq!(Disk::readlink(config_dir.join("config.work")).unwrap(), bundle_dir.join("config.work"));
+        assert!(check_status(&env).unwrap().ok);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_link_directories_per_file() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("a");
+        let local = env.storage.join("bundle/test_bundle/a");
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).unwrap();
+
+        let options = LinkOptions { per_file: true, ..Default::default() };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+
+        assert!(Disk::is_dir(&remote) && !Disk::is_symlink(&remote));
+        assert!(Disk::is_dir(remote.join("sub")) && !Disk::is_symlink(remote.join("sub")));
+        assert_eq!(Disk::readlink(remote.join("config")).unwrap(), local.join("config"));
+        assert_eq!(Disk::readlink(remote.join("sub/config")).unwrap(), local.join("sub/config"));
+        assert!(read_bundle(&env, "test_bundle").unwrap().entries[0].per_file);
+
+        // new files stay next to the linked ones, and linking
+        // again keeps the mode that was recorded
+        Disk::put(remote.join("new"), "hello new").unwrap();
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+        assert!(!Disk::symlink_exists(local.join("new")));
+        assert!(check_status(&env).unwrap().ok);
+
+        cmd_unlink(&env, "test_bundle", &Default::default()).expect("Unlink should have worked");
+        assert!(!Disk::symlink_exists(remote.join("config")));
+        assert_eq!(Disk::get(remote.join("new")).unwrap(), "hello new");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_per_file_should_follow_the_conflict_policy() {
+        let (mut env, config_dir) = setup();
+        let remote = config_dir.join("a");
+        let local = env.storage.join("bundle/test_bundle/a");
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).unwrap();
+        Disk::put(remote.join("config"), "mine").unwrap();
+
+        // there are no answers, so asking about anything panics
+        env.resolver = Rc::new(ScriptedResolver::default());
+        env.config.conflict = ConflictPolicy::Skip;
+
+        let options = LinkOptions { per_file: true, ..Default::default() };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+        assert_eq!(Disk::get(remote.join("config")).unwrap(), "mine");
+        assert_eq!(Disk::readlink(remote.join("sub/config")).unwrap(), local.join("sub/config"));
+
+        env.config.conflict = ConflictPolicy::Overwrite;
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+        assert_eq!(Disk::readlink(remote.join("config")).unwrap(), local.join("config"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_refuse_remotes_of_other_bundles() {
+        let (env, config_dir) = setup();
+        let remote = format!("{}", config_dir.join("config").display());
+
+        let other = Linked {
+            id: String::from("other"),
+            host: Some(env.host.clone()),
+            entries: vec![Entry {
+                local: String::from("/elsewhere/config"),
+                remote: remote.clone(),
+                link_type: LinkType::Symlink,
+                host: None,
+                on_conflict: None,
+                origin: None,
+                note: None,
+                per_file: false,
+                xattrs: Default::default(),
+            }],
+            ..Default::default()
+        };
+
+        write_lockfile(&env, &Lock { linked: vec![other] }).unwrap();
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &options).unwrap();
+
+        match cmd_link(&env, "test_bundle", &Default::default()) {
+            Err(Error::RemoteClaimed(path, bundle)) => {
+                assert_eq!(path, remote);
+                assert_eq!(bundle, "other");
+            },
+            other => panic!("Expected a claimed remote, got {:?}", other),
+        };
+
+        assert!(!Disk::symlink_exists(config_dir.join("config")));
+
+        let options = LinkOptions { ignore_cross_bundle: true, ..Default::default() };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+        assert!(Disk::is_symlink(config_dir.join("config")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_only_link_picked_entries() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("a"), config_dir.join("b"), config_dir.join("config")];
+        let remote = |name: &str| format!("{}", config_dir.join(name).display());
+
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).unwrap();
+
+        let options = LinkOptions { entries: vec![remote("a"), remote("config")], ..Default::default() };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+
+        assert!(Disk::is_symlink(config_dir.join("a")));
+        assert!(Disk::is_symlink(config_dir.join("config")));
+        assert!(!Disk::symlink_exists(config_dir.join("b")));
+
+        let lockfile = get_lockfile(&env).unwrap();
+        assert_eq!(lockfile.linked[0].only, vec![remote("a"), remote("config")]);
+
+        // the rest isn't missing, it just wasn't picked
+        assert!(check_status(&env).unwrap().ok);
+
+        let options = LinkOptions { entries: vec![remote("nothing")], ..Default::default() };
+        match cmd_link(&env, "test_bundle", &options) {
+            Err(Error::UnknownEntry(it)) => assert_eq!(it, remote("nothing")),
+            other => panic!("Expected an unknown entry, got {:?}", other),
+        };
+
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        assert!(!Disk::symlink_exists(config_dir.join("a")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_back_up_when_overwriting() {
+        let (mut env, config_dir) = setup();
+        let remote = config_dir.join("config");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+        Disk::put(&remote, "mine").unwrap();
+
+        env.config.conflict = ConflictPolicy::Overwrite;
+        env.config.backup = true;
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+
+        Disk::print();
+
+        assert!(Disk::is_symlink(&remote));
+
+        let backups = Disk::walk(env.storage.join(BACKUP_DIR)).unwrap();
+        let backup = backups
+            .iter()
+            .find(|it| it.ends_with("config/config"))
+            .expect("Backup should exist");
+
+        assert_eq!(Disk::get(backup).unwrap(), "mine");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_rename_entry_should_move_the_link() {
+        let (env, config_dir) = setup();
+        let old = config_dir.join("config");
+        let new = config_dir.join("renamed");
+        let local = env.storage.join("bundle/test_bundle/config");
+
+        let paths = vec![old.clone(), config_dir.join("b")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        cmd_rename_entry(&env, "test_bundle", &old, &new).expect("Rename should have worked");
+
+        assert!(!Disk::symlink_exists(&old));
+        assert_eq!(Disk::readlink(&new).unwrap(), local);
+
+        let remote = format!("{}", new.display());
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert!(bundle.entries.iter().any(|it| it.remote == remote));
+
+        let lockfile = get_lockfile(&env).unwrap();
+        assert!(lockfile.linked[0].entries.iter().any(|it| it.remote == remote));
+        assert!(check_status(&env).unwrap().ok);
+
+        match cmd_rename_entry(&env, "test_bundle", &new, &config_dir.join("b")) {
+            Err(Error::RemoteClaimed(..)) => {},
+            other => panic!("Expected the remote to be claimed, got {:?}", other),
+        };
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_unlink_should_restore_files() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+        let options = UnlinkOptions { restore: true, ..Default::default() };
+        cmd_unlink(&env, "test_bundle", &options).expect("Unlink should have worked");
+
+        assert!(Disk::is_dir(config_dir.join("a")));
+        assert!(!Disk::is_symlink(config_dir.join("a")));
+        assert_eq!(Disk::get(config_dir.join("a/sub/config")).unwrap(), "hello config");
+        assert!(Disk::is_file(config_dir.join("config")));
+        assert!(!Disk::is_symlink(config_dir.join("config")));
+
+        assert!(Disk::is_file(bundle_dir.join("config")));
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_unlink_should_keep_links_pointed_elsewhere() {
+        let (mut env, config_dir) = setup();
+        let remote = config_dir.join("config");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+
+        // the user points the link at their own file
+        Disk::put(config_dir.join("mine"), "my config").unwrap();
+        Disk::remove(&remote).unwrap();
+        Disk::symlink(config_dir.join("mine"), &remote).unwrap();
+
+        let restore = UnlinkOptions { restore: true, ..Default::default() };
+        cmd_unlink(&env, "test_bundle", &restore).expect("Unlink should have worked");
+        assert_eq!(Disk::readlink(&remote).unwrap(), config_dir.join("mine"));
+
+        // the user's link is in the way, so this only marks it as linked
+        env.config.conflict = ConflictPolicy::Skip;
+        let summary = cmd_link(&env, "test_bundle", &Default::default()).unwrap();
+        assert!(summary.linked.is_empty());
+
+        let force = UnlinkOptions { force: true, ..Default::default() };
+        cmd_unlink(&env, "test_bundle", &force).expect("Unlink should have worked");
+        assert!(!Disk::symlink_exists(&remote));
+        assert_eq!(Disk::get(config_dir.join("mine")).unwrap(), "my config");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_unlink_should_restore_and_purge() {
+        let (env, config_dir) = setup();
+
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+        let options = UnlinkOptions { restore: true, purge: true, ..Default::default() };
+        cmd_unlink(&env, "test_bundle", &options).expect("Unlink should have worked");
+
+        assert!(Disk::is_file(config_dir.join("a/config")));
+        assert!(Disk::is_file(config_dir.join("config")));
+        assert!(!Disk::symlink_exists(env.storage.join("bundle/test_bundle")));
+        assert!(list_ids(&env, SortKey::Name, false).is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_unlink_all_should_unlink_every_bundle() {
+        let (env, config_dir) = setup();
+
+        cmd_add(&env, "one", &vec![config_dir.join("a")], &Default::default()).unwrap();
+        cmd_add(&env, "two", &vec![config_dir.join("b")], &Default::default()).unwrap();
+        cmd_add(&env, "three", &vec![config_dir.join("config")], &Default::default()).unwrap();
+
+        cmd_unlink_all(&env, &Default::default()).expect("Unlink should have worked");
+
+        Disk::print();
+
+        assert!(!Disk::is_symlink(config_dir.join("a")));
+        assert!(!Disk::is_symlink(config_dir.join("b")));
+        assert!(!Disk::is_symlink(config_dir.join("config")));
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_all_should_link_every_bundle() {
+        let (env, config_dir) = setup();
+
+        cmd_add(&env, "one", &vec![config_dir.join("a")], &Default::default()).unwrap();
+        cmd_add(&env, "two", &vec![config_dir.join("b")], &Default::default()).unwrap();
+        cmd_add(&env, "three", &vec![config_dir.join("config")], &Default::default()).unwrap();
+        cmd_unlink_all(&env, &Default::default()).unwrap();
+
+        cmd_link_all(&env, &Default::default()).expect("Link should have worked");
+
+        Disk::print();
+
+        assert!(Disk::is_symlink(config_dir.join("a")));
+        assert!(Disk::is_symlink(config_dir.join("b")));
+        assert!(Disk::is_symlink(config_dir.join("config")));
+
+        let mut linked = get_lockfile(&env).unwrap().linked
+            .into_iter()
+            .map(|it| it.id)
+            .collect::<Vec<String>>();
+
+        linked.sort();
+        assert_eq!(linked, vec!["one", "three", "two"]);
+
+        clean();
+    }
+
+    #[test]
+    fn merge_dir_should_link_leaves_into_existing_dir() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        let remote = config_dir.join("a");
+
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()).unwrap();
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        // the user already has their own version of the directory
+        Disk::mkdir_all(&remote).unwrap();
+        Disk::put(remote.join("own-config"), "mine").unwrap();
+
+        let merged = merge_dir(&env, &bundle_dir.join("a"), &remote, ConflictPolicy::Prompt, &Default::default())
+            .expect("Merge should have worked");
+
+        Disk::print();
+
+        assert_eq!(merged.len(), 3);
+        assert!(Disk::is_dir(&remote));
+        assert!(!Disk::is_symlink(&remote));
+        assert!(Disk::is_file(remote.join("own-config")));
+        assert!(Disk::is_symlink(remote.join("config")));
+        assert!(Disk::is_symlink(remote.join(".hidden-config")));
+        assert!(Disk::is_dir(remote.join("sub")));
+        assert!(Disk::is_symlink(remote.join("sub/config")));
+
+        // unlinking should only remove the leaves we put there
+        let mut lockfile = get_lockfile(&env).unwrap();
+        mark_linked(&mut lockfile, &Bundle { entries: vec![], ..read_bundle(&env, "test_bundle").unwrap() }, &env.host, &env.host, &[], merged);
+        write_lockfile(&env, &lockfile).unwrap();
+
+        cmd_unlink(&env, "test_bundle", &Default::default()).expect("Unlink should have worked");
+
+        assert!(Disk::is_file(remote.join("own-config")));
+        assert!(!Disk::is_symlink(remote.join("config")));
+        assert!(!Disk::is_symlink(remote.join("sub/config")));
+
+        clean();
+    }
+
+    #[test]
+    fn list_long_should_show_the_state_of_every_entry() {
+        let (env, config_dir) = setup();
+        let remote = |name: &str| format!("{}", config_dir.join(name).display());
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("a"), config_dir.join("config")], &Default::default())
+            .expect("Add should have worked");
+
+        Disk::remove(config_dir.join("config")).unwrap();
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        let rendered = render_entries(&env, &get_lockfile(&env).unwrap(), &bundle).unwrap();
+        assert_eq!(rendered, [format!("\t{}       symlink  linked", remote("a")),
+            format!("\t{}  symlink  missing link", remote("config"))].join("\n"));
+
+        cmd_unlink(&env, "test_bundle", &Default::default()).unwrap();
+
+        let rendered = render_entries(&env, &get_lockfile(&env).unwrap(), &bundle).unwrap();
+        assert!(rendered.lines().all(|it| it.ends_with("  not linked")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_copy_the_files_tracked_by_git() {
+        let (env, _) = setup();
+        let repo = PathBuf::from("/repo");
+        Disk::put(repo.join(".vimrc"), "set number").unwrap();
+        Disk::put(repo.join(".config/nvim/init.vim"), "set number").unwrap();
+        Disk::put(repo.join("untracked"), "not in git").unwrap();
+
+        Shell::set_output(".vimrc\0.config/nvim/init.vim\0deleted\0");
+
+        let options = AddOptions { paths_from_git: Some(repo.clone()), ..Default::default() };
+        cmd_add(&env, "dots", &vec![], &options).expect("Add should have worked");
+
+        assert_eq!(Shell::calls(), vec![(String::from("git ls-files -z"), repo.clone())]);
+
+        let bundle = read_bundle(&env, "dots").unwrap();
+        let entries = bundle.entries
+            .iter()
+            .map(|it| (it.remote.clone(), it.local.clone()))
+            .collect::<Vec<(String, String)>>();
+
+        let bundle_dir = env.storage.join("bundle/dots");
+        assert_eq!(entries, vec![
+            (format!("{}", env.home.join(".config/nvim/init.vim").display()), format!("{}", bundle_dir.join("config/nvim/init.vim").display())),
+            (format!("{}", env.home.join(".vimrc").display()), format!("{}", bundle_dir.join("vimrc").display())),
+        ]);
+
+        // the repo itself is left alone
+        assert!(Disk::is_file(repo.join(".vimrc")));
+        assert!(links_to(&env.home.join(".vimrc"), &bundle_dir.join("vimrc")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_copy_any_file_tracked_by_git_without_replacing_home() {
+        let (mut env, _) = setup();
+        let repo = PathBuf::from("/repo");
+        Disk::put_bytes(repo.join(".fonts/icons.ttf"), &[0, 159, 146, 150, 255]).unwrap();
+        Disk::put(repo.join(".vimrc"), "set number").unwrap();
+        Disk::put(env.home.join(".vimrc"), "my vimrc").unwrap();
+
+        Shell::set_output(".fonts/icons.ttf\0.vimrc\0");
+
+        env.config.conflict = ConflictPolicy::Skip;
+        let options = AddOptions { paths_from_git: Some(repo.clone()), ..Default::default() };
+        cmd_add(&env, "dots", &vec![], &options).expect("Add should have worked");
+
+        let bundle_dir = env.storage.join("bundle/dots");
+        assert_eq!(Disk::get_bytes(bundle_dir.join("fonts/icons.ttf")).unwrap(), vec![0, 159, 146, 150, 255]);
+        assert!(links_to(&env.home.join(".fonts/icons.ttf"), &bundle_dir.join("fonts/icons.ttf")));
+
+        assert!(!Disk::is_symlink(env.home.join(".vimrc")));
+        assert_eq!(Disk::get(env.home.join(".vimrc")).unwrap(), "my vimrc");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_keep_notes_for_entries() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        let remote = format!("{}", config_dir.join("config").display());
+
+        let options = AddOptions { note: Some(String::from("for everything")), ..Default::default() };
+        assert!(cmd_add(&env, "test_bundle", &paths, &options).is_err());
+
+        let options = AddOptions {
+            notes: vec![(config_dir.join("config"), String::from("from the old laptop"))],
+            ..Default::default()
+        };
+        cmd_add(&env, "test_bundle", &paths, &options).expect("Add should have worked");
+
+        let raw = Disk::get(env.storage.join("bundle/test_bundle").join(BUNDLE_FILE)).unwrap();
+        assert!(raw.contains("note = \"from the old laptop\""));
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        let rendered = render_entries(&env, &get_lockfile(&env).unwrap(), &bundle).unwrap();
+        assert!(rendered.lines().nth(1).unwrap().ends_with("linked  # from the old laptop"));
+        assert!(rendered.lines().next().unwrap().ends_with("linked"));
+
+        Disk::remove(config_dir.join("config")).unwrap();
+        let rendered = render_health(&check_status(&env).unwrap(), false).unwrap();
+        assert!(rendered.contains(&remote) && rendered.ends_with("  # from the old laptop"));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_info_should_show_everything_about_a_bundle() {
+        let (mut env, _) = setup();
+        seed_bundles(&env);
+
+        let mut bundle = read_bundle(&env, "alpha").unwrap();
+        bundle.hosts = vec![String::from("laptop"), String::from("work")];
+        bundle.base = Some(String::from("$HOME"));
+        bundle.dependencies = vec![String::from("gamma")];
+        bundle.entries.push(Entry { local: format!("{}/missing", env.storage.join("bundle/alpha").display()), ..bundle.entries[0].clone() });
+        write_bundle(&env, &bundle).unwrap();
+        cmd_link(&env, "alpha", &Default::default()).unwrap();
+
+        let out = PathBuf::from("/out/info");
+        env.output.path = Some(out.clone());
+
+        let info = cmd_info(&env, "alpha", false).expect("Info should have worked");
+        assert_eq!(info.size, get_bundle_size(&env, "alpha").unwrap());
+
+        let text = Disk::get(&out).unwrap();
+        let lines = text.lines().map(|it| it.split_whitespace().collect::<Vec<&str>>().join(" ")).collect::<Vec<String>>();
+        assert_eq!(lines, vec![
+            String::from("id: alpha"),
+            String::from("created: 200"),
+            String::from("enabled: true"),
+            String::from("hosts: laptop, work"),
+            String::from("base: $HOME"),
+            String::from("dependencies: gamma"),
+            String::from("entries: 2"),
+            format!("size: {} bytes", info.size),
+            String::from("linked: true"),
+            String::from("states: 1 linked, 1 missing source"),
+        ]);
+
+        cmd_info(&env, "alpha", true).unwrap();
+        let parsed = serde_json::from_str::<BundleInfo>(&Disk::get(&out).unwrap()).unwrap();
+        assert_eq!(parsed.hosts, info.hosts);
+        assert_eq!(parsed.states.get("missing source"), Some(&1));
+
+        assert!(matches!(cmd_info(&env, "missing", false), Err(Error::BundleNotFound)));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_list_should_render_the_output_template() {
+        let (mut env, _) = setup();
+        seed_bundles(&env);
+
+        let out = PathBuf::from("/out/list");
+        env.output.path = Some(out.clone());
+
+        cmd_list(&env, SortKey::Created, false, false, Some("{id}\\t{count}\\t{created} {{id}")).unwrap();
+        assert_eq!(Disk::get(&out).unwrap(), "gamma\t1\t100 {id}\nalpha\t1\t200 {id}\nbeta\t1\t {id}");
+
+        for template in &["{id} {tags}", "{id"] {
+            match cmd_list(&env, SortKey::Name, false, false, Some(template)) {
+                Err(Error::UnknownPlaceholder(_)) => {},
+                other => panic!("Expected an unknown placeholder, got {:?}", other),
+            };
+        }
+
+        clean();
+    }
+
+    #[test]
+    fn list_bundles_should_sort_by_name() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let ids = list_ids(&env, SortKey::Name, false);
+        assert_eq!(ids, vec!["alpha", "beta", "gamma"]);
+
+        let ids = list_ids(&env, SortKey::Name, true);
+        assert_eq!(ids, vec!["gamma", "beta", "alpha"]);
+
+        clean();
+    }
+
+    #[test]
+    fn list_bundles_should_sort_by_size() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let ids = list_ids(&env, SortKey::Size, false);
+        assert_eq!(ids, vec!["beta", "gamma", "alpha"]);
+
+        let ids = list_ids(&env, SortKey::Size, true);
+        assert_eq!(ids, vec!["alpha", "gamma", "beta"]);
+
+        clean();
+    }
+
+    #[test]
+    fn list_bundles_should_sort_by_created_with_unknown_last() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let ids = list_ids(&env, SortKey::Created, false);
+        assert_eq!(ids, vec!["gamma", "alpha", "beta"]);
+
+        let ids = list_ids(&env, SortKey::Created, true);
+        assert_eq!(ids, vec!["alpha", "gamma", "beta"]);
+
+        clean();
+    }
+
+    #[test]
+    fn status_json_should_list_broken_entries() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link_all(&env, &Default::default()).expect("Link should have worked");
+
+        let health = check_status(&env).expect("Status should have worked");
+        assert!(health.ok);
+
+        Disk::remove("/config/alpha").unwrap();
+        Disk::remove("/config/beta").unwrap();
+        Disk::put("/config/beta", "not a link").unwrap();
+        Disk::remove(env.storage.join("bundle/gamma/config")).unwrap();
+
+        let health = check_status(&env).expect("Status should have worked");
+        let json = render_health(&health, true).unwrap();
+        let parsed = serde_json::from_str::<Health>(&json).expect("Should be valid json");
+
+        let problem = |bundle: &str, kind| Problem {
+            bundle: String::from(bundle),
+            path: format!("/config/{}", bundle),
+            kind,
+            origin: None,
+            note: None,
+        };
+
+        assert!(!parsed.ok);
+        assert_eq!(parsed.problems, vec![
+            problem("alpha", ProblemKind::MissingLink),
+            problem("beta", ProblemKind::Foreign),
+            problem("gamma", ProblemKind::MissingSource),
+        ]);
+
+        let options = StatusOptions { json: true, ..Default::default() };
+        match cmd_status(&env, &options) {
+            Err(Error::Unhealthy(3)) => {},
+            other => panic!("Expected unhealthy status, got {:?}", other),
+        };
+
+        clean();
+    }
+
+    #[test]
+    fn doctor_check_should_only_report_through_the_result() {
+        let (mut env, _) = setup();
+        let out = env.storage.join("doctor.out");
+        env.output.path = Some(out.clone());
+
+        seed_bundles(&env);
+        let options = DoctorOptions { check: true, ..Default::default() };
+        cmd_doctor(&env, &options).expect("Doctor should have passed");
+
+        Disk::remove(env.storage.join("bundle/gamma/config")).unwrap();
+        match cmd_doctor(&env, &options) {
+            Err(Error::Unhealthy(1)) => {},
+            other => panic!("Expected unhealthy status, got {:?}", other),
+        };
+
+        assert!(!Disk::symlink_exists(&out));
+
+        clean();
+    }
+
+    #[test]
+    fn doctor_json_should_include_unlinked_bundles() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link(&env, "alpha", &Default::default()).expect("Link should have worked");
+
+        Disk::remove(env.storage.join("bundle/beta").join(BUNDLE_FILE)).unwrap();
+        Disk::remove(env.storage.join("bundle/gamma/config")).unwrap();
+
+        assert!(check_status(&env).unwrap().ok);
+
+        let health = check_doctor(&env).expect("Doctor should have worked");
+        let json = render_health(&health, true).unwrap();
+        let parsed = serde_json::from_str::<Health>(&json).expect("Should be valid json");
+
+        assert!(!parsed.ok);
+        assert_eq!(parsed.problems, vec![
+            Problem {
+                bundle: String::from("beta"),
+                path: String::new(),
+                kind: ProblemKind::MissingMeta,
+                origin: None,
+                note: None,
+            },
+            Problem {
+                bundle: String::from("gamma"),
+                path: String::from("/config/gamma"),
+                kind: ProblemKind::MissingSource,
+                origin: None,
+                note: None,
+            },
+        ]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_not_move_anything_it_cant_link_back() {
+        let (env, config_dir) = setup();
+        let remote = config_dir.join("a");
+        let local = env.storage.join("bundle/test_bundle/a");
+
+        Disk::set_readonly(&config_dir, true);
+
+        match cmd_add(&env, "test_bundle", &vec![remote.clone()], &Default::default()) {
+            Err(Error::PermissionDenied(path)) => assert_eq!(path, config_dir),
+            other => panic!("Expected a permission error, got {:?}", other),
+        };
+
+        assert!(Disk::is_dir(&remote) && !Disk::is_symlink(&remote));
+        assert!(!Disk::symlink_exists(&local));
+
+        clean();
+    }
+
+    #[test]
+    fn readonly_storage_should_only_allow_linking() {
+        let (mut env, config_dir) = setup();
+        let remote = config_dir.join("config");
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &vec![remote.clone()], &options).unwrap();
+
+        env.storage_readonly = true;
+        let lock = Disk::get(env.storage.join(LOCK_FILE)).ok();
+
+        match cmd_add(&env, "other_bundle", &vec![config_dir.join("a")], &Default::default()) {
+            Err(Error::StorageReadOnly) => {},
+            other => panic!("Expected storage to be read only, got {:?}", other),
+        };
+
+        assert!(!Disk::is_symlink(config_dir.join("a")));
+        assert!(!Disk::symlink_exists(env.storage.join("bundle/other_bundle")));
+
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+        assert!(links_to(&remote, &env.storage.join("bundle/test_bundle/config")));
+        assert_eq!(Disk::get(env.storage.join(LOCK_FILE)).ok(), lock);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_reindex_should_rebuild_a_lost_lock() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+        cmd_add(&env, "other_bundle", &vec![config_dir.join("b")], &Default::default()).unwrap();
+        cmd_unlink(&env, "other_bundle", &Default::default()).unwrap();
+
+        let lock_path = env.storage.join(LOCK_FILE);
+        let before = Disk::get(&lock_path).unwrap();
+        Disk::remove(&lock_path).unwrap();
+
+        cmd_reindex(&env).expect("Reindexing should have worked");
+
+        assert_eq!(Disk::get(&lock_path).unwrap(), before);
+        assert!(!Disk::symlink_exists(env.storage.join(BACKUP_DIR)));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_reindex_should_only_count_entries_in_place() {
+        let (env, config_dir) = setup();
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+        let lock_path = env.storage.join(LOCK_FILE);
+        Disk::put(&lock_path, "not a lock").unwrap();
+        Disk::remove(config_dir.join("config")).unwrap();
+
+        cmd_reindex(&env).expect("Reindexing should have worked");
+
+        let lockfile = get_lockfile(&env).unwrap();
+        assert_eq!(lockfile.linked.len(), 1);
+        assert_eq!(lockfile.linked[0].only, vec![format!("{}", config_dir.join("a").display())]);
+        assert_eq!(lockfile.linked[0].entries.len(), 2);
+
+        // the broken lock is kept around
+        let backups = Disk::walk(env.storage.join(BACKUP_DIR)).unwrap();
+        let saved = backups.iter().find(|it| it.ends_with(LOCK_FILE)).expect("Lock should have been backed up");
+        assert_eq!(Disk::get(saved).unwrap(), "not a lock");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_export_should_stream_bundles_to_import() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        let paths = vec![config_dir.join("a"), config_dir.join("config")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).unwrap();
+
+        Disk::put_bytes(bundle_dir.join("font.ttf"), &[0, 159, 146, 150, 255]).unwrap();
+
+        let mut streamed = Vec::new();
+        cmd_export(&env, "test_bundle", &mut streamed).expect("Export should have worked");
+        let before = Manifest::generate(&bundle_dir).unwrap();
+
+        Disk::remove(&bundle_dir).unwrap();
+        let id = cmd_import(&env, &mut streamed.as_slice()).expect("Import should have worked");
+
+        assert_eq!(id, "test_bundle");
+        assert_eq!(Manifest::generate(&bundle_dir).unwrap(), before);
+        assert_eq!(Disk::get_bytes(bundle_dir.join("font.ttf")).unwrap(), vec![0, 159, 146, 150, 255]);
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().entries.len(), 2);
+
+        // what's already there is left alone
+        match cmd_import(&env, &mut streamed.as_slice()) {
+            Err(Error::StorageEntryExists(_)) => {},
+            other => panic!("Expected the bundle to exist, got {:?}", other),
+        };
+
+        assert!(!Disk::symlink_exists(env.storage.join(IMPORT_DIR)));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_import_should_point_locals_into_this_storage() {
+        let (mut env, config_dir) = setup();
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default()).unwrap();
+
+        let mut streamed = Vec::new();
+        cmd_export(&env, "test_bundle", &mut streamed).unwrap();
+
+        env.storage = PathBuf::from("/elsewhere/dotgirl");
+        Disk::mkdir_all(&env.storage).unwrap();
+        cmd_import(&env, &mut streamed.as_slice()).expect("Import should have worked");
+
+        let bundle = read_bundle(&env, "test_bundle").unwrap();
+        assert_eq!(bundle.entries[0].local, "/elsewhere/dotgirl/bundle/test_bundle/config");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_import_should_refuse_tampered_archives() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default()).unwrap();
+
+        // edited through the link after the manifest was written
+        Disk::put(bundle_dir.join("config"), "hello edited").unwrap();
+
+        let mut streamed = Vec::new();
+        cmd_export(&env, "test_bundle", &mut streamed).expect("Export should have worked");
+        Disk::remove(&bundle_dir).unwrap();
+
+        let at = streamed
+            .windows(12)
+            .position(|it| it == b"hello edited")
+            .expect("Archive should contain the file");
+        streamed[at..at + 12].copy_from_slice(b"hello tamper");
+
+        match cmd_import(&env, &mut streamed.as_slice()) {
+            Err(Error::ManifestMismatch(paths)) => assert_eq!(paths, vec!["config"]),
+            other => panic!("Expected a manifest mismatch, got {:?}", other),
+        };
+
+        assert!(!Disk::symlink_exists(&bundle_dir));
+        assert!(!Disk::symlink_exists(env.storage.join(IMPORT_DIR)));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_print_lock_should_show_the_parsed_lock() {
+        let (mut env, _) = setup();
+        let out = env.storage.join("lock.out");
+        env.output.path = Some(out.clone());
+
+        let raw = "[[linked]]\nid = \"beta\"\n\n[[linked]]\nid = \"alpha\"\nhost = \"laptop\"\n";
+        Disk::put(env.storage.join(LOCK_FILE), raw).unwrap();
+
+        cmd_print_lock(&env, LockDump::Parsed).unwrap();
+        assert_eq!(Disk::get(&out).unwrap(), format!("{:#?}", Lock {
+            linked: vec![
+                Linked { id: String::from("beta"), host: None, ..Default::default() },
+                Linked { id: String::from("alpha"), host: Some(String::from("laptop")), ..Default::default() },
+            ],
+        }));
+
+        cmd_print_lock(&env, LockDump::Canonical).unwrap();
+        assert_eq!(
+            Disk::get(&out).unwrap(),
+            "[[linked]]\nid = \"alpha\"\nhost = \"laptop\"\n\n[[linked]]\nid = \"beta\"\n",
+        );
+
+        cmd_print_lock(&env, LockDump::Raw).unwrap();
+        assert_eq!(Disk::get(&out).unwrap(), raw);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_verify_links_should_pass_when_everything_is_linked() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link_all(&env, &Default::default()).expect("Link should have worked");
+
+        cmd_verify_links(&env).expect("All links should be fine");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_verify_links_should_fail_for_broken_links() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link_all(&env, &Default::default()).expect("Link should have worked");
+
+        Disk::remove("/config/alpha").unwrap();
+        Disk::put("/config/alpha", "replaced").unwrap();
+
+        match cmd_verify_links(&env) {
+            Err(Error::Unhealthy(1)) => {},
+            other => panic!("Expected one broken link, got {:?}", other),
+        };
+
+        clean();
+    }
+
+    #[test]
+    fn check_lock_should_pass_for_linked_bundles() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+        cmd_link_all(&env, &Default::default()).expect("Link should have worked");
+
+        // nothing on disk is looked at besides the bundle dirs
+        Disk::remove("/config/alpha").unwrap();
+        assert!(check_lock(&env).unwrap().ok);
+
+        clean();
+    }
+
+    #[test]
+    fn check_lock_should_report_inconsistencies() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        Disk::put(env.storage.join(LOCK_FILE), r#"
+            [[linked]]
+            id = "alpha"
+            entries = [{ local = "/dotgirl/bundle/alpha/config", remote = "/config/shared" }]
+
+            [[linked]]
+            id = "beta"
+            entries = [
+                { local = "/dotgirl/bundle/beta/config", remote = "/config/shared" },
+                { local = "", remote = "/config/beta" },
+                { local = "/dotgirl/bundle/beta/config", remote = "/dotgirl/bundle/beta/config" },
+            ]
+
+            [[linked]]
+            id = "alpha"
+
+            [[linked]]
+            id = "missing"
+        "#).unwrap();
+
+        let problem = |bundle: &str, path: &str, kind| Problem {
+            bundle: String::from(bundle),
+            path: String::from(path),
+            kind,
+            origin: None,
+            note: None,
+        };
+
+        let health = check_lock(&env).expect("Validating should have worked");
+        assert_eq!(health.problems, vec![
+            problem("beta", "", ProblemKind::EmptyPath),
+            problem("beta", "/dotgirl/bundle/beta/config", ProblemKind::SelfReferential),
+            problem("beta", "/config/shared", ProblemKind::DuplicateRemote),
+            problem("alpha", "", ProblemKind::DuplicateBundle),
+            problem("missing", "", ProblemKind::MissingBundle),
+        ]);
+
+        // a lock that doesn't parse can't be checked at all
+        Disk::put(env.storage.join(LOCK_FILE), "[[linked]]\nid = 1").unwrap();
+        match check_lock(&env) {
+            Err(Error::ParseError(_)) => {},
+            other => panic!("Expected parse error, got {:?}", other),
+        };
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_link_dependencies_first() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        for (id, dependencies) in &[("alpha", vec!["beta"]), ("beta", vec!["gamma"]), ("gamma", vec![])] {
+            let mut bundle = read_bundle(&env, id).unwrap();
+            bundle.dependencies = dependencies.iter().map(|it| String::from(*it)).collect();
+            bundle.hooks.after_link = Some(format!("echo {}", id));
+            write_bundle(&env, &bundle).unwrap();
+        }
+
+        let options = LinkOptions { no_deps: true, ..Default::default() };
+        cmd_link(&env, "alpha", &options).expect("Link should have worked");
+        assert!(!Disk::symlink_exists("/config/beta"));
+
+        cmd_link(&env, "alpha", &Default::default()).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/beta"));
+        assert!(Disk::is_symlink("/config/gamma"));
+        assert_eq!(get_lockfile(&env).unwrap().linked.len(), 3);
+
+        let hooks = Shell::calls().into_iter().map(|(it, _)| it).collect::<Vec<String>>();
+        assert_eq!(hooks, vec!["echo alpha", "echo gamma", "echo beta", "echo alpha"]);
+
+        // linked dependencies are left as they are
+        Shell::clear();
+        cmd_link(&env, "alpha", &Default::default()).expect("Link should have worked");
+        let hooks = Shell::calls().into_iter().map(|(it, _)| it).collect::<Vec<String>>();
+        assert_eq!(hooks, vec!["echo alpha"]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_link_should_refuse_dependency_cycles() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        for (id, dependency) in &[("alpha", "beta"), ("beta", "gamma"), ("gamma", "beta")] {
+            let mut bundle = read_bundle(&env, id).unwrap();
+            bundle.dependencies = vec![String::from(*dependency)];
+            write_bundle(&env, &bundle).unwrap();
+        }
+
+        match cmd_link(&env, "alpha", &Default::default()) {
+            Err(Error::DependencyCycle(cycle)) => assert_eq!(cycle, vec!["beta", "gamma", "beta"]),
+            other => panic!("Expected a dependency cycle, got {:?}", other),
+        };
+
+        assert!(!Disk::symlink_exists("/config/alpha"));
+        assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+        clean();
+    }
+
+    #[test]
+    fn link_should_run_after_link_hook() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        let mut bundle = read_bundle(&env, "alpha").unwrap();
+        bundle.hooks.after_link = Some(String::from("fc-cache"));
+        write_bundle(&env, &bundle).unwrap();
+
+        // a failing hook is only reported
+        Shell::set_exit_code(1);
+        cmd_link(&env, "alpha", &Default::default()).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/alpha"));
+        assert_eq!(Shell::calls(), vec![
+            (String::from("fc-cache"), env.storage.join("bundle/alpha")),
+        ]);
+
+        cmd_unlink(&env, "alpha", &Default::default()).expect("Unlink should have worked");
+
+        let options = LinkOptions { no_hooks: true, ..Default::default() };
+        cmd_link(&env, "alpha", &options).expect("Link should have worked");
+        assert_eq!(Shell::calls().len(), 1);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_sync_should_relink_changed_bundles_between_pull_and_push() {
+        let (env, config_dir) = setup();
+        assert!(cmd_sync(&env).is_err());
+        assert!(Shell::calls().is_empty());
+
+        Disk::mkdir_all(env.storage.join(".git")).unwrap();
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default()).unwrap();
+        cmd_add(&env, "other", &vec![config_dir.join("b")], &Default::default()).unwrap();
+
+        let mut bundle = read_bundle(&env, "test_bundle").unwrap();
+        bundle.hooks.after_link = Some(String::from("relinked"));
+        write_bundle(&env, &bundle).unwrap();
+
+        // what the pull would have changed, the manifest stays behind
+        Disk::put(env.storage.join("bundle/test_bundle/config"), "pulled").unwrap();
+
+        cmd_sync(&env).expect("Sync should have worked");
+        assert_eq!(Shell::calls(), vec![
+            (String::from("git pull --rebase"), env.storage.clone()),
+            (String::from("relinked"), env.storage.join("bundle/test_bundle")),
+            (String::from("git push"), env.storage.clone()),
+        ]);
+
+        // nothing changed this time, and a failed pull stops everything
+        Shell::clear();
+        Shell::set_exit_code(1);
+        match cmd_sync(&env) {
+            Err(Error::GitError(_)) => {},
+            other => panic!("Expected a git error, got {:?}", other),
+        };
+
+        assert_eq!(Shell::calls(), vec![(String::from("git pull --rebase"), env.storage.clone())]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_sync_should_relink_the_host_a_bundle_was_linked_from() {
+        let (env, config_dir) = setup();
+        Disk::mkdir_all(env.storage.join(".git")).unwrap();
+
+        let paths = vec![config_dir.join("config"), config_dir.join("b")];
+        let options = AddOptions { no_link: true, ..Default::default() };
+        cmd_add(&env, "test_bundle", &paths, &options).unwrap();
+
+        let mut bundle = read_bundle(&env, "test_bundle").unwrap();
+        for it in bundle.entries.iter_mut() {
+            let host = if it.remote.ends_with("config") { "desktop" } else { "laptop" };
+            it.host = Some(String::from(host));
+        }
+        write_bundle(&env, &bundle).unwrap();
+
+        let options = LinkOptions { from: Some(String::from("desktop")), ..Default::default() };
+        cmd_link(&env, "test_bundle", &options).expect("Link should have worked");
+
+        Disk::put(env.storage.join("bundle/test_bundle/config"), "pulled").unwrap();
+        cmd_sync(&env).expect("Sync should have worked");
+
+        assert!(Disk::is_symlink(config_dir.join("config")));
+        assert!(!Disk::symlink_exists(config_dir.join("b")));
+        assert_eq!(get_lockfile(&env).unwrap().linked[0].host, Some(String::from("desktop")));
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_sync_should_only_report_commands_when_dry_running() {
+        let (env, config_dir) = setup();
+        Disk::mkdir_all(env.storage.join(".git")).unwrap();
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("config")], &Default::default()).unwrap();
+
+        let mut bundle = read_bundle(&env, "test_bundle").unwrap();
+        bundle.hooks.after_link = Some(String::from("relinked"));
+        write_bundle(&env, &bundle).unwrap();
+        let lock = Disk::get(env.storage.join(LOCK_FILE)).unwrap();
+
+        disk::set_dry_run(true);
+        let result = cmd_sync(&env);
+        disk::set_dry_run(false);
+
+        result.expect("Sync should have worked");
+        assert!(Shell::calls().is_empty());
+        assert_eq!(Disk::get(env.storage.join(LOCK_FILE)).unwrap(), lock);
+
+        clean();
+    }
+
+    #[test]
+    fn after_link_hook_should_not_run_if_link_fails() {
+        let (env, _) = setup();
+        seed_bundles(&env);
+
+        for id in &["alpha", "beta"] {
+            let mut bundle = read_bundle(&env, id).unwrap();
+            bundle.hooks.after_link = Some(format!("hook {}", id));
+            write_bundle(&env, &bundle).unwrap();
+        }
+
+        // hardlinking a file that's gone from storage fails
+        Disk::remove(env.storage.join("bundle/beta/config")).unwrap();
+        let options = LinkOptions {
+            link_type: Some(LinkType::Hardlink),
+            ..Default::default()
+        };
+
+        assert!(cmd_link_all(&env, &options).is_err());
+        assert_eq!(Shell::calls(), vec![
+            (String::from("hook alpha"), env.storage.join("bundle/alpha")),
+        ]);
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_write_manifest() {
+        let (env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+
+        let paths = vec![config_dir.join("a"), config_dir.join("b")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+
+        let manifest = Manifest::load(&bundle_dir).unwrap().expect("Manifest should exist");
+        let files = manifest.files.keys().map(String::as_str).collect::<Vec<&str>>();
+        assert_eq!(files, vec!["a/.hidden-config", "a/config", "a/sub/config", "b/config"]);
+
+        cmd_verify_manifest(&env, "test_bundle").expect("Manifest should match");
+
+        clean();
+    }
+
+    #[test]
+    fn cmd_add_should_hash_files_that_are_not_text() {
+        let (env, config_dir) = setup();
+        let font = config_dir.join("font.ttf");
+        Disk::put_bytes(&font, &[0, 159, 146, 150, 255]).unwrap();
+
+        cmd_add(&env, "test_bundle", &vec![font.clone()], &Default::default()).expect("Add should have worked");
+
+        let manifest = Manifest::load(&env.bundle_dir().join("test_bundle")).unwrap().unwrap();
+        assert!(manifest.files.contains_key("font.ttf"));
+        assert!(Disk::is_symlink(&font));
+        assert_eq!(get_lockfile(&env).unwrap().linked[0].id, "test_bundle");
+        cmd_verify_manifest(&env, "test_bundle").expect("Manifest should match");
+
+        clean();
+    }
+
+    #[test]
+    fn link_should_refuse_corrupted_files() {
+        let (mut env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+
+        let paths = vec![config_dir.join("a"), config_dir.join("b")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+        cmd_unlink(&env, "test_bundle", &Default::default()).expect("Unlink should have worked");
+
+        Disk::put(bundle_dir.join("a/config"), "tampered").unwrap();
+        Disk::remove(bundle_dir.join("b/config")).unwrap();
+
+        match cmd_verify_manifest(&env, "test_bundle") {
+            Err(Error::ManifestMismatch(paths)) => assert_eq!(paths, vec!["a/config", "b/config"]),
+            other => panic!("Expected a manifest mismatch, got {:?}", other),
+        };
+
+        env.verify = true;
+        assert!(cmd_link(&env, "test_bundle", &Default::default()).is_err());
+        assert!(!Disk::is_symlink(config_dir.join("a")));
+
+        clean();
+    }
+
+    #[test]
+    fn read_bundle_should_check_the_checksum_when_verifying() {
+        let (mut env, config_dir) = setup();
+        let bundle_dir = env.storage.join("bundle/test_bundle");
+        env.verify = true;
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("a")], &Default::default()).unwrap();
+        assert!(get_lockfile(&env).unwrap().linked[0].checksum.is_some());
+        read_bundle(&env, "test_bundle").expect("Checksum should match");
+
+        Disk::put(bundle_dir.join("a/config"), "tampered").unwrap();
+        match read_bundle(&env, "test_bundle") {
+            Err(Error::BundleChecksumMismatch(it)) => assert_eq!(it, "test_bundle"),
+            other => panic!("Expected a checksum mismatch, got {:?}", other),
+        };
+
+        // locks from before checksums can't be verified
+        let mut lockfile = get_lockfile(&env).unwrap();
+        lockfile.linked[0].checksum = None;
+        write_lockfile(&env, &lockfile).unwrap();
+        read_bundle(&env, "test_bundle").expect("Missing checksums should be trusted");
+
+        clean();
+    }
+
+    #[test]
+    fn checksum_should_cover_metadata_and_survive_linking() {
+        let (mut env, config_dir) = setup();
+        let bundle_file = env.storage.join("bundle/test_bundle").join(BUNDLE_FILE);
+
+        cmd_add(&env, "test_bundle", &vec![config_dir.join("a")], &Default::default()).unwrap();
+        let checksum = get_lockfile(&env).unwrap().linked[0].checksum.clone();
+
+        let tampered = format!("{}\n# tampered\n", Disk::get(&bundle_file).unwrap());
+        Disk::put(&bundle_file, &tampered).unwrap();
+
+        // linking without verifying mustn't make the change look fine
+        cmd_link(&env, "test_bundle", &Default::default()).expect("Link should have worked");
+        assert_eq!(get_lockfile(&env).unwrap().linked[0].checksum, checksum);
+
+        env.verify = true;
+        match read_bundle(&env, "test_bundle") {
+            Err(Error::BundleChecksumMismatch(it)) => assert_eq!(it, "test_bundle"),
+            other => panic!("Expected a checksum mismatch, got {:?}", other),
+        };
+
+        clean();
+    }
+
+    #[test]
+    fn pre_add_hook_should_abort_add() {
+        let (env, config_dir) = setup();
+
+        let paths = vec![config_dir.join("a")];
+        cmd_add(&env, "test_bundle", &paths, &Default::default()).expect("Add should have worked");
+
+        let mut bundle = read_bundle(&env, "test_bundle").unwrap();
+        bundle.hooks.pre_add = Some(String::from("exit 1"));
+        write_bundle(&env, &bundle).unwrap();
+
+        Shell::set_exit_code(1);
+        let paths = vec![config_dir.join("b")];
+        match cmd_add(&env, "test_bundle", &paths, &Default::default()) {
+            Err(Error::HookFailed(1)) => {},
+            other => panic!("Expected the hook to fail, got {:?}", other),
+        };
+
+        assert!(Disk::is_dir(config_dir.join("b")));
+        assert!(!Disk::is_symlink(config_dir.join("b")));
+        assert_eq!(read_bundle(&env, "test_bundle").unwrap().entries.len(), 1);
+
+        clean();
+    }
+
+    #[test]
+    fn link_should_only_link_entries_for_host() {
+        let (env, _) = setup();
+        let dir = env.storage.join("bundle/shell");
+
+        let entry = |name: &str, host: Option<&str>| {
+            Disk::put(dir.join(name), "content").unwrap();
+            Entry {
+                local: format!("{}", dir.join(name).display()),
+                remote: format!("/config/{}", name),
+                link_type: LinkType::Symlink,
+                host: host.map(String::from),
+                on_conflict: None,
+                origin: None,
+                note: None,
+                per_file: false,
+                xattrs: Default::default(),
+            }
+        };
+
+        Disk::mkdir_all(&dir).unwrap();
+        let bundle = Bundle {
+            id: String::from("shell"),
+            created: None,
+            enabled: true,
+            base: None,
+            hosts: vec![],
+            dependencies: vec![],
+            hooks: Default::default(),
+            entries: vec![
+                entry("shared", None),
+                entry("laptop", Some("laptop")),
+                entry("desktop", Some("desktop")),
+            ],
+        };
+        write_bundle(&env, &bundle).unwrap();
+
+        // defaults to the current host
+        cmd_link(&env, "shell", &Default::default()).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/shared"));
+        assert!(Disk::is_symlink("/config/laptop"));
+        assert!(!Disk::symlink_exists("/config/desktop"));
+
+        cmd_unlink(&env, "shell", &Default::default()).expect("Unlink should have worked");
+
+        let options = LinkOptions { from: Some(String::from("desktop")), ..Default::default() };
+        cmd_link(&env, "shell", &options).expect("Link should have worked");
+        assert!(Disk::is_symlink("/config/shared"));
+        assert!(!Disk::symlink_exists("/config/laptop"));
+        assert!(Disk::is_symlink("/config/desktop"));
+
+        let lockfile = get_lockfile(&env).unwrap();
+        assert_eq!(lockfile.linked[0].host, Some(String::from("desktop")));
+
+        // a status check looks at the host that was linked
+        assert!(check_status(&env).unwrap().ok);
+
+        cmd_unlink(&env, "shell", &Default::default()).expect("Unlink should have worked");
+        assert!(!Disk::symlink_exists("/config/shared"));
+        assert!(!Disk::symlink_exists("/config/desktop"));
+
+        clean();
+    }
+
+    fn list_ids(env: &Env, sort: SortKey, reverse: bool) -> Vec<String> {
+        list_bundles(env, sort, reverse)
+            .expect("List should have worked")
+            .into_iter()
+            .map(|it| it.id)
+            .collect()
+    }
+
+    #[test]
+    fn schema_should_describe_every_field() {
+        let fields = |schema: &serde_json::Value| {
+            let properties = schema["properties"]
+                .as_object()
+                .expect("Schema should have properties")
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>();
+
+            let required = schema["required"]
+                .as_array()
+                .expect("Schema should list required fields")
+                .iter()
+                .map(|it| String::from(it.as_str().unwrap()))
+                .collect::<Vec<String>>();
+
+            (properties, required)
+        };
+
+        let bundle = serde_json::to_value(schema(SchemaKind::Bundle)).unwrap();
+        let (properties, required) = fields(&bundle);
+        assert_eq!(properties, vec!["base", "created", "dependencies", "enabled", "entries", "hooks", "hosts", "id"]);
+        assert_eq!(required, vec!["entries", "id"]);
+
+        let (properties, required) = fields(&bundle["definitions"]["Entry"]);
+        assert_eq!(properties, vec![
+            "host", "link_type", "local", "note", "on_conflict", "origin", "per_file", "remote", "xattrs",
+        ]);
+        assert_eq!(required, vec!["local", "remote"]);
+
+        let lock = serde_json::to_value(schema(SchemaKind::Lock)).unwrap();
+        let (properties, required) = fields(&lock);
+        assert_eq!(properties, vec!["linked"]);
+        assert_eq!(required, vec!["linked"]);
+
+        let (properties, required) = fields(&lock["definitions"]["Linked"]);
+        assert_eq!(properties, vec!["base", "checksum", "created", "dependencies", "entries", "hooks", "host", "hosts", "id", "merged", "only", "suffix"]);
+        assert_eq!(required, vec!["id"]);
+    }
+
+    // Seeds three bundles with distinct sizes and creation times:
+    //   alpha: largest, created second
+    //   beta: smallest, no creation time
+    //   gamma: medium, created first
+    fn seed_bundles(env: &Env) {
+        let seed = |id: &str, created: Option<u64>, content: &str| {
+            let dir = env.bundle_dir().join(id);
+            Disk::mkdir_all(&dir).unwrap();
+            Disk::put(dir.join("config"), content).unwrap();
+
+            let bundle = Bundle {
+                id: String::from(id),
+                created,
+                enabled: true,
+                base: None,
+                hosts: vec![],
+                dependencies: vec![],
+                hooks: Default::default(),
+                entries: vec![Entry {
+                    local: format!("{}", dir.join("config").display()),
+                    remote: format!("/config/{}", id),
+                    link_type: LinkType::Symlink,
+                    host: None,
+                    on_conflict: None,
+                    origin: None,
+                    note: None,
+                    per_file: false,
+                    xattrs: Default::default(),
+                }],
+            };
+
+            let ser = toml::to_string(&bundle).unwrap();
+            Disk::put(dir.join(BUNDLE_FILE), &ser).unwrap();
+        };
+
+        seed("alpha", Some(200), "a much longer config file");
+        seed("beta", None, "x");
+        seed("gamma", Some(100), "medium size");
+    }
+
+    // Returns a temp env and a folder with test files
+    // ./test_tmp
+    //     /dotgirl (storage dir)
+    //     /config
+    //         config
+    //         .hidden-config
+    //         /a
+    //             /sub
+    //                 config
+    //                 .hidden-config
+    //             config
+    //         /b
+    //             config
+    //
+    fn setup() -> (Env, PathBuf) {
+        let root = PathBuf::from("/");
+
+        let storage = root.join(STORAGE_DIR);
+        Disk::mkdir_all(&storage).unwrap();
+
+        let conf = root.join("config");
+
+        let conf_a = conf.join("a");
+        let conf_a_sub = conf_a.join("sub");
+        Disk::mkdir_all(&conf_a_sub).unwrap();
+
+        Disk::put(conf.join("config"), "hello config").unwrap();
+        Disk::put(conf.join(".hidden-config"), "hello config").unwrap();
+
+        Disk::put(conf_a.join("config"), "hello config").unwrap();
+        Disk::put(conf_a.join(".hidden-config"), "hello config").unwrap();
+        Disk::put(conf_a_sub.join("config"), "hello config").unwrap();
+
+        let conf_b = conf.join("b");
+        Disk::mkdir_all(&conf_b).unwrap();
+        Disk::put(conf_b.join("config"), "hello config").unwrap();
+
+        let env = Env {
+            home: root,
+            storage,
+            config: Default::default(),
+            host: String::from("laptop"),
+            interactive: false,
+            output: Default::default(),
+            update_lock: true,
+            clock: Clock::Fixed(1_600_000_000),
+            verify: false,
+            storage_readonly: false,
+            resolver: Rc::new(DialoguerResolver::default()),
+        };
+
+        (env, conf)
+    }
+
+    fn clean() {
+        Disk::clear();
+        Shell::clear();
+    }
+}
+
@@ -1,453 +1,561 @@
+use std::io::Read;
 use std::path::{Path, PathBuf};
-use clap::{clap_app, AppSettings};
-use serde::{Serialize, Deserialize};
-use dialoguer::{Confirmation, Select, theme};
-
-mod util;
-mod disk;
-
-use disk::{Disk, Filesystem as _};
-
-const STORAGE_DIR: &'static str = "dotgirl";
-const BUNDLE_DIR: &'static str = "bundle";
-
-// const CONFIG_FILE: &'static str = "config.toml";
-const LOCK_FILE: &'static str = "lock.toml";
-const BUNDLE_FILE: &'static str = "bundle.toml";
-
-#[derive(Debug)]
-pub enum Error {
-    IoError(std::io::Error),
-    IoExtraError(fs_extra::error::Error),
-    HomedirNotFound,
-    ParseError(toml::de::Error),
-    SerializeError(toml::ser::Error),
-    LastComponentInvalid(String),
-    BundleNotFound,
-    BundleMissingMeta,
-    Simple(&'static str),
-}
-
-impl std::convert::From<fs_extra::error::Error> for Error {
-    fn from(error: fs_extra::error::Error) -> Self {
-        Error::IoExtraError(error)
-    }
-}
+use std::rc::Rc;
+use clap::{clap_app, AppSettings, ArgMatches};
 
-impl std::convert::From<std::io::Error> for Error {
-    fn from(error: std::io::Error) -> Self {
-        Error::IoError(error)
-    }
-}
-
-impl std::convert::From<toml::de::Error> for Error {
-    fn from(error: toml::de::Error) -> Self {
-        Error::ParseError(error)
-    }
-}
-
-impl std::convert::From<toml::ser::Error> for Error {
-    fn from(error: toml::ser::Error) -> Self {
-        Error::SerializeError(error)
-    }
-}
-
-type Result<T> = std::result::Result<T, Error>;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Bundle {
-    id: String,
-    entries: Vec<Entry>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Entry {
-    local: String,
-    remote: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Linked {
-    id: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Lock {
-    linked: Vec<Linked>,
-}
-
-#[derive(Debug, Clone)]
-struct Env {
-    storage: PathBuf,
-}
-
-impl Default for Lock {
-    fn default() -> Self {
-        Lock {
-            linked: vec![],
-        }
-    }
-}
+use dotgirl::{
+    Env, Error, Result, AddOptions, LinkOptions, UnlinkOptions, GcOptions, StatusOptions, DoctorOptions,
+    LinkType, Reflink, SortKey, SchemaKind, LockDump, Config, ConfigOverrides, Output, DialoguerResolver,
+    expand_input, target_home, set_tracing, set_dry_run, cmd_add, cmd_link, cmd_link_all, cmd_unlink, cmd_unlink_all, cmd_rename_entry, cmd_sync, cmd_which, cmd_export, cmd_import, cmd_reindex, cmd_list, cmd_info,
+    cmd_status, cmd_doctor, cmd_validate, cmd_verify_manifest, cmd_verify_links, cmd_gc, cmd_enable, cmd_disable, cmd_schema, cmd_print_lock,
+};
 
 fn main() -> Result<()> {
     let matches = clap_app!(dotgirl =>
         (version: env!("CARGO_PKG_VERSION"))
         (author: env!("CARGO_PKG_AUTHORS"))
         (about: env!("CARGO_PKG_DESCRIPTION"))
+        (@arg conflict: --conflict +global +takes_value possible_value[prompt skip overwrite] "what to do with existing files when linking")
+        (@arg backup: --backup +global conflicts_with[no_backup] "back up existing files before overwriting them")
+        (@arg no_backup: --("no-backup") +global "don't back up existing files")
+        (@arg color: --color +global +takes_value possible_value[auto always never] "when to use colors")
+        (@arg editor: --editor +global +takes_value "editor to open files with, instead of the one in the config")
+        (@arg format: --format +global +takes_value possible_value[text json] "how to print command results")
+        (@arg out: --out +global +takes_value "write command results to this file instead of stdout")
+        (@arg summary: --summary +global "end commands that change entries with a count of what they did")
+        (@arg no_lock_update: --("no-lock-update") +global "change links without recording it in the lock")
+        (@arg trace: --trace +global "print every change to the filesystem as it happens")
+        (@arg dry_run: --("dry-run") +global "show what would change without changing anything")
+        (@arg verify: --verify +global "refuse bundles whose storage changed since they were linked")
+        (@arg storage_readonly: --("storage-readonly") +global "only link and unlink, without changing anything in storage")
+        (@arg target_home: --("target-home") +global +takes_value "place links in this home instead of your own, also read from DOTGIRL_TARGET_HOME")
         (@subcommand add =>
             (about: "add to a bundle")
             (@arg BUNDLE: +required "bundle name")
-            (@arg INPUT: +required ... "input")
+            (@arg INPUT: required_unless_one(&["remote", "bundle_from_dir", "paths_from_git"]) ... "input")
+            (@arg name: -n --name +takes_value "name to store the input as (single input only)")
+            (@arg force: -f --force "overwrite existing entries in storage")
+            (@arg link_type: -t --("link-type") +takes_value possible_value[symlink junction hardlink copy] "how to link the inputs")
+            (@arg follow_symlinks: --("follow-symlinks") "add the targets of symlinked inputs")
+            (@arg host: --host +takes_value "only link the inputs on this host")
+            (@arg keep_dots: --("keep-dots") "keep leading dots in stored names")
+            (@arg no_link: --("no-link") "only move the inputs into storage, without linking them")
+            (@arg archive: --archive conflicts_with[link_type] "store directories as a single archive that's extracted when linking")
+            (@arg parents_only: --("parents-only") conflicts_with[archive link_type] "only store the directories inside, and create them when linking")
+            (@arg stdin_content: --("stdin-content") requires[remote] conflicts_with[archive follow_symlinks] "create the entry from what's piped in")
+            (@arg remote: --remote +takes_value "where to link the single input or the piped in content")
+            (@arg bundle_from_dir: --("bundle-from-dir") +takes_value conflicts_with[INPUT remote name archive] "add each child of a directory laid out like home")
+            (@arg paths_from_git: --("paths-from-git") +takes_value conflicts_with[INPUT remote name archive bundle_from_dir] "add every file tracked by a git repo laid out like home")
+            (@arg max_parallel: --("max-parallel") +takes_value default_value("1") "how many inputs to copy into storage at once")
+            (@arg reflink: --reflink +takes_value possible_value[auto always never] default_value("auto") "share data with the inputs when copying them into storage")
+            (@arg relative_to: --("relative-to") +takes_value "record the inputs relative to this directory")
+            (@arg confirm: --confirm "show what's about to happen and ask before moving anything")
+            (@arg xattrs: --xattrs "keep the extended attributes of added files for copies of them")
+            (@arg note: --note +takes_value conflicts_with[note_for] "why the single input was added")
+            (@arg note_for: --("note-for") +takes_value +multiple number_of_values(2) value_names(&["REMOTE", "TEXT"]) "why one of the inputs was added")
+            (@arg only_new: --("only-new") "leave out inputs that are already linked into the bundle")
         )
         (@subcommand link =>
             (about: "link a bundle")
-            (@arg BUNDLE: +required "bundle name")
+            (@arg BUNDLE: required_unless[all] conflicts_with[all] "bundle name")
+            (@arg all: -a --all "link all bundles")
+            (@arg link_type: -t --("link-type") +takes_value possible_value[symlink junction hardlink copy] "how to link the entries")
+            (@arg no_hooks: --("no-hooks") "don't run the bundle's after_link hook")
+            (@arg from: --from +takes_value "link the entries of this host instead of the current one")
+            (@arg sudo: --sudo "retry with sudo when not allowed to write somewhere")
+            (@arg quiet_skip: --("quiet-skip") "don't print entries that were skipped")
+            (@arg ignore_cross_bundle: --("ignore-cross-bundle") "link entries even if another bundle manages them")
+            (@arg relative: --relative "create symlinks relative to where they are")
+            (@arg entry: -e --entry +takes_value +multiple number_of_values(1) conflicts_with[all] "only link the entry at this path")
+            (@arg no_deps: --("no-deps") "don't link the bundle's dependencies first")
+            (@arg per_file: --("per-file") "link directories as real directories with each file linked on its own")
+            (@arg preserve_mtime: --("preserve-mtime") "keep the modification times from storage for copies")
+            (@arg verbose: -v --verbose "print what happened to every entry")
+            (@arg force_host: --("force-host") "link bundles even on hosts they aren't meant for")
+            (@arg hostname_suffix: --("hostname-suffix") +takes_value "prefer entries stored with this suffix over generic ones, defaults to the host")
         )
         (@subcommand unlink =>
             (about: "unlink a bundle")
+            (@arg BUNDLE: required_unless[all] conflicts_with[all] "bundle name")
+            (@arg all: -a --all "unlink all linked bundles")
+            (@arg restore: --restore "put copies of the files back in place")
+            (@arg purge: --purge requires[restore] "remove the bundle from storage afterwards")
+            (@arg force: -f --force "also remove links that were pointed somewhere else")
+        )
+        (@subcommand rename_entry =>
+            (name: "rename-entry")
+            (about: "link an entry of a bundle somewhere else")
+            (@arg BUNDLE: +required "bundle name")
+            (@arg OLD: +required "where the entry is linked now")
+            (@arg NEW: +required "where the entry should be linked")
+        )
+        (@subcommand which =>
+            (about: "show which bundle a path belongs to")
+            (@arg PATH: +required "path to look up")
+            (@arg bundle: -b --bundle +takes_value "only look in this bundle")
+        )
+        (@subcommand sync =>
+            (about: "pull storage with git, link what changed again and push")
+        )
+        (@subcommand reindex =>
+            (about: "write the lock again from storage and what's linked right now")
+        )
+        (@subcommand export =>
+            (about: "pack a bundle into a single archive")
+            (@arg BUNDLE: +required "bundle name")
+            (@arg FILE: required_unless[stdout] conflicts_with[stdout] "where to write the archive")
+            (@arg stdout: --stdout "write the archive to stdout")
+        )
+        (@subcommand import =>
+            (about: "unpack an exported bundle into storage")
+            (@arg FILE: +required "archive to import, `-` reads from stdin")
+        )
+        (@subcommand enable =>
+            (about: "link a bundle again when linking everything")
+            (@arg BUNDLE: +required "bundle name")
+        )
+        (@subcommand disable =>
+            (about: "unlink a bundle and leave it out when linking everything")
+            (@arg BUNDLE: +required "bundle name")
+        )
+        (@subcommand list =>
+            (about: "list all bundles")
+            (@arg sort: -s --sort +takes_value possible_value[name size created] default_value[name] "sort bundles by")
+            (@arg reverse: -r --reverse "reverse the sort order")
+            (@arg long: -l --long "also list each bundle's entries with how they're linked")
+            (@arg output_template: --("output-template") +takes_value conflicts_with[long] "print each bundle like this, with {id}, {count}, {created} and {linked} filled in")
+        )
+        (@subcommand info =>
+            (about: "show everything about a bundle")
             (@arg BUNDLE: +required "bundle name")
+            (@arg json: --json "print the result as json")
+        )
+        (@subcommand status =>
+            (about: "check that linked bundles are still in place")
+            (@arg json: --json "print the result as json")
+            (@arg fix: --fix "link missing or wrong links again")
+            (@arg force: -f --force requires[fix] "also replace things that aren't ours while fixing")
+        )
+        (@subcommand verify =>
+            (about: "check a bundle's files against its manifest")
+            (@arg BUNDLE: +required "bundle name")
+        )
+        (@subcommand verify_links =>
+            (name: "verify-links")
+            (about: "only check that linked symlinks still point into storage, printing nothing if they do")
+        )
+        (@subcommand gc =>
+            (about: "remove old backups from storage")
+            (@arg older_than: --("older-than") +takes_value "remove backups older than this, e.g. 30d")
+            (@arg keep: --keep +takes_value "always keep this many of the newest backups")
+        )
+        (@subcommand doctor =>
+            (about: "check all bundles and the storage for problems")
+            (@arg json: --json "print the result as json")
+            (@arg check: --check conflicts_with[json] "print nothing, only exit with 1 if there are problems")
+        )
+        (@subcommand lock =>
+            (about: "look at the lockfile")
+            (@arg validate: --validate +required "check that the lockfile makes sense, without looking at what's linked")
+        )
+        (@subcommand debug =>
+            (about: "look at how dotgirl understands its files")
+            (@setting SubcommandRequiredElseHelp)
+            (@subcommand print_lock =>
+                (name: "print-lock")
+                (about: "print the lockfile as it was parsed")
+                (@arg canonical: --canonical conflicts_with[raw] "print it the way it would be written")
+                (@arg raw: --raw "print the file without parsing it")
+            )
+        )
+        (@subcommand schema =>
+            (@setting Hidden)
+            (about: "print a json schema for lock or bundle files")
+            (@arg KIND: +required possible_value[lock bundle] "which file to print the schema for")
         )
     )
         .setting(AppSettings::SubcommandRequiredElseHelp)
         .get_matches();
 
     // default storage path
-    let storage = dirs::home_dir()
-        .ok_or(Error::HomedirNotFound)?
-        .join(STORAGE_DIR);
+    let home = dirs::home_dir()
+        .ok_or(Error::HomedirNotFound)?;
+
+    set_tracing(matches.subcommand().1.unwrap_or(&matches).is_present("trace"));
+    // add and link also show a preview of their own
+    set_dry_run(matches.subcommand().1.unwrap_or(&matches).is_present("dry_run"));
 
-    let env = Env { storage };
+    let mut env = Env::new(home);
+    if let Some(target) = target_home(matches.subcommand().1.unwrap_or(&matches).value_of("target_home")) {
+        env.home = absolute(&target);
+    }
+    env.config = Config::load(&env.storage)?.merge(&parse_overrides(&matches)?);
+    env.resolver = Rc::new(DialoguerResolver { color: env.config.color });
+    env.output = parse_output(&matches)?;
+    env.update_lock = !matches.subcommand().1.unwrap_or(&matches).is_present("no_lock_update");
+    env.verify = matches.subcommand().1.unwrap_or(&matches).is_present("verify");
+    env.storage_readonly |= matches.subcommand().1.unwrap_or(&matches).is_present("storage_readonly");
+
+    if env.storage_readonly {
+        eprintln!("warning: storage is read only, the lock won't be updated");
+    } else if !env.update_lock {
+        eprintln!("warning: not updating the lock, it might not match what's linked anymore");
+    }
 
     match matches.subcommand() {
         ("add", Some(matches)) => {
             let bundle = matches.value_of("BUNDLE")
                 .expect("Invalid: BUNDLE is required");
 
-            let paths = matches.values_of("INPUT")
-                .expect("Invalid: INPUT is required")
-                .map(Path::new)
-                .map(|it| it.canonicalize().expect("invalid path"))
-                .collect::<Vec<PathBuf>>();
-
-			cmd_add(&env, &bundle, &paths)?;
-        },
-        ("link", Some(matches)) => {
-            let bundle = matches.value_of("BUNDLE")
-                .expect("Invalid: BUNDLE is required");
-
-            cmd_link(&env, &bundle)?;
-        },
-        _ => {},
-    };
-
-	Ok(())
-}
-
-fn get_storage_dir(env: &Env) -> Result<PathBuf> {
-    let path = env.storage.clone();
-    Disk::mkdir_all(&env.storage)?;
-
-    Ok(path)
-}
-
-fn get_lockfile(env: &Env) -> Result<Lock> {
-    let path = env.storage.join(LOCK_FILE);
-
-    if !Disk::is_file(&path) {
-        return Ok(Default::default());
-    }
-
-    let contents = Disk::get(path)?;
-    let parsed = toml::from_str::<Lock>(&contents)?;
-
-    Ok(parsed)
-}
-
-fn write_lockfile(env: &Env, lockfile: &Lock) -> Result<()> {
-    let mut lock_path = get_storage_dir(&env)?;
-    lock_path.push(LOCK_FILE);
-
-    let ser = toml::to_string(&lockfile)?;
-    Disk::put(&lock_path, &ser)?;
-
-    Ok(())
-}
-
-fn cmd_add(env: &Env, bundle_name: &str, paths: &Vec<PathBuf>) -> Result<()> {
-    let mut lockfile = get_lockfile(&env)?;
-
-    // Filter out symlinks
-    // TODO(happens): More validation
-    //   - Check that directories don't contain each other
-    //   - Check for duplicates
-    //   - Exclude storage directory
-    let paths = paths
-        .into_iter()
-        .filter(|it| !Disk::is_symlink(&it))
-        .collect::<Vec<_>>();
-
-    let bundle_path = env.storage
-        .join("bundle")
-        .join(bundle_name);
-
-    if bundle_path.is_dir() {
-        println!("adding to existing bundle `{}`", bundle_name);
-    } else {
-        println!("creating bundle `{}`", bundle_name);
-        Disk::mkdir_all(&bundle_path)?;
-    }
-
-    let entries = paths
-        .iter()
-        .map(|remote| {
-            let remote_name = util::get_name(&remote)?;
-            let local = bundle_path.join(remote_name);
-
-            Disk::copy(&remote, &local)?;
-            Disk::remove(&remote)?;
-
-            let local = format!("{}", local.display());
-            let remote = format!("{}", remote.display());
-
-            Ok(Entry { local, remote })
-        })
-        .filter_map(Result::ok)
-        .collect::<Vec<Entry>>();
-
-    // TODO(happens): Report on skipped
-
-    let bundle = Bundle {
-        id: String::from(bundle_name),
-        entries,
-    };
-
-    // Save the dotfile for the bundle itself, this has all the paths
-    let dot_meta_path = bundle_path.join(BUNDLE_FILE);
-
-    let ser = toml::to_string(&bundle)?;
-    Disk::put(&dot_meta_path, &ser)?;
-
-    // Save the new dotfile, which contains only the paths that have
-    // been linked successfully (which in this case should always be
-    // all of them, but still)
-    let _ = link(&bundle, &[], true)?;
-    lockfile.linked.push(Linked { id: bundle.id.clone() });
-    write_lockfile(&env, &lockfile)?;
+            // piped in content has no input, so it's added at the remote
+            let stdin_content = matches.is_present("stdin_content");
+            let inputs = match stdin_content {
+                true => matches.values_of("remote"),
+                false => matches.values_of("INPUT"),
+            };
+            let inputs = inputs.map(|it| it.collect::<Vec<&str>>()).unwrap_or_default();
 
-    Ok(())
-}
-
-fn cmd_link(env: &Env, bundle_name: &str) -> Result<()> {
-    let mut lockfile = get_lockfile(&env)?;
-
-    // TODO(happens): Confirm if already linked
+            if !stdin_content && inputs.is_empty() && matches.is_present("remote") {
+                return Err(Error::Simple("--remote needs an input or --stdin-content"));
+            }
 
-    let dir = env.storage.join(BUNDLE_DIR).join(bundle_name);
-    if !Disk::is_dir(&dir) {
-        return Err(Error::BundleNotFound);
-    }
+            // only expanded, so a relative remote can still be refused
+            let remote = match matches.value_of("remote") {
+                Some(remote) if !stdin_content => expand_input(&env, remote)?.first().cloned(),
+                _ => None,
+            };
 
-    let dot_meta_path = dir.join(BUNDLE_FILE);
-    if !Disk::is_file(&dot_meta_path) {
-        return Err(Error::BundleMissingMeta);
-    }
+            let mut paths = Vec::new();
+            for input in inputs {
+                paths.extend(expand_input(&env, input)?);
+            }
 
-    let raw = Disk::get(&dot_meta_path)?;
-    let bundle = toml::from_str::<Bundle>(&raw)?;
+            let from_dir = match matches.value_of("bundle_from_dir") {
+                Some(dir) => expand_input(&env, dir)?.first().map(|it| absolute(it)),
+                None => None,
+            };
+
+            let paths_from_git = match matches.value_of("paths_from_git") {
+                Some(repo) => expand_input(&env, repo)?.first().map(|it| absolute(it)),
+                None => None,
+            };
+
+            let relative_to = match matches.value_of("relative_to") {
+                Some(base) => expand_input(&env, base)?.first().map(|it| absolute(it)),
+                None => None,
+            };
+
+            let max_parallel = matches.value_of("max_parallel")
+                .expect("Invalid: max_parallel has a default")
+                .parse::<usize>()
+                .map_err(|_| Error::Simple("invalid number for --max-parallel"))?;
+
+            let content = if stdin_content {
+                let mut content = String::new();
+                std::io::stdin().read_to_string(&mut content)?;
+                Some(content)
+            } else {
+                None
+            };
+
+            let paths = paths
+                .iter()
+                .map(|it| absolute(it))
+                .collect::<Vec<PathBuf>>();
 
-    let _ = link(&bundle, &[], false)?;
-    lockfile.linked.push(Linked { id: bundle.id.clone() });
-    write_lockfile(&env, &lockfile)?;
+            let mut notes = Vec::new();
+            let note_for = matches.values_of("note_for").map(|it| it.collect::<Vec<&str>>()).unwrap_or_default();
+            for pair in note_for.chunks(2) {
+                let remote = expand_input(&env, pair[0])?.first().map(|it| absolute(it))
+                    .ok_or_else(|| Error::UnknownEntry(pair[0].to_string()))?;
 
-    Ok(())
-}
+                notes.push((remote, pair[1].to_string()));
+            }
 
-fn link(
-    bundle: &Bundle,
-    overwrite: &[&str],
-    overwrite_all: bool
-) -> Result<Vec<Entry>> {
-    // TODO(happens): Check if linked bundles conflict with this one
-
-    let mut result = Vec::new();
-    let mut overwrite_all = overwrite_all;
-    for it in &bundle.entries {
-        // first, make sure that all dirs leading up to the file
-        // or dir exist (if there is no parent that means we
-        // are placing the file at '/', which is fine, i guess?)
-        let remote_path: PathBuf = it.remote.clone().into();
-        let local_path: PathBuf = it.local.clone().into();
-
-        if let Some(parent) = remote_path.parent() {
-            // this is weird, but can happen
-            if Disk::is_file(&parent) {
-                let text = format!(
-                    "You're trying to link the file {}, but {} is a file. {}",
-                    it.remote, parent.display(),
-                    "Do you want to overwrite the file and create a directory instead?",
-                );
-
-                if Confirmation::new()
-                    .with_text(&text)
-                    .default(false)
-                    .interact()
-                    .expect("Failed to show prompt")
-                {
-                    Disk::remove(&parent)?;
-                }
+            let options = AddOptions {
+                name: matches.value_of("name").map(String::from),
+                force: matches.is_present("force"),
+                link_type: parse_link_type(matches)?,
+                follow_symlinks: matches.is_present("follow_symlinks"),
+                host: matches.value_of("host").map(String::from),
+                keep_dots: matches.is_present("keep_dots"),
+                no_link: matches.is_present("no_link"),
+                archive: matches.is_present("archive"),
+                content,
+                max_parallel,
+                reflink: matches.value_of("reflink")
+                    .expect("Invalid: reflink has a default")
+                    .parse::<Reflink>()?,
+                from_dir,
+                relative_to,
+                dry_run: matches.is_present("dry_run"),
+                xattrs: matches.is_present("xattrs"),
+                confirm: matches.is_present("confirm"),
+                parents_only: matches.is_present("parents_only"),
+                remote,
+                paths_from_git,
+                note: matches.value_of("note").map(String::from),
+                notes,
+                only_new: matches.is_present("only_new"),
+            };
+
+            cmd_add(&env, bundle, &paths, &options)?;
+        },
+        ("link", Some(matches)) => {
+            let mut entries = Vec::new();
+            for input in matches.values_of("entry").unwrap_or_default() {
+                entries.extend(expand_input(&env, input)?
+                    .iter()
+                    .map(|it| format!("{}", absolute(it).display())));
             }
 
-            if !parent.is_dir() {
-                Disk::mkdir_all(&parent)?;
+            let options = LinkOptions {
+                link_type: parse_link_type(matches)?,
+                no_hooks: matches.is_present("no_hooks"),
+                from: matches.value_of("from").map(String::from),
+                sudo: matches.is_present("sudo"),
+                quiet_skip: matches.is_present("quiet_skip"),
+                dry_run: matches.is_present("dry_run"),
+                relative: matches.is_present("relative"),
+                entries,
+                ignore_cross_bundle: matches.is_present("ignore_cross_bundle"),
+                hostname_suffix: matches.value_of("hostname_suffix").map(String::from),
+                no_deps: matches.is_present("no_deps"),
+                per_file: matches.is_present("per_file"),
+                preserve_mtime: matches.is_present("preserve_mtime"),
+                verbose: matches.is_present("verbose"),
+                force_host: matches.is_present("force_host"),
+            };
+
+            if matches.is_present("all") {
+                cmd_link_all(&env, &options)?;
+            } else {
+                let bundle = matches.value_of("BUNDLE")
+                    .expect("Invalid: BUNDLE is required");
+
+                cmd_link(&env, bundle, &options)?;
             }
-        }
-
-        if remote_path.exists() {
-            if !overwrite_all && !overwrite.contains(&it.remote.as_ref()) {
-                let choices = &["skip", "overwrite", "overwrite all"];
-                let selection = Select::with_theme(&theme::ColorfulTheme::default())
-                    .with_prompt(&format!("{} already exists.", it.remote))
-                    .default(0)
-                    .items(&choices[..])
-                    .interact()
-                    .expect("Failed to show prompt");
-
-                match selection {
-                    0 => continue,
-                    2 => overwrite_all = true,
-                    _ => {},
-                };
+        },
+        ("unlink", Some(matches)) => {
+            let options = UnlinkOptions {
+                restore: matches.is_present("restore"),
+                purge: matches.is_present("purge"),
+                force: matches.is_present("force"),
+            };
+
+            if matches.is_present("all") {
+                cmd_unlink_all(&env, &options)?;
+            } else {
+                let bundle = matches.value_of("BUNDLE")
+                    .expect("Invalid: BUNDLE is required");
+
+                cmd_unlink(&env, bundle, &options)?;
             }
+        },
+        ("rename-entry", Some(matches)) => {
+            let bundle = matches.value_of("BUNDLE")
+                .expect("Invalid: BUNDLE is required");
+            let old = matches.value_of("OLD").expect("Invalid: OLD is required");
+            let new = matches.value_of("NEW").expect("Invalid: NEW is required");
 
-            // if we drop through to here, we're supposed to nuke it and
-            // replace it
-            Disk::remove(&remote_path)?;
-        }
-
-        Disk::symlink(&local_path, &remote_path)?;
-        result.push(it.clone());
-    }
-
-    Ok(result)
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn cmd_add_should_work_for_new_bundle() {
-        let (env, config_dir) = setup();
-        let bundle_dir = env.storage.join("bundle/test_bundle");
+            // the new path doesn't exist yet, so it can't be globbed
+            let old = expand_input(&env, old)?.first().map(|it| absolute(it))
+                .ok_or_else(|| Error::UnknownEntry(old.to_string()))?;
 
-        let paths = vec![
-            config_dir.join("a"),
-            config_dir.join("b"),
-        ];
+            cmd_rename_entry(&env, bundle, &old, &absolute(Path::new(new)))?;
+        },
+        ("which", Some(matches)) => {
+            let path = matches.value_of("PATH").expect("Invalid: PATH is required");
+            let path = expand_input(&env, path)?.first().cloned()
+                .ok_or_else(|| Error::PathNotFound(PathBuf::from(path)))?;
+
+            // not canonical, that would lead into storage for
+            // anything inside of a linked directory
+            let path = if path.is_relative() { std::env::current_dir()?.join(path) } else { path };
+
+            // like `which` itself, not finding anything is a failure
+            if cmd_which(&env, &path, matches.value_of("bundle"))?.is_none() {
+                std::process::exit(1);
+            }
+        },
+        ("sync", Some(_)) => cmd_sync(&env)?,
+        ("reindex", Some(_)) => cmd_reindex(&env)?,
+        ("export", Some(matches)) => {
+            let bundle = matches.value_of("BUNDLE").expect("Invalid: BUNDLE is required");
+
+            match matches.value_of("FILE") {
+                Some(file) => cmd_export(&env, bundle, &mut std::fs::File::create(file)?)?,
+                None => cmd_export(&env, bundle, &mut std::io::stdout().lock())?,
+            };
+        },
+        ("import", Some(matches)) => {
+            let file = matches.value_of("FILE").expect("Invalid: FILE is required");
 
-        cmd_add(&env, "test_bundle", &paths).expect("Add should have worked");
+            if file == "-" {
+                cmd_import(&env, &mut std::io::stdin().lock())?;
+            } else {
+                cmd_import(&env, &mut std::fs::File::open(file)?)?;
+            }
+        },
+        ("enable", Some(matches)) => {
+            let bundle = matches.value_of("BUNDLE")
+                .expect("Invalid: BUNDLE is required");
 
-        Disk::print();
+            cmd_enable(&env, bundle)?;
+        },
+        ("disable", Some(matches)) => {
+            let bundle = matches.value_of("BUNDLE")
+                .expect("Invalid: BUNDLE is required");
 
-        assert!(Disk::is_symlink(config_dir.join("a")));
-        assert!(Disk::is_symlink(config_dir.join("b")));
+            cmd_disable(&env, bundle)?;
+        },
+        ("list", Some(matches)) => {
+            let sort = matches.value_of("sort")
+                .expect("Invalid: sort has a default")
+                .parse::<SortKey>()?;
 
-        assert!(Disk::is_file(bundle_dir.join("bundle.toml")));
+            let template = matches.value_of("output_template");
+            cmd_list(&env, sort, matches.is_present("reverse"), matches.is_present("long"), template)?;
+        },
+        ("info", Some(matches)) => {
+            let bundle = matches.value_of("BUNDLE")
+                .expect("Invalid: BUNDLE is required");
 
-        assert!(Disk::is_dir(bundle_dir.join("a")));
-        assert!(Disk::is_dir(bundle_dir.join("a/sub")));
-        assert!(Disk::is_dir(bundle_dir.join("b")));
+            cmd_info(&env, bundle, matches.is_present("json"))?;
+        },
+        ("status", Some(matches)) => {
+            let options = StatusOptions {
+                json: matches.is_present("json"),
+                fix: matches.is_present("fix"),
+                force: matches.is_present("force"),
+            };
+
+            cmd_status(&env, &options)?;
+        },
+        ("doctor", Some(matches)) => {
+            let options = DoctorOptions {
+                json: matches.is_present("json"),
+                check: matches.is_present("check"),
+            };
+
+            match cmd_doctor(&env, &options) {
+                // returning the error would print it
+                Err(Error::Unhealthy(_)) if options.check => std::process::exit(1),
+                other => other?,
+            };
+        },
+        ("lock", Some(_)) => {
+            let problems = cmd_validate(&env)?;
+            if !problems.is_empty() {
+                return Err(Error::Unhealthy(problems.len()));
+            }
+        },
+        ("gc", Some(matches)) => {
+            let keep = matches.value_of("keep")
+                .map(|it| it.parse::<usize>().map_err(|_| Error::Simple("invalid number for --keep")))
+                .transpose()?;
 
-        assert!(Disk::is_file(bundle_dir.join("a/config")));
-        assert!(Disk::is_file(bundle_dir.join("a/sub/config")));
-        assert!(Disk::is_file(bundle_dir.join("a/.hidden-config")));
+            let options = GcOptions {
+                older_than: matches.value_of("older_than").map(dotgirl::parse_duration).transpose()?,
+                keep,
+            };
 
-        clean();
-    }
+            cmd_gc(&env, &options)?;
+        },
+        ("verify", Some(matches)) => {
+            let bundle = matches.value_of("BUNDLE")
+                .expect("Invalid: BUNDLE is required");
 
-    #[test]
-    fn cmd_add_should_work_for_existing_bundle() {
-        let (env, config_dir) = setup();
-        let bundle_dir = env.storage.join("bundle/test_bundle");
+            cmd_verify_manifest(&env, bundle)?;
+        },
+        ("verify-links", Some(_)) => match cmd_verify_links(&env) {
+            // the broken links were already printed
+            Err(Error::Unhealthy(_)) => std::process::exit(1),
+            other => other?,
+        },
+        ("debug", Some(matches)) => {
+            if let ("print-lock", Some(matches)) = matches.subcommand() {
+                let dump = if matches.is_present("raw") {
+                    LockDump::Raw
+                } else if matches.is_present("canonical") {
+                    LockDump::Canonical
+                } else {
+                    LockDump::Parsed
+                };
 
-        // create bundle
-        let paths = vec![config_dir.join("a")];
-        cmd_add(&env, "test_bundle", &paths).expect("Add should have worked");
-        println!("printing disk after first add");
-        Disk::print();
+                cmd_print_lock(&env, dump)?;
+            }
+        },
+        ("schema", Some(matches)) => {
+            let kind = matches.value_of("KIND")
+                .expect("Invalid: KIND is required")
+                .parse::<SchemaKind>()?;
 
-        assert!(Disk::is_symlink(config_dir.join("a")));
-        assert!(Disk::is_dir(bundle_dir.join("a")));
+            cmd_schema(&env, kind)?;
+        },
+        _ => {},
+    };
 
-        // add to bundle
-        let paths = vec![config_dir.join("b")];
-        cmd_add(&env, "test_bundle", &paths).expect("Add should have worked");
-        println!("printing disk after second add");
-        Disk::print();
+	Ok(())
+}
 
-        assert!(Disk::is_symlink(config_dir.join("b")));
-        assert!(Disk::is_dir(bundle_dir.join("b")));
+// Canonicalizes everything but the last component, so inputs
+// that are symlinks can still be recognized as such. Paths that
+// don't exist are left alone, `cmd_add` reports those.
+fn absolute(path: &Path) -> PathBuf {
+    let canonical = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+
+            parent.canonicalize().map(|it| it.join(name))
+        },
+        _ => path.canonicalize(),
+    };
 
-        clean();
-    }
+    canonical.unwrap_or_else(|_| path.to_path_buf())
+}
 
-    #[test]
-    fn cmd_add_should_trim_dot_prefix() {
-        let (env, config_dir) = setup();
-        let paths = vec![config_dir.join(".hidden-config")];
+fn parse_link_type(matches: &ArgMatches) -> Result<Option<LinkType>> {
+    matches.value_of("link_type")
+        .map(|it| it.parse::<LinkType>())
+        .transpose()
+}
 
-        cmd_add(&env, "test_bundle", &paths).expect("Add should have worked");
+fn parse_output(matches: &ArgMatches) -> Result<Output> {
+    let matches = matches.subcommand().1.unwrap_or(matches);
 
-        Disk::print();
+    let format = match matches.value_of("format") {
+        Some(format) => format.parse()?,
+        None => Output::default().format,
+    };
 
-        assert!(Disk::is_symlink(config_dir.join(".hidden-config")));
-        assert!(Disk::is_file(env.storage.join("bundle/test_bundle/hidden-config")));
+    Ok(Output {
+        format,
+        path: matches.value_of("out").map(PathBuf::from),
+        summary: matches.is_present("summary"),
+    })
+}
 
-        clean();
-    }
+fn parse_overrides(matches: &ArgMatches) -> Result<ConfigOverrides> {
+    // global args end up on whichever subcommand they were given to
+    let matches = matches.subcommand().1.unwrap_or(matches);
 
-    // Returns a temp env and a folder with test files
-    // ./test_tmp
-    //     /dotgirl (storage dir)
-    //     /config
-    //         config
-    //         .hidden-config
-    //         /a
-    //             /sub
-    //                 config
-    //                 .hidden-config
-    //             config
-    //         /b
-    //             config
-    //
-    fn setup() -> (Env, PathBuf) {
-        let root = PathBuf::from("/");
-
-        let storage = root.join(STORAGE_DIR);
-        Disk::mkdir_all(&storage).unwrap();
-
-        let conf = root.join("config");
-
-        let conf_a = conf.join("a");
-        let conf_a_sub = conf_a.join("sub");
-        Disk::mkdir_all(&conf_a_sub).unwrap();
-
-        Disk::put(conf.join("config"), "hello config").unwrap();
-        Disk::put(conf.join(".hidden-config"), "hello config").unwrap();
-
-        Disk::put(conf_a.join("config"), "hello config").unwrap();
-        Disk::put(conf_a.join(".hidden-config"), "hello config").unwrap();
-        Disk::put(conf_a_sub.join("config"), "hello config").unwrap();
-
-        let conf_b = conf.join("b");
-        Disk::mkdir_all(&conf_b).unwrap();
-        Disk::put(conf_b.join("config"), "hello config").unwrap();
-
-        (Env { storage }, conf)
-    }
+    let backup = if matches.is_present("backup") {
+        Some(true)
+    } else if matches.is_present("no_backup") {
+        Some(false)
+    } else {
+        None
+    };
 
-    fn clean() {
-        Disk::clear();
-    }
+    Ok(ConfigOverrides {
+        conflict: matches.value_of("conflict").map(str::parse).transpose()?,
+        color: matches.value_of("color").map(str::parse).transpose()?,
+        backup,
+        editor: matches.value_of("editor").map(String::from),
+    })
 }
-
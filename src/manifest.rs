@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Result, Error, BUNDLE_FILE, util};
+use crate::disk::{Disk, Filesystem as _};
+
+pub const MANIFEST_FILE: &str = "manifest.toml";
+
+// Content hashes of everything stored in a bundle dir, keyed by
+// their path relative to it (always separated by `/`)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    pub fn generate(dir: &Path) -> Result<Self> {
+        let mut files = hash_files(dir, "", util::hash)?;
+
+        // the metadata is allowed to change, so it's not part of the manifest
+        files.remove(BUNDLE_FILE);
+        files.remove(MANIFEST_FILE);
+
+        Ok(Manifest { files })
+    }
+
+    // Replaces whatever the manifest had at `key` with the files at
+    // `path`, which is a single file or a dir. this reads everything,
+    // so it can be done before `path` is moved to `key`.
+    pub fn replace(&mut self, key: &str, path: &Path) -> Result<()> {
+        let files = hash_files(path, key, util::hash)?;
+        self.forget(key);
+        self.files.extend(files);
+
+        Ok(())
+    }
+
+    // Same as `replace`, for a file with the given content
+    pub fn replace_content(&mut self, key: &str, content: &[u8]) {
+        self.forget(key);
+        self.files.insert(String::from(key), util::hash(content));
+    }
+
+    // Leaves out everything at `key`
+    pub fn forget(&mut self, key: &str) {
+        let inside = format!("{}/", key);
+        self.files.retain(|path, _| path != key && !path.starts_with(&inside));
+    }
+
+    // Old bundles might not have a manifest yet
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(MANIFEST_FILE);
+        if !Disk::is_file(&path) {
+            return Ok(None);
+        }
+
+        let raw = Disk::get(&path)?;
+        let parsed = toml::from_str::<Manifest>(&raw)?;
+
+        Ok(Some(parsed))
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let ser = toml::to_string(&self)?;
+        Disk::put(dir.join(MANIFEST_FILE), &ser)?;
+
+        Ok(())
+    }
+
+    // Compares against what's in `dir` right now, reporting every path
+    // that changed, went missing or wasn't there before
+    pub fn verify(&self, dir: &Path) -> Result<()> {
+        let current = Manifest::generate(dir)?;
+
+        let mut mismatched = self.files
+            .iter()
+            .filter(|(path, hash)| current.files.get(*path) != Some(hash))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<String>>();
+
+        current.files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .for_each(|path| mismatched.push(path.clone()));
+
+        if !mismatched.is_empty() {
+            mismatched.sort();
+            return Err(Error::ManifestMismatch(mismatched));
+        }
+
+        Ok(())
+    }
+}
+
+// A single hash over everything stored in `dir`, metadata included.
+// this is what the lock keeps to notice storage being tampered with,
+// so unlike the manifest it uses a cryptographic hash.
+pub fn checksum(dir: &Path) -> Result<String> {
+    let joined = hash_files(dir, "", sha256)?
+        .iter()
+        .map(|(path, hash)| format!("{} {}\n", path, hash))
+        .collect::<String>();
+
+    Ok(sha256(joined.as_bytes()))
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(bytes);
+
+    hasher.finalize().iter().map(|it| format!("{:02x}", it)).collect()
+}
+
+// Hashes of the files at `path`, keyed by where they are below it
+// with `prefix` in front
+fn hash_files(path: &Path, prefix: &str, hash: fn(&[u8]) -> String) -> Result<BTreeMap<String, String>> {
+    let mut files = BTreeMap::new();
+
+    if Disk::is_file(path) {
+        files.insert(String::from(prefix), hash(&Disk::get_bytes(path)?));
+        return Ok(files);
+    }
+
+    for it in Disk::walk(path)? {
+        if !Disk::is_file(&it) {
+            continue;
+        }
+
+        let relative = match it.strip_prefix(path) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+
+        let key = Path::new(prefix)
+            .join(relative)
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy().into_owned())
+            .collect::<Vec<String>>()
+            .join("/");
+
+        files.insert(key, hash(&Disk::get_bytes(&it)?));
+    }
+
+    Ok(files)
+}
@@ -0,0 +1,114 @@
+use std::path::Path;
+use dialoguer::{Confirmation, Select, theme};
+
+use crate::config::ColorChoice;
+
+// What to do with something that's in the way of a link
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resolution {
+    Skip,
+    Overwrite,
+    // overwrite this and everything else that's in the way
+    OverwriteAll,
+    // link the contents of a directory into the existing one,
+    // only asked for if `can_merge` was set
+    Merge,
+}
+
+// Decides conflicts while linking, for when the conflict policy says
+// to ask. Tools embedding dotgirl can answer these however they like,
+// by default the user is asked in the terminal.
+pub trait ConflictResolver: std::fmt::Debug {
+    fn resolve_existing(&self, remote: &str, can_merge: bool) -> Resolution;
+    // whether the file at `parent` should be replaced with a
+    // directory, so `remote` can be linked inside of it
+    fn resolve_parent_is_file(&self, remote: &str, parent: &Path) -> bool;
+    // whether to retry writing to `path` with sudo
+    fn allow_sudo(&self, path: &Path) -> bool;
+    // whether to go ahead with everything in `plan`
+    fn confirm_plan(&self, plan: &str) -> bool;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DialoguerResolver {
+    pub color: ColorChoice,
+}
+
+impl Default for DialoguerResolver {
+    fn default() -> Self {
+        DialoguerResolver { color: ColorChoice::Auto }
+    }
+}
+
+impl DialoguerResolver {
+    // prompts are shown on stderr, so that's what `Auto` looks at
+    fn colorful(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Auto => atty::is(atty::Stream::Stderr),
+            ColorChoice::Never => false,
+        }
+    }
+}
+
+impl ConflictResolver for DialoguerResolver {
+    fn resolve_existing(&self, remote: &str, can_merge: bool) -> Resolution {
+        let mut choices = vec!["skip", "overwrite", "overwrite all"];
+        if can_merge {
+            choices.push("merge");
+        }
+
+        let colorful = theme::ColorfulTheme::default();
+        let theme: &dyn theme::Theme = if self.colorful() {
+            &colorful
+        } else {
+            &theme::SimpleTheme
+        };
+
+        let picked = Select::with_theme(theme)
+            .with_prompt(&format!("{} already exists.", remote))
+            .default(0)
+            .items(&choices)
+            .interact()
+            .expect("Failed to show prompt");
+
+        match picked {
+            0 => Resolution::Skip,
+            2 => Resolution::OverwriteAll,
+            3 => Resolution::Merge,
+            _ => Resolution::Overwrite,
+        }
+    }
+
+    fn resolve_parent_is_file(&self, remote: &str, parent: &Path) -> bool {
+        let text = format!(
+            "You're trying to link the file {}, but {} is a file. {}",
+            remote, parent.display(),
+            "Do you want to overwrite the file and create a directory instead?",
+        );
+
+        Confirmation::new()
+            .with_text(&text)
+            .default(false)
+            .interact()
+            .expect("Failed to show prompt")
+    }
+
+    fn allow_sudo(&self, path: &Path) -> bool {
+        Confirmation::new()
+            .with_text(&format!("Not allowed to write to {}. Retry with sudo?", path.display()))
+            .default(false)
+            .interact()
+            .expect("Failed to show prompt")
+    }
+
+    fn confirm_plan(&self, plan: &str) -> bool {
+        println!("{}", plan);
+
+        Confirmation::new()
+            .with_text("Continue?")
+            .default(false)
+            .interact()
+            .expect("Failed to show prompt")
+    }
+}
@@ -1,10 +1,16 @@
 use crate::{Result, Error};
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn get_name(path: &PathBuf) -> Result<String> {
-    let result = path
+// The name an input is stored under. Leading dots are trimmed
+// unless `keep_dots` is set, so hidden files don't stay hidden
+// inside storage.
+pub fn get_name(path: &Path, keep_dots: bool) -> Result<String> {
+    let name = path
         .components()
-        .last()
+        .next_back()
         .ok_or(Error::LastComponentInvalid(
             String::from(path.to_str().unwrap_or(""))
         ))?
@@ -12,13 +18,255 @@ pub fn get_name(path: &PathBuf) -> Result<String> {
         .to_str()
         .ok_or(Error::LastComponentInvalid(
             String::from(path.to_str().unwrap_or(""))
-        ))?
-        .trim_start_matches(".")
-        .to_owned();
+        ))?;
+
+    if keep_dots {
+        return Ok(name.to_owned());
+    }
+
+    Ok(name.trim_start_matches(".").to_owned())
+}
+
+// Makes sure a user supplied name can be used as a single
+// path component inside a bundle
+pub fn validate_name(name: &str) -> Result<()> {
+    let invalid = name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.contains(std::path::is_separator);
+
+    if invalid {
+        return Err(Error::InvalidName(String::from(name)));
+    }
+
+    Ok(())
+}
+
+// Best effort, falls back to `localhost` if we can't find out
+pub fn hostname() -> String {
+    std::process::Command::new("hostname")
+        .output()
+        .ok()
+        .and_then(|it| String::from_utf8(it.stdout).ok())
+        .map(|it| it.trim().to_owned())
+        .filter(|it| !it.is_empty())
+        .unwrap_or_else(|| String::from("localhost"))
+}
+
+// Resolves `.` and `..` without touching the filesystem, so this
+// also works for paths that don't exist (yet)
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for it in path.components() {
+        match it {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                result.pop();
+            },
+            other => result.push(other.as_os_str()),
+        };
+    }
+
+    result
+}
+
+// The path that leads from the dir `from` to `to`, or nothing if
+// they don't start at the same root (e.g. different drives)
+pub fn relative_path(from: &Path, to: &Path) -> Option<PathBuf> {
+    let from = normalize(from);
+    let to = normalize(to);
+
+    let root = |path: &Path| path
+        .components()
+        .take_while(|it| matches!(it, Component::Prefix(_) | Component::RootDir))
+        .collect::<PathBuf>();
+
+    if !from.has_root() || root(&from) != root(&to) {
+        return None;
+    }
+
+    let from = from.components().collect::<Vec<Component>>();
+    let to = to.components().collect::<Vec<Component>>();
+    let common = from
+        .iter()
+        .zip(to.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut result = PathBuf::new();
+    for _ in common..from.len() {
+        result.push("..");
+    }
+
+    for it in &to[common..] {
+        result.push(it.as_os_str());
+    }
 
+    Some(result)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|it| it.as_secs())
+        .unwrap_or(0)
+}
+
+// Replaces `$VAR` and `${VAR}` in `raw` with what `lookup` returns
+// for them. A `$` that isn't followed by a name is kept as it is.
+pub fn expand_vars<F>(raw: &str, lookup: F) -> Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let is_name = |c: char| c.is_ascii_alphanumeric() || c == '_';
+
+    let mut result = String::new();
+    let mut rest = raw;
+    while let Some(start) = rest.find('$') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, after) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(end) => (&rest[1..end], &rest[end + 1..]),
+                None => return Err(Error::Simple("unclosed variable in path")),
+            }
+        } else {
+            let end = rest.find(|c| !is_name(c)).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+
+        if name.is_empty() {
+            result.push('$');
+            continue;
+        }
+
+        let value = lookup(name).ok_or_else(|| Error::UnsetEnvVar(String::from(name)))?;
+        result.push_str(&value);
+        rest = after;
+    }
+
+    result.push_str(rest);
     Ok(result)
 }
 
+// Runs `job` for every item on up to `max` threads, returning the
+// results in the same order as the items
+pub fn run_parallel<T, R, F>(items: Vec<T>, max: usize, job: F) -> Vec<R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    if max <= 1 || items.len() <= 1 {
+        return items.into_iter().map(job).collect();
+    }
+
+    let count = items.len();
+    let queue = Arc::new(Mutex::new(items.into_iter().enumerate()));
+    let job = Arc::new(job);
+
+    let workers = (0..max.min(count))
+        .map(|_| {
+            let queue = queue.clone();
+            let job = job.clone();
+
+            thread::spawn(move || {
+                let mut done = Vec::new();
+                loop {
+                    // the lock is released before the job runs
+                    let next = queue.lock().expect("Invalid: queue was poisoned").next();
+                    match next {
+                        Some((index, item)) => done.push((index, job(item))),
+                        None => return done,
+                    };
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut results = workers
+        .into_iter()
+        .flat_map(|it| it.join().expect("Invalid: parallel job panicked"))
+        .collect::<Vec<(usize, R)>>();
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, it)| it).collect()
+}
+
+// Parses durations like `30d` or `12h` into seconds
+pub fn parse_duration(raw: &str) -> Result<u64> {
+    let raw = raw.trim();
+    let split = raw.find(|c: char| !c.is_ascii_digit()).unwrap_or(raw.len());
+    let (amount, unit) = raw.split_at(split);
+
+    let amount = amount.parse::<u64>()
+        .map_err(|_| Error::Simple("invalid duration"))?;
+
+    let factor = match unit {
+        "s" | "" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(Error::Simple("invalid duration unit")),
+    };
+
+    Ok(amount * factor)
+}
+
+// FNV-1a, which is stable and plenty to notice files that changed.
+// It's not meant to hold up against anyone forging contents.
+pub fn hash(content: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in content {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    format!("{:016x}", hash)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|it| format!("{:02x}", it)).collect()
+}
+
+pub fn from_hex(raw: &str) -> Result<Vec<u8>> {
+    if !raw.len().is_multiple_of(2) || !raw.is_ascii() {
+        return Err(Error::Simple("invalid hex string"));
+    }
+
+    (0..raw.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&raw[i..i + 2], 16).map_err(|_| Error::Simple("invalid hex string")))
+        .collect()
+}
+
+// A single hunk unified diff going from `old` to `new`, with
+// the whole file as context
+pub fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    // `diff::lines` would count the end of a trailing newline as
+    // one more (empty) line, which isn't in either file
+    let old_lines = old.lines().collect::<Vec<&str>>();
+    let new_lines = new.lines().collect::<Vec<&str>>();
+
+    let mut lines = vec![
+        format!("--- {}", old_name),
+        format!("+++ {}", new_name),
+        format!("@@ -1,{} +1,{} @@", old_lines.len(), new_lines.len()),
+    ];
+
+    for it in diff::slice(&old_lines, &new_lines) {
+        lines.push(match it {
+            diff::Result::Left(line) => format!("-{}", line),
+            diff::Result::Both(line, _) => format!(" {}", line),
+            diff::Result::Right(line) => format!("+{}", line),
+        });
+    }
+
+    lines.join("\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -27,18 +275,122 @@ mod tests {
     fn get_name_should_work() {
         // should return directory names
         let dir_path = PathBuf::from("/foo/bar/baz/");
-        let dir_name = get_name(&dir_path).unwrap();
+        let dir_name = get_name(&dir_path, false).unwrap();
         assert_eq!(dir_name, "baz".to_owned());
 
         // should return file names
         let file_path = PathBuf::from("/foo/bar/baz.conf");
-        let file_name = get_name(&file_path).unwrap();
+        let file_name = get_name(&file_path, false).unwrap();
         assert_eq!(file_name, "baz.conf".to_owned());
 
         // should trim dots at the start
         let dot_path = PathBuf::from("/foo/bar/.baz.conf");
-        let dot_name = get_name(&dot_path).unwrap();
+        let dot_name = get_name(&dot_path, false).unwrap();
         assert_eq!(dot_name, "baz.conf".to_owned());
+
+        // unless we're asked to keep them
+        let dot_name = get_name(&dot_path, true).unwrap();
+        assert_eq!(dot_name, ".baz.conf".to_owned());
+    }
+
+    #[test]
+    fn validate_name_should_work() {
+        assert!(validate_name("custom").is_ok());
+        assert!(validate_name(".custom").is_ok());
+
+        assert!(validate_name("").is_err());
+        assert!(validate_name("..").is_err());
+        assert!(validate_name("foo/bar").is_err());
+    }
+
+    #[test]
+    fn normalize_should_work() {
+        assert_eq!(normalize(Path::new("/foo/./bar")), PathBuf::from("/foo/bar"));
+        assert_eq!(normalize(Path::new("/foo/bar/../baz")), PathBuf::from("/foo/baz"));
+        assert_eq!(normalize(Path::new("/foo/../../..")), PathBuf::from("/"));
+    }
+
+    #[test]
+    fn hash_should_work() {
+        // reference values for 64 bit FNV-1a
+        assert_eq!(hash(b""), "cbf29ce484222325");
+        assert_eq!(hash(b"a"), "af63dc4c8601ec8c");
+        assert_ne!(hash(b"hello config"), hash(b"hello config!"));
     }
-}
 
+    #[test]
+    fn hex_should_round_trip() {
+        assert_eq!(to_hex(b"\x00ab\xff"), "006162ff");
+        assert_eq!(from_hex("006162ff").unwrap(), b"\x00ab\xff");
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn relative_path_should_work() {
+        let relative = |from: &str, to: &str| relative_path(Path::new(from), Path::new(to));
+
+        assert_eq!(relative("/home/me/.config", "/home/me/dotgirl/bundle/x/config"),
+            Some(PathBuf::from("../dotgirl/bundle/x/config")));
+        assert_eq!(relative("/home/me", "/home/me/dotgirl/a"), Some(PathBuf::from("dotgirl/a")));
+        assert_eq!(relative("/etc/app/", "/home/me/a"), Some(PathBuf::from("../../home/me/a")));
+        assert_eq!(relative("/home/./me/sub/..", "/home/me/a"), Some(PathBuf::from("a")));
+
+        // nothing to be relative to
+        assert_eq!(relative("home/me", "/home/me/a"), None);
+        assert_eq!(relative("/home/me", "home/me/a"), None);
+    }
+
+    #[test]
+    fn parse_duration_should_work() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("15m").unwrap(), 15 * 60);
+        assert_eq!(parse_duration("2d").unwrap(), 2 * 24 * 60 * 60);
+        assert_eq!(parse_duration("1w").unwrap(), 7 * 24 * 60 * 60);
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("3y").is_err());
+    }
+
+    #[test]
+    fn expand_vars_should_work() {
+        let lookup = |name: &str| match name {
+            "XDG_CONFIG_HOME" => Some(String::from("/home/me/.config")),
+            "USER" => Some(String::from("me")),
+            _ => None,
+        };
+
+        assert_eq!(expand_vars("$XDG_CONFIG_HOME/app", lookup).unwrap(), "/home/me/.config/app");
+        assert_eq!(expand_vars("/home/${USER}rc", lookup).unwrap(), "/home/merc");
+        assert_eq!(expand_vars("/cost/$/5", lookup).unwrap(), "/cost/$/5");
+        assert_eq!(expand_vars("/plain/path", lookup).unwrap(), "/plain/path");
+
+        match expand_vars("$NOTHING/app", lookup) {
+            Err(Error::UnsetEnvVar(name)) => assert_eq!(name, "NOTHING"),
+            other => panic!("Expected an unset variable, got {:?}", other),
+        };
+
+        assert!(expand_vars("${USER", lookup).is_err());
+    }
+
+    #[test]
+    fn run_parallel_should_keep_order() {
+        // later items finish first
+        let slow_first = |it: u64| {
+            thread::sleep(std::time::Duration::from_millis(20 - it * 2));
+            it * 10
+        };
+
+        let expected = (0..8).map(|it| it * 10).collect::<Vec<u64>>();
+        assert_eq!(run_parallel((0..8).collect(), 1, slow_first), expected);
+        assert_eq!(run_parallel((0..8).collect(), 3, slow_first), expected);
+        assert_eq!(run_parallel((0..8).collect(), 16, slow_first), expected);
+    }
+
+    #[test]
+    fn unified_diff_should_mark_changed_lines() {
+        let diff = unified_diff("a\nb\nc\n", "a\nx\nc\n", "old", "new");
+        assert_eq!(diff, "--- old\n+++ new\n@@ -1,3 +1,3 @@\n a\n-b\n+x\n c");
+    }
+}
@@ -0,0 +1,323 @@
+// These run the commands against the real filesystem inside a temp
+// directory, since the memory filesystem used by the unit tests can't
+// tell us how symlinks, copies and permissions behave on an actual os.
+#![cfg(not(feature = "mem-fs"))]
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use dotgirl::{
+    Env, Error, AddOptions, LinkOptions, UnlinkOptions,
+    expand_input, cmd_add, cmd_link, cmd_unlink, get_lockfile, check_status,
+};
+
+use dotgirl::LinkType;
+
+// Returns a temp dir that's used as home (and storage, since that lives
+// inside home) together with an env pointing at it. The temp dir has
+// to be kept alive for the duration of the test.
+//     /.gitconfig
+//     /.config
+//         /app
+//             config
+//             /sub
+//                 config
+fn setup() -> (tempfile::TempDir, Env) {
+    let root = tempfile::tempdir().expect("Failed to create temp dir");
+
+    // the temp dir itself might be behind a symlink (macos), and we
+    // compare canonical paths below
+    let home = root.path().canonicalize().unwrap();
+
+    fs::create_dir_all(home.join(".config/app/sub")).unwrap();
+    fs::write(home.join(".config/app/config"), "hello config").unwrap();
+    fs::write(home.join(".config/app/sub/config"), "hello config").unwrap();
+    fs::write(home.join(".gitconfig"), "hello gitconfig").unwrap();
+
+    (root, Env::new(home))
+}
+
+fn is_symlink(path: &PathBuf) -> bool {
+    fs::symlink_metadata(path)
+        .map(|it| it.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+#[test]
+fn add_link_unlink_should_round_trip() {
+    let (_root, env) = setup();
+    let remote = env.home.join(".gitconfig");
+    let local = env.storage.join("bundle/git/gitconfig");
+
+    cmd_add(&env, "git", &vec![remote.clone()], &Default::default())
+        .expect("Add should have worked");
+
+    assert!(local.is_file());
+    assert!(is_symlink(&remote));
+    assert_eq!(fs::read_link(&remote).unwrap(), local);
+    assert_eq!(fs::read_to_string(&remote).unwrap(), "hello gitconfig");
+
+    cmd_unlink(&env, "git", &Default::default()).expect("Unlink should have worked");
+
+    assert!(!is_symlink(&remote));
+    assert!(!remote.exists());
+    assert!(local.is_file());
+    assert!(get_lockfile(&env).unwrap().linked.is_empty());
+
+    cmd_link(&env, "git", &Default::default()).expect("Link should have worked");
+
+    assert_eq!(fs::read_link(&remote).unwrap(), local);
+    assert_eq!(fs::read_to_string(&remote).unwrap(), "hello gitconfig");
+    assert_eq!(get_lockfile(&env).unwrap().linked.len(), 1);
+}
+
+#[test]
+fn add_should_move_directories_into_storage() {
+    let (_root, env) = setup();
+    let remote = env.home.join(".config/app");
+    let local = env.storage.join("bundle/app/app");
+
+    cmd_add(&env, "app", &vec![remote.clone()], &Default::default())
+        .expect("Add should have worked");
+
+    assert!(local.is_dir());
+    assert!(local.join("config").is_file());
+    assert!(local.join("sub/config").is_file());
+
+    assert!(is_symlink(&remote));
+    assert_eq!(fs::read_link(&remote).unwrap(), local);
+    assert_eq!(fs::read_to_string(remote.join("sub/config")).unwrap(), "hello config");
+}
+
+#[test]
+fn add_should_expand_globs() {
+    let (_root, env) = setup();
+    fs::write(env.home.join(".config/zshrc"), "zsh").unwrap();
+    fs::write(env.home.join(".config/bashrc"), "bash").unwrap();
+
+    let paths = expand_input(&env, "~/.config/*rc").expect("Pattern should have matched");
+    assert_eq!(paths, vec![
+        env.home.join(".config/bashrc"),
+        env.home.join(".config/zshrc"),
+    ]);
+
+    cmd_add(&env, "shell", &paths, &Default::default())
+        .expect("Add should have worked");
+
+    assert!(env.storage.join("bundle/shell/bashrc").is_file());
+    assert!(env.storage.join("bundle/shell/zshrc").is_file());
+    assert!(is_symlink(&env.home.join(".config/zshrc")));
+}
+
+#[test]
+fn add_should_fail_for_globs_without_matches() {
+    let (_root, env) = setup();
+
+    match expand_input(&env, "~/.config/*.nothing") {
+        Err(Error::NoGlobMatches(pattern)) => assert!(pattern.ends_with(".config/*.nothing")),
+        other => panic!("Expected no matches, got {:?}", other),
+    };
+
+    // plain paths are left alone, even if they don't exist
+    let paths = expand_input(&env, "~/.nothing").unwrap();
+    assert_eq!(paths, vec![env.home.join(".nothing")]);
+}
+
+#[cfg(unix)]
+#[test]
+fn add_should_adopt_symlink_targets() {
+    let (_root, env) = setup();
+    let target = env.home.join(".gitconfig");
+    let remote = env.home.join(".config/gitconfig");
+    let local = env.storage.join("bundle/git/gitconfig");
+
+    std::os::unix::fs::symlink("../.gitconfig", &remote).unwrap();
+
+    let options = AddOptions {
+        follow_symlinks: true,
+        ..Default::default()
+    };
+
+    cmd_add(&env, "git", &vec![remote.clone()], &options)
+        .expect("Add should have worked");
+
+    assert!(local.is_file());
+    assert!(target.is_file());
+    assert_eq!(fs::read_link(&remote).unwrap(), local);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn add_should_keep_xattrs_for_restored_copies() {
+    let (_root, env) = setup();
+    let remote = env.home.join(".gitconfig");
+
+    // not every filesystem takes user attributes
+    if xattr::set(&remote, "user.dotgirl.test", b"tagged").is_err() {
+        return;
+    }
+
+    let options = AddOptions { xattrs: true, ..Default::default() };
+    cmd_add(&env, "git", &vec![remote.clone()], &options).expect("Add should have worked");
+
+    let bundle = fs::read_to_string(env.storage.join("bundle/git/bundle.toml")).unwrap();
+    assert!(bundle.contains("\"user.dotgirl.test\" = \"746167676564\""));
+
+    let options = UnlinkOptions { restore: true, ..Default::default() };
+    cmd_unlink(&env, "git", &options).expect("Unlink should have worked");
+
+    assert!(!is_symlink(&remote));
+    assert_eq!(xattr::get(&remote, "user.dotgirl.test").unwrap(), Some(b"tagged".to_vec()));
+}
+
+#[cfg(unix)]
+#[test]
+fn link_should_create_relative_symlinks() {
+    let (_root, env) = setup();
+    let remote = env.home.join(".config/app");
+    let local = env.storage.join("bundle/app/app");
+
+    cmd_add(&env, "app", &vec![remote.clone()], &Default::default())
+        .expect("Add should have worked");
+    cmd_unlink(&env, "app", &Default::default()).expect("Unlink should have worked");
+
+    let options = LinkOptions { relative: true, ..Default::default() };
+    cmd_link(&env, "app", &options).expect("Link should have worked");
+
+    let target = fs::read_link(&remote).unwrap();
+    assert!(target.is_relative());
+    assert_eq!(remote.canonicalize().unwrap(), local);
+    assert_eq!(fs::read_to_string(remote.join("sub/config")).unwrap(), "hello config");
+}
+
+#[cfg(windows)]
+#[test]
+fn add_should_link_directories_as_junctions() {
+    let (_root, env) = setup();
+    let remote = env.home.join(".config/app");
+    let local = env.storage.join("bundle/app/app");
+
+    let options = AddOptions {
+        link_type: Some(LinkType::Junction),
+        ..Default::default()
+    };
+
+    cmd_add(&env, "app", &vec![remote.clone()], &options)
+        .expect("Add should have worked");
+
+    assert!(local.is_dir());
+    assert!(is_symlink(&remote));
+    assert_eq!(fs::read_to_string(remote.join("sub/config")).unwrap(), "hello config");
+
+    cmd_unlink(&env, "app", &Default::default()).expect("Unlink should have worked");
+
+    assert!(!remote.exists());
+    assert!(local.join("sub/config").is_file());
+}
+
+#[cfg(windows)]
+#[test]
+fn add_should_link_files_as_hardlinks() {
+    let (_root, env) = setup();
+    let remote = env.home.join(".gitconfig");
+    let local = env.storage.join("bundle/git/gitconfig");
+
+    let options = AddOptions {
+        link_type: Some(LinkType::Hardlink),
+        ..Default::default()
+    };
+
+    cmd_add(&env, "git", &vec![remote.clone()], &options)
+        .expect("Add should have worked");
+
+    assert!(local.is_file());
+    assert!(remote.is_file());
+    assert!(!is_symlink(&remote));
+
+    // writes through either path should show up in both
+    fs::write(&remote, "changed").unwrap();
+    assert_eq!(fs::read_to_string(&local).unwrap(), "changed");
+
+    let bundle = fs::read_to_string(env.storage.join("bundle/git/bundle.toml")).unwrap();
+    assert!(bundle.contains("link_type = \"hardlink\""));
+}
+
+#[cfg(unix)]
+#[test]
+fn link_should_preserve_mtimes_of_copies() {
+    let (_root, env) = setup();
+    let remote = env.home.join(".config/app");
+    let local = env.storage.join("bundle/app/app");
+
+    cmd_add(&env, "app", &vec![remote.clone()], &Default::default())
+        .expect("Add should have worked");
+    cmd_unlink(&env, "app", &Default::default()).expect("Unlink should have worked");
+
+    // far enough back that a fresh copy can't end up close to it
+    let old = SystemTime::now() - Duration::from_secs(60 * 60 * 24);
+    for it in &["config", "sub/config"] {
+        fs::File::options().write(true).open(local.join(it)).unwrap().set_modified(old).unwrap();
+    }
+
+    let options = LinkOptions {
+        link_type: Some(LinkType::Copy),
+        preserve_mtime: true,
+        ..Default::default()
+    };
+    cmd_link(&env, "app", &options).expect("Link should have worked");
+
+    assert!(!is_symlink(&remote));
+    for it in &["config", "sub/config"] {
+        let copied = fs::metadata(remote.join(it)).unwrap().modified().unwrap();
+        let drift = copied.duration_since(old).unwrap_or_else(|it| it.duration());
+        assert!(drift < Duration::from_secs(1), "{} is off by {:?}", it, drift);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn status_should_tell_hardlinks_from_copies() {
+    use std::os::unix::fs::MetadataExt;
+
+    let (_root, env) = setup();
+    let remote = env.home.join(".gitconfig");
+    let local = env.storage.join("bundle/git/gitconfig");
+
+    let options = AddOptions { link_type: Some(LinkType::Hardlink), ..Default::default() };
+    cmd_add(&env, "git", &vec![remote.clone()], &options).expect("Add should have worked");
+
+    assert_eq!(fs::metadata(&remote).unwrap().ino(), fs::metadata(&local).unwrap().ino());
+    assert!(check_status(&env).unwrap().ok);
+
+    // the same content, but not the same file anymore
+    fs::remove_file(&remote).unwrap();
+    fs::write(&remote, "hello gitconfig").unwrap();
+
+    assert!(!check_status(&env).unwrap().ok);
+}
+
+#[cfg(unix)]
+#[test]
+fn add_should_skip_fifos() {
+    use std::os::unix::ffi::OsStrExt;
+
+    let (_root, env) = setup();
+    let fifo = env.home.join("fifo");
+    let raw = std::ffi::CString::new(fifo.as_os_str().as_bytes()).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(raw.as_ptr(), 0o644) }, 0);
+
+    match cmd_add(&env, "pipes", &vec![fifo.clone()], &Default::default()) {
+        Err(Error::UnsupportedFileType(it)) => assert_eq!(it, fifo),
+        other => panic!("Expected an unsupported file type, got {:?}", other),
+    };
+
+    // the fifo is skipped, everything else is still added
+    let remote = env.home.join(".gitconfig");
+    cmd_add(&env, "pipes", &vec![fifo.clone(), remote.clone()], &Default::default())
+        .expect("Add should have worked");
+
+    assert!(is_symlink(&remote));
+    assert!(!is_symlink(&fifo));
+    assert!(!env.storage.join("bundle/pipes/fifo").exists());
+}